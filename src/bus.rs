@@ -5,7 +5,17 @@
 extern crate sdl2;
 use sdl2::keyboard::{Keycode, Mod};
 
+use cpu;
 use cpu::Cpu;
+use symbols;
+use programs;
+use overlay;
+use overlay::FpsCounter;
+use pacer;
+use pacer::Pacer;
+use png;
+use symbols::SymbolTable;
+use rng::Rng;
 use super::{Screen, EmulatorEvent};
 
 use io::vic;
@@ -16,8 +26,22 @@ use io::sid::Sid;
 
 use io::cia::Cia;
 
+use io::georam;
+use io::georam::GeoRam;
+
+use io::mouse::Mouse1351;
+
+use io::parallel::ParallelCable;
+
+use io::Region;
+
 use std::fs::File;
-use std::io::{Read, Write, stdin, stdout};
+use std::path::{Path, PathBuf};
+use std::panic::{self, AssertUnwindSafe};
+use std::any::Any;
+use std::process;
+use std::io::{self, Read, Write, BufRead, BufReader, stdin, stdout};
+use std::collections::VecDeque;
 
 use std::time::{Instant, Duration};
 use std::thread::sleep;
@@ -31,19 +55,133 @@ const KERNAL_ROM_SIZE: usize = 8192;
 const BASIC_ROM_SIZE: usize = 8192;
 const CHAR_ROM_SIZE: usize = 4096;
 
+const ADDR_MASK: usize = 0xffff;
+
 const IO_START: usize = 0xd000;
 const IO_END: usize = 0xdfff;
 
+// Value read from I/O addresses with nothing connected to them
+const OPEN_BUS_VALUE: u8 = 0xff;
+
 const COLOR_RAM_START: usize = 0xd800;
 const COLOR_RAM_END: usize = 0xdbff;
+const COLOR_RAM_SIZE: usize = 1024;
+
+// Memory snapshots are the 64K RAM followed by the color RAM
+const SNAPSHOT_SIZE: usize = 65536 + COLOR_RAM_SIZE;
+
+// Where a second SID can be mapped. It takes the place of SID mirrors or expansion I/O.
+const EXPANSION_START: usize = 0xde00;
 
 const CIA1_MIN_CONTROL_ADDR: usize = 0xdc00;
 const CIA1_MAX_CONTROL_ADDR: usize = 0xdcff;
 const CIA2_MIN_CONTROL_ADDR: usize = 0xdd00;
 const CIA2_MAX_CONTROL_ADDR: usize = 0xddff;
 
-const SCREEN_X: u32 = 320;
-const SCREEN_Y: u32 = 240;
+const TEXT_COLUMNS: usize = 40;
+const TEXT_ROWS: usize = 25;
+
+// KERNAL keyboard buffer
+const KEYBOARD_BUFFER_ADDR: usize = 0x0277;
+const KEYBOARD_BUFFER_COUNT_ADDR: usize = 0x00c6;
+const KEYBOARD_BUFFER_SIZE: usize = 10;
+
+// Host keys for keys that aren't in the normal layout. RESTORE isn't part of the keyboard matrix;
+// it's wired to the CPU's NMI line.
+const RESTORE_KEY: Keycode = Keycode::PageUp;
+const RUN_STOP_KEY: Keycode = Keycode::Escape;
+
+// Keyboard matrix position (port A line, port B line) of RUN/STOP
+const RUN_STOP_MATRIX_POS: (u8, u8) = (7, 7);
+
+// KERNAL loop that waits for a key once the system has booted
+const KERNAL_WAIT_KEY_START: u16 = 0xe5cd;
+const KERNAL_WAIT_KEY_END: u16 = 0xe5d4;
+
+// The KERNAL's SAVE routine after it has copied the start address to STAL. Saves to the host
+// device are handled here instead.
+const KERNAL_SAVE_ADDR: u16 = 0xf5ed;
+const HOST_DEVICE: u8 = 8;
+
+// Zero page locations SAVE uses
+const SAVE_START_ADDR: usize = 0xc1;    // STAL: first address to save
+const SAVE_END_ADDR: usize = 0xae;      // EAL: address after the last one to save
+const FILE_NAME_LEN_ADDR: usize = 0xb7; // FNLEN
+const DEVICE_ADDR: usize = 0xba;        // FA
+const FILE_NAME_ADDR: usize = 0xbb;     // FNADR
+
+// KERNAL error codes returned in A
+const KERNAL_ERR_NOT_OUTPUT_FILE: u8 = 7;
+const KERNAL_ERR_MISSING_FILE_NAME: u8 = 8;
+
+// Entries in the KERNAL jump table that --kernal-log watches for
+const KERNAL_API: [(u16, &str); 39] = [
+    (0xff81, "CINT"), (0xff84, "IOINIT"), (0xff87, "RAMTAS"), (0xff8a, "RESTOR"),
+    (0xff8d, "VECTOR"), (0xff90, "SETMSG"), (0xff93, "SECOND"), (0xff96, "TKSA"),
+    (0xff99, "MEMTOP"), (0xff9c, "MEMBOT"), (0xff9f, "SCNKEY"), (0xffa2, "SETTMO"),
+    (0xffa5, "ACPTR"), (0xffa8, "CIOUT"), (0xffab, "UNTLK"), (0xffae, "UNLSN"),
+    (0xffb1, "LISTEN"), (0xffb4, "TALK"), (0xffb7, "READST"), (0xffba, "SETLFS"),
+    (0xffbd, "SETNAM"), (0xffc0, "OPEN"), (0xffc3, "CLOSE"), (0xffc6, "CHKIN"),
+    (0xffc9, "CHKOUT"), (0xffcc, "CLRCHN"), (0xffcf, "CHRIN"), (0xffd2, "CHROUT"),
+    (0xffd5, "LOAD"), (0xffd8, "SAVE"), (0xffdb, "SETTIM"), (0xffde, "RDTIM"),
+    (0xffe1, "STOP"), (0xffe4, "GETIN"), (0xffe7, "CLALL"), (0xffea, "UDTIM"),
+    (0xffed, "SCREEN"), (0xfff0, "PLOT"), (0xfff3, "IOBASE"),
+];
+
+// Default number of cycles to wait between injected keypresses (about one frame)
+pub const DEFAULT_INJECT_INTERVAL: u32 = 20000;
+
+// The CPU is considered idle if it stays in a loop this small for a whole PAL frame without
+// writing anything
+const IDLE_LOOP_SIZE: u16 = 8;
+const IDLE_FRAME_CYCLES: u32 = 63 * 312;
+
+// How long to sleep for each idle frame
+const IDLE_SLEEP_MS: u64 = 1;
+
+// How long to wait between checks for events while paused
+const PAUSE_SLEEP_MS: u64 = 10;
+
+// Sample rate of the audio output in Hz
+const AUDIO_SAMPLE_RATE: u32 = 44100;
+
+// Scale from the SID's mixed output to a 16-bit sample. Three voices at full volume fill most of
+// the range.
+const AUDIO_SCALE: i32 = 5;
+
+// Exit status when --max-instructions stops the emulator
+const INSTRUCTION_LIMIT_STATUS: i32 = 2;
+
+// Exit status when --strict-opcodes stops the emulator
+const ILLEGAL_OPCODE_STATUS: i32 = 3;
+
+// Number of instructions shown by the disassemble command
+const DISASSEMBLY_LINES: u16 = 10;
+
+// Names of the VIC-II registers shown by the monitor, starting at $D000
+const VIC_REGISTER_NAMES: [&str; 47] = [
+    "M0X", "M0Y", "M1X", "M1Y", "M2X", "M2Y", "M3X", "M3Y",
+    "M4X", "M4Y", "M5X", "M5Y", "M6X", "M6Y", "M7X", "M7Y",
+    "MSBX", "CR1", "RASTER", "LPX", "LPY", "MXE", "CR2", "MXYE",
+    "MEM", "IRQ", "IRQEN", "MXDP", "MXMC", "MXXE", "MXM", "MXD",
+    "EC", "B0C", "B1C", "B2C", "B3C", "MM0", "MM1",
+    "M0C", "M1C", "M2C", "M3C", "M4C", "M5C", "M6C", "M7C",
+];
+
+// Maximum number of matches the monitor's find command prints
+const MAX_FIND_RESULTS: usize = 32;
+
+// Seed used for random state unless another one is given
+const DEFAULT_SEED: u64 = 0x64;
+
+// Power-on RAM alternates between blocks of $00 and $FF this size
+const POWER_ON_BLOCK_SIZE: usize = 64;
+
+// Number of instructions the debugger can step back through
+const UNDO_HISTORY_SIZE: usize = 1000;
+
+// Number of recent instructions kept for crash reports
+const TRACE_SIZE: usize = 32;
 
 #[derive(PartialEq, Eq)]
 enum SystemMode {
@@ -52,41 +190,308 @@ enum SystemMode {
     DebugStep,
 }
 
+// Sources of data in the CPU's address space
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum MemRegion {
+    CpuPort,
+    Ram,
+    Kernal,
+    Basic,
+    Char,
+    Cartridge,
+    Io(IoDevice),
+}
+
+// Processor port lines that control banking
+const PORT_LORAM: u8 = 0x01;
+const PORT_HIRAM: u8 = 0x02;
+const PORT_CHAREN: u8 = 0x04;
+
+// Where the cartridge ROMs show up
+const ROML_START: usize = 0x8000;
+const ROMH_START: usize = 0xa000;
+const ROMH_ULTIMAX_START: usize = 0xe000;
+const CART_ROM_SIZE: usize = 8192;
+
+// What the CPU sees in each of the banked areas
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct BankConfig {
+    pub kernal: bool,       // KERNAL ROM at $E000-$FFFF
+    pub basic: bool,        // BASIC ROM at $A000-$BFFF
+    pub char_rom: bool,     // Character ROM at $D000-$DFFF
+    pub io: bool,           // I/O at $D000-$DFFF
+    pub roml: bool,         // Cartridge ROM at $8000-$9FFF
+    pub romh: Option<u16>,  // Start of the high cartridge ROM, $A000 or $E000 in Ultimax mode
+}
+
+// Work out the banking from the processor port and the cartridge's GAME and EXROM lines. The
+// banking lines are pulled up, so any of them set as inputs read as high.
+//
+// With no cartridge HIRAM maps in the KERNAL, and BASIC needs LORAM too. $D000-$DFFF is RAM when
+// LORAM and HIRAM are both low. Otherwise CHAREN picks between I/O and the character ROM. An 8K
+// cartridge adds ROML wherever BASIC could be seen with LORAM, and a 16K cartridge also replaces
+// BASIC with ROMH. Ultimax cartridges ignore the port and only leave I/O mapped in.
+pub fn compute_banking(ddr: u8, port: u8, game: bool, exrom: bool) -> BankConfig {
+    if !game && exrom {
+        return BankConfig {
+            kernal: false,
+            basic: false,
+            char_rom: false,
+            io: true,
+            roml: true,
+            romh: Some(ROMH_ULTIMAX_START as u16),
+        };
+    }
+
+    let lines = (port & ddr) | (!ddr & 0x07);
+    let loram = lines & PORT_LORAM != 0;
+    let hiram = lines & PORT_HIRAM != 0;
+    let charen = lines & PORT_CHAREN != 0;
+    BankConfig {
+        kernal: hiram,
+        basic: loram && hiram && game,
+        char_rom: (loram || hiram) && !charen,
+        io: (loram || hiram) && charen,
+        roml: loram && hiram && !exrom,
+        romh: if hiram && !game { Some(ROMH_START as u16) } else { None },
+    }
+}
+
+// Devices in the I/O area
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum IoDevice {
+    Vic,
+    Sid,
+    StereoSid,
+    ColorRam,
+    Cia1,
+    Cia2,
+    Expansion,
+}
+
+// Types of memory access a watchpoint can break on
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+// CPU state at the start of an instruction and the RAM bytes the instruction overwrote
+struct UndoRecord {
+    cpu: Cpu,
+    writes: Vec<(usize, u8)>,
+}
+
+// Everything that changes as the machine runs, so tests can skip the boot sequence
+#[cfg(test)]
+#[derive(Clone)]
+pub struct MachineState {
+    ram: [u8; 65536],
+    color_ram: [u8; COLOR_RAM_SIZE],
+    cpu: Cpu,
+    vic: Vic,
+    sid: Sid,
+    cia_1: Cia,
+    cia_2: Cia,
+    last_raster: u16,
+    instructions: u64,
+}
+
 pub struct Bus {
     mode: SystemMode,
     ram: [u8; 65536],
-    color_ram: [u8; 1024], // Only the 4 low bits of each byte are used
+    color_ram: [u8; COLOR_RAM_SIZE], // Only the 4 low bits of each byte are used
     kernal_rom: [u8; KERNAL_ROM_SIZE],
     basic_rom: [u8; BASIC_ROM_SIZE],
     char_rom: [u8; CHAR_ROM_SIZE],
+    rom_files: Option<(String, String, String)>,    // KERNAL, BASIC, and character ROM files
 
     cpu: Cpu,
     vic: Vic,
     sid: Sid,
+    stereo_sid: Sid,
+    stereo_sid_addr: Option<usize>,    // Base address of the second SID if there is one
+    georam: Option<GeoRam>,
+    mouse: Option<Mouse1351>,   // 1351 mouse in control port 1
+    parallel: Option<ParallelCable>,    // Parallel cable in the user port
+    game: bool,     // Cartridge port lines. Both are pulled high with nothing plugged in.
+    exrom: bool,
     cia_1: Cia,
     cia_2: Cia,
+    cia_2_irq: bool,    // CIA 2's interrupt output as of the last cycle. It's wired to NMI.
+
+    event_log: Option<File>,
+    video_out: Option<File>,
+    audio_out: Option<File>,
+    audio_samples: Vec<u8>,     // Samples waiting to be written out with the next frame
+    audio_phase: u32,           // Goes up by the sample rate every cycle. A sample is due at the clock rate.
+    clock_hz: u32,              // Clock frequency of the chips
+    io_trace: Option<File>,
+    io_trace_devices: Vec<IoDevice>,
+    kernal_log: Option<File>,
+    playback: VecDeque<(u64, EmulatorEvent)>,
+    playback_enabled: bool,
+
+    watchpoints: Vec<(usize, WatchKind)>,
+    breakpoints: Vec<(u16, bool)>,
+    raster_breaks: Vec<u16>,    // Raster lines to break on
+    last_raster: u16,   // Raster line as of the last cycle, so each line only breaks once
+    cycle_exact: bool,
+    fast_boot: bool,
+    quiet: bool,
+
+    jiffy_irq: bool,        // Raise an IRQ every frame in place of the CIA 1 timer
+    jiffy_pending: bool,
+
+    idle_sleep: bool,
+    idle_lo: u16,
+    idle_hi: u16,
+    idle_cycles: u32,
+
+    inject_queue: VecDeque<u8>,
+    inject_interval: u32,
+    inject_wait: u32,
+
+    history: VecDeque<UndoRecord>,
+    instr_pc: u16,  // Address of the instruction the CPU is running
+    instructions: u64,  // Instructions started since power on
+    max_instructions: Option<u64>,  // Stop once this many instructions have finished
+    trace: VecDeque<u16>,   // Addresses of the last few instructions
+    asm_addr: u16,  // Where the monitor's assemble command puts the next instruction
+    vic_step: bool, // Only run the VIC-II on the next cycle
+    fps_overlay: bool,  // Show the frame rate on the screen
+    paused: bool,
+
+    host_dir: Option<PathBuf>,  // Where programs saved to device 8 go
+
+    symbols: SymbolTable,
+    monitor_script: Option<String>, // Monitor commands to run once the CPU is out of reset
+    screenshot: Option<String>, // Where to save the next frame
+
+    exit_dumps: Vec<(u16, usize, String)>,  // Address, length, and file name
+
+    exec_map_file: String,
+    exec_counts: Vec<u32>,  // Times each address was fetched as an opcode. Empty if not counting.
+
+    rng: Rng,   // Source of all randomness so runs can be reproduced
 }
 
 impl Bus {
-    pub fn new(debug: bool) -> Bus {
+    pub fn new(debug: bool, screen_width: u32, screen_height: u32) -> Bus {
         Bus {
             mode: if debug { SystemMode::DebugStep } else { SystemMode::Run },
             ram: [0u8; 65536],
-            color_ram: [0u8; 1024],
+            color_ram: [0u8; COLOR_RAM_SIZE],
             kernal_rom: [0u8; KERNAL_ROM_SIZE],
             basic_rom: [0u8; BASIC_ROM_SIZE],
             char_rom: [0u8; CHAR_ROM_SIZE],
+            rom_files: None,
 
             cpu: Cpu::new(),
-            vic: Vic::new(),
+            vic: Vic::new(screen_width, screen_height),
             sid: Sid::new(),
+            stereo_sid: Sid::new(),
+            stereo_sid_addr: None,
+            georam: None,
+            mouse: None,
+            parallel: None,
+            game: true,
+            exrom: true,
             cia_1: Cia::new(CIA1_MIN_CONTROL_ADDR),
             cia_2: Cia::new(CIA2_MIN_CONTROL_ADDR),
+            cia_2_irq: false,
+
+            event_log: None,
+            video_out: None,
+            audio_out: None,
+            audio_samples: Vec::new(),
+            audio_phase: 0,
+            clock_hz: Region::Pal.clock_hz(),
+            io_trace: None,
+            io_trace_devices: Vec::new(),
+            kernal_log: None,
+            playback: VecDeque::new(),
+            playback_enabled: false,
+
+            watchpoints: Vec::new(),
+            breakpoints: Vec::new(),
+            raster_breaks: Vec::new(),
+            last_raster: 0,
+            cycle_exact: false,
+            fast_boot: false,
+            quiet: false,
+
+            jiffy_irq: false,
+            jiffy_pending: false,
+
+            idle_sleep: false,
+            idle_lo: 0,
+            idle_hi: 0,
+            idle_cycles: 0,
+
+            inject_queue: VecDeque::new(),
+            inject_interval: DEFAULT_INJECT_INTERVAL,
+            inject_wait: 0,
+
+            history: VecDeque::new(),
+            instr_pc: 0,
+            instructions: 0,
+            max_instructions: None,
+            trace: VecDeque::with_capacity(TRACE_SIZE),
+            asm_addr: 0,
+            vic_step: false,
+            fps_overlay: false,
+            paused: false,
+
+            host_dir: None,
+
+            symbols: SymbolTable::new(),
+            monitor_script: None,
+            screenshot: None,
+
+            exit_dumps: Vec::new(),
+
+            exec_map_file: String::new(),
+            exec_counts: Vec::new(),
+
+            rng: Rng::new(DEFAULT_SEED),
+        }
+    }
+
+    // Seed the random state. The SID's noise generators also start from the seed.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+        let noise = self.rng.next_u32();
+        self.sid.seed_noise(noise);
+        let noise = self.rng.next_u32();
+        self.stereo_sid.seed_noise(noise);
+    }
+
+    pub fn clear_ram(&mut self) {
+        self.ram = [0u8; 65536];
+    }
+
+    // Fill RAM with the pattern it has when the machine is switched on. A few bits are random.
+    pub fn fill_ram_pattern(&mut self) {
+        for addr in 0..self.ram.len() {
+            let mut value = if (addr / POWER_ON_BLOCK_SIZE) % 2 == 0 { 0x00 } else { 0xff };
+            if self.rng.next_u8() == 0 {
+                value ^= 1 << (self.rng.next_u8() & 0x07);
+            }
+            self.ram[addr] = value;
         }
     }
 
-    // Write default values into memory
-    pub fn initialize(&mut self, ram_file: &str) {
+    // Set up RAM at power on. An image file replaces the power-on pattern.
+    pub fn initialize(&mut self, ram_file: Option<&str>) {
+        let ram_file = match ram_file {
+            Some(f) => f,
+            None => {
+                self.fill_ram_pattern();
+                return;
+            },
+        };
         let mut file = match File::open(ram_file) {
             Ok(f) => f,
             Err(e) => panic!("Failed to open RAM image file: {}", e)
@@ -101,262 +506,1916 @@ impl Bus {
 
     // Load data for the various ROM chips
     pub fn load_roms(&mut self, kernal_rom_file: &str, basic_rom_file: &str, char_rom_file: &str) {
-        let mut k_file = match File::open(kernal_rom_file) {
+        let kernal = match read_file(kernal_rom_file, "KERNAL ROM") {
+            Ok(d) => d,
+            Err(e) => panic!("{}", e),
+        };
+        let basic = match read_file(basic_rom_file, "BASIC ROM") {
+            Ok(d) => d,
+            Err(e) => panic!("{}", e),
+        };
+        let char_rom = match read_file(char_rom_file, "character ROM") {
+            Ok(d) => d,
+            Err(e) => panic!("{}", e),
+        };
+        if let Err(e) = self.load_roms_from_bytes(&kernal, &basic, &char_rom) {
+            panic!("{}", e);
+        }
+        self.rom_files = Some((kernal_rom_file.to_string(), basic_rom_file.to_string(),
+                               char_rom_file.to_string()));
+    }
+
+    // Read the ROM files again, e.g. after editing a custom KERNAL. A ROM that can't be read or is
+    // the wrong size keeps its old contents.
+    pub fn reload_roms(&mut self) -> Result<(), String> {
+        let (kernal_file, basic_file, char_file) = match self.rom_files.clone() {
+            Some(f) => f,
+            None => return Err("No ROM files loaded".to_string()),
+        };
+
+        let mut errors = Vec::new();
+        match read_rom(&kernal_file, "KERNAL ROM", KERNAL_ROM_SIZE) {
+            Ok(d) => self.kernal_rom.copy_from_slice(&d),
+            Err(e) => errors.push(e),
+        }
+        match read_rom(&basic_file, "BASIC ROM", BASIC_ROM_SIZE) {
+            Ok(d) => self.basic_rom.copy_from_slice(&d),
+            Err(e) => errors.push(e),
+        }
+        match read_rom(&char_file, "character ROM", CHAR_ROM_SIZE) {
+            Ok(d) => self.char_rom.copy_from_slice(&d),
+            Err(e) => errors.push(e),
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    // Copy ROM images into the ROM chips. Each image must be exactly the size of its chip.
+    pub fn load_roms_from_bytes(&mut self, kernal: &[u8], basic: &[u8], char_rom: &[u8]) -> Result<(), String> {
+        if kernal.len() != KERNAL_ROM_SIZE {
+            return Err(format!("KERNAL ROM must be {} bytes, not {}", KERNAL_ROM_SIZE, kernal.len()));
+        }
+        if basic.len() != BASIC_ROM_SIZE {
+            return Err(format!("BASIC ROM must be {} bytes, not {}", BASIC_ROM_SIZE, basic.len()));
+        }
+        if char_rom.len() != CHAR_ROM_SIZE {
+            return Err(format!("Character ROM must be {} bytes, not {}", CHAR_ROM_SIZE, char_rom.len()));
+        }
+
+        self.kernal_rom.copy_from_slice(kernal);
+        self.basic_rom.copy_from_slice(basic);
+        self.char_rom.copy_from_slice(char_rom);
+        Ok(())
+    }
+
+    // Load labels for the debugger
+    pub fn load_symbols(&mut self, symbol_file: &str) {
+        self.symbols.load(symbol_file);
+    }
+
+    #[cfg(test)]
+    pub fn symbols_mut(&mut self) -> &mut SymbolTable {
+        &mut self.symbols
+    }
+
+    // Save the contents of RAM and color RAM to a file
+    pub fn save_snapshot(&self, fname: &str) -> Result<(), String> {
+        let mut data = Vec::with_capacity(SNAPSHOT_SIZE);
+        data.extend_from_slice(&self.ram);
+        data.extend_from_slice(&self.color_ram);
+
+        let mut file = match File::create(fname) {
             Ok(f) => f,
-            Err(e) => panic!("Failed to open KERNAL ROM file: {}", e)
+            Err(e) => return Err(format!("Failed to create snapshot file: {}", e)),
         };
-        match k_file.read(&mut self.kernal_rom) {
-            Ok(_) => { },
+        match file.write_all(&data) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Error writing snapshot file: {}", e)),
+        }
+    }
+
+    // Run a function on the bus. If it panics, write a crash report to a file before passing the
+    // panic on.
+    pub fn catch_crash<F, R>(&mut self, fname: &str, f: F) -> R where F: FnOnce(&mut Bus) -> R {
+        match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(r) => r,
             Err(e) => {
-                panic!("Error reading KERNAL ROM file: {}", e);
+                self.write_crash_report(fname, &*e);
+                panic::resume_unwind(e)
+            },
+        }
+    }
+
+    // Write the machine state and the last few instructions to a text file, and RAM to a snapshot
+    // next to it. Errors are only printed since this happens while crashing anyway.
+    fn write_crash_report(&self, fname: &str, cause: &(dyn Any + Send)) {
+        let message = match cause.downcast_ref::<&str>() {
+            Some(m) => m.to_string(),
+            None => match cause.downcast_ref::<String>() {
+                Some(m) => m.clone(),
+                None => "Unknown panic".to_string(),
             },
+        };
+        let snapshot = Path::new(fname).with_extension("bin");
+
+        let mut report = format!("Emulator crashed: {}\n\n", message);
+        report.push_str(&format!("{:?}\n{:?}\n{}\n\n", self.cpu, self.vic, self.state_json()));
+        report.push_str("Recent instructions:\n");
+        for &addr in &self.trace {
+            // The instruction that crashed might not disassemble either
+            let line = panic::catch_unwind(AssertUnwindSafe(|| self.disassemble(addr).0))
+                .unwrap_or_else(|_| {
+                    format!("${:0>4X}  {:0>2X}  ???", addr, self.read_byte(addr as usize))
+                });
+            report.push_str(&format!("{}\n", line));
         }
+        report.push_str(&format!("\nRAM is in {}. Load it with the monitor's restore command.\n",
+                                 snapshot.display()));
 
-        let mut b_file = match File::open(basic_rom_file) {
+        let result = File::create(fname).and_then(|mut f| f.write_all(report.as_bytes()));
+        match result {
+            Ok(_) => eprintln!("Wrote crash report to {}", fname),
+            Err(e) => eprintln!("Failed to write crash report: {}", e),
+        }
+        if let Err(e) = self.save_snapshot(&snapshot.to_string_lossy()) {
+            eprintln!("{}", e);
+        }
+    }
+
+    // Write a range of memory as the CPU sees it to a file. The range wraps at the end of memory.
+    pub fn dump_memory_to_file(&self, addr: u16, len: usize, fname: &str) -> Result<(), String> {
+        let data: Vec<u8> = (0..len).map(|i| {
+            self.read_byte(addr.wrapping_add(i as u16) as usize)
+        }).collect();
+
+        let mut file = match File::create(fname) {
             Ok(f) => f,
-            Err(e) => panic!("Failed to open BASIC ROM file: {}", e)
+            Err(e) => return Err(format!("Failed to create memory dump file: {}", e)),
         };
-        match b_file.read(&mut self.basic_rom) {
-            Ok(_) => { },
-            Err(e) => {
-                panic!("Error reading BASIC ROM file: {}", e);
-            },
+        match file.write_all(&data) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Error writing memory dump file: {}", e)),
+        }
+    }
+
+    // Dump a range of memory to a file when the emulator shuts down
+    pub fn add_exit_dump(&mut self, addr: u16, len: usize, fname: &str) {
+        self.exit_dumps.push((addr, len, fname.to_string()));
+    }
+
+    // Stop the emulator after this many instructions
+    pub fn set_max_instructions(&mut self, max: u64) {
+        self.max_instructions = Some(max);
+    }
+
+    // Determine whether the instruction limit has been hit. Only true between instructions so the
+    // last one always finishes.
+    pub fn instruction_limit_reached(&self) -> bool {
+        match self.max_instructions {
+            Some(max) => self.instructions >= max && self.cpu.at_instruction_start(),
+            None => false,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn instruction_count(&self) -> u64 {
+        self.instructions
+    }
+
+    // Count how many times each instruction is executed and write the counts to a file on exit
+    pub fn set_exec_map_file(&mut self, fname: &str) {
+        self.exec_map_file = fname.to_string();
+        self.exec_counts = vec![0u32; 65536];
+    }
+
+    #[cfg(test)]
+    pub fn exec_count(&self, addr: u16) -> u32 {
+        match self.exec_counts.get(addr as usize) {
+            Some(&c) => c,
+            None => 0,
         }
+    }
+
+    // Write the disassembly of every executed instruction along with the number of times it
+    // was executed, hottest first
+    pub fn write_exec_map(&self, fname: &str) -> Result<(), String> {
+        let mut hits: Vec<(u16, u32)> = self.exec_counts.iter().enumerate()
+            .filter(|&(_, &c)| c > 0)
+            .map(|(a, &c)| (a as u16, c))
+            .collect();
+        hits.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
 
-        let mut c_file = match File::open(char_rom_file) {
+        let mut file = match File::create(fname) {
             Ok(f) => f,
-            Err(e) => panic!("Failed to open character ROM file: {}", e)
+            Err(e) => return Err(format!("Failed to create execution map file: {}", e)),
         };
-        match c_file.read(&mut self.char_rom) {
-            Ok(_) => { },
-            Err(e) => {
-                panic!("Error reading character ROM file: {}", e);
-            },
+        for (addr, count) in hits {
+            let (text, _) = self.disassemble(addr);
+            if let Err(e) = writeln!(file, "{:>10}  {}", count, text) {
+                return Err(format!("Error writing execution map file: {}", e));
+            }
         }
+        Ok(())
     }
-    
-    // Read a byte from the given address
-    pub fn read_byte(&self, addr: usize) -> u8 {
-        if addr == 0 {
-            return self.cpu.read_ddr();
-        } else if addr == 1 {
-            return self.cpu.read_dataport();
-        }
-
-        if self.cpu.krom_enabled() && addr >= KERNAL_ROM_START && addr < KERNAL_ROM_START + KERNAL_ROM_SIZE
-        {
-            let offset_addr = addr - KERNAL_ROM_START;
-            self.kernal_rom[offset_addr]
-
-        } else if self.cpu.brom_enabled() && addr >= BASIC_ROM_START && addr < BASIC_ROM_START + BASIC_ROM_SIZE {
-            let offset_addr = addr - BASIC_ROM_START;
-            self.basic_rom[offset_addr]
-
-        } else if self.cpu.crom_enabled() && addr >= CHAR_ROM_START && addr < CHAR_ROM_START + CHAR_ROM_SIZE {
-            let offset_addr = addr - CHAR_ROM_START;
-            self.char_rom[offset_addr]
-        } else if self.cpu.io_enabled() && addr >= IO_START && addr <= IO_END {
-            self.io_read(addr)
-        } else {
-            self.ram[addr]
+
+    // Write the files requested for when the emulator shuts down
+    pub fn write_exit_files(&self) {
+        for &(addr, len, ref fname) in &self.exit_dumps {
+            if let Err(e) = self.dump_memory_to_file(addr, len, fname) {
+                println!("{}", e);
+            }
+        }
+        if !self.exec_map_file.is_empty() {
+            if let Err(e) = self.write_exec_map(&self.exec_map_file) {
+                println!("{}", e);
+            }
         }
     }
 
-    // Read from an I/O device
-    fn io_read(&self, addr: usize) -> u8 {
-        if addr >= vic::MIN_CONTROL_ADDR && addr <= vic::MAX_CONTROL_ADDR {
-            self.vic.read_register(addr)
-        } else if addr >= sid::MIN_CONTROL_ADDR && addr <= sid::MAX_CONTROL_ADDR {
-            self.sid.read_register(addr)
-        } else if addr >= COLOR_RAM_START && addr <= COLOR_RAM_END {
-            self.color_ram[addr - COLOR_RAM_START]
-        } else if addr >= CIA1_MIN_CONTROL_ADDR && addr <= CIA1_MAX_CONTROL_ADDR {
-            self.cia_1.read_register(addr)
-        } else if addr >= CIA2_MIN_CONTROL_ADDR && addr <= CIA2_MAX_CONTROL_ADDR {
-            self.cia_2.read_register(addr)
-        } else {
-            panic!("Unimplemented I/O address: ${:0>4X}", addr);
+    // Restore RAM and color RAM from a file made with save_snapshot
+    pub fn load_snapshot(&mut self, fname: &str) -> Result<(), String> {
+        let data = read_file(fname, "snapshot")?;
+        if data.len() != SNAPSHOT_SIZE {
+            return Err(format!("Invalid snapshot size: {} bytes", data.len()));
+        }
+
+        self.ram.copy_from_slice(&data[..65536]);
+        for (i, c) in data[65536..].iter().enumerate() {
+            self.color_ram[i] = c & 0x0f;
         }
+        Ok(())
     }
 
-    // Write a byte to the given address
-    pub fn write_byte(&mut self, addr: usize, value: u8) {
-        if addr == 0 {
-            self.cpu.write_ddr(value);
-        } else if addr == 1 {
-            self.cpu.write_dataport(value);
-        } else {
-            let io_enabled = (self.cpu.read_dataport() & 7) > 4;
+    // Save the state of RAM, the CPU, and the chips
+    #[cfg(test)]
+    pub fn save_state(&self) -> MachineState {
+        MachineState {
+            ram: self.ram,
+            color_ram: self.color_ram,
+            cpu: self.cpu.clone(),
+            vic: self.vic.clone(),
+            sid: self.sid.clone(),
+            cia_1: self.cia_1.clone(),
+            cia_2: self.cia_2.clone(),
+            last_raster: self.last_raster,
+            instructions: self.instructions,
+        }
+    }
+
+    // Go back to a state made with save_state. The ROMs have to be the ones it was saved with.
+    #[cfg(test)]
+    pub fn restore_state(&mut self, state: &MachineState) {
+        self.ram = state.ram;
+        self.color_ram = state.color_ram;
+        self.cpu = state.cpu.clone();
+        self.vic = state.vic.clone();
+        self.sid = state.sid.clone();
+        self.cia_1 = state.cia_1.clone();
+        self.cia_2 = state.cia_2.clone();
+        self.last_raster = state.last_raster;
+        self.instructions = state.instructions;
+    }
+
+    // Load a PRG file at the address in its header. Returns the address range that was loaded.
+    #[cfg(test)]
+    pub fn load_prg(&mut self, fname: &str) -> Result<(u16, u16), String> {
+        self.load_program(fname, 0).map(|(_, start, end)| (start, end))
+    }
 
-            if io_enabled && addr >= IO_START && addr <= IO_END {
-                self.io_write(addr, value);
+    // Load a program from a PRG, P00, or T64 file. T64 images can have more than one program, so
+    // this picks one by its index. Returns the name of the program and the address range that
+    // was loaded.
+    pub fn load_program(&mut self, fname: &str, index: usize) -> Result<(String, u16, u16), String> {
+        let data = read_file(fname, "PRG")?;
+        let mut found = programs::parse_programs(&data)?;
+        if index >= found.len() {
+            return Err(format!("No program {} in {} ({} found)", index, fname, found.len()));
+        }
+        let program = found.swap_remove(index);
+
+        let start = program.load_addr as usize;
+        let mut addr = start;
+        for &byte in &program.data {
+            addr &= ADDR_MASK;
+            if (COLOR_RAM_START..=COLOR_RAM_END).contains(&addr) {
+                // Colors for the screen go into color RAM no matter how memory is banked
+                self.color_ram[addr - COLOR_RAM_START] = byte & 0x0f;
             } else {
-                // System always writes to RAM even if it's masked by a ROM
-                self.ram[addr] = value;
+                self.ram[addr] = byte;
             }
+            addr += 1;
         }
+        Ok((program.name, start as u16, (addr & ADDR_MASK) as u16))
     }
 
-    // Write to an I/O device
-    fn io_write(&mut self, addr: usize, value: u8) {
-        if addr >= vic::MIN_CONTROL_ADDR && addr <= vic::MAX_CONTROL_ADDR {
-            self.vic.write_register(addr, value);
-        } else if addr >= sid::MIN_CONTROL_ADDR && addr <= sid::MAX_CONTROL_ADDR {
-            self.sid.write_register(addr, value);
-        } else if addr >= COLOR_RAM_START && addr <= COLOR_RAM_END {
-            self.color_ram[addr - COLOR_RAM_START] = value & 0x0f;
-        } else if addr >= CIA1_MIN_CONTROL_ADDR && addr <= CIA1_MAX_CONTROL_ADDR {
-            self.cia_1.write_register(addr, value);
-        } else if addr >= CIA2_MIN_CONTROL_ADDR && addr <= CIA2_MAX_CONTROL_ADDR {
-            self.cia_2.write_register(addr, value);
-        } else {
-            panic!("Unimplemented I/O address: ${:0>4X}", addr);
+    // Set the timing of the chips that depend on the video standard
+    pub fn set_region(&mut self, region: Region) {
+        self.clock_hz = region.clock_hz();
+        self.vic.set_region(region);
+        self.sid.set_region(region);
+        self.stereo_sid.set_region(region);
+        self.cia_1.set_region(region);
+        self.cia_2.set_region(region);
+    }
+
+    // Map a second SID at an address in the SID's mirrors or the expansion I/O area. The address
+    // has to be on a register block boundary.
+    pub fn set_stereo_sid(&mut self, addr: usize) -> Result<(), String> {
+        let in_mirrors = (sid::MIN_CONTROL_ADDR + 1..=sid::MAX_CONTROL_ADDR).contains(&addr);
+        let in_expansion = (EXPANSION_START..=IO_END).contains(&addr);
+        if !(in_mirrors || in_expansion) || addr % sid::CONTROL_REG_COUNT != 0 {
+            return Err(format!("Invalid address for second SID: ${:0>4X}", addr));
         }
+        self.stereo_sid_addr = Some(addr);
+        Ok(())
     }
 
-    // Convert a 14-bit VIC-II address to a 16-bit address
-    fn convert_vic_ii_addr(&self, addr: u16) -> usize {
-        // Two high bits come from port A on CIA 2
-        let high_bits = (!self.read_byte(CIA2_MIN_CONTROL_ADDR)) & 0x03;
-        let bank = 0x4000 * (high_bits as u16);
-        (bank + (addr & 0x3ff)) as usize
+    // Plug in a GEORAM expansion. The size is in K and has to be a power of two.
+    pub fn set_georam(&mut self, size_kb: usize) -> Result<(), String> {
+        if !size_kb.is_power_of_two() || !(georam::MIN_SIZE_KB..=georam::MAX_SIZE_KB).contains(&size_kb) {
+            return Err(format!("Invalid GEORAM size: {}K", size_kb));
+        }
+        self.georam = Some(GeoRam::new(size_kb));
+        Ok(())
     }
 
-    pub fn run(&mut self, clock_speed_mhz: u32, screen_tx: Sender<Screen>, event_rx: Receiver<EmulatorEvent>) {
-        self.cpu.reset();
-        let mut cycles: u64 = 0;
+    // Move the paddles plugged into the control ports
+    pub fn set_paddles(&mut self, x: u8, y: u8) {
+        self.sid.set_paddles(x, y);
+    }
 
-        let total_t = Instant::now();
-        let mut idle_time = Duration::new(0, 0);
-        let idle_step = Duration::new(0, 100);
+    // Plug a 1351 mouse into control port 1
+    pub fn set_mouse(&mut self, mouse: bool) {
+        self.mouse = if mouse { Some(Mouse1351::new()) } else { None };
+    }
 
-        let mut screen = Screen::new(SCREEN_X, SCREEN_Y);
+    // Move the 1351 mouse and set its buttons. The buttons show up on the joystick lines right
+    // away, but the position only reaches the POT registers once a frame.
+    pub fn mouse_event(&mut self, dx: i32, dy: i32, left: bool, right: bool) {
+        if let Some(ref mut mouse) = self.mouse {
+            mouse.move_by(dx, dy);
+            mouse.set_buttons(left, right);
+            self.cia_1.set_port_b_input(mouse.port_lines());
+        }
+    }
 
-        'emulator: loop {
-            // Get events from the main thread
-            if let Ok(e) = event_rx.try_recv() {
-                match e {
-                    EmulatorEvent::Key(keycode, m) => {
-                        // TODO: Handle keyboard events with CIA1
-                    },
-                    EmulatorEvent::Quit => {
-                        break 'emulator;
-                    },
-                }
-            }
+    // Plug a parallel cable into the user port that sends a file a byte at a time
+    pub fn set_parallel_input(&mut self, fname: &str) -> Result<(), String> {
+        let data = read_file(fname, "parallel input")?;
+        self.parallel = Some(ParallelCable::new(data));
+        Ok(())
+    }
 
-            // Run the VIC-II
-            let addr = self.convert_vic_ii_addr(self.vic.read_addr_bus());
-            let byte = self.read_byte(addr);
-            let color = self.color_ram[addr & 0x03ff];  // Lowest 10 bits of addr always point to color RAM
+    // Move the joystick in control port 2. It's on port A of CIA 1 alongside the keyboard columns.
+    pub fn joystick_event(&mut self, lines: u8) {
+        self.cia_1.set_port_a_input(lines);
+    }
 
-            self.vic.data_in(byte);
-            self.vic.color_in(color);
+    // Leave a SID voice (1-3) out of the mix
+    pub fn set_voice_muted(&mut self, voice: u8, muted: bool) {
+        self.sid.set_voice_muted(voice, muted);
+    }
 
-            if self.mode == SystemMode::Run {
-                self.vic.rising_edge(&mut screen, false);
-            } else {
-                self.vic.rising_edge(&mut screen, true);
-            }
+    // Let the VIC-II stall the CPU for bad lines
+    pub fn set_cycle_exact(&mut self, cycle_exact: bool) {
+        self.cycle_exact = cycle_exact;
+    }
 
-            // Is the CPU allowed to use the bus or does the VIC need both clock edges?
-            if self.vic.aec() {
-                if !self.vic.irq() && self.vic.rdy() {
-                    self.cpu.trigger_interrupt();
-                }
+    // Run at full speed until the KERNAL has finished booting
+    pub fn set_fast_boot(&mut self, fast_boot: bool) {
+        self.fast_boot = fast_boot;
+    }
 
-                // Read/write the CPU data bus
-                if self.cpu.addr_enable {
-                    let addr = self.cpu.addr_bus as usize;
-                    if self.cpu.rw {
-                        let byte = self.read_byte(addr);
-                        self.cpu.data_in(byte);
-                    } else {
-                        let data = self.cpu.data_out();
-                        self.write_byte(addr, data);
-                    }
-                }
-                if self.mode == SystemMode::Run {
-                    self.cpu.cycle(false);
-                } else {
-                    self.cpu.cycle(true);
-                }
-            } else if self.mode == SystemMode::Run {
-                self.vic.falling_edge(&mut screen, false);
-            } else {
-                self.vic.falling_edge(&mut screen, true);
-            }
+    // Don't print status information while the system is running
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
 
-            if self.mode != SystemMode::Run {
-                let elapsed = total_t.elapsed();
-                let total_time_ms = (elapsed.as_secs() * 1000) + ((elapsed.subsec_nanos() / 1_000_000) as u64);
-                let speed = (cycles as f32) / (total_time_ms as f32);
-                println!("----------");
-                println!("  Mean Clock speed: {:8.3} kHz", speed);
-                println!("{:?}", self.cpu);
-                println!("{:?}", self.vic);
-                println!("----------");
+    // Determine whether to print the periodic clock speed and CPU state. Only the debugger shows
+    // it, and not at all when quiet.
+    pub fn show_status(&self) -> bool {
+        self.mode != SystemMode::Run && !self.quiet
+    }
 
-                if self.mode == SystemMode::DebugStep {
-                    print!("] ");
-                    match stdout().flush() {
-                        Ok(_) => { },
-                        Err(e) => { println!("Error flushing STDOUT: {:?}", e); }
-                    }
+    // Interrupt the CPU once a frame so the KERNAL's jiffy clock runs. This stands in for the
+    // CIA 1 timer interrupt until that's connected.
+    pub fn set_jiffy_irq(&mut self, jiffy_irq: bool) {
+        self.jiffy_irq = jiffy_irq;
+    }
 
-                    let mut input = String::new();
-                    match stdin().read_line(&mut input) {
-                        Ok(_) => { },
-                        Err(e) => { panic!("Error reading STDIN: {}", e); },
-                    }
-                    
-                    match input.trim() {
-                        "r" | "run" => {
-                            self.mode = SystemMode::DebugRun;
-                        },
-                        "h" | "help" => {
-                            println!("Help not implemented");
-                        },
-                        "" => {
-                        },
-                        _ => {
-                            println!("Invalid command");
-                        }
-                    }
-                }
-            } else if idle_time.subsec_nanos() > 0 {
-                sleep(idle_time);
-            }
+    // Set the magic constant the CPU uses for the unstable LAX #imm instruction
+    pub fn set_lax_magic(&mut self, magic: u8) {
+        self.cpu.set_lax_magic(magic);
+    }
 
-            // Send a frame to the main thread if one is ready
-            if self.vic.frame_ready() {
-                match screen_tx.send(screen.clone()) {
-                    Ok(_) => continue,
-                    Err(e) => panic!("Error sending screen data: {}", e),
-                }
-            }
+    pub fn set_strict_opcodes(&mut self, strict: bool) {
+        self.cpu.set_strict_opcodes(strict);
+    }
 
-            cycles = cycles.wrapping_add(1);
+    // Draw the VIC-II's test pattern instead of real video
+    pub fn set_vic_test_pattern(&mut self, test_pattern: bool) {
+        self.vic.set_test_pattern(test_pattern);
+    }
 
-            // Sample the speed every 10k cycles to make sure the clock speed isn't too fast
-            if cycles % 10000 == 0 {
-                let elapsed = total_t.elapsed();
-                let total_time_ms = (elapsed.as_secs() * 1000) + ((elapsed.subsec_nanos() / 1_000_000) as u64);
-                let speed = (cycles as f32) / (total_time_ms as f32);
+    // Save programs written to device 8 as PRG files in a directory
+    pub fn set_host_dir(&mut self, dir: &str) {
+        self.host_dir = Some(PathBuf::from(dir));
+    }
 
-                if speed > (clock_speed_mhz as f32) / 1_000_000f32 {
-                    idle_time += idle_step;
-                } else if idle_time > Duration::new(0, 0) {
-                    idle_time -= idle_step;
-                }
+    // Sleep while the CPU is stuck in a wait loop to save host CPU time
+    pub fn set_idle_sleep(&mut self, idle_sleep: bool) {
+        self.idle_sleep = idle_sleep;
+    }
 
-                if self.mode != SystemMode::Run {
-                    println!("Ideal clock speed: {} kHz", clock_speed_mhz/1_000_000);
-                    println!("Mean clock speed:  {} kHz", speed);
-                    println!("Idle time: {} ns", idle_time.subsec_nanos());
-                    println!("{:?}", self.cpu);
-                }
-            }
+    // Determine whether the CPU has been spinning in a tight loop for at least a frame
+    pub fn cpu_idle(&self) -> bool {
+        self.idle_cycles >= IDLE_FRAME_CYCLES
+    }
+
+    // Keep track of how long the CPU has been in the same small loop
+    fn update_idle(&mut self) {
+        // Keep the count between one and two frames once the CPU is idle so it can't overflow
+        self.idle_cycles += 1;
+        if self.idle_cycles >= 2 * IDLE_FRAME_CYCLES {
+            self.idle_cycles -= IDLE_FRAME_CYCLES;
         }
+        if !self.cpu.at_instruction_start() {
+            return;
+        }
+
+        let pc = self.cpu.pc();
+        let lo = if pc < self.idle_lo { pc } else { self.idle_lo };
+        let hi = if pc > self.idle_hi { pc } else { self.idle_hi };
+        if hi - lo > IDLE_LOOP_SIZE {
+            self.idle_lo = pc;
+            self.idle_hi = pc;
+            self.idle_cycles = 0;
+        } else {
+            self.idle_lo = lo;
+            self.idle_hi = hi;
+        }
+    }
+
+    // Determine whether the KERNAL has finished starting up and is waiting for input
+    pub fn is_booted(&self) -> bool {
+        let pc = self.cpu.pc();
+        self.banking().kernal && (KERNAL_WAIT_KEY_START..=KERNAL_WAIT_KEY_END).contains(&pc)
+    }
+
+    // Run KERNAL routines that need the host instead of letting the CPU run them
+    fn check_kernal_traps(&mut self) {
+        let pc = self.cpu.pc();
+        if self.banking().kernal && pc == KERNAL_SAVE_ADDR && self.ram[DEVICE_ADDR] == HOST_DEVICE {
+            let result = self.save_to_host();
+            if let Err(ref e) = result {
+                println!("SAVE failed: {}", e.1);
+            }
+            let error = result.err().map(|e| e.0);
+            // The stack is always in RAM
+            self.cpu.trap_return(&self.ram, error);
+        }
+    }
+
+    // Write the memory the KERNAL was asked to save to a PRG file. CBM DOS only replaces a file
+    // when its name starts with '@'. Returns the KERNAL error code and a description on failure.
+    fn save_to_host(&mut self) -> Result<(), (u8, String)> {
+        let len = self.ram[FILE_NAME_LEN_ADDR] as usize;
+        let name_addr = word_at(&self.ram, FILE_NAME_ADDR) as usize;
+        let name: String = (0..len).map(|i| {
+            petscii_to_ascii(self.read_byte(name_addr + i))
+        }).collect();
+
+        // Drop the replace flag and drive number from names like "@0:NAME"
+        let replace = name.starts_with('@');
+        let name = name.trim_start_matches('@');
+        let name = match name.find(':') {
+            Some(i) => &name[i + 1..],
+            None => name,
+        };
+        if name.is_empty() {
+            return Err((KERNAL_ERR_MISSING_FILE_NAME, "Missing file name".to_string()));
+        }
+
+        let mut path = self.host_dir.clone().unwrap_or_default();
+        path.push(format!("{}.prg", name.to_lowercase()));
+        if path.exists() && !replace {
+            return Err((KERNAL_ERR_NOT_OUTPUT_FILE, format!("{} already exists", path.display())));
+        }
+
+        let start = word_at(&self.ram, SAVE_START_ADDR);
+        let end = word_at(&self.ram, SAVE_END_ADDR);
+        let mut data = vec![start as u8, (start >> 8) as u8];
+        let mut addr = start;
+        while addr != end {
+            data.push(self.read_byte(addr as usize));
+            addr = addr.wrapping_add(1);
+        }
+
+        let result = File::create(&path).and_then(|mut f| f.write_all(&data));
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err((KERNAL_ERR_NOT_OUTPUT_FILE, format!("{}: {}", path.display(), e))),
+        }
+    }
+
+    // Queue text to be typed into the KERNAL keyboard buffer
+    pub fn inject_text(&mut self, text: &str) {
+        for c in text.chars() {
+            if let Some(p) = ascii_to_petscii(c) {
+                self.inject_queue.push_back(p);
+            }
+        }
+    }
+
+    // Set the minimum number of cycles between injected keypresses
+    pub fn set_inject_interval(&mut self, cycles: u32) {
+        self.inject_interval = cycles;
+    }
+
+    // Handle a key being pressed or released on the host
+    // TODO: Map the rest of the keyboard to the matrix
+    pub fn key_event(&mut self, keycode: Keycode, _m: Mod, pressed: bool) {
+        if keycode == RESTORE_KEY {
+            // The KERNAL's NMI handler checks for RUN/STOP to do a warm start
+            if pressed {
+                self.cpu.trigger_nmi();
+            }
+        } else if keycode == RUN_STOP_KEY {
+            let (col, row) = RUN_STOP_MATRIX_POS;
+            self.cia_1.set_key(col, row, pressed);
+        }
+    }
+
+    // Interrupt the CPU like a freezer cartridge and break into the debugger when it enters the
+    // NMI handler
+    pub fn freeze(&mut self) {
+        let vector = cpu::NMI_VEC_ADDR as usize;
+        let handler = (self.read_byte(vector) as u16) | ((self.read_byte(vector + 1) as u16) << 8);
+        println!("Freezing. NMI handler at ${:0>4X}", handler);
+        self.add_breakpoint(handler, true);
+        self.cpu.trigger_nmi();
+    }
+
+    // Put the next injected character in the keyboard buffer if the KERNAL has made room for it
+    fn feed_keyboard_buffer(&mut self) {
+        if self.inject_queue.is_empty() {
+            return;
+        } else if self.inject_wait > 0 {
+            self.inject_wait -= 1;
+            return;
+        }
+
+        let count = self.ram[KEYBOARD_BUFFER_COUNT_ADDR] as usize;
+        if count < KEYBOARD_BUFFER_SIZE {
+            if let Some(c) = self.inject_queue.pop_front() {
+                self.ram[KEYBOARD_BUFFER_ADDR + count] = c;
+                self.ram[KEYBOARD_BUFFER_COUNT_ADDR] = (count + 1) as u8;
+                self.inject_wait = self.inject_interval;
+            }
+        }
+    }
+
+    // Log every event received from the main thread to a file
+    pub fn record_events(&mut self, log_file: &str) -> io::Result<()> {
+        self.event_log = Some(File::create(log_file)?);
+        Ok(())
+    }
+
+    // Write every frame to a file or pipe as raw 320x240 RGB24 video
+    pub fn set_video_out(&mut self, fname: &str) -> io::Result<()> {
+        self.video_out = Some(File::create(fname)?);
+        Ok(())
+    }
+
+    // Write the SID output to a file or pipe as raw signed 16-bit little-endian stereo samples
+    pub fn set_audio_out(&mut self, fname: &str) -> io::Result<()> {
+        self.audio_out = Some(File::create(fname)?);
+        Ok(())
+    }
+
+    // Log every write to the registers of the given devices to a file
+    pub fn set_io_trace(&mut self, devices: &[IoDevice], fname: &str) -> io::Result<()> {
+        self.io_trace = Some(File::create(fname)?);
+        self.io_trace_devices = devices.to_vec();
+        Ok(())
+    }
+
+    fn trace_io_write(&mut self, device: IoDevice, addr: usize, value: u8) {
+        if !self.io_trace_devices.contains(&device) {
+            return;
+        }
+        if let Some(ref mut f) = self.io_trace {
+            let line = format!("${:0>4X}  {:?} ${:0>4X} = ${:0>2X}\n", self.instr_pc, device, addr, value);
+            if let Err(e) = f.write_all(line.as_bytes()) {
+                println!("Error writing I/O trace: {}", e);
+            }
+        }
+    }
+
+    // Log calls to the KERNAL jump table to a file
+    pub fn set_kernal_log(&mut self, fname: &str) -> io::Result<()> {
+        self.kernal_log = Some(File::create(fname)?);
+        Ok(())
+    }
+
+    // Log the registers if the CPU is about to run a KERNAL entry point, along with the address
+    // of the instruction that got it there
+    fn log_kernal_call(&mut self) {
+        let pc = self.cpu.pc();
+        if !self.banking().kernal || pc < KERNAL_API[0].0 {
+            return;
+        }
+        let name = match KERNAL_API.iter().find(|&&(addr, _)| addr == pc) {
+            Some(&(_, name)) => name,
+            None => return,
+        };
+        let caller = if self.trace.len() > 1 { self.trace[self.trace.len() - 2] } else { pc };
+        let (a, x, y) = self.cpu.registers();
+
+        let mut line = format!("${:0>4X}  {} A=${:0>2X} X=${:0>2X} Y=${:0>2X}", caller, name, a, x, y);
+        if name == "CHROUT" && (0x20..0x7f).contains(&a) {
+            line.push_str(&format!(" '{}'", a as char));
+        }
+        if let Some(ref mut f) = self.kernal_log {
+            if let Err(e) = writeln!(f, "{}", line) {
+                println!("Error writing KERNAL log: {}", e);
+            }
+        }
+    }
+
+    fn write_video_frame(&mut self, screen: &Screen) {
+        if let Some(ref mut f) = self.video_out {
+            if let Err(e) = f.write_all(&screen.rgb24_data()) {
+                println!("Error writing video frame: {}", e);
+            }
+        }
+    }
+
+    // Take a sample of the SIDs' output if one is due. The first SID is on the left and the second
+    // one is on the right. Without a second SID both channels get the first one.
+    fn sample_audio(&mut self) {
+        self.audio_phase += AUDIO_SAMPLE_RATE;
+        if self.audio_phase < self.clock_hz {
+            return;
+        }
+        self.audio_phase -= self.clock_hz;
+
+        let left = self.sid.output();
+        let right = if self.stereo_sid_addr.is_some() { self.stereo_sid.output() } else { left };
+        for &s in &[left, right] {
+            let sample = (s * AUDIO_SCALE) as i16;
+            self.audio_samples.push(sample as u8);
+            self.audio_samples.push((sample >> 8) as u8);
+        }
+    }
+
+    fn write_audio_frame(&mut self) {
+        if let Some(ref mut f) = self.audio_out {
+            if let Err(e) = f.write_all(&self.audio_samples) {
+                println!("Error writing audio: {}", e);
+            }
+        }
+        self.audio_samples.clear();
+    }
+
+    // Load a list of events to replay instead of reading live input
+    pub fn load_playback(&mut self, log_file: &str) -> Result<(), String> {
+        let file = match File::open(log_file) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Failed to open playback file: {}", e)),
+        };
+        let mut events = VecDeque::new();
+        for line in BufReader::new(file).lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(e) => return Err(format!("Error reading playback file: {}", e)),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match EmulatorEvent::from_log_line(&line) {
+                Some(event) => events.push_back(event),
+                None => return Err(format!("Invalid event in playback file: {}", line)),
+            }
+        }
+        self.playback = events;
+        self.playback_enabled = true;
+        Ok(())
+    }
+
+    // Get the next event from the main thread or the playback list
+    fn next_event(&mut self, cycles: u64, event_rx: &Receiver<EmulatorEvent>) -> Option<EmulatorEvent> {
+        let live_event = event_rx.try_recv().ok();
+        if !self.playback_enabled {
+            return live_event;
+        }
+
+        // Live input is ignored during playback, but the user can still quit or pause
+        match live_event {
+            Some(EmulatorEvent::Quit) | Some(EmulatorEvent::Pause) | Some(EmulatorEvent::Resume) => {
+                return live_event;
+            },
+            _ => { },
+        }
+        let ready = match self.playback.front() {
+            Some(&(c, _)) => c <= cycles,
+            None => false,
+        };
+        if ready {
+            self.playback.pop_front().map(|(_, e)| e)
+        } else {
+            None
+        }
+    }
+
+    // Get the current banking configuration
+    pub fn banking(&self) -> BankConfig {
+        compute_banking(self.cpu.read_ddr(), self.cpu.read_dataport(), self.game, self.exrom)
+    }
+
+    // Determine what the CPU sees at an address with the current banking configuration
+    pub fn region_at(&self, addr: u16) -> MemRegion {
+        let addr = addr as usize;
+        let banking = self.banking();
+        let in_romh = match banking.romh {
+            Some(start) => (start as usize..start as usize + CART_ROM_SIZE).contains(&addr),
+            None => false,
+        };
+        if addr < 2 {
+            MemRegion::CpuPort
+        } else if in_romh || (banking.roml && (ROML_START..ROML_START + CART_ROM_SIZE).contains(&addr)) {
+            MemRegion::Cartridge
+        } else if banking.kernal && (KERNAL_ROM_START..KERNAL_ROM_START + KERNAL_ROM_SIZE).contains(&addr) {
+            MemRegion::Kernal
+        } else if banking.basic && (BASIC_ROM_START..BASIC_ROM_START + BASIC_ROM_SIZE).contains(&addr) {
+            MemRegion::Basic
+        } else if banking.char_rom && (CHAR_ROM_START..CHAR_ROM_START + CHAR_ROM_SIZE).contains(&addr) {
+            MemRegion::Char
+        } else if banking.io && (IO_START..=IO_END).contains(&addr) {
+            MemRegion::Io(self.io_device_at(addr))
+        } else {
+            MemRegion::Ram
+        }
+    }
+
+    // Read a byte from the given address
+    pub fn read_byte(&self, addr: usize) -> u8 {
+        // Addresses wrap around the 16-bit address space
+        let addr = addr & ADDR_MASK;
+        match self.region_at(addr as u16) {
+            MemRegion::CpuPort => {
+                if addr == 0 {
+                    self.cpu.read_ddr()
+                } else {
+                    self.cpu.read_dataport()
+                }
+            },
+            MemRegion::Kernal => self.kernal_rom[addr - KERNAL_ROM_START],
+            MemRegion::Basic => self.basic_rom[addr - BASIC_ROM_START],
+            MemRegion::Char => self.char_rom[addr - CHAR_ROM_START],
+            // There's no cartridge ROM to read yet
+            MemRegion::Cartridge => OPEN_BUS_VALUE,
+            MemRegion::Io(device) => self.io_read(device, addr),
+            MemRegion::Ram => self.ram[addr],
+        }
+    }
+
+    // Determine which device handles an address in the I/O area. A second SID covers whatever was
+    // there before.
+    fn io_device_at(&self, addr: usize) -> IoDevice {
+        match self.stereo_sid_addr {
+            Some(base) if (base..base + sid::CONTROL_REG_COUNT).contains(&addr) => IoDevice::StereoSid,
+            _ => io_device_at(addr),
+        }
+    }
+
+    // Read from an I/O device
+    fn io_read(&self, device: IoDevice, addr: usize) -> u8 {
+        match device {
+            IoDevice::Vic => self.vic.read_register(addr),
+            IoDevice::Sid => self.sid.read_register(addr),
+            IoDevice::StereoSid => self.stereo_sid.read_register(addr),
+            IoDevice::ColorRam => self.color_ram[addr - COLOR_RAM_START],
+            IoDevice::Cia1 => self.cia_1.read_register(addr),
+            IoDevice::Cia2 => self.cia_2.read_register(addr),
+            // Nothing drives the bus in the expansion I/O areas unless there's a cartridge
+            IoDevice::Expansion => match self.georam {
+                Some(ref georam) => georam.read_register(addr).unwrap_or(OPEN_BUS_VALUE),
+                None => OPEN_BUS_VALUE,
+            },
+        }
+    }
+
+    // Write a byte to the given address
+    pub fn write_byte(&mut self, addr: usize, value: u8) {
+        let addr = addr & ADDR_MASK;
+        match self.region_at(addr as u16) {
+            MemRegion::CpuPort => {
+                if addr == 0 {
+                    self.cpu.write_ddr(value);
+                } else {
+                    self.cpu.write_dataport(value);
+                }
+            },
+            MemRegion::Io(device) => {
+                self.io_write(device, addr, value);
+            },
+            _ => {
+                // System always writes to RAM even if it's masked by a ROM
+                self.ram[addr] = value;
+            },
+        }
+    }
+
+    // Write to an I/O device
+    fn io_write(&mut self, device: IoDevice, addr: usize, value: u8) {
+        if self.io_trace.is_some() {
+            self.trace_io_write(device, addr, value);
+        }
+        match device {
+            IoDevice::Vic => self.vic.write_register(addr, value),
+            IoDevice::Sid => self.sid.write_register(addr, value),
+            IoDevice::StereoSid => self.stereo_sid.write_register(addr, value),
+            IoDevice::ColorRam => { self.color_ram[addr - COLOR_RAM_START] = value & 0x0f; },
+            IoDevice::Cia1 => self.cia_1.write_register(addr, value),
+            IoDevice::Cia2 => self.cia_2.write_register(addr, value),
+            IoDevice::Expansion => {
+                if let Some(ref mut georam) = self.georam {
+                    georam.write_register(addr, value);
+                }
+            },
+        }
+    }
+
+    // Get the start address of the 16K bank the VIC-II can see
+    fn vic_bank(&self) -> u16 {
+        // Two high bits come from port A on CIA 2. The VIC is wired straight to the CIA, so this
+        // works even when the CPU has I/O banked out.
+        let high_bits = (!self.cia_2.read_register(CIA2_MIN_CONTROL_ADDR)) & 0x03;
+        0x4000 * (high_bits as u16)
+    }
+
+    // Convert a 14-bit VIC-II address to a 16-bit address
+    fn convert_vic_ii_addr(&self, addr: u16) -> usize {
+        (self.vic_bank() + (addr & 0x3fff)) as usize
+    }
+
+    // Read the text on the screen as a string of 25 lines of 40 characters
+    pub fn dump_screen_text(&self) -> String {
+        let base = self.vic.video_matrix_base();
+        let lowercase = self.vic.lowercase_chars();
+
+        let mut text = String::with_capacity((TEXT_COLUMNS + 1) * TEXT_ROWS);
+        for row in 0..TEXT_ROWS {
+            for col in 0..TEXT_COLUMNS {
+                let addr = self.convert_vic_ii_addr(base + (row * TEXT_COLUMNS + col) as u16);
+                text.push(screen_code_to_char(self.ram[addr], lowercase));
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    // Run a debug monitor command
+    pub fn debug_command(&mut self, input: &str) {
+        let mut args = input.split_whitespace();
+        let command = args.next().unwrap_or("");
+        match command {
+            "r" | "run" => {
+                self.mode = SystemMode::DebugRun;
+            },
+            "s" | "screen" => {
+                print!("{}", self.dump_screen_text());
+            },
+            "m" | "mem" => {
+                match args.next().and_then(|a| self.lookup_addr(a)) {
+                    Some(a) => {
+                        self.dump_memory(a as u16, 8);
+                    },
+                    None => {
+                        println!("Usage: mem $ADDR");
+                    },
+                }
+            },
+            "d" | "disasm" => {
+                // Disassemble from the PC if there's no address
+                let start = match args.next() {
+                    Some(a) => self.lookup_addr(a),
+                    None => Some(self.cpu.pc() as usize),
+                };
+                match start {
+                    Some(a) => {
+                        let mut addr = a as u16;
+                        for _ in 0..DISASSEMBLY_LINES {
+                            let (text, len) = self.disassemble(addr);
+                            println!("{}", text);
+                            addr = addr.wrapping_add(len);
+                        }
+                    },
+                    None => {
+                        println!("Usage: disasm [$ADDR]");
+                    },
+                }
+            },
+            "a" | "asm" => {
+                // Continue after the last assembled instruction if there's no address
+                let rest = input.trim_start()[command.len()..].trim_start();
+                let starts_addr = rest.starts_with(|c: char| {
+                    c == '$' || c == '%' || c == '.' || c.is_ascii_digit()
+                });
+                let text = if starts_addr {
+                    let (a, text) = rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len()));
+                    match self.lookup_addr(a) {
+                        Some(a) => self.asm_addr = a as u16,
+                        None => return,
+                    }
+                    text
+                } else {
+                    rest
+                };
+                if text.trim().is_empty() {
+                    println!("Usage: asm [$ADDR] MNEMONIC [OPERAND]");
+                    return;
+                }
+                let addr = self.asm_addr;
+                match cpu::assemble(addr, text, &self.symbols) {
+                    Ok(bytes) => {
+                        for (i, b) in bytes.iter().enumerate() {
+                            self.write_byte(addr.wrapping_add(i as u16) as usize, *b);
+                        }
+                        let (text, len) = self.disassemble(addr);
+                        println!("{}", text);
+                        self.asm_addr = addr.wrapping_add(len);
+                    },
+                    Err(e) => {
+                        println!("{}", e);
+                    },
+                }
+            },
+            "snap" => {
+                match args.next() {
+                    Some(f) => {
+                        if let Err(e) = self.save_snapshot(f) {
+                            println!("{}", e);
+                        }
+                    },
+                    None => {
+                        println!("Usage: snap FILE");
+                    },
+                }
+            },
+            "shot" => {
+                match args.next() {
+                    Some(f) => { self.screenshot = Some(f.to_string()); },
+                    None => { println!("Usage: shot FILE"); },
+                }
+            },
+            "restore" => {
+                match args.next() {
+                    Some(f) => {
+                        if let Err(e) = self.load_snapshot(f) {
+                            println!("{}", e);
+                        }
+                    },
+                    None => {
+                        println!("Usage: restore FILE");
+                    },
+                }
+            },
+            "l" | "load" => {
+                let fname = args.next();
+                let index = match args.next().map(|i| i.parse::<usize>()) {
+                    Some(Ok(i)) => Some(i),
+                    Some(Err(_)) => None,
+                    None => Some(0),
+                };
+                match (fname, index) {
+                    (Some(f), Some(i)) => {
+                        match self.load_program(f, i) {
+                            Ok((name, start, end)) => {
+                                if name.is_empty() {
+                                    println!("Loaded ${:0>4X}-${:0>4X}", start, end);
+                                } else {
+                                    println!("Loaded \"{}\" ${:0>4X}-${:0>4X}", name, start, end);
+                                }
+                            },
+                            Err(e) => {
+                                println!("{}", e);
+                            },
+                        }
+                    },
+                    _ => {
+                        println!("Usage: load FILE [INDEX]");
+                    },
+                }
+            },
+            "mute" | "unmute" => {
+                let muted = command == "mute";
+                match args.next().and_then(|v| v.parse::<u8>().ok()) {
+                    Some(v) if (1..=3).contains(&v) => {
+                        self.sid.set_voice_muted(v, muted);
+                    },
+                    _ => {
+                        println!("Usage: {} 1|2|3", command);
+                    },
+                }
+            },
+            "solo" => {
+                match args.next().and_then(|v| v.parse::<u8>().ok()) {
+                    Some(v) if (1..=3).contains(&v) => {
+                        for voice in 1..4 {
+                            self.sid.set_voice_muted(voice, voice != v);
+                        }
+                    },
+                    _ => {
+                        println!("Usage: solo 1|2|3");
+                    },
+                }
+            },
+            "f" | "find" => {
+                // Quoted text is searched for as both PETSCII and screen codes
+                let rest = input.trim_start()[command.len()..].trim();
+                let matches = if rest.len() >= 2 && rest.starts_with('"') && rest.ends_with('"') {
+                    Some(self.find_text(&rest[1..rest.len() - 1]))
+                } else {
+                    let bytes: Vec<Option<u8>> = args.map(|b| {
+                        u8::from_str_radix(b.trim_start_matches('$'), 16).ok()
+                    }).collect();
+                    if !bytes.is_empty() && bytes.iter().all(|b| b.is_some()) {
+                        let pattern: Vec<u8> = bytes.into_iter().map(|b| b.unwrap()).collect();
+                        Some(self.find_bytes(&pattern))
+                    } else {
+                        None
+                    }
+                };
+                match matches {
+                    Some(found) => {
+                        for addr in found.iter().take(MAX_FIND_RESULTS) {
+                            println!("${:0>4X}", addr);
+                        }
+                        if found.len() > MAX_FIND_RESULTS {
+                            println!("... and {} more", found.len() - MAX_FIND_RESULTS);
+                        } else if found.is_empty() {
+                            println!("Not found");
+                        }
+                    },
+                    None => {
+                        println!("Usage: find \"TEXT\" | find b0 b1 ...");
+                    },
+                }
+            },
+            "vic" => {
+                println!("{:?}", self.vic);
+                for (i, name) in VIC_REGISTER_NAMES.iter().enumerate() {
+                    let addr = vic::MIN_CONTROL_ADDR + i;
+                    println!("${:0>4X}  {:<6} ${:0>2X}", addr, name, self.vic.read_register(addr));
+                }
+            },
+            "c" | "cycle" => {
+                // The whole system steps after every command and both chips get printed, so all
+                // this has to do is make sure a pending VIC-only step doesn't happen instead
+                self.vic_step = false;
+            },
+            "vstep" => {
+                // The VIC state is printed after the step like it is for the CPU
+                self.vic_step = true;
+            },
+            "raster" => {
+                println!("Raster line ${:0>3X} ({})", self.raster_line(), self.raster_line());
+            },
+            "j" | "json" => {
+                println!("{}", self.state_json());
+            },
+            "b" | "break" => {
+                match args.next().and_then(|a| self.lookup_addr(a)) {
+                    Some(a) => {
+                        self.add_breakpoint(a as u16, false);
+                        println!("Breakpoint at ${:0>4X}", a);
+                    },
+                    None => {
+                        println!("Usage: break $ADDR");
+                    },
+                }
+            },
+            "rbreak" => {
+                match args.next().and_then(|a| self.lookup_addr(a)) {
+                    Some(line) => {
+                        self.add_raster_break(line as u16);
+                        println!("Raster breakpoint set at line ${:0>3X} ({})", line, line);
+                    },
+                    None => {
+                        println!("Usage: rbreak LINE");
+                    },
+                }
+            },
+            "g" | "go" => {
+                // Run until the address is reached, or just run if there's no address
+                match args.next().map(|a| self.lookup_addr(a)) {
+                    Some(Some(a)) => {
+                        self.add_breakpoint(a as u16, true);
+                        self.mode = SystemMode::DebugRun;
+                    },
+                    Some(None) => {
+                        println!("Usage: go [$ADDR]");
+                    },
+                    None => {
+                        self.mode = SystemMode::DebugRun;
+                    },
+                }
+            },
+            "set" => {
+                let reg = args.next();
+                let value = args.next().and_then(|v| self.lookup_addr(v));
+                match (reg, value) {
+                    (Some(r), Some(v)) => {
+                        match self.cpu.set_register(r, v as u16) {
+                            Ok(_) => println!("{:?}", self.cpu),
+                            Err(e) => println!("{}", e),
+                        }
+                    },
+                    _ => {
+                        println!("Usage: set REG VALUE");
+                    },
+                }
+            },
+            "w" | "watch" => {
+                let addr = args.next().and_then(|a| self.lookup_addr(a));
+                let kind = match args.next() {
+                    Some("r") => Some(WatchKind::Read),
+                    Some("w") => Some(WatchKind::Write),
+                    Some("rw") | None => Some(WatchKind::ReadWrite),
+                    Some(_) => None,
+                };
+                match (addr, kind) {
+                    (Some(a), Some(k)) => {
+                        self.add_watchpoint(a, k);
+                        println!("Watching ${:0>4X}", a);
+                    },
+                    _ => {
+                        println!("Usage: watch $ADDR [r|w|rw]");
+                    },
+                }
+            },
+            "roms" => {
+                let reset = match args.next() {
+                    Some("reset") => true,
+                    Some(_) => {
+                        println!("Usage: roms [reset]");
+                        return;
+                    },
+                    None => false,
+                };
+                match self.reload_roms() {
+                    Ok(_) => println!("Reloaded ROMs"),
+                    Err(e) => println!("{}", e),
+                }
+                if reset {
+                    self.reset();
+                    self.history.clear();
+                }
+            },
+            "reset" => {
+                // A hard reset is like switching the machine off and on again
+                match args.next() {
+                    Some("hard") => self.fill_ram_pattern(),
+                    Some("clear") => self.clear_ram(),
+                    Some(_) => {
+                        println!("Usage: reset [hard|clear]");
+                        return;
+                    },
+                    None => { },
+                }
+                self.reset();
+                self.history.clear();
+            },
+            "u" | "back" => {
+                if !self.step_back() {
+                    println!("No more history");
+                }
+            },
+            "h" | "help" => {
+                println!("Help not implemented");
+            },
+            "" => {
+            },
+            _ => {
+                println!("Invalid command");
+            }
+        }
+    }
+
+    // Run a file of monitor commands when the emulator starts, e.g. to set up breakpoints
+    pub fn set_monitor_script(&mut self, fname: &str) {
+        self.monitor_script = Some(fname.to_string());
+    }
+
+    // Run each line of a file as a monitor command. Blank lines and lines starting with ';' are
+    // skipped.
+    pub fn run_monitor_script(&mut self, fname: &str) -> Result<(), String> {
+        let mut text = String::new();
+        let result = File::open(fname).and_then(|mut f| f.read_to_string(&mut text));
+        if let Err(e) = result {
+            return Err(format!("Failed to read monitor script: {}", e));
+        }
+
+        for line in text.lines().map(|l| l.trim()) {
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            println!("] {}", line);
+            self.debug_command(line);
+        }
+        Ok(())
+    }
+
+    // Find every address in RAM where a byte pattern starts. Matches can wrap around from $FFFF
+    // to $0000.
+    pub fn find_bytes(&self, pattern: &[u8]) -> Vec<u16> {
+        if pattern.is_empty() || pattern.len() > self.ram.len() {
+            return Vec::new();
+        }
+        (0..self.ram.len()).filter(|&start| {
+            pattern.iter().enumerate().all(|(i, &b)| self.ram[(start + i) & ADDR_MASK] == b)
+        }).map(|a| a as u16).collect()
+    }
+
+    // Find text stored in RAM either as PETSCII or as screen codes
+    pub fn find_text(&self, text: &str) -> Vec<u16> {
+        let petscii: Option<Vec<u8>> = text.chars().map(ascii_to_petscii).collect();
+        let petscii = match petscii {
+            Some(p) => p,
+            None => return Vec::new(),
+        };
+        let screen_codes: Vec<u8> = petscii.iter().map(|&p| petscii_to_screen_code(p)).collect();
+
+        let mut found = self.find_bytes(&petscii);
+        if screen_codes != petscii {
+            found.extend(self.find_bytes(&screen_codes));
+            found.sort();
+            found.dedup();
+        }
+        found
+    }
+
+    // Parse an address argument to a monitor command, printing why if it's no good
+    fn lookup_addr(&self, s: &str) -> Option<usize> {
+        match symbols::parse_addr(s, &self.symbols) {
+            Ok(a) => Some(a as usize),
+            Err(e) => {
+                println!("{}", e);
+                None
+            },
+        }
+    }
+
+    // Disassemble the instruction at an address. Returns a line of text with the address, the
+    // instruction bytes, and the assembly along with the length of the instruction.
+    pub fn disassemble(&self, addr: u16) -> (String, u16) {
+        let mut bytes = [0u8; 3];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = self.read_byte(addr.wrapping_add(i as u16) as usize);
+        }
+        let (text, len) = cpu::disassemble(addr, bytes, &self.symbols);
+
+        let mut hex = String::new();
+        for b in bytes.iter().take(len as usize) {
+            hex.push_str(&format!("{:0>2X} ", b));
+        }
+        let label = match self.symbols.name_of(addr) {
+            Some(name) => format!(".{}:", name),
+            None => String::new(),
+        };
+        (format!("${:0>4X}  {:<9} {:<12} {}", addr, hex, label, text), len)
+    }
+
+    // Print rows of 16 bytes starting at an address along with where each row is mapped from
+    fn dump_memory(&self, start: u16, rows: u16) {
+        for row in 0..rows {
+            let addr = start.wrapping_add(row * 16);
+            let mut line = format!("${:0>4X}  ", addr);
+            for i in 0..16 {
+                line.push_str(&format!("{:0>2X} ", self.read_byte(addr.wrapping_add(i) as usize)));
+            }
+            println!("{} {:?}", line, self.region_at(addr));
+        }
+    }
+
+    // Break into the debugger when the CPU reaches an address. One-shot breakpoints are removed
+    // once they've been hit.
+    pub fn add_breakpoint(&mut self, addr: u16, one_shot: bool) {
+        self.breakpoints.push((addr, one_shot));
+    }
+
+    // Break into the debugger when the VIC-II starts a raster line
+    pub fn add_raster_break(&mut self, line: u16) {
+        self.raster_breaks.push(line);
+    }
+
+    // Drop into the debugger if the VIC-II has just moved onto a raster line with a break
+    fn check_raster_breaks(&mut self) {
+        let line = self.vic.current_raster();
+        if line == self.last_raster {
+            return;
+        }
+        self.last_raster = line;
+        if self.mode != SystemMode::DebugStep && self.raster_breaks.contains(&line) {
+            println!("Raster break at line ${:0>3X} ({})", line, line);
+            self.mode = SystemMode::DebugStep;
+        }
+    }
+
+    // Drop into the debugger if the CPU is about to execute an instruction at a breakpoint
+    fn check_breakpoints(&mut self) {
+        if self.mode == SystemMode::DebugStep || !self.cpu.at_instruction_start() {
+            return;
+        }
+
+        let pc = self.cpu.pc();
+        if self.breakpoints.iter().any(|&(a, _)| a == pc) {
+            println!("Breakpoint at ${:0>4X}", pc);
+            self.breakpoints.retain(|&(a, one_shot)| !(one_shot && a == pc));
+            self.mode = SystemMode::DebugStep;
+        }
+    }
+
+    // Save the CPU state before it starts an instruction so the debugger can step back to it
+    fn record_instruction(&mut self) {
+        if self.history.len() == UNDO_HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(UndoRecord {
+            cpu: self.cpu.clone(),
+            writes: Vec::new(),
+        });
+    }
+
+    // Remember the old value of a RAM byte the CPU is about to overwrite. Writes to I/O chips
+    // and color RAM can't be undone.
+    fn record_write(&mut self, addr: usize) {
+        match self.region_at(addr as u16) {
+            MemRegion::CpuPort | MemRegion::Io(_) => { },
+            _ => {
+                let old = self.ram[addr];
+                if let Some(record) = self.history.back_mut() {
+                    record.writes.push((addr, old));
+                }
+            },
+        }
+    }
+
+    // Restore the CPU and RAM to the way they were before the last instruction started. Returns
+    // false if there's no history left.
+    pub fn step_back(&mut self) -> bool {
+        match self.history.pop_back() {
+            Some(record) => {
+                for &(addr, old) in record.writes.iter().rev() {
+                    self.ram[addr] = old;
+                }
+                self.cpu = record.cpu;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Format the state of the CPU and the chip timers as a JSON object
+    pub fn state_json(&self) -> String {
+        format!(
+            "{{\"cpu\":{},\"vic\":{{\"raster\":{}}},\"cia1\":{},\"cia2\":{}}}",
+            self.cpu.state_json(), self.vic.current_raster(), cia_json(&self.cia_1), cia_json(&self.cia_2)
+            )
+    }
+
+    pub fn raster_line(&self) -> u16 {
+        self.vic.current_raster()
+    }
+
+    #[cfg(test)]
+    pub fn vic(&self) -> &Vic {
+        &self.vic
+    }
+
+    #[cfg(test)]
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.mode == SystemMode::DebugStep
+    }
+
+    // Whether the run loop should wait for a monitor command. A monitor script runs as soon as the
+    // CPU is out of reset, so nothing prompts before then.
+    pub fn awaiting_command(&self) -> bool {
+        self.stopped() && self.monitor_script.is_none()
+    }
+
+    #[cfg(test)]
+    pub fn pc(&self) -> u16 {
+        self.cpu.pc()
+    }
+
+    // Break into the debugger when the CPU accesses an address
+    pub fn add_watchpoint(&mut self, addr: usize, kind: WatchKind) {
+        self.watchpoints.push((addr & ADDR_MASK, kind));
+    }
+
+    // Determine whether a CPU access to an address should trigger a watchpoint
+    pub fn watchpoint_hit(&self, addr: usize, write: bool) -> bool {
+        self.watchpoints.iter().any(|&(a, kind)| {
+            a == addr && match kind {
+                WatchKind::Read => !write,
+                WatchKind::Write => write,
+                WatchKind::ReadWrite => true,
+            }
+        })
+    }
+
+    // Drop into the debugger if a CPU access hits a watchpoint
+    fn check_watchpoints(&mut self, addr: usize, value: u8, write: bool) {
+        if self.watchpoint_hit(addr, write) {
+            println!(
+                "Watchpoint: {} ${:0>4X} = ${:0>2X} at PC ${:0>4X}",
+                if write { "write" } else { "read" }, addr, value, self.cpu.pc()
+                );
+            self.mode = SystemMode::DebugStep;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    // Put the byte at the VIC's address on its data bus. The VIC sees memory through its own bank
+    // rather than the CPU's memory map, so this never touches the I/O registers.
+    fn vic_fetch(&mut self) {
+        let addr = self.convert_vic_ii_addr(self.vic.read_addr_bus());
+        let byte = vic_read(&self.ram, &self.char_rom, addr);
+        // Color RAM hangs off the VIC's low 10 address lines. The video matrix is 1K aligned, so on a
+        // matrix fetch these are the position of the cell being read.
+        let color = self.color_ram[addr & 0x03ff];
+
+        self.vic.data_in(byte);
+        self.vic.color_in(color);
+    }
+
+    // Run the whole system for one clock cycle
+    pub fn cycle(&mut self, screen: &mut Screen) {
+        let debug = self.mode != SystemMode::Run;
+
+        // The monitor can hold everything but the VIC-II for a cycle
+        if self.vic_step {
+            self.vic_step = false;
+            self.step_vic(screen);
+            return;
+        }
+
+        // Run the VIC-II. It always has the bus during the first phase of the cycle.
+        self.vic_rising_edge(screen, debug);
+
+        // The cable sees the PC pulse from the CPU's last access to port B
+        if let Some(ref mut cable) = self.parallel {
+            if cable.cycle(self.cia_2.pc()) {
+                self.cia_2.pulse_flag();
+            }
+            self.cia_2.set_port_b_input(cable.port_lines());
+        }
+
+        self.cia_1.cycle();
+        self.cia_2.cycle();
+
+        // NMI triggers on an edge, so CIA 2 has to be acknowledged before it can interrupt again
+        let cia_2_irq = self.cia_2.irq_requested();
+        if cia_2_irq && !self.cia_2_irq {
+            self.cpu.trigger_nmi();
+        }
+        self.cia_2_irq = cia_2_irq;
+        self.sid.cycle();
+        if self.stereo_sid_addr.is_some() {
+            self.stereo_sid.cycle();
+        }
+        if self.audio_out.is_some() {
+            self.sample_audio();
+        }
+
+        // Is the CPU allowed to use the bus or does the VIC need both clock edges? The VIC only
+        // stalls the CPU in cycle-exact mode. RDY only stops the CPU on read cycles.
+        let vic_has_bus = self.cycle_exact && !self.vic.aec();
+        let cpu_stunned = self.cycle_exact && !self.vic.rdy() && self.cpu.rw;
+
+        if vic_has_bus {
+            // The VIC also gets the second phase, so the CPU doesn't touch the bus at all
+            self.vic_fetch();
+            self.vic.falling_edge(screen, debug);
+        } else if !cpu_stunned {
+            // Only keep undo history while debugging
+            if debug && self.cpu.at_instruction_start() {
+                self.record_instruction();
+            }
+            if self.cpu.at_instruction_start() {
+                self.instr_pc = self.cpu.pc();
+                self.instructions += 1;
+                if self.trace.len() == TRACE_SIZE {
+                    self.trace.pop_front();
+                }
+                self.trace.push_back(self.instr_pc);
+            }
+            if !self.exec_counts.is_empty() && self.cpu.at_instruction_start() {
+                self.exec_counts[self.cpu.pc() as usize] += 1;
+            }
+            if self.kernal_log.is_some() && self.cpu.at_instruction_start() {
+                self.log_kernal_call();
+            }
+            if self.host_dir.is_some() && self.cpu.at_instruction_start() {
+                self.check_kernal_traps();
+            }
+
+            // The jiffy interrupt holds the IRQ line until the CPU takes it so it isn't lost
+            // while interrupts are disabled. CIA 1 holds it until it's acknowledged.
+            if self.jiffy_pending && self.cpu.servicing_irq() {
+                self.jiffy_pending = false;
+            }
+            let irq = !self.vic.irq() || self.jiffy_pending || self.cia_1.irq_requested();
+            if irq && self.vic.rdy() {
+                self.cpu.trigger_interrupt();
+            }
+
+            // Read/write the CPU data bus
+            if self.cpu.addr_enable {
+                let addr = self.cpu.addr_bus as usize;
+                if self.cpu.rw {
+                    let byte = self.read_byte(addr);
+                    self.check_watchpoints(addr, byte, false);
+                    self.cpu.data_in(byte);
+                } else {
+                    let data = self.cpu.data_out();
+                    self.check_watchpoints(addr, data, true);
+                    if debug {
+                        self.record_write(addr);
+                    }
+                    self.idle_cycles = 0;
+                    self.write_byte(addr, data);
+                }
+            }
+            self.cpu.cycle(debug);
+            self.update_idle();
+        }
+
+        self.check_breakpoints();
+        self.feed_keyboard_buffer();
+    }
+
+    // Run the VIC-II through the first phase of a cycle
+    fn vic_rising_edge(&mut self, screen: &mut Screen, debug: bool) {
+        self.vic_fetch();
+        self.vic.rising_edge(screen, debug);
+        if !self.raster_breaks.is_empty() {
+            self.check_raster_breaks();
+        }
+        if self.jiffy_irq && self.vic.frame_ready() {
+            self.jiffy_pending = true;
+        }
+        if self.vic.frame_ready() {
+            if let Some(ref mouse) = self.mouse {
+                self.sid.set_paddles(mouse.pot_x(), mouse.pot_y());
+            }
+        }
+        if self.vic.line_fetch_pending() {
+            let bank = self.vic_bank();
+            let (ram, char_rom, color_ram) = (&self.ram, &self.char_rom, &self.color_ram);
+            self.vic.fetch_line(
+                |addr| vic_read(ram, char_rom, (bank + (addr & 0x3fff)) as usize),
+                |pos| color_ram[pos as usize]);
+        }
+    }
+
+    // Advance the VIC-II by one cycle without running the CPU or the other chips
+    pub fn step_vic(&mut self, screen: &mut Screen) {
+        let debug = self.mode != SystemMode::Run;
+        self.vic_rising_edge(screen, debug);
+        if !self.vic.aec() {
+            self.vic_fetch();
+            self.vic.falling_edge(screen, debug);
+        }
+    }
+
+    // Advance the whole system by one master clock cycle: one cycle of the VIC-II and the other
+    // chips, and one CPU cycle if the VIC-II lets it have the bus. Finished frames are recorded
+    // and saved here. Returns true if a frame is ready for display.
+    pub fn step_system(&mut self, screen: &mut Screen) -> bool {
+        // Wait until the CPU is out of reset so the script can change registers
+        if self.monitor_script.is_some() && self.cpu.at_instruction_start() {
+            if let Some(f) = self.monitor_script.take() {
+                if let Err(e) = self.run_monitor_script(&f) {
+                    println!("{}", e);
+                }
+            }
+        }
+
+        self.cycle(screen);
+
+        if !self.vic.frame_ready() {
+            return false;
+        }
+        self.write_video_frame(screen);
+        self.write_audio_frame();
+        if let Some(f) = self.screenshot.take() {
+            match save_indexed_png(screen, &f) {
+                Ok(_) => println!("Saved screenshot to {}", f),
+                Err(e) => println!("{}", e),
+            }
+        }
+        true
+    }
+
+    pub fn run(&mut self, clock_speed_mhz: u32, screen_tx: Sender<Screen>, event_rx: Receiver<EmulatorEvent>) {
+        self.reset();
+        let mut cycles: u64 = 0;
+
+        let total_t = Instant::now();
+        let mut pacer = Pacer::new(clock_speed_mhz);
+
+        let (width, height) = self.vic.screen_size();
+        let mut screen = Screen::new(width, height);
+        let mut fps = FpsCounter::new();
+
+        'emulator: loop {
+            // Get events from the main thread
+            if let Some(e) = self.next_event(cycles, &event_rx) {
+                if let Some(ref mut f) = self.event_log {
+                    if let Err(err) = writeln!(f, "{}", e.to_log_line(cycles)) {
+                        println!("Error writing to event log: {}", err);
+                    }
+                }
+                match e {
+                    EmulatorEvent::Key(keycode, m, pressed) => {
+                        self.key_event(keycode, m, pressed);
+                    },
+                    EmulatorEvent::Freeze => {
+                        self.freeze();
+                    },
+                    EmulatorEvent::ToggleFpsOverlay => {
+                        self.fps_overlay = !self.fps_overlay;
+                    },
+                    EmulatorEvent::Pause => {
+                        self.paused = true;
+                    },
+                    EmulatorEvent::Resume => {
+                        self.paused = false;
+                    },
+                    EmulatorEvent::Paddles(x, y) => {
+                        self.set_paddles(x, y);
+                    },
+                    EmulatorEvent::Mouse(dx, dy, left, right) => {
+                        self.mouse_event(dx, dy, left, right);
+                    },
+                    EmulatorEvent::Joystick(lines) => {
+                        self.joystick_event(lines);
+                    },
+                    EmulatorEvent::Quit => {
+                        self.write_exit_files();
+                        break 'emulator;
+                    },
+                }
+            }
+
+            if self.paused {
+                sleep(Duration::from_millis(PAUSE_SLEEP_MS));
+                continue;
+            }
+
+            if self.instruction_limit_reached() {
+                println!("Stopped after {} instructions at ${:0>4X}", self.instructions, self.cpu.pc());
+                self.write_exit_files();
+                process::exit(INSTRUCTION_LIMIT_STATUS);
+            }
+
+            if let Some(code) = self.cpu.illegal_opcode() {
+                println!("Illegal opcode ${:0>2X} at ${:0>4X}", code, self.cpu.pc());
+                self.write_exit_files();
+                process::exit(ILLEGAL_OPCODE_STATUS);
+            }
+
+            let frame_ready = self.step_system(&mut screen);
+
+            let pause = pacer.should_sleep(cycles);
+            if cycles % pacer::SAMPLE_CYCLES == 0 && self.show_status() {
+                let idle_ns = pause.map(|t| t.subsec_nanos()).unwrap_or(0);
+                println!("Ideal clock speed: {} kHz", clock_speed_mhz/1_000_000);
+                println!("Mean clock speed:  {} kHz", pacer.speed_khz());
+                println!("Idle time: {} ns", idle_ns);
+                println!("{:?}", self.cpu);
+            }
+
+            if self.mode != SystemMode::Run && self.monitor_script.is_none() {
+                // Always show the state when stepping
+                if self.show_status() || self.awaiting_command() {
+                    let elapsed = total_t.elapsed();
+                    let total_time_ms = (elapsed.as_secs() * 1000) + ((elapsed.subsec_nanos() / 1_000_000) as u64);
+                    let speed = (cycles as f32) / (total_time_ms as f32);
+                    println!("----------");
+                    println!("  Mean Clock speed: {:8.3} kHz", speed);
+                    println!("{:?}", self.cpu);
+                    println!("{:?}", self.vic);
+                    println!("----------");
+                }
+
+                if self.awaiting_command() {
+                    print!("] ");
+                    match stdout().flush() {
+                        Ok(_) => { },
+                        Err(e) => { println!("Error flushing STDOUT: {:?}", e); }
+                    }
+
+                    let mut input = String::new();
+                    match stdin().read_line(&mut input) {
+                        Ok(_) => { },
+                        Err(e) => { panic!("Error reading STDIN: {}", e); },
+                    }
+                    
+                    self.debug_command(&input);
+                }
+            } else if self.idle_sleep && self.idle_cycles % IDLE_FRAME_CYCLES == 0 && self.cpu_idle() {
+                sleep(Duration::from_millis(IDLE_SLEEP_MS));
+            } else if let Some(t) = pause {
+                if !self.fast_boot || self.is_booted() {
+                    sleep(t);
+                }
+            }
+
+            // Send a frame to the main thread if one is ready
+            if frame_ready {
+                fps.add_frame(total_t.elapsed(), cycles);
+                // The overlay only goes on the copy that's displayed so recordings don't get it
+                let mut frame = screen.clone();
+                if self.fps_overlay {
+                    overlay::draw_fps(&mut frame, &fps);
+                }
+                match screen_tx.send(frame) {
+                    Ok(_) => continue,
+                    Err(e) => panic!("Error sending screen data: {}", e),
+                }
+            }
+
+            cycles = cycles.wrapping_add(1);
+        }
+    }
+}
+
+// Let the CPU be run directly against the system's memory map
+impl cpu::Memory for Bus {
+    fn read(&self, addr: u16) -> u8 {
+        self.read_byte(addr as usize)
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.write_byte(addr as usize, value);
+    }
+}
+
+// Read a byte the way the VIC-II sees memory. The character ROM shows up at $1000-$1FFF in banks
+// 0 and 2 instead of RAM.
+fn vic_read(ram: &[u8], char_rom: &[u8], addr: usize) -> u8 {
+    if addr & 0x7000 == 0x1000 {
+        char_rom[addr & 0x0fff]
+    } else {
+        ram[addr]
+    }
+}
+
+// Convert a screen code to the closest ASCII character
+fn screen_code_to_char(code: u8, lowercase: bool) -> char {
+    // The high bit only selects reverse video
+    let code = code & 0x7f;
+    match code {
+        0x00 => '@',
+        0x01..=0x1a => {
+            if lowercase {
+                (b'a' + code - 0x01) as char
+            } else {
+                (b'A' + code - 0x01) as char
+            }
+        },
+        0x1b => '[',
+        0x1c => '#',    // Pound sign
+        0x1d => ']',
+        0x1e => '^',    // Up arrow
+        0x1f => '<',    // Left arrow
+        0x20..=0x3f => code as char,
+        0x41..=0x5a if lowercase => (b'A' + code - 0x41) as char,
+        _ => '.',       // Graphics characters
+    }
+}
+
+// Format the timers and serial pins of a CIA as a JSON object
+fn cia_json(cia: &Cia) -> String {
+    format!("{{\"timer_a\":{},\"timer_b\":{},\"cnt\":{},\"sp\":{}}}",
+        cia.timer_a(), cia.timer_b(), cia.cnt(), cia.sp())
+}
+
+// Read a whole file for the debugger
+fn read_file(fname: &str, kind: &str) -> Result<Vec<u8>, String> {
+    let mut file = match File::open(fname) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Failed to open {} file: {}", kind, e)),
+    };
+    let mut data = Vec::new();
+    match file.read_to_end(&mut data) {
+        Ok(_) => Ok(data),
+        Err(e) => Err(format!("Error reading {} file: {}", kind, e)),
+    }
+}
+
+// Save a frame as a PNG indexed into the VIC-II's palette
+pub fn save_indexed_png(screen: &Screen, fname: &str) -> Result<(), String> {
+    let palette = vic::palette();
+    let indices = screen.palette_indices(&palette)?;
+    let data = png::encode_indexed(screen.width, screen.height, &palette, &indices);
+
+    let mut file = match File::create(fname) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Failed to create screenshot file: {}", e)),
+    };
+    match file.write_all(&data) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error writing screenshot file: {}", e)),
+    }
+}
+
+// Read a ROM image and make sure it fits its chip
+fn read_rom(fname: &str, kind: &str, size: usize) -> Result<Vec<u8>, String> {
+    let data = read_file(fname, kind)?;
+    if data.len() != size {
+        return Err(format!("{} file must be {} bytes, not {}", kind, size, data.len()));
+    }
+    Ok(data)
+}
+
+// Determine which device handles an address in the I/O area
+fn io_device_at(addr: usize) -> IoDevice {
+    if (vic::MIN_CONTROL_ADDR..=vic::MAX_CONTROL_ADDR).contains(&addr) {
+        IoDevice::Vic
+    } else if (sid::MIN_CONTROL_ADDR..=sid::MAX_CONTROL_ADDR).contains(&addr) {
+        IoDevice::Sid
+    } else if (COLOR_RAM_START..=COLOR_RAM_END).contains(&addr) {
+        IoDevice::ColorRam
+    } else if (CIA1_MIN_CONTROL_ADDR..=CIA1_MAX_CONTROL_ADDR).contains(&addr) {
+        IoDevice::Cia1
+    } else if (CIA2_MIN_CONTROL_ADDR..=CIA2_MAX_CONTROL_ADDR).contains(&addr) {
+        IoDevice::Cia2
+    } else {
+        IoDevice::Expansion
+    }
+}
+
+// Look up an I/O device by the name used on the command line
+pub fn io_device_named(name: &str) -> Option<IoDevice> {
+    match name {
+        "vic" => Some(IoDevice::Vic),
+        "sid" => Some(IoDevice::Sid),
+        "sid2" => Some(IoDevice::StereoSid),
+        "color" => Some(IoDevice::ColorRam),
+        "cia1" => Some(IoDevice::Cia1),
+        "cia2" => Some(IoDevice::Cia2),
+        "expansion" => Some(IoDevice::Expansion),
+        _ => None,
+    }
+}
+
+// Convert an ASCII character to the PETSCII code for the key that types it
+fn ascii_to_petscii(c: char) -> Option<u8> {
+    match c {
+        'a'..='z' => Some(c.to_ascii_uppercase() as u8),
+        'A'..='Z' | ' '..='@' | '[' | ']' => Some(c as u8),
+        '\n' => Some(0x0d),
+        _ => None,
+    }
+}
+
+// Convert a PETSCII character in a file name to ASCII for the host. Anything that can't be in a
+// host file name becomes an underscore.
+fn petscii_to_ascii(p: u8) -> char {
+    match p {
+        b'/' | b'\\' => '_',
+        0x20..=0x5d => p as char,
+        _ => '_',
+    }
+}
+
+// Read a little-endian word from memory
+fn word_at(ram: &[u8], addr: usize) -> u16 {
+    (ram[addr] as u16) | ((ram[addr + 1] as u16) << 8)
+}
+
+// Convert an unshifted PETSCII character to the screen code that displays it
+fn petscii_to_screen_code(p: u8) -> u8 {
+    match p {
+        0x40..=0x5f => p - 0x40,
+        0x60..=0x7f => p - 0x20,
+        _ => p,
     }
 }
@@ -0,0 +1,124 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+//
+// Functions for turning assembly into machine code
+use super::instruction::Instruction;
+use super::addressing_mode::AddressingMode;
+use super::opcode::Opcode;
+
+use symbols::{self, SymbolTable};
+
+// Assemble a single instruction to be placed at the given address. Accepts the same syntax the
+// disassembler produces. Returns the instruction bytes.
+pub fn assemble(addr: u16, text: &str, symbols: &SymbolTable) -> Result<Vec<u8>, String> {
+    use self::AddressingMode::*;
+
+    let text = text.trim();
+    let (mnemonic, operand) = match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], &text[i..]),
+        None => (text, ""),
+    };
+    let mnemonic = mnemonic.to_uppercase();
+    let operand: String = operand.chars().filter(|c| !c.is_whitespace()).collect();
+    // Only the syntax is case insensitive; symbols keep their case
+    let upper = operand.to_ascii_uppercase();
+
+    if is_branch(&mnemonic) {
+        let target = parse_value(&operand, symbols)?.0;
+        let offset = (target as i32) - (addr.wrapping_add(2) as i32);
+        if !(-128..=127).contains(&offset) {
+            return Err(format!("Branch out of range: {}", operand));
+        }
+        let code = find_opcode(&mnemonic, &[Immediate])?;
+        return Ok(vec![code, offset as u8]);
+    }
+
+    // Work out which addressing modes the operand could mean, most compact first
+    let (modes, value): (&[AddressingMode], u16) = if upper.is_empty() || upper == "A" {
+        (&[Implied], 0)
+    } else if let Some(imm) = operand.strip_prefix('#') {
+        let (value, _) = parse_value(imm, symbols)?;
+        if value > 0xff {
+            return Err(format!("Immediate value too large: {}", operand));
+        }
+        (&[Immediate], value)
+    } else if upper.starts_with('(') && upper.ends_with(",X)") {
+        (&[IndexedIndirect], parse_zp(&operand[1..operand.len() - 3], symbols)?)
+    } else if upper.starts_with('(') && upper.ends_with("),Y") {
+        (&[IndirectIndexed], parse_zp(&operand[1..operand.len() - 3], symbols)?)
+    } else if upper.starts_with('(') && upper.ends_with(')') {
+        (&[IndirectLo], parse_value(&operand[1..operand.len() - 1], symbols)?.0)
+    } else if upper.ends_with(",X") {
+        match parse_value(&operand[..operand.len() - 2], symbols)? {
+            (v, false) => (&[ZeropageX, AbsoluteLoX], v),
+            (v, true) => (&[AbsoluteLoX], v),
+        }
+    } else if upper.ends_with(",Y") {
+        match parse_value(&operand[..operand.len() - 2], symbols)? {
+            (v, false) => (&[ZeropageY, AbsoluteLoY], v),
+            (v, true) => (&[AbsoluteLoY], v),
+        }
+    } else {
+        match parse_value(&operand, symbols)? {
+            (v, false) => (&[Zeropage, AbsoluteLo], v),
+            (v, true) => (&[AbsoluteLo], v),
+        }
+    };
+
+    let code = find_opcode(&mnemonic, modes)?;
+    let mut bytes = vec![code];
+    match Instruction::from_u8(code).addr_mode {
+        Implied => { },
+        Immediate | Zeropage | ZeropageX | ZeropageY | IndexedIndirect | IndirectIndexed => {
+            bytes.push(value as u8);
+        },
+        _ => {
+            bytes.push(value as u8);
+            bytes.push((value >> 8) as u8);
+        },
+    }
+    Ok(bytes)
+}
+
+fn is_branch(mnemonic: &str) -> bool {
+    matches!(mnemonic, "BCC" | "BCS" | "BEQ" | "BMI" | "BNE" | "BPL" | "BVC" | "BVS")
+}
+
+// Find the opcode for a mnemonic in the first of the addressing modes it supports. Documented
+// encodings win over undocumented duplicates like the extra NOPs.
+fn find_opcode(mnemonic: &str, modes: &[AddressingMode]) -> Result<u8, String> {
+    let opcode = mnemonic.parse::<Opcode>()?;
+    if opcode == Opcode::KIL {
+        return Err("Refusing to assemble KIL".to_string());
+    }
+    // Column F isn't in the decode table yet
+    let codes = (0..256u16).map(|c| c as u8).filter(|c| c & 0x0f != 0x0f);
+    for mode in modes {
+        let code = codes.clone().filter(|&c| {
+            let instr = Instruction::from_u8(c);
+            instr.addr_mode == *mode && instr.opcode == opcode
+        }).min_by_key(|&c| (c & 0x03 == 0x03, c != 0xea));
+        if let Some(c) = code {
+            return Ok(c);
+        }
+    }
+    if codes.clone().any(|c| Opcode::from_u8(c) == opcode) {
+        Err(format!("Invalid addressing mode for {}", mnemonic))
+    } else {
+        Err(format!("Unknown instruction: {}", mnemonic))
+    }
+}
+
+// Parse an operand value. Also returns whether it has to be a 16-bit address, either because it's
+// too big for the zero page or because it was written with four hex digits.
+fn parse_value(s: &str, symbols: &SymbolTable) -> Result<(u16, bool), String> {
+    let value = symbols::parse_addr(s, symbols)?;
+    Ok((value, value > 0xff || (s.starts_with('$') && s.len() > 3)))
+}
+
+fn parse_zp(s: &str, symbols: &SymbolTable) -> Result<u16, String> {
+    match parse_value(s, symbols)? {
+        (v, false) => Ok(v),
+        _ => Err(format!("Not a zero page address: {}", s)),
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+//
+// Functions for finding out which opcodes the CPU can actually run
+use std::panic::{self, AssertUnwindSafe};
+
+use super::Cpu;
+use super::opcode::Opcode;
+use super::addressing_mode::AddressingMode;
+
+// Where the probe instruction goes
+const PROBE_ADDR: u16 = 0x1000;
+
+// What the CPU makes of one opcode
+pub struct OpcodeInfo {
+    pub code: u8,
+    pub mnemonic: String,
+    pub addr_mode: Option<String>,  // None if the opcode can't be decoded
+    pub cycles: Option<u32>,        // None if the CPU panics running it
+    pub documented: bool,
+}
+
+// Decode and run every opcode. Panics are caught, so callers may want to silence the panic hook
+// first.
+pub fn opcode_table() -> Vec<OpcodeInfo> {
+    (0..256u16).map(|c| {
+        let code = c as u8;
+        let opcode = Opcode::from_u8(code);
+        let addr_mode = panic::catch_unwind(|| AddressingMode::from_u8(code)).ok();
+        OpcodeInfo {
+            code: code,
+            mnemonic: format!("{:?}", opcode),
+            addr_mode: addr_mode.map(|m| format!("{:?}", m)),
+            cycles: probe(code),
+            documented: is_documented(code),
+        }
+    }).collect()
+}
+
+// Run a single instruction with zero operands straight after a reset and count its cycles.
+// Branches are taken since every flag but the unused one starts clear.
+fn probe(code: u8) -> Option<u32> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut ram = [0u8; 65536];
+        ram[PROBE_ADDR as usize] = code;
+        ram[0xfffc] = PROBE_ADDR as u8;
+        ram[0xfffd] = (PROBE_ADDR >> 8) as u8;
+
+        let mut cpu = Cpu::new();
+        cpu.reset();
+        cpu.run_until_fetch(&mut ram);
+        cpu.run_until_fetch(&mut ram)
+    })).ok()
+}
+
+// The 151 opcodes in the MOS programming manual. The undocumented opcodes either have their own
+// mnemonics or are extra encodings of NOP and SBC.
+pub fn is_documented(code: u8) -> bool {
+    use super::opcode::Opcode::*;
+
+    match Opcode::from_u8(code) {
+        KIL | SLO | RLA | SRE | RRA | SAX | LAX | DCP | ISC | ANC | ALR | ARR | XAA | AXS | AHX |
+            SHY | SHX | TAS | LAS => false,
+        NOP => code == 0xea,
+        _ => code & 0x03 != 0x03,
+    }
+}
@@ -0,0 +1,55 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+//
+// Functions for turning machine code back into assembly
+use super::instruction::Instruction;
+use super::addressing_mode::AddressingMode;
+use super::opcode::Opcode;
+
+use symbols::SymbolTable;
+
+// Disassemble the instruction starting with the first of the given bytes. Returns the assembly
+// and the length of the instruction in bytes.
+pub fn disassemble(addr: u16, bytes: [u8; 3], symbols: &SymbolTable) -> (String, u16) {
+    use self::AddressingMode::*;
+    use self::Opcode::*;
+
+    let instr = Instruction::from_u8(bytes[0]);
+    let byte = bytes[1];
+    let word = ((bytes[2] as u16) << 8) + (bytes[1] as u16);
+    let zp = |a: u8| match symbols.name_of(a as u16) {
+        Some(name) => format!(".{}", name),
+        None => format!("${:0>2X}", a),
+    };
+
+    let (operand, len) = match instr.addr_mode {
+        Implied => (String::new(), 1),
+        Immediate => {
+            match instr.opcode {
+                BCC | BCS | BEQ | BMI | BNE | BPL | BVC | BVS => {
+                    // Branch offsets are relative to the next instruction
+                    let target = addr.wrapping_add(2).wrapping_add(byte as i8 as u16);
+                    (symbols.format_addr(target), 2)
+                },
+                _ => (format!("#${:0>2X}", byte), 2),
+            }
+        },
+        Zeropage => (zp(byte), 2),
+        ZeropageX => (format!("{},X", zp(byte)), 2),
+        ZeropageY => (format!("{},Y", zp(byte)), 2),
+        IndexedIndirect => (format!("({},X)", zp(byte)), 2),
+        IndirectIndexed => (format!("({}),Y", zp(byte)), 2),
+        AbsoluteLo => (symbols.format_addr(word), 3),
+        AbsoluteLoX => (format!("{},X", symbols.format_addr(word)), 3),
+        AbsoluteLoY => (format!("{},Y", symbols.format_addr(word)), 3),
+        IndirectLo => (format!("({})", symbols.format_addr(word)), 3),
+        _ => (String::new(), 1),
+    };
+
+    let mut text = format!("{:?}", instr.opcode);
+    if !operand.is_empty() {
+        text.push(' ');
+        text.push_str(&operand);
+    }
+    (text, len)
+}
@@ -0,0 +1,20 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+//
+// Trait for anything the CPU can be connected to
+
+pub trait Memory {
+    fn read(&self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, value: u8);
+}
+
+// A flat 64K of RAM with nothing mapped over it
+impl Memory for [u8; 65536] {
+    fn read(&self, addr: u16) -> u8 {
+        self[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self[addr as usize] = value;
+    }
+}
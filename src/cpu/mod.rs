@@ -7,20 +7,38 @@ mod opcode;
 mod addressing_mode;
 mod instruction;
 mod status_register;
+mod disassembler;
+mod assembler;
+mod memory;
+mod coverage;
 
 use self::opcode::Opcode;
 use self::instruction::Instruction;
 
 use self::status_register::StatusRegister;
 
+pub use self::disassembler::disassemble;
+pub use self::assembler::assemble;
+pub use self::memory::Memory;
+pub use self::coverage::opcode_table;
+
 use std::fmt;
 
-const RESET_VECTOR_ADDR: u16 = 0xfce2;
 const STACK_START_ADDR: u16 = 0x0100;
 const IRQ_VEC_ADDR: u16 = 0xfffe;
+pub const NMI_VEC_ADDR: u16 = 0xfffa;
+const RESET_VEC_ADDR: u16 = 0xfffc;
+
+// Reset takes 7 cycles; the last two read the vector
+const RESET_DELAY_CYCLES: u8 = 5;
 
-#[derive(Eq, PartialEq, Debug)]
+// LAX #imm puts (A | magic) & imm in A and X. The magic constant depends on the chip and even its
+// temperature; $EE is what most 6510s give.
+pub const DEFAULT_LAX_MAGIC: u8 = 0xee;
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
 enum CpuState {
+    Reset(u8),      // Cycles left before the reset vector is read
     Interrupt,
     InterruptLo,
     InterruptHi,
@@ -32,16 +50,24 @@ enum CpuState {
     PushWordHi,
     PullWordLo,
     PullWordHi,
+    PullStack,      // Dummy stack read before SP moves up to the byte being pulled
 
     Address,
 
     ToLoad,
+    Branch,         // Extra cycle for a taken branch
+    BranchPage,     // Extra cycle for a taken branch to another page
+    IndexFixup,     // Extra read while an indexed address carries into the high byte
     Halt,
 }
 
+#[derive(Clone, PartialEq)]
 pub struct Cpu {
     // Input pins
     irq: bool,
+    nmi: bool,          // An NMI edge has been seen but not handled yet
+    nmi_active: bool,   // The interrupt sequence in progress is for an NMI
+    hw_interrupt: bool, // The interrupt sequence in progress is for an IRQ or NMI rather than BRK
 
     // Registers
     pc: u16,
@@ -51,11 +77,6 @@ pub struct Cpu {
     sr: StatusRegister,
     sp: u8,
     dataport: u8,
-    // ROM status flags derived from the dataport value
-    kernal_rom_enabled: bool,
-    basic_rom_enabled: bool,
-    char_rom_enabled: bool,
-    io_enabled: bool,
 
     data_direction_reg: u8,
 
@@ -73,12 +94,19 @@ pub struct Cpu {
     stack_word_ready: bool,
     stack_word: u16,
     state: CpuState,
+
+    lax_magic: u8,
+    strict_opcodes: bool,           // Halt on undocumented opcodes instead of running them
+    illegal_opcode: Option<u8>,     // Undocumented opcode that halted the CPU in strict mode
 }
 
 impl Cpu { 
     pub fn new() -> Cpu {
         Cpu {
             irq: false,
+            nmi: false,
+            nmi_active: false,
+            hw_interrupt: false,
 
             pc: 0u16,
             a: 0u8,
@@ -87,10 +115,6 @@ impl Cpu {
             sr: StatusRegister::new(),
             sp: 0u8,
             dataport: 0u8,
-            kernal_rom_enabled: false,
-            basic_rom_enabled: false,
-            char_rom_enabled: false,
-            io_enabled: false,
 
             data_direction_reg: 0u8,
 
@@ -108,12 +132,16 @@ impl Cpu {
             stack_word_ready: false,
             stack_word: 0u16,
             state: CpuState::Halt,
+
+            lax_magic: DEFAULT_LAX_MAGIC,
+            strict_opcodes: false,
+            illegal_opcode: None,
         }
     }
 
-    // Reset sets the program counter to the address of the reset routine
+    // Reset starts the sequence that loads the program counter from the reset vector
     pub fn reset(&mut self) {
-        self.pc = RESET_VECTOR_ADDR;
+        self.pc = RESET_VEC_ADDR;
         self.a = 0xaa;
         self.x = 0;
         self.y = 0;
@@ -126,7 +154,8 @@ impl Cpu {
         self.addr_enable = true;
         self.rw = true;
 
-        self.state = CpuState::Fetch;
+        self.illegal_opcode = None;
+        self.state = CpuState::Reset(RESET_DELAY_CYCLES);
     }
 
     // Write an address to the address bus
@@ -148,17 +177,21 @@ impl Cpu {
                 if debug {
 					println!("ADC #${:0>2X}", self.data_bus);
 				}
-                let old_sign = self.a & 0x80;
-                let result = (self.a as u16) + (self.data_bus as u16);
+                let data = self.read_data_bus();
+                let result = (self.a as u16) + (data as u16) + (self.sr.carry as u16);
                 if self.sr.decimal {
                     self.sr.carry = result > 99;
                 } else {
                     self.sr.carry = result > 0xff;
                 }
-                self.a = self.a.wrapping_add(self.data_bus);
 
-                self.sr.overflow = old_sign != (self.a & 0x80);
+                // Overflow if both operands have the same sign and the result's sign is different
+                let result = result as u8;
+                self.sr.overflow = (!(self.a ^ data) & (self.a ^ result)) & 0x80 == 0x80;
+                self.a = result;
+
                 self.sr.determine_zero(self.a);
+                self.sr.determine_negative(self.a);
                 Fetch
             },
 
@@ -184,10 +217,6 @@ impl Cpu {
                     self.sr.determine_zero(self.a);
                     self.sr.determine_negative(self.a);
                     Fetch
-                } else if addr_mode == AbsoluteHiX {
-                    // Kill a cycle for absolute, x
-                    self.curr_instr.addr_mode = AbsoluteHi;
-                    Load
                 } else {
                     let data = self.read_data_bus();
                     self.sr.determine_carry(data);
@@ -206,9 +235,10 @@ impl Cpu {
 				}
 
                 if !self.sr.carry {
-                    self.relative_branch();
+                    self.relative_branch()
+                } else {
+                    Fetch
                 }
-                Fetch
             },
 
             // BCS -- branch if carry set
@@ -216,11 +246,11 @@ impl Cpu {
                 if debug {
 					println!("BCS ${:0>2X}", self.data_bus);
 				}
-                self.pc += 2;
                 if self.sr.carry {
-                    self.relative_branch();
+                    self.relative_branch()
+                } else {
+                    Fetch
                 }
-                Fetch
             },
             
             // BEQ -- branch if zero
@@ -230,9 +260,10 @@ impl Cpu {
 				}
 
                 if self.sr.zero_result {
-                    self.relative_branch();
+                    self.relative_branch()
+                } else {
+                    Fetch
                 }
-                Fetch
             },
 
             // BIT -- test bits against A
@@ -256,9 +287,10 @@ impl Cpu {
 				}
 
                 if self.sr.negative {
-                    self.relative_branch();
+                    self.relative_branch()
+                } else {
+                    Fetch
                 }
-                Fetch
             },
             
             // BNE -- branch on result not zero
@@ -268,9 +300,10 @@ impl Cpu {
 				}
 
                 if !self.sr.zero_result {
-                    self.relative_branch();
+                    self.relative_branch()
+                } else {
+                    Fetch
                 }
-                Fetch
             },
 
             // BPL -- branch if plus
@@ -280,21 +313,35 @@ impl Cpu {
 				}
 
                 if !self.sr.negative {
-                    self.relative_branch();
+                    self.relative_branch()
+                } else {
+                    Fetch
                 }
-                Fetch
             },
 
             // BRK -- force break
-            // TODO: This should take 7 cycles, not 10
             (BRK, addr_mode) => {
                 if debug {
 					println!("BRK");
 				}
                 if self.state == Address && addr_mode == Implied {
+                    // PC has already moved past the opcode. BRK skips a padding byte while an
+                    // interrupt returns to the instruction it interrupted.
                     self.stack_word_ready = false;
-                    self.stack_word = self.pc.wrapping_add(2);
-                    PushWordHi
+                    self.stack_word = if self.hw_interrupt {
+                        self.pc.wrapping_sub(1)
+                    } else {
+                        self.pc.wrapping_add(1)
+                    };
+
+                    // The padding byte read is this cycle, so the high byte goes out on the next
+                    let sp = self.get_stack_addr();
+                    self.set_addr_bus(sp);
+                    let hi_byte = (self.stack_word >> 8) as u8;
+                    self.set_data_bus(hi_byte);
+                    self.sp = self.sp.wrapping_sub(1);
+
+                    PushWordLo
                 } else if self.state == ToLoad {
                     if !self.stack_word_ready {
                         self.stack_word_ready = true;
@@ -303,15 +350,23 @@ impl Cpu {
                         self.sp = self.sp.wrapping_sub(1);
                         self.set_addr_bus(sp);
 
-                        let sr = self.sr.to_u8() | 24;  // Set BRK flag in the stored SR
+                        // Only BRK sets the break flag in the stored SR
+                        let sr = if self.hw_interrupt {
+                            self.sr.to_u8() & !0x10
+                        } else {
+                            self.sr.to_u8() | 0x10
+                        };
                         self.set_data_bus(sr);
                         self.sr.int_disable = true;
 
-                        Store
+                        ToLoad
                     } else {
                         // Read interrupt vector
-                        self.pc = IRQ_VEC_ADDR;
-                        self.set_addr_bus(IRQ_VEC_ADDR);
+                        let vector = if self.nmi_active { NMI_VEC_ADDR } else { IRQ_VEC_ADDR };
+                        self.nmi_active = false;
+                        self.hw_interrupt = false;
+                        self.pc = vector;
+                        self.set_addr_bus(vector);
                         self.curr_instr.addr_mode = AbsoluteLo;
 
                         Address
@@ -331,9 +386,10 @@ impl Cpu {
 				}
 
                 if !self.sr.overflow {
-                    self.relative_branch();
+                    self.relative_branch()
+                } else {
+                    Fetch
                 }
-                Fetch
             },
 
             // BVS -- branch on overflow set
@@ -343,9 +399,10 @@ impl Cpu {
 				}
 
                 if self.sr.overflow {
-                    self.relative_branch();
+                    self.relative_branch()
+                } else {
+                    Fetch
                 }
-                Fetch
             },
 
             // CLC -- clear carry flag
@@ -412,21 +469,15 @@ impl Cpu {
             },
 
             // DEC -- decrement
-            (DEC, addr_mode) => {
+            (DEC, _) => {
                 if debug {
 					println!("DEC ${:0>2X}", self.addr_lo);
 				}
-                if addr_mode == AbsoluteHiX {
-                    // Kill a cycle for absolute, x
-                    self.curr_instr.addr_mode = AbsoluteHi;
-                    Load
-                } else {
-                    let data = self.read_data_bus().wrapping_sub(1);
-                    self.sr.determine_negative(self.data_bus);
-                    self.sr.determine_zero(self.data_bus);
-                    self.set_data_bus(data);
-                    Store
-                }
+                let data = self.read_data_bus().wrapping_sub(1);
+                self.sr.determine_negative(data);
+                self.sr.determine_zero(data);
+                self.set_data_bus(data);
+                Store
             },
 
             // DEX -- decrement X
@@ -463,21 +514,15 @@ impl Cpu {
             },
 
             // INC -- increment
-            (INC, addr_mode) => {
+            (INC, _) => {
                 if debug {
 					println!("INC ${:0>2X}", self.addr_lo);
 				}
-                if addr_mode == AbsoluteHiX {
-                    // Kill a cycle for absolute, x
-                    self.curr_instr.addr_mode = AbsoluteHi;
-                    Load
-                } else {
-                    let data = self.read_data_bus().wrapping_add(1);
-                    self.sr.determine_negative(self.data_bus);
-                    self.sr.determine_zero(self.data_bus);
-                    self.set_data_bus(data);
-                    Store
-                }
+                let data = self.read_data_bus().wrapping_add(1);
+                self.sr.determine_negative(data);
+                self.sr.determine_zero(data);
+                self.set_data_bus(data);
+                Store
             },
 
             // INX -- increment X
@@ -513,7 +558,8 @@ impl Cpu {
 
             // JSR -- jump and save return addr
             (JSR, _) => {
-                self.stack_word = self.pc;
+                // The return address on the stack is the last byte of the JSR instruction
+                self.stack_word = self.pc.wrapping_sub(1);
                 self.pc = self.addr_from_hi_lo();
                 if debug {
 					println!("JSR ${:0>4X}", self.pc);
@@ -565,10 +611,6 @@ impl Cpu {
                     self.sr.determine_zero(self.a);
                     self.sr.determine_negative(self.a);
                     Fetch
-                } else if addr_mode == AbsoluteHiX {
-                    // Kill a cycle for absolute, x
-                    self.curr_instr.addr_mode = AbsoluteHi;
-                    Load
                 } else {
                     let data = self.read_data_bus();
                     self.sr.determine_carry(data);
@@ -597,19 +639,18 @@ impl Cpu {
             },
 
             // PHA -- push A on stack
-            // TODO: Cycle counts are wrong for the four stack functions
+            // The push is written during the next cycle, which then goes straight to the fetch
             (PHA, _) => {
                 if debug {
 					println!("PHA");
 				}
-                let a = self.a;
-                self.set_data_bus(a);
                 let sp = self.get_stack_addr();
                 self.set_addr_bus(sp);
+                let a = self.a;
+                self.set_data_bus(a);
                 self.sp  = self.sp.wrapping_sub(1);
-                self.pc = self.pc.wrapping_add(1);
 
-                Store
+                ToLoad
             },
 
             // PHP -- push SR on stack
@@ -617,14 +658,13 @@ impl Cpu {
                 if debug {
 					println!("PHP");
 				}
-                let sr = self.sr.to_u8();
-                self.set_data_bus(sr);
                 let sp = self.get_stack_addr();
                 self.set_addr_bus(sp);
+                let sr = self.sr.to_u8();
+                self.set_data_bus(sr);
                 self.sp  = self.sp.wrapping_sub(1);
-                self.pc = self.pc.wrapping_add(1);
 
-                Store
+                ToLoad
             },
 
             // PLA -- pull A from stack
@@ -632,13 +672,11 @@ impl Cpu {
                 if debug {
                     println!("PLA");
                 }
-                if addr_mode == Implied {
-                    self.sp.wrapping_add(1);
+                if self.state == Address && addr_mode == Implied {
                     let sp = self.get_stack_addr();
                     self.set_addr_bus(sp);
-                    self.pc = self.pc.wrapping_add(1);
 
-                    Load
+                    PullStack
                 } else {
                     self.a = self.read_data_bus();
                     self.sr.determine_zero(self.a);
@@ -650,15 +688,13 @@ impl Cpu {
             // PLP -- pull SR from stack
             (PLP, addr_mode) => {
                 if debug {
-                    println!("PLA");
+                    println!("PLP");
                 }
-                if addr_mode == Implied {
-                    self.sp.wrapping_add(1);
+                if self.state == Address && addr_mode == Implied {
                     let sp = self.get_stack_addr();
                     self.set_addr_bus(sp);
-                    self.pc = self.pc.wrapping_add(1);
 
-                    Load
+                    PullStack
                 } else {
                     let data = self.read_data_bus();
                     self.sr.set_all_flags(data);
@@ -677,10 +713,6 @@ impl Cpu {
                     self.sr.determine_zero(self.a);
                     self.sr.determine_carry(self.a);
                     Fetch
-                } else if addr_mode == AbsoluteHiX {
-                    // Kill a cycle for absolute, x
-                    self.curr_instr.addr_mode = AbsoluteHi;
-                    Load
                 } else {
                     let data = self.read_data_bus();
                     self.sr.determine_negative(data);
@@ -703,10 +735,6 @@ impl Cpu {
                     self.sr.determine_zero(self.a);
                     self.sr.determine_carry(self.a);
                     Fetch
-                } else if addr_mode == AbsoluteHiX {
-                    // Kill a cycle for absolute, x
-                    self.curr_instr.addr_mode = AbsoluteHi;
-                    Load
                 } else {
                     let data = self.read_data_bus();
                     self.sr.determine_negative(data);
@@ -720,7 +748,35 @@ impl Cpu {
 
             // RTI -- return from interrupt
             (RTI, _) => {
-                panic!();
+                if debug {
+                    println!("RTI");
+                }
+                if self.state == Address {
+                    // Pull SR first
+                    self.sp = self.sp.wrapping_add(1);
+                    let sp = self.get_stack_addr();
+                    self.set_addr_bus(sp);
+
+                    Load
+                } else if self.state == Load {
+                    // The break flag only exists on the stack
+                    let data = self.read_data_bus();
+                    self.sr.set_all_flags(data);
+                    self.sr.break_cmd = false;
+
+                    // Then the return address
+                    self.sp = self.sp.wrapping_add(1);
+                    let sp = self.get_stack_addr();
+                    self.set_addr_bus(sp);
+                    self.stack_word_ready = false;
+                    self.stack_word = 0u16;
+
+                    PullWordHi
+                } else {
+                    self.pc = self.stack_word;
+                    self.stack_word_ready = false;
+                    ToLoad
+                }
             },
 
             // RTS -- return from subroutine
@@ -729,7 +785,7 @@ impl Cpu {
 					println!("RTS");
 				}
                 if self.stack_word_ready {
-                    self.pc = self.stack_word;
+                    self.pc = self.stack_word.wrapping_add(1);
                     self.stack_word_ready = false;
                     ToLoad
                 } else {
@@ -743,22 +799,38 @@ impl Cpu {
                     println!("SBC #${:0>2X}", self.read_data_bus());
                 }
 
-                let data = if self.sr.carry {
-                    !self.read_data_bus()
-                } else {
-                    (!self.read_data_bus()).wrapping_add(1)
-                };
+                // A - M - borrow is the same as A + ~M + carry
+                let data = self.read_data_bus();
+                let borrow = !self.sr.carry;
+                let result = (self.a as u16) + ((!data) as u16) + (self.sr.carry as u16);
 
-                // Determine whether a borrow will be required
-                self.sr.carry = self.read_data_bus() > self.a;
+                // Carry is clear if a borrow was required
+                self.sr.carry = result > 0xff;
 
-                self.a = self.a.wrapping_add(data);
+                // Overflow if the operands have different signs and the result's sign differs from A
+                let result = result as u8;
+                self.sr.overflow = ((self.a ^ data) & (self.a ^ result)) & 0x80 == 0x80;
+
+                // The flags always come from the binary result, even in decimal mode
+                self.sr.determine_negative(result);
+                self.sr.determine_zero(result);
+
+                if self.sr.decimal {
+                    // Subtract each BCD digit and adjust any digit that borrowed
+                    let mut lo = (self.a & 0x0f) as i16 - (data & 0x0f) as i16 - (borrow as i16);
+                    let mut hi = (self.a >> 4) as i16 - (data >> 4) as i16;
+                    if lo < 0 {
+                        lo -= 6;
+                        hi -= 1;
+                    }
+                    if hi < 0 {
+                        hi -= 6;
+                    }
+                    self.a = (((hi << 4) | (lo & 0x0f)) & 0xff) as u8;
+                } else {
+                    self.a = result;
+                }
 
-                self.sr.determine_negative(self.a);
-                self.sr.determine_zero(self.a);
-                let result =(self.a as i16) - (self.read_data_bus() as i16);
-                self.sr.overflow = result < -128 || result > 127;
-                    
                 Fetch
             },
 
@@ -961,6 +1033,18 @@ impl Cpu {
             },
 
             // LAX -- LDA then TAX
+            // The immediate form mixes in A and a magic constant
+            (LAX, Immediate) => {
+                if debug {
+                    println!("!! LAX #${:0>2X}", self.read_data_bus());
+                }
+                self.a = (self.a | self.lax_magic) & self.read_data_bus();
+                self.x = self.a;
+                self.sr.determine_zero(self.x);
+                self.sr.determine_negative(self.x);
+
+                Fetch
+            },
             (LAX, _) => {
                 if debug {
                     println!("!! LAX $#{:0>2X}", self.read_data_bus());
@@ -986,6 +1070,8 @@ impl Cpu {
 
             // KIL -- halt the CPU
             (KIL, _) => {
+                // Leave PC on the KIL so it's clear where the CPU stopped
+                self.pc = self.pc.wrapping_sub(1);
                 Halt
             },
 
@@ -1011,13 +1097,42 @@ impl Cpu {
                     self.do_instr(debug)
                 }
             },
+            Branch => {
+                let pc = self.pc;
+                self.set_addr_bus(pc);
+                Fetch
+            },
+            BranchPage => {
+                Branch
+            },
+            IndexFixup => {
+                // The address is already on the bus, so the read just happens again
+                Load
+            },
+            Reset(n) => {
+                if n > 1 {
+                    Reset(n - 1)
+                } else {
+                    InterruptLo
+                }
+            },
             Interrupt => {
+                // NMIs can't be disabled
+                if self.nmi {
+                    self.nmi = false;
+                    self.nmi_active = true;
+                    self.hw_interrupt = true;
+                    self.curr_instr = Instruction::from_u8(0x00);
+
+                    Address
                 // Ignore the interrupt if disabled
-                if self.sr.int_disable {
+                } else if self.sr.int_disable {
                     self.irq = false;
                     Fetch
                 // Trigger a BRK and load the IRQ routine address
                 } else if self.curr_instr.opcode != Opcode::BRK {
+                    self.irq = false;
+                    self.hw_interrupt = true;
                     self.curr_instr = Instruction::from_u8(0x00);
 
                     Address
@@ -1041,10 +1156,22 @@ impl Cpu {
                 Fetch
             },
             Fetch => {
+                // The IRQ line is only checked when interrupts are enabled
+                if self.irq && self.sr.int_disable {
+                    self.irq = false;
+                }
 
-                if !self.irq {
-                    self.curr_instr = Instruction::from_u8(self.read_data_bus());
-                    Address
+                if !self.irq && !self.nmi {
+                    let code = self.read_data_bus();
+                    if self.strict_opcodes && !coverage::is_documented(code) {
+                        // Leave PC on the opcode like KIL does
+                        self.pc = self.pc.wrapping_sub(1);
+                        self.illegal_opcode = Some(code);
+                        Halt
+                    } else {
+                        self.curr_instr = Instruction::from_u8(code);
+                        Address
+                    }
                 } else {
                     Interrupt
                 }
@@ -1058,7 +1185,9 @@ impl Cpu {
                 s
             },
             Store => {
-                self.rw = false;
+                // The write happened at the start of this cycle, so let go of the bus until the
+                // next access
+                self.addr_enable = false;
                 ToLoad
             },
             Address => {
@@ -1114,8 +1243,10 @@ impl Cpu {
                         let addr = self.addr_from_hi_lo();
                         self.set_addr_bus(addr);
 
-                        // JMP and JSR are special cases since we don't care what's on the data bus
-                        if self.curr_instr.opcode == Opcode::JMP || self.curr_instr.opcode == Opcode::JSR {
+                        // JMP, JSR and the BRK vector are special cases since we don't care what's
+                        // on the data bus
+                        let opcode = self.curr_instr.opcode;
+                        if opcode == Opcode::JMP || opcode == Opcode::JSR || opcode == Opcode::BRK {
                             self.do_instr(debug)
                         } else {
                             Load
@@ -1126,7 +1257,15 @@ impl Cpu {
                         let addr = self.addr_from_hi_lo().wrapping_add(self.x as u16);
                         self.set_addr_bus(addr);
 
-                        Load
+                        // Read-modify-write instructions always take a cycle to fix up the high
+                        // byte of the address. The undocumented NOPs only do when X crosses a page.
+                        let page_cross = (self.addr_lo as u16) + (self.x as u16) > 0xff;
+                        let opcode = self.curr_instr.opcode;
+                        if opcode.is_read_modify_write() || (opcode == Opcode::NOP && page_cross) {
+                            IndexFixup
+                        } else {
+                            Load
+                        }
                     },
                     AbsoluteHiY => {
                         self.addr_hi = self.read_data_bus();
@@ -1161,7 +1300,8 @@ impl Cpu {
                         Address
                     },
                     IndexedIndirectAdd => {
-                        let addr = self.addr_bus.wrapping_add(self.x as u16);
+                        // The pointer is always in zeropage
+                        let addr = (self.addr_bus as u8).wrapping_add(self.x) as u16;
                         self.set_addr_bus(addr);
 
                         self.curr_instr.addr_mode = IndexedIndirectLo;
@@ -1169,7 +1309,7 @@ impl Cpu {
                     },
                     IndexedIndirectLo => {
                         self.addr_lo = self.read_data_bus();
-                        let addr = self.addr_bus.wrapping_add(1);
+                        let addr = (self.addr_bus as u8).wrapping_add(1) as u16;
                         self.set_addr_bus(addr);
 
                         self.curr_instr.addr_mode = IndexedIndirectHi;
@@ -1226,13 +1366,10 @@ impl Cpu {
                         Load
                     },
 
+                    // PC only moved past the opcode, which is all a one byte instruction needs.
+                    // Anything that changes the flow of the program sets PC itself.
                     Implied => {
-                        let s = self.do_instr(debug);
-                        if s != Fetch {
-                            // Program counter shouldn't have been incremented
-                            self.pc = self.pc.wrapping_sub(1);
-                        }
-                        s
+                        self.do_instr(debug)
                     },
                     Immediate => {
                         self.do_instr(debug)
@@ -1271,6 +1408,13 @@ impl Cpu {
                     PullWordHi
                 }
             },
+            PullStack => {
+                self.sp = self.sp.wrapping_add(1);
+                let sp = self.get_stack_addr();
+                self.set_addr_bus(sp);
+
+                Load
+            },
             PullWordLo => {
                 self.sp = self.sp.wrapping_add(1);
                 let sp = self.get_stack_addr();
@@ -1298,10 +1442,127 @@ impl Cpu {
         self.rw = false;
     }
 
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    // A, X, and Y
+    pub fn registers(&self) -> (u8, u8, u8) {
+        (self.a, self.x, self.y)
+    }
+
+    // Set a register by name for the debugger. PC can only be changed between instructions.
+    pub fn set_register(&mut self, name: &str, value: u16) -> Result<(), String> {
+        let name = name.to_lowercase();
+        if name != "pc" && value > 0xff {
+            return Err(format!("Value too large for {}: ${:X}", name.to_uppercase(), value));
+        }
+        match name.as_str() {
+            "a" => { self.a = value as u8; },
+            "x" => { self.x = value as u8; },
+            "y" => { self.y = value as u8; },
+            "sp" => { self.sp = value as u8; },
+            "sr" | "p" => { self.sr.set_all_flags(value as u8); },
+            "pc" => {
+                if !self.at_instruction_start() {
+                    return Err("PC can only be set between instructions".to_string());
+                }
+                self.pc = value;
+                self.set_addr_bus(value);
+            },
+            _ => return Err(format!("Unknown register: {}", name)),
+        }
+        Ok(())
+    }
+
+    // Set the magic constant used by LAX #imm
+    pub fn set_lax_magic(&mut self, magic: u8) {
+        self.lax_magic = magic;
+    }
+
+    // Treat undocumented opcodes as errors rather than running them
+    pub fn set_strict_opcodes(&mut self, strict: bool) {
+        self.strict_opcodes = strict;
+    }
+
+    // Get the undocumented opcode that stopped the CPU in strict mode, if there was one
+    pub fn illegal_opcode(&self) -> Option<u8> {
+        self.illegal_opcode
+    }
+
+    // Number of cycles since startup
+    #[cfg(test)]
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Format the registers, flags, and cycle count as a JSON object
+    pub fn state_json(&self) -> String {
+        format!(
+            "{{\"pc\":{},\"a\":{},\"x\":{},\"y\":{},\"sp\":{},\"status\":{},\"cycles\":{}}}",
+            self.pc, self.a, self.x, self.y, self.sp, self.sr.to_json(), self.cycles
+            )
+    }
+
+    // Run the CPU connected to a memory until it's about to fetch the next instruction or it
+    // halts. Returns the number of cycles run.
+    pub fn run_until_fetch<M: Memory + ?Sized>(&mut self, mem: &mut M) -> u32 {
+        let mut cycles = 0;
+        loop {
+            if self.addr_enable {
+                if self.rw {
+                    let byte = mem.read(self.addr_bus);
+                    self.data_in(byte);
+                } else {
+                    mem.write(self.addr_bus, self.data_out());
+                }
+            }
+            self.cycle(false);
+            cycles += 1;
+            if self.at_instruction_start() || self.state == CpuState::Halt {
+                return cycles;
+            }
+        }
+    }
+
+    // Return from a subroutine the emulator ran in place of the CPU, the way RTS would. KERNAL
+    // routines report errors by setting carry and putting the error code in A.
+    pub fn trap_return<M: Memory + ?Sized>(&mut self, mem: &M, error: Option<u8>) {
+        let lo = mem.read(STACK_START_ADDR + self.sp.wrapping_add(1) as u16);
+        let hi = mem.read(STACK_START_ADDR + self.sp.wrapping_add(2) as u16);
+        self.sp = self.sp.wrapping_add(2);
+        self.pc = (((hi as u16) << 8) | lo as u16).wrapping_add(1);
+
+        self.sr.carry = error.is_some();
+        if let Some(e) = error {
+            self.a = e;
+        }
+
+        let pc = self.pc;
+        self.set_addr_bus(pc);
+        self.state = CpuState::Fetch;
+    }
+
+    // Determine whether the CPU is about to fetch a new instruction
+    pub fn at_instruction_start(&self) -> bool {
+        self.state == CpuState::Fetch
+    }
+
     pub fn trigger_interrupt(&mut self) {
         self.irq = true;
     }
 
+    // Whether the CPU is in the middle of starting an IRQ, which is when an interrupt source knows
+    // it's been seen
+    pub fn servicing_irq(&self) -> bool {
+        self.hw_interrupt && !self.nmi_active
+    }
+
+    // NMI is edge-triggered, so each call causes exactly one interrupt
+    pub fn trigger_nmi(&mut self) {
+        self.nmi = true;
+    }
+
     pub fn data_in(&mut self, value: u8) {
         if self.rw {
             self.data_bus = value;
@@ -1323,29 +1584,6 @@ impl Cpu {
     pub fn write_dataport(&mut self, value: u8) {
         // TODO: This is not quite how the DDR masking works
         self.dataport = self.data_direction_reg & value;
-        
-        // Reset rom statuses
-        let rom_status = self.read_dataport() & 7;
-        self.kernal_rom_enabled = rom_status % 4 > 1;
-        self.basic_rom_enabled = rom_status % 4 == 3;
-        self.char_rom_enabled = rom_status < 4 && rom_status > 0;
-        self.io_enabled = rom_status > 4;
-    }
-
-    pub fn krom_enabled(&self) -> bool {
-        self.kernal_rom_enabled
-    }
-
-    pub fn brom_enabled(&self) -> bool {
-        self.basic_rom_enabled
-    }
-
-    pub fn crom_enabled(&self) -> bool {
-        self.char_rom_enabled
-    }
-
-    pub fn io_enabled(&self) -> bool {
-        self.io_enabled
     }
 
     pub fn read_dataport(&self) -> u8 {
@@ -1388,9 +1626,11 @@ impl Cpu {
         ((self.addr_hi as u16) << 8) + (self.addr_lo as u16)
     }
 
-    // Apply an offset for relative addressing
-    fn relative_branch(&mut self) {
+    // Take a branch. Taken branches need an extra cycle, and another one if the target is on a
+    // different page.
+    fn relative_branch(&mut self) -> CpuState {
         let offset = self.data_bus;
+        let old_pc = self.pc;
         if offset < 0x80 {
             self.pc = self.pc.wrapping_add(offset as u16);
         } else {
@@ -1398,6 +1638,12 @@ impl Cpu {
         }
         let pc = self.pc;
         self.set_addr_bus(pc);
+
+        if (old_pc ^ pc) & 0xff00 != 0 {
+            CpuState::BranchPage
+        } else {
+            CpuState::Branch
+        }
     }
 }
 
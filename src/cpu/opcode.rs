@@ -2,6 +2,7 @@
 // Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
 //
 // Opcodes and function for mapping bytes to opcodes
+use std::str::FromStr;
 
 #[derive(Eq, PartialEq, Debug, Clone, Copy)]
 pub enum Opcode {
@@ -362,4 +363,98 @@ impl Opcode {
             _ => panic!("Opcode out of range: {:0>4X}", code),
         }
     }
+
+    // Does the instruction read a byte from memory, change it, and write it back? The undocumented
+    // ones that combine a shift or increment with another operation count too.
+    pub fn is_read_modify_write(&self) -> bool {
+        use self::Opcode::*;
+        matches!(*self, ASL | LSR | ROL | ROR | INC | DEC | SLO | RLA | SRE | RRA | DCP | ISC)
+    }
+}
+
+// Parse a mnemonic as written in assembly
+impl FromStr for Opcode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Opcode, String> {
+        use self::Opcode::*;
+        match s {
+            "ADC" => Ok(ADC),
+            "AND" => Ok(AND),
+            "ASL" => Ok(ASL),
+            "BCC" => Ok(BCC),
+            "BCS" => Ok(BCS),
+            "BEQ" => Ok(BEQ),
+            "BIT" => Ok(BIT),
+            "BMI" => Ok(BMI),
+            "BNE" => Ok(BNE),
+            "BPL" => Ok(BPL),
+            "BRK" => Ok(BRK),
+            "BVC" => Ok(BVC),
+            "BVS" => Ok(BVS),
+            "CLC" => Ok(CLC),
+            "CLD" => Ok(CLD),
+            "CLI" => Ok(CLI),
+            "CLV" => Ok(CLV),
+            "CMP" => Ok(CMP),
+            "CPX" => Ok(CPX),
+            "CPY" => Ok(CPY),
+            "DEC" => Ok(DEC),
+            "DEX" => Ok(DEX),
+            "DEY" => Ok(DEY),
+            "EOR" => Ok(EOR),
+            "INC" => Ok(INC),
+            "INX" => Ok(INX),
+            "INY" => Ok(INY),
+            "JMP" => Ok(JMP),
+            "JSR" => Ok(JSR),
+            "KIL" => Ok(KIL),
+            "LDA" => Ok(LDA),
+            "LDX" => Ok(LDX),
+            "LDY" => Ok(LDY),
+            "LSR" => Ok(LSR),
+            "NOP" => Ok(NOP),
+            "ORA" => Ok(ORA),
+            "PHA" => Ok(PHA),
+            "PHP" => Ok(PHP),
+            "PLA" => Ok(PLA),
+            "PLP" => Ok(PLP),
+            "ROL" => Ok(ROL),
+            "ROR" => Ok(ROR),
+            "RTI" => Ok(RTI),
+            "RTS" => Ok(RTS),
+            "SBC" => Ok(SBC),
+            "SEC" => Ok(SEC),
+            "SED" => Ok(SED),
+            "SEI" => Ok(SEI),
+            "STA" => Ok(STA),
+            "STX" => Ok(STX),
+            "STY" => Ok(STY),
+            "TAX" => Ok(TAX),
+            "TAY" => Ok(TAY),
+            "TYA" => Ok(TYA),
+            "TSX" => Ok(TSX),
+            "TXA" => Ok(TXA),
+            "TXS" => Ok(TXS),
+            "SLO" => Ok(SLO),
+            "RLA" => Ok(RLA),
+            "SRE" => Ok(SRE),
+            "RRA" => Ok(RRA),
+            "SAX" => Ok(SAX),
+            "LAX" => Ok(LAX),
+            "DCP" => Ok(DCP),
+            "ISC" => Ok(ISC),
+            "ANC" => Ok(ANC),
+            "ALR" => Ok(ALR),
+            "ARR" => Ok(ARR),
+            "XAA" => Ok(XAA),
+            "AXS" => Ok(AXS),
+            "AHX" => Ok(AHX),
+            "SHY" => Ok(SHY),
+            "SHX" => Ok(SHX),
+            "TAS" => Ok(TAS),
+            "LAS" => Ok(LAS),
+            _ => Err(format!("Unknown instruction: {}", s)),
+        }
+    }
 }
@@ -3,7 +3,7 @@
 //
 // Functions and datatypes related to the CPU status register
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct StatusRegister {
     pub negative: bool,
     pub overflow: bool,
@@ -20,7 +20,8 @@ impl StatusRegister {
         // NV-BDIZC
         self.negative = value & 128 == 128;
         self.overflow = value & 64 == 64;
-        self.expansion = value & 32 == 32;
+        // Bit 5 isn't connected to anything and always reads as set
+        self.expansion = true;
         self.break_cmd = value & 16 == 16;
         self.decimal = value & 8 == 8;
         self.int_disable = value & 4 == 4;
@@ -29,16 +30,14 @@ impl StatusRegister {
     }
 
     pub fn to_u8(&self) -> u8 {
-        let mut val = 0u8;
+        // The unused bit is always set
+        let mut val = 32u8;
         if self.negative {
             val += 128;
         }
         if self.overflow {
             val += 64;
         }
-        if self.expansion {
-            val += 32;
-        }
         if self.break_cmd {
             val += 16;
         }
@@ -57,11 +56,19 @@ impl StatusRegister {
         val
     }
 
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"n\":{},\"v\":{},\"b\":{},\"d\":{},\"i\":{},\"z\":{},\"c\":{}}}",
+            self.negative, self.overflow, self.break_cmd, self.decimal, self.int_disable,
+            self.zero_result, self.carry
+            )
+    }
+
     pub fn new() -> StatusRegister {
         StatusRegister {
             negative: false,
             overflow: false,
-            expansion: false,
+            expansion: true,
             break_cmd: false,
             decimal: false,
             int_disable: false,
@@ -73,17 +80,9 @@ impl StatusRegister {
     // Compare two values and store the results
     pub fn compare(&mut self, a: &u8, b: &u8) {
         let diff = a.wrapping_sub(*b);
-        self.negative = diff > 0x80;
-        self.zero_result = false;
-
-        if a < b {
-            self.carry = false;
-        } else if a == b {
-            self.carry = true;
-            self.zero_result = true;
-        } else {
-            self.carry = true;
-        }
+        self.carry = a >= b;
+        self.determine_zero(diff);
+        self.determine_negative(diff);
     }
 
     // Determine whether a number is zero and set the corresponding status bit
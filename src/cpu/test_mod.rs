@@ -1,42 +1,152 @@
 // Copyright 2016 Peter Beard
 // Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
 use super::*;
-
-// Run a program consisting of a single instruction
-fn run_program(program: &[u8], cpu: &mut Cpu) {
-    let mut ram: [u8; 65536] = [0u8; 65536];
-    if program[0] == 0 {
-        ram = [80u8; 65536];
+use symbols::SymbolTable;
+
+// Test programs are loaded at the KERNAL's reset address
+const PROGRAM_ADDR: u16 = 0xfce2;
+
+// Default number of cycles a test program can run for before the harness gives up on it
+const MAX_TEST_CYCLES: u64 = 1000;
+
+// Machine state to set up before running a test program
+struct TestSetup {
+    fill: u8,   // Value of every byte of RAM outside the program
+    a: u8,
+    x: u8,
+    y: u8,
+    sp: u8,
+    sr: u8,
+    irq: bool,  // IRQ line asserted when the program starts
+    max_cycles: u64,    // Guard against programs that never leave
+}
+
+impl TestSetup {
+    // The state the CPU is in after a reset with RAM cleared
+    fn new() -> TestSetup {
+        TestSetup {
+            fill: 0x00,
+            a: 0xaa,
+            x: 0x00,
+            y: 0x00,
+            sp: 0xfd,
+            sr: 0x20,
+            irq: false,
+            max_cycles: MAX_TEST_CYCLES,
+        }
     }
+}
+
+// Run a program consisting of a single instruction and return the resulting RAM contents
+fn run_program(program: &[u8], cpu: &mut Cpu) -> [u8; 65536] {
+    run_program_with(program, &TestSetup::new(), cpu)
+}
+
+// Run a program starting from the given state and return the resulting RAM contents. Fails the
+// test if the program doesn't finish.
+fn run_program_with(program: &[u8], setup: &TestSetup, cpu: &mut Cpu) -> [u8; 65536] {
+    let (ram, finished) = try_run_program(program, setup, cpu);
+    assert!(finished, "Program {:?} still running after {} cycles", program, setup.max_cycles);
+    ram
+}
 
-    // Write the program to the reset location
+// Run a program until its last instruction retires, i.e. the CPU is back at Fetch with PC outside
+// the program. Returns the RAM contents and false if it hit the cycle guard first.
+fn try_run_program(program: &[u8], setup: &TestSetup, cpu: &mut Cpu) -> ([u8; 65536], bool) {
+    let mut ram: [u8; 65536] = [setup.fill; 65536];
+
+    // Write the program and point the reset vector at it
     for addr in 0..program.len() {
-        ram[super::RESET_VECTOR_ADDR as usize + addr] = program[addr];
+        ram[PROGRAM_ADDR as usize + addr] = program[addr];
     }
+    ram[0xfffc] = PROGRAM_ADDR as u8;
+    ram[0xfffd] = (PROGRAM_ADDR >> 8) as u8;
 
+    // Only count the program's cycles
     cpu.reset();
+    cpu.run_until_fetch(&mut ram);
+    cpu.cycles = 0;
+    cpu.a = setup.a;
+    cpu.x = setup.x;
+    cpu.y = setup.y;
+    cpu.sp = setup.sp;
+    cpu.sr.set_all_flags(setup.sr);
+    if setup.irq {
+        cpu.trigger_interrupt();
+    }
 
+    let end = PROGRAM_ADDR as usize + program.len();
     loop {
-        let addr = cpu.addr_bus as usize;
-
-        if (cpu.pc < super::RESET_VECTOR_ADDR || cpu.pc >= super::RESET_VECTOR_ADDR + program.len() as u16) && cpu.state == super::CpuState::Fetch {
-            break;
+        // run_until_fetch always stops at the start of an instruction
+        if !(PROGRAM_ADDR as usize..end).contains(&(cpu.pc as usize)) {
+            return (ram, true);
         }
-
-        if cpu.rw {
-            cpu.data_in(ram[addr]);
-        } else {
-            ram[addr] = cpu.data_out();
+        if cpu.cycles >= setup.max_cycles {
+            return (ram, false);
         }
+        cpu.run_until_fetch(&mut ram);
         println!("{:?}", cpu);
-        cpu.cycle(false);
-        println!("{:?}", cpu);
+    }
+}
 
-        if cpu.cycles > 20 {
-            break;
-        }
+// RAM that keeps a log of every write
+struct LoggedRam {
+    ram: [u8; 65536],
+    writes: Vec<(u16, u8)>,
+}
+
+impl Memory for LoggedRam {
+    fn read(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.ram[addr as usize] = value;
+        self.writes.push((addr, value));
     }
 }
+
+// Reset the CPU into a program at PROGRAM_ADDR in a logged RAM
+fn logged_ram(program: &[u8], cpu: &mut Cpu) -> LoggedRam {
+    let mut mem = LoggedRam { ram: [0u8; 65536], writes: Vec::new() };
+    mem.ram[PROGRAM_ADDR as usize..PROGRAM_ADDR as usize + program.len()].copy_from_slice(program);
+    mem.ram[0xfffc] = PROGRAM_ADDR as u8;
+    mem.ram[0xfffd] = (PROGRAM_ADDR >> 8) as u8;
+    cpu.reset();
+    cpu.run_until_fetch(&mut mem);
+    mem
+}
+
+// Test running the CPU against a mock memory
+#[test]
+fn run_until_fetch_counts_cycles() {
+    let mut cpu = Cpu::new();
+    let mut mem = logged_ram(&[0xa9, 0x42, 0x6d, 0x00, 0x0f], &mut cpu);
+
+    assert_eq!(2, cpu.run_until_fetch(&mut mem));
+    assert_eq!(4, cpu.run_until_fetch(&mut mem));
+    assert_eq!(PROGRAM_ADDR + 5, cpu.pc);
+}
+
+#[test]
+fn sta_abs_only_writes_target() {
+    let mut cpu = Cpu::new();
+    let mut mem = logged_ram(&[0xa9, 0x42, 0x8d, 0x00, 0x0f], &mut cpu);
+
+    cpu.run_until_fetch(&mut mem);
+    cpu.run_until_fetch(&mut mem);
+    assert_eq!(vec![(0x0f00, 0x42)], mem.writes);
+}
+
+#[test]
+fn jsr_pushes_return_address_high_byte_first() {
+    let mut cpu = Cpu::new();
+    let mut mem = logged_ram(&[0x20, 0x00, 0x0f], &mut cpu);
+
+    assert_eq!(6, cpu.run_until_fetch(&mut mem));
+    // The return address is the last byte of the JSR
+    assert_eq!(vec![(0x01fd, 0xfc), (0x01fc, 0xe4)], mem.writes);
+}
 // Test cycle-accuracy of instructions
 // ADC
 #[test]
@@ -254,9 +364,11 @@ fn asl_absx_cycles() {
 #[test]
 fn bcc_test_cycles() {
     let mut cpu = Cpu::new();
+    let mut setup = TestSetup::new();
+    setup.sr = 0x21;
 
     let program = [0x90, 0x0f];
-    run_program(&program[..], &mut cpu);
+    run_program_with(&program[..], &setup, &mut cpu);
 
     assert_eq!(2, cpu.cycles);
 }
@@ -314,9 +426,11 @@ fn bmi_test_cycles() {
 #[test]
 fn bne_test_cycles() {
     let mut cpu = Cpu::new();
+    let mut setup = TestSetup::new();
+    setup.sr = 0x22;
 
     let program = [0xd0, 0x0f];
-    run_program(&program[..], &mut cpu);
+    run_program_with(&program[..], &setup, &mut cpu);
 
     assert_eq!(2, cpu.cycles);
 }
@@ -324,19 +438,23 @@ fn bne_test_cycles() {
 #[test]
 fn bpl_test_cycles() {
     let mut cpu = Cpu::new();
+    let mut setup = TestSetup::new();
+    setup.sr = 0xa0;
 
     let program = [0x10, 0x0f];
-    run_program(&program[..], &mut cpu);
+    run_program_with(&program[..], &setup, &mut cpu);
 
     assert_eq!(2, cpu.cycles);
 }
 
-//#[test]
+#[test]
 fn brk_test_cycles() {
     let mut cpu = Cpu::new();
 
+    // Point the IRQ vector outside the program
+    let setup = TestSetup { fill: 0x50, ..TestSetup::new() };
     let program = [0x00];
-    run_program(&program[..], &mut cpu);
+    run_program_with(&program[..], &setup, &mut cpu);
 
     assert_eq!(7, cpu.cycles);
 }
@@ -344,9 +462,11 @@ fn brk_test_cycles() {
 #[test]
 fn bvc_test_cycles() {
     let mut cpu = Cpu::new();
+    let mut setup = TestSetup::new();
+    setup.sr = 0x60;
 
     let program = [0x50, 0x0f];
-    run_program(&program[..], &mut cpu);
+    run_program_with(&program[..], &setup, &mut cpu);
 
     assert_eq!(2, cpu.cycles);
 }
@@ -361,6 +481,91 @@ fn bvs_test_cycles() {
     assert_eq!(2, cpu.cycles);
 }
 
+#[test]
+fn harness_reports_program_that_never_finishes() {
+    let mut cpu = Cpu::new();
+    let setup = TestSetup { max_cycles: 50, ..TestSetup::new() };
+
+    // BNE to itself
+    let program = [0xd0, 0xfe];
+    let (_, finished) = try_run_program(&program[..], &setup, &mut cpu);
+
+    assert!(!finished);
+    assert!((50..60).contains(&cpu.cycles));
+}
+
+// Run a branch that's taken with the given status register and return how many cycles it took
+fn branch_cycles(opcode: u8, offset: u8, sr: u8) -> u64 {
+    let mut cpu = Cpu::new();
+    let mut setup = TestSetup::new();
+    setup.sr = sr;
+
+    let program = [opcode, offset];
+    run_program_with(&program[..], &setup, &mut cpu);
+    cpu.cycles
+}
+
+#[test]
+fn bcc_taken_cycles() {
+    assert_eq!(3, branch_cycles(0x90, 0x0f, 0x20));
+    assert_eq!(4, branch_cycles(0x90, 0x7f, 0x20));
+}
+
+#[test]
+fn bcs_taken_cycles() {
+    assert_eq!(3, branch_cycles(0xb0, 0x0f, 0x21));
+    assert_eq!(4, branch_cycles(0xb0, 0x7f, 0x21));
+}
+
+#[test]
+fn beq_taken_cycles() {
+    assert_eq!(3, branch_cycles(0xf0, 0x0f, 0x22));
+    assert_eq!(4, branch_cycles(0xf0, 0x7f, 0x22));
+}
+
+#[test]
+fn bmi_taken_cycles() {
+    assert_eq!(3, branch_cycles(0x30, 0x0f, 0xa0));
+    assert_eq!(4, branch_cycles(0x30, 0x7f, 0xa0));
+}
+
+#[test]
+fn bne_taken_cycles() {
+    assert_eq!(3, branch_cycles(0xd0, 0x0f, 0x20));
+    assert_eq!(4, branch_cycles(0xd0, 0x7f, 0x20));
+}
+
+#[test]
+fn bpl_taken_cycles() {
+    assert_eq!(3, branch_cycles(0x10, 0x0f, 0x20));
+    assert_eq!(4, branch_cycles(0x10, 0x7f, 0x20));
+}
+
+#[test]
+fn bvc_taken_cycles() {
+    assert_eq!(3, branch_cycles(0x50, 0x0f, 0x20));
+    assert_eq!(4, branch_cycles(0x50, 0x7f, 0x20));
+}
+
+#[test]
+fn bvs_taken_cycles() {
+    assert_eq!(3, branch_cycles(0x70, 0x0f, 0x60));
+    assert_eq!(4, branch_cycles(0x70, 0x7f, 0x60));
+}
+
+#[test]
+fn branch_target() {
+    let mut cpu = Cpu::new();
+    let mut setup = TestSetup::new();
+    setup.sr = 0x21;
+
+    // Branches are relative to the next instruction
+    run_program_with(&[0xb0, 0x0f][..], &setup, &mut cpu);
+    assert_eq!(PROGRAM_ADDR + 0x11, cpu.pc);
+    run_program_with(&[0xb0, 0xf0][..], &setup, &mut cpu);
+    assert_eq!(PROGRAM_ADDR - 0x0e, cpu.pc);
+}
+
 #[test]
 fn clc_test_cycles() {
     let mut cpu = Cpu::new();
@@ -721,6 +926,40 @@ fn inc_absx_test_cycles() {
     assert_eq!(7, cpu.cycles);
 }
 
+#[test]
+fn rmw_absx_cycles_with_page_cross() {
+    // INC, DEC, ASL, LSR, ROL, ROR $0FF0,X with X = $20 all write $1010 in 7 cycles
+    for &code in &[0xfe, 0xde, 0x1e, 0x5e, 0x3e, 0x7e] {
+        let mut cpu = Cpu::new();
+        let mut setup = TestSetup::new();
+        setup.x = 0x20;
+        setup.fill = 0x40;
+
+        let ram = run_program_with(&[code, 0xf0, 0x0f], &setup, &mut cpu);
+        assert_eq!(7, cpu.cycles, "${:0>2X}", code);
+        assert!(ram[0x1010] != 0x40, "${:0>2X}", code);
+        assert_eq!(0x40, ram[0x0ff0], "${:0>2X}", code);
+    }
+}
+
+#[test]
+fn undocumented_rmw_opcodes() {
+    // SLO, RLA, SRE, RRA, DCP, ISC $nnnn,X
+    for &code in &[0x1f, 0x3f, 0x5f, 0x7f, 0xdf, 0xff] {
+        assert!(opcode::Opcode::from_u8(code).is_read_modify_write(), "${:0>2X}", code);
+    }
+}
+
+#[test]
+fn rmw_absx_keeps_addressing_mode() {
+    let mut cpu = Cpu::new();
+    let mut setup = TestSetup::new();
+    setup.x = 0x01;
+
+    run_program_with(&[0xfe, 0x00, 0x0f], &setup, &mut cpu);
+    assert_eq!(addressing_mode::AddressingMode::AbsoluteHiX, cpu.curr_instr.addr_mode);
+}
+
 #[test]
 fn inx_test_cycles() {
     let mut cpu = Cpu::new();
@@ -1011,6 +1250,115 @@ fn nop_test_cycles() {
     assert_eq!(2, cpu.cycles);
 }
 
+// Run an undocumented NOP with X set and return how many cycles it took
+fn illegal_nop_cycles(program: &[u8], x: u8) -> u64 {
+    let mut cpu = Cpu::new();
+    let mut setup = TestSetup::new();
+    setup.x = x;
+
+    run_program_with(program, &setup, &mut cpu);
+    assert_eq!(PROGRAM_ADDR + program.len() as u16, cpu.pc);
+    cpu.cycles
+}
+
+// Run LAX #imm with A set and return A and X
+fn lax_imm(a: u8, imm: u8, magic: Option<u8>) -> (u8, u8) {
+    let mut cpu = Cpu::new();
+    if let Some(m) = magic {
+        cpu.set_lax_magic(m);
+    }
+    let mut setup = TestSetup::new();
+    setup.a = a;
+
+    run_program_with(&[0xab, imm], &setup, &mut cpu);
+    (cpu.a, cpu.x)
+}
+
+#[test]
+fn lax_imm_uses_magic_constant() {
+    // (A | $EE) & imm
+    assert_eq!((0x0f, 0x0f), lax_imm(0x11, 0x0f, None));
+    assert_eq!((0x01, 0x01), lax_imm(0x11, 0x0f, Some(0x00)));
+    assert_eq!((0x0f, 0x0f), lax_imm(0x00, 0x0f, Some(0xff)));
+}
+
+#[test]
+fn strict_opcodes_halt_on_undocumented() {
+    // LAX #$0F runs normally
+    assert_eq!((0x0e, 0x0e), lax_imm(0xaa, 0x0f, None));
+    assert_eq!(None, Cpu::new().illegal_opcode());
+
+    // With strict opcodes the CPU stops on it without touching the registers
+    let mut cpu = Cpu::new();
+    cpu.set_strict_opcodes(true);
+    let mut ram = [0u8; 65536];
+    ram[PROGRAM_ADDR as usize] = 0xab;
+    ram[PROGRAM_ADDR as usize + 1] = 0x0f;
+    ram[0xfffc] = PROGRAM_ADDR as u8;
+    ram[0xfffd] = (PROGRAM_ADDR >> 8) as u8;
+
+    cpu.reset();
+    cpu.run_until_fetch(&mut ram);
+    cpu.run_until_fetch(&mut ram);
+
+    assert_eq!(Some(0xab), cpu.illegal_opcode());
+    assert_eq!(PROGRAM_ADDR, cpu.pc);
+    assert_eq!((0xaa, 0x00), (cpu.a, cpu.x));
+}
+
+#[test]
+fn strict_opcodes_run_documented() {
+    let mut cpu = Cpu::new();
+    cpu.set_strict_opcodes(true);
+
+    run_program(&[0xa9, 0x0f, 0xea], &mut cpu);
+    assert_eq!(0x0f, cpu.a);
+    assert_eq!(None, cpu.illegal_opcode());
+}
+
+#[test]
+fn lax_zp_loads_memory() {
+    let mut cpu = Cpu::new();
+    let mut setup = TestSetup::new();
+    setup.fill = 0x85;
+
+    let program = [0xa7, 0x03];
+    run_program_with(&program[..], &setup, &mut cpu);
+
+    assert_eq!(0x85, cpu.a);
+    assert_eq!(0x85, cpu.x);
+    assert!(cpu.sr.negative);
+}
+
+#[test]
+fn nop_illegal_imm_cycles() {
+    assert_eq!(2, illegal_nop_cycles(&[0x80, 0xff], 0));
+    assert_eq!(2, illegal_nop_cycles(&[0xe2, 0xff], 0));
+}
+
+#[test]
+fn nop_illegal_implied_cycles() {
+    assert_eq!(2, illegal_nop_cycles(&[0x1a], 0));
+}
+
+#[test]
+fn nop_illegal_zp_cycles() {
+    assert_eq!(3, illegal_nop_cycles(&[0x04, 0x10], 0));
+    assert_eq!(4, illegal_nop_cycles(&[0x14, 0x10], 0x01));
+}
+
+#[test]
+fn nop_illegal_abs_cycles() {
+    assert_eq!(4, illegal_nop_cycles(&[0x0c, 0x00, 0x0f], 0));
+}
+
+#[test]
+fn nop_illegal_absx_cycles() {
+    assert_eq!(4, illegal_nop_cycles(&[0x1c, 0x00, 0x0f], 0x01));
+    // Crossing a page takes an extra cycle
+    assert_eq!(5, illegal_nop_cycles(&[0xfc, 0x80, 0x0f], 0x80));
+}
+
 #[test]
 fn ora_imm_test_cycles() {
     let mut cpu = Cpu::new();
@@ -1091,7 +1439,7 @@ fn ora_indy_test_cycles() {
     assert_eq!(5, cpu.cycles);
 }
 
-//#[test]
+#[test]
 fn pha_test_cycles() {
     let mut cpu = Cpu::new();
 
@@ -1101,7 +1449,7 @@ fn pha_test_cycles() {
     assert_eq!(3, cpu.cycles);
 }
 
-//#[test]
+#[test]
 fn php_test_cycles() {
     let mut cpu = Cpu::new();
 
@@ -1111,7 +1459,7 @@ fn php_test_cycles() {
     assert_eq!(3, cpu.cycles);
 }
 
-//#[test]
+#[test]
 fn pla_test_cycles() {
     let mut cpu = Cpu::new();
 
@@ -1121,7 +1469,7 @@ fn pla_test_cycles() {
     assert_eq!(4, cpu.cycles);
 }
 
-//#[test]
+#[test]
 fn plp_test_cycles() {
     let mut cpu = Cpu::new();
 
@@ -1231,7 +1579,7 @@ fn ror_absx_test_cycles() {
     assert_eq!(7, cpu.cycles);
 }
 
-//#[test]
+#[test]
 fn rti_test_cycles() {
     let mut cpu = Cpu::new();
 
@@ -1552,3 +1900,684 @@ fn txs_test_cycles() {
 
     assert_eq!(2, cpu.cycles);
 }
+
+// Test flags set by read-modify-write instructions
+#[test]
+fn inc_zp_flags_from_result() {
+    let mut cpu = Cpu::new();
+
+    let setup = TestSetup { fill: 0xff, ..TestSetup::new() };
+    let program = [0xe6, 0x10];
+    let ram = run_program_with(&program[..], &setup, &mut cpu);
+
+    assert_eq!(0x00, ram[0x10]);
+    assert!(cpu.sr.zero_result);
+    assert!(!cpu.sr.negative);
+}
+
+#[test]
+fn dec_zp_flags_from_result() {
+    let mut cpu = Cpu::new();
+
+    let program = [0xc6, 0x10];
+    let ram = run_program(&program[..], &mut cpu);
+
+    assert_eq!(0xff, ram[0x10]);
+    assert!(!cpu.sr.zero_result);
+    assert!(cpu.sr.negative);
+}
+
+// Test status register flag logic
+// compare
+#[test]
+fn compare_equal_flags() {
+    let mut sr = StatusRegister::new();
+
+    sr.compare(&0x42, &0x42);
+
+    assert!(sr.carry);
+    assert!(sr.zero_result);
+    assert!(!sr.negative);
+}
+
+#[test]
+fn compare_greater_flags() {
+    let mut sr = StatusRegister::new();
+
+    sr.compare(&0x42, &0x10);
+
+    assert!(sr.carry);
+    assert!(!sr.zero_result);
+    assert!(!sr.negative);
+}
+
+#[test]
+fn compare_less_flags() {
+    let mut sr = StatusRegister::new();
+
+    sr.compare(&0x10, &0x42);
+
+    assert!(!sr.carry);
+    assert!(!sr.zero_result);
+    assert!(sr.negative);
+}
+
+#[test]
+fn compare_wraparound_flags() {
+    let mut sr = StatusRegister::new();
+
+    sr.compare(&0x00, &0x01);
+
+    assert!(!sr.carry);
+    assert!(!sr.zero_result);
+    assert!(sr.negative);
+}
+
+#[test]
+fn compare_negative_boundary_flags() {
+    let mut sr = StatusRegister::new();
+
+    // A difference of exactly $80 has bit 7 set
+    sr.compare(&0x80, &0x00);
+
+    assert!(sr.carry);
+    assert!(!sr.zero_result);
+    assert!(sr.negative);
+}
+
+#[test]
+fn compare_clears_stale_zero_flag() {
+    let mut sr = StatusRegister::new();
+
+    sr.compare(&0xff, &0xff);
+    sr.compare(&0xff, &0x00);
+
+    assert!(sr.carry);
+    assert!(!sr.zero_result);
+    assert!(sr.negative);
+}
+
+// expansion bit
+#[test]
+fn expansion_bit_always_set() {
+    let mut sr = StatusRegister::new();
+
+    sr.set_all_flags(0x00);
+
+    assert_eq!(0x20, sr.to_u8());
+}
+
+#[test]
+fn php_pushes_expansion_bit() {
+    let mut cpu = Cpu::new();
+
+    let program = [0x08];
+    let ram = run_program(&program[..], &mut cpu);
+
+    assert_eq!(0x20, ram[0x01fd] & 0x20);
+}
+
+// Test arithmetic flags
+#[test]
+fn adc_sets_overflow() {
+    let mut cpu = Cpu::new();
+
+    // CLC; LDA #$50; ADC #$50
+    let program = [0x18, 0xa9, 0x50, 0x69, 0x50];
+    run_program(&program[..], &mut cpu);
+
+    assert_eq!(0xa0, cpu.a);
+    assert!(cpu.sr.overflow);
+    assert!(cpu.sr.negative);
+    assert!(!cpu.sr.carry);
+}
+
+#[test]
+fn adc_clears_overflow() {
+    let mut cpu = Cpu::new();
+
+    // CLC; LDA #$50; ADC #$10
+    let program = [0x18, 0xa9, 0x50, 0x69, 0x10];
+    run_program(&program[..], &mut cpu);
+
+    assert_eq!(0x60, cpu.a);
+    assert!(!cpu.sr.overflow);
+    assert!(!cpu.sr.carry);
+}
+
+#[test]
+fn adc_adds_carry() {
+    let mut cpu = Cpu::new();
+
+    // SEC; LDA #$ff; ADC #$00
+    let program = [0x38, 0xa9, 0xff, 0x69, 0x00];
+    run_program(&program[..], &mut cpu);
+
+    assert_eq!(0x00, cpu.a);
+    assert!(cpu.sr.carry);
+    assert!(cpu.sr.zero_result);
+    assert!(!cpu.sr.overflow);
+}
+
+#[test]
+fn sbc_sets_overflow() {
+    let mut cpu = Cpu::new();
+
+    // SEC; LDA #$d0; SBC #$70
+    let program = [0x38, 0xa9, 0xd0, 0xe9, 0x70];
+    run_program(&program[..], &mut cpu);
+
+    assert_eq!(0x60, cpu.a);
+    assert!(cpu.sr.overflow);
+    assert!(cpu.sr.carry);
+}
+
+#[test]
+fn sbc_borrows() {
+    let mut cpu = Cpu::new();
+
+    // Carry set
+    let setup = TestSetup { a: 0x10, sr: 0x21, ..TestSetup::new() };
+
+    // SBC #$20
+    let program = [0xe9, 0x20];
+    run_program_with(&program[..], &setup, &mut cpu);
+
+    assert_eq!(2, cpu.cycles);
+    assert_eq!(0xf0, cpu.a);
+    assert!(!cpu.sr.overflow);
+    assert!(!cpu.sr.carry);
+    assert!(cpu.sr.negative);
+}
+
+// Test zeropage wraparound
+#[test]
+fn indexed_indirect_pointer_wraps() {
+    let mut cpu = Cpu::new();
+
+    // LDA #$c0; STA $00; LDX #$01; LDA #$42; STA ($fe,X)
+    // The pointer is $FF/$00, not $FF/$100
+    let program = [0xa9, 0xc0, 0x85, 0x00, 0xa2, 0x01, 0xa9, 0x42, 0x81, 0xfe];
+    let ram = run_program(&program[..], &mut cpu);
+
+    assert_eq!(0x42, ram[0xc000]);
+    assert_eq!(0xc0, ram[0x0000]);
+}
+
+#[test]
+fn indexed_indirect_base_wraps() {
+    let mut cpu = Cpu::new();
+
+    // LDA #$c0; STA $01; LDX #$03; LDA #$42; STA ($fe,X)
+    // $FE + 3 wraps to $01, so the pointer is $01/$02
+    let program = [0xa9, 0xc0, 0x85, 0x01, 0xa2, 0x03, 0xa9, 0x42, 0x81, 0xfe];
+    let ram = run_program(&program[..], &mut cpu);
+
+    assert_eq!(0x42, ram[0x00c0]);
+}
+
+// Test the JSON state dump
+#[test]
+fn state_json_after_program() {
+    let mut cpu = Cpu::new();
+
+    // SEC; LDA #$80; LDX #$01; LDY #$02
+    let program = [0x38, 0xa9, 0x80, 0xa2, 0x01, 0xa0, 0x02];
+    run_program(&program[..], &mut cpu);
+
+    assert_eq!(
+        "{\"pc\":64745,\"a\":128,\"x\":1,\"y\":2,\"sp\":253,\
+         \"status\":{\"n\":false,\"v\":false,\"b\":false,\"d\":false,\"i\":false,\"z\":false,\"c\":true},\
+         \"cycles\":8}",
+        cpu.state_json()
+        );
+}
+
+// Test decimal mode SBC
+#[test]
+fn sbc_decimal() {
+    let mut cpu = Cpu::new();
+
+    // Decimal and carry set
+    let setup = TestSetup { a: 0x05, sr: 0x29, ..TestSetup::new() };
+
+    // SBC #$01
+    let program = [0xe9, 0x01];
+    run_program_with(&program[..], &setup, &mut cpu);
+
+    assert_eq!(2, cpu.cycles);
+    assert_eq!(0x04, cpu.a);
+    assert!(cpu.sr.carry);
+}
+
+#[test]
+fn sbc_decimal_digit_borrow() {
+    let mut cpu = Cpu::new();
+
+    // SED; SEC; LDA #$10; SBC #$01
+    let program = [0xf8, 0x38, 0xa9, 0x10, 0xe9, 0x01];
+    run_program(&program[..], &mut cpu);
+
+    assert_eq!(0x09, cpu.a);
+    assert!(cpu.sr.carry);
+}
+
+#[test]
+fn sbc_decimal_borrows() {
+    let mut cpu = Cpu::new();
+
+    // SED; SEC; LDA #$00; SBC #$01
+    let program = [0xf8, 0x38, 0xa9, 0x00, 0xe9, 0x01];
+    run_program(&program[..], &mut cpu);
+
+    assert_eq!(0x99, cpu.a);
+    assert!(!cpu.sr.carry);
+}
+
+#[test]
+fn sbc_decimal_uses_borrow_in() {
+    let mut cpu = Cpu::new();
+
+    // SED; CLC; LDA #$50; SBC #$20
+    let program = [0xf8, 0x18, 0xa9, 0x50, 0xe9, 0x20];
+    run_program(&program[..], &mut cpu);
+
+    assert_eq!(0x29, cpu.a);
+    assert!(cpu.sr.carry);
+}
+
+// Test stack wraparound
+#[test]
+fn jsr_push_wraps_in_stack_page() {
+    let mut cpu = Cpu::new();
+
+    // LDX #$00; TXS; JSR $C000
+    let program = [0xa2, 0x00, 0x9a, 0x20, 0x00, 0xc0];
+    let ram = run_program(&program[..], &mut cpu);
+
+    assert_eq!(0xfc, ram[0x0100]);
+    assert_eq!(0xe7, ram[0x01ff]);
+    assert_eq!(0x00, ram[0x0200]);
+    assert_eq!(0xfe, cpu.sp);
+}
+
+#[test]
+fn rts_pull_wraps_in_stack_page() {
+    let mut cpu = Cpu::new();
+
+    // LDX #$00; TXS; LDA #$bf; PHA; LDA #$ff; PHA; RTS
+    let program = [0xa2, 0x00, 0x9a, 0xa9, 0xbf, 0x48, 0xa9, 0xff, 0x48, 0x60];
+    run_program(&program[..], &mut cpu);
+
+    assert_eq!(0xc000, cpu.pc);
+    assert_eq!(0x00, cpu.sp);
+}
+
+// Every documented opcode
+const OFFICIAL_OPCODES: [u8; 151] = [
+    0x00, 0x01, 0x05, 0x06, 0x08, 0x09, 0x0a, 0x0d, 0x0e, 0x10, 0x11, 0x15, 0x16, 0x18, 0x19, 0x1d,
+    0x1e, 0x20, 0x21, 0x24, 0x25, 0x26, 0x28, 0x29, 0x2a, 0x2c, 0x2d, 0x2e, 0x30, 0x31, 0x35, 0x36,
+    0x38, 0x39, 0x3d, 0x3e, 0x40, 0x41, 0x45, 0x46, 0x48, 0x49, 0x4a, 0x4c, 0x4d, 0x4e, 0x50, 0x51,
+    0x55, 0x56, 0x58, 0x59, 0x5d, 0x5e, 0x60, 0x61, 0x65, 0x66, 0x68, 0x69, 0x6a, 0x6c, 0x6d, 0x6e,
+    0x70, 0x71, 0x75, 0x76, 0x78, 0x79, 0x7d, 0x7e, 0x81, 0x84, 0x85, 0x86, 0x88, 0x8a, 0x8c, 0x8d,
+    0x8e, 0x90, 0x91, 0x94, 0x95, 0x96, 0x98, 0x99, 0x9a, 0x9d, 0xa0, 0xa1, 0xa2, 0xa4, 0xa5, 0xa6,
+    0xa8, 0xa9, 0xaa, 0xac, 0xad, 0xae, 0xb0, 0xb1, 0xb4, 0xb5, 0xb6, 0xb8, 0xb9, 0xba, 0xbc, 0xbd,
+    0xbe, 0xc0, 0xc1, 0xc4, 0xc5, 0xc6, 0xc8, 0xc9, 0xca, 0xcc, 0xcd, 0xce, 0xd0, 0xd1, 0xd5, 0xd6,
+    0xd8, 0xd9, 0xdd, 0xde, 0xe0, 0xe1, 0xe4, 0xe5, 0xe6, 0xe8, 0xe9, 0xea, 0xec, 0xed, 0xee, 0xf0,
+    0xf1, 0xf5, 0xf6, 0xf8, 0xf9, 0xfd, 0xfe,
+];
+
+// Make sure every documented instruction can run without hitting an unimplemented case
+#[test]
+fn all_official_opcodes_execute() {
+    for op in OFFICIAL_OPCODES.iter() {
+        let mut cpu = Cpu::new();
+
+        let program = [*op, 0x10, 0xea];
+        run_program(&program[..], &mut cpu);
+
+        assert!(cpu.cycles > 1, "Opcode {:0>2X} didn't run", op);
+    }
+}
+
+// The assembler should turn the disassembler's output for every documented instruction back into
+// the same bytes
+#[test]
+fn assemble_round_trips_official_opcodes() {
+    use symbols::SymbolTable;
+    let symbols = SymbolTable::new();
+
+    for op in OFFICIAL_OPCODES.iter() {
+        let bytes = [*op, 0x34, 0x12];
+        let (text, len) = disassemble(0x1000, bytes, &symbols);
+        let assembled = assemble(0x1000, &text, &symbols);
+        assert_eq!(Ok(bytes[..len as usize].to_vec()), assembled, "{}", text);
+    }
+}
+
+// Every mnemonic the disassembler prints has to parse back to the same opcode
+#[test]
+fn opcode_mnemonics_parse() {
+    for code in 0..=255u8 {
+        let op = opcode::Opcode::from_u8(code);
+        assert_eq!(Ok(op), format!("{:?}", op).parse(), "${:0>2X}", code);
+    }
+    assert!("FOO".parse::<opcode::Opcode>().is_err());
+}
+
+#[test]
+fn assemble_picks_zeropage_for_short_operands() {
+    use symbols::SymbolTable;
+    let symbols = SymbolTable::new();
+
+    assert_eq!(Ok(vec![0xa5, 0x10]), assemble(0, "lda $10", &symbols));
+    assert_eq!(Ok(vec![0xad, 0x10, 0x00]), assemble(0, "lda $0010", &symbols));
+    assert_eq!(Ok(vec![0xb9, 0x10, 0x00]), assemble(0, "lda $10,y", &symbols));
+    assert_eq!(Ok(vec![0xea]), assemble(0, "nop", &symbols));
+    assert_eq!(Ok(vec![0x0a]), assemble(0, "asl a", &symbols));
+    assert_eq!(Ok(vec![0xd0, 0xfe]), assemble(0xc000, "bne $c000", &symbols));
+    assert!(assemble(0xc000, "bne $d000", &symbols).is_err());
+    assert!(assemble(0, "lda ($1234),y", &symbols).is_err());
+    assert!(assemble(0, "foo", &symbols).is_err());
+}
+
+#[test]
+fn pla_pulls_pushed_value() {
+    let mut cpu = Cpu::new();
+
+    // LDA #$42; PHA; LDA #$00; PLA
+    let program = [0xa9, 0x42, 0x48, 0xa9, 0x00, 0x68];
+    run_program(&program[..], &mut cpu);
+
+    assert_eq!(0x42, cpu.a);
+    assert_eq!(0xfd, cpu.sp);
+}
+
+#[test]
+fn rti_restores_sr_and_pc() {
+    let mut cpu = Cpu::new();
+
+    // LDA #$c0; PHA; PHA; LDA #$d3; PHA; RTI
+    let program = [0xa9, 0xc0, 0x48, 0x48, 0xa9, 0xd3, 0x48, 0x40];
+    run_program(&program[..], &mut cpu);
+
+    assert_eq!(0xc0c0, cpu.pc);
+    assert_eq!(0xfd, cpu.sp);
+    assert_eq!(0xe3, cpu.sr.to_u8());
+}
+
+#[test]
+fn irq_pushes_interrupted_pc() {
+    let mut cpu = Cpu::new();
+
+    // NOP
+    let setup = TestSetup { irq: true, ..TestSetup::new() };
+    let program = [0xea];
+    let ram = run_program_with(&program[..], &setup, &mut cpu);
+
+    assert_eq!(0xfc, ram[0x01fd]);
+    assert_eq!(0xe2, ram[0x01fc]);
+    assert_eq!(0x00, ram[0x01fb] & 0x10);
+}
+
+#[test]
+fn irq_ignored_when_disabled() {
+    let mut cpu = Cpu::new();
+
+    // LDA #$42 with interrupts disabled
+    let setup = TestSetup { irq: true, sr: 0x24, ..TestSetup::new() };
+    let program = [0xa9, 0x42];
+    let ram = run_program_with(&program[..], &setup, &mut cpu);
+
+    assert_eq!(0x42, cpu.a);
+    assert_eq!(PROGRAM_ADDR + 2, cpu.pc);
+    assert_eq!(0x00, ram[0x01fd]);
+}
+
+#[test]
+fn irq_handled_once() {
+    let mut cpu = Cpu::new();
+
+    // The handler at $0000 is a string of NOPs, so the IRQ must not be taken again there
+    let setup = TestSetup { irq: true, fill: 0xea, ..TestSetup::new() };
+    let program = [0xea];
+    run_program_with(&program[..], &setup, &mut cpu);
+    let mut ram = [0xeau8; 65536];
+    cpu.run_until_fetch(&mut ram);
+    cpu.run_until_fetch(&mut ram);
+
+    assert_eq!(0xeaec, cpu.pc);
+}
+
+#[test]
+fn brk_pushes_pc_plus_two() {
+    let mut cpu = Cpu::new();
+
+    let setup = TestSetup { fill: 0x50, ..TestSetup::new() };
+    let program = [0x00];
+    let ram = run_program_with(&program[..], &setup, &mut cpu);
+
+    assert_eq!(0xfc, ram[0x01fd]);
+    assert_eq!(0xe4, ram[0x01fc]);
+    assert_eq!(0x10, ram[0x01fb] & 0x10);
+}
+
+// Test the reset sequence
+#[test]
+fn reset_reads_vector() {
+    let mut cpu = Cpu::new();
+    let mut ram = [0u8; 65536];
+    ram[0xfffc] = 0x34;
+    ram[0xfffd] = 0x12;
+
+    cpu.reset();
+    while !cpu.at_instruction_start() {
+        cpu.data_in(ram[cpu.addr_bus as usize]);
+        cpu.cycle(false);
+    }
+
+    assert_eq!(0x1234, cpu.pc);
+    assert_eq!(0x1234, cpu.addr_bus);
+    assert_eq!(7, cpu.cycles);
+}
+
+// One step of a VICE CPU trace: the registers before the instruction at PC runs
+#[derive(PartialEq)]
+struct TraceStep {
+    pc: u16,
+    a: u8,
+    x: u8,
+    y: u8,
+    sp: u8,
+    sr: u8,
+}
+
+impl ::std::fmt::Debug for TraceStep {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "PC: ${:0>4X} // A: ${:0>2X} // X: ${:0>2X} // Y: ${:0>2X} // SP: ${:0>2X} // SR: {:0>8b}",
+               self.pc, self.a, self.x, self.y, self.sp, self.sr)
+    }
+}
+
+// Parse a line of VICE trace output like
+// .C:fce2  A2 FF       LDX #$FF       - A:00 X:00 Y:00 SP:f3 ..-..IZC
+fn parse_trace_line(line: &str) -> Option<TraceStep> {
+    let mut fields = line.split_whitespace();
+    let pc = fields.next()
+        .map(|f| f.trim_start_matches(".C:"))
+        .and_then(|f| u16::from_str_radix(f, 16).ok());
+
+    let reg = |name: &str| {
+        line.split_whitespace()
+            .find(|f| f.starts_with(name))
+            .and_then(|f| u8::from_str_radix(&f[name.len()..], 16).ok())
+    };
+
+    // Flags follow SP as NV-BDIZC with '.' for clear bits
+    let flags = line.split_whitespace()
+        .skip_while(|f| !f.starts_with("SP:"))
+        .nth(1)
+        .filter(|f| f.len() == 8)
+        .map(|f| {
+            f.chars().enumerate().fold(0u8, |sr, (i, c)| {
+                if c == '.' { sr } else { sr | (0x80 >> i) }
+            })
+        });
+
+    match (pc, reg("A:"), reg("X:"), reg("Y:"), reg("SP:"), flags) {
+        (Some(pc), Some(a), Some(x), Some(y), Some(sp), Some(sr)) => {
+            Some(TraceStep { pc: pc, a: a, x: x, y: y, sp: sp, sr: sr })
+        },
+        _ => None,
+    }
+}
+
+#[test]
+fn parse_vice_trace_line() {
+    let step = parse_trace_line(".C:fce2  A2 FF       LDX #$FF       - A:00 X:01 Y:02 SP:f3 N.-..IZC    1234");
+    assert_eq!(Some(TraceStep { pc: 0xfce2, a: 0x00, x: 0x01, y: 0x02, sp: 0xf3, sr: 0xa7 }), step);
+
+    assert_eq!(None, parse_trace_line("(C:$fce2) "));
+}
+
+// Run the CPU in lockstep with a VICE trace. Memory is flat RAM, so traces that touch I/O will
+// diverge when they do.
+#[cfg(feature = "vice-trace")]
+#[test]
+fn matches_vice_trace() {
+    use std::env;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader, Read};
+
+    let (trace_file, ram_file) = match (env::var("C64_TRACE_FILE"), env::var("C64_TRACE_RAM")) {
+        (Ok(t), Ok(r)) => (t, r),
+        _ => {
+            println!("C64_TRACE_FILE and C64_TRACE_RAM aren't set; skipping");
+            return;
+        },
+    };
+
+    let mut ram = [0u8; 65536];
+    let mut image = Vec::new();
+    File::open(&ram_file).unwrap().read_to_end(&mut image).unwrap();
+    for (i, b) in image.iter().take(ram.len()).enumerate() {
+        ram[i] = *b;
+    }
+
+    let steps: Vec<TraceStep> = BufReader::new(File::open(&trace_file).unwrap())
+        .lines()
+        .filter_map(|l| l.ok().and_then(|l| parse_trace_line(&l)))
+        .collect();
+    assert!(!steps.is_empty(), "No trace steps in {}", trace_file);
+
+    // Start from the first step's state
+    let mut cpu = Cpu::new();
+    cpu.reset();
+    cpu.pc = steps[0].pc;
+    cpu.addr_bus = steps[0].pc;
+    cpu.state = CpuState::Fetch;
+
+    for (i, step) in steps.iter().enumerate() {
+        if i == 0 {
+            cpu.a = step.a;
+            cpu.x = step.x;
+            cpu.y = step.y;
+            cpu.sp = step.sp;
+            cpu.sr.set_all_flags(step.sr);
+        }
+
+        // The break flag and bit 5 don't exist in the register
+        let actual = TraceStep {
+            pc: cpu.pc, a: cpu.a, x: cpu.x, y: cpu.y, sp: cpu.sp, sr: cpu.sr.to_u8() & 0xcf,
+        };
+        let expected = TraceStep { sr: step.sr & 0xcf, ..*step };
+        if actual != expected {
+            let prev = if i > 0 { format!("{:?}", steps[i - 1]) } else { String::from("none") };
+            panic!("Trace diverged at step {}\n  previous: {}\n  expected: {:?}\n  actual:   {:?}",
+                   i, prev, expected, actual);
+        }
+
+        // Run one instruction
+        loop {
+            if cpu.rw {
+                cpu.data_in(ram[cpu.addr_bus as usize]);
+            } else {
+                ram[cpu.addr_bus as usize] = cpu.data_out();
+            }
+            cpu.cycle(false);
+            if cpu.at_instruction_start() {
+                break;
+            }
+        }
+    }
+}
+
+// Every documented opcode should decode and run
+#[test]
+fn documented_opcodes_implemented() {
+    let table = opcode_table();
+
+    assert_eq!(151, table.iter().filter(|op| op.documented).count());
+    assert_eq!(151, table.iter().filter(|op| op.documented && op.cycles.is_some()).count());
+    assert_eq!(Some(2), table[0xea].cycles);
+}
+
+// Run one instruction at PROGRAM_ADDR with the given index registers and get the PC it leaves
+fn pc_after(program: &[u8], x: u8, y: u8) -> u16 {
+    let mut cpu = Cpu::new();
+    let mut ram = [0u8; 65536];
+    ram[PROGRAM_ADDR as usize..PROGRAM_ADDR as usize + program.len()].copy_from_slice(program);
+    ram[0xfffc] = PROGRAM_ADDR as u8;
+    ram[0xfffd] = (PROGRAM_ADDR >> 8) as u8;
+
+    cpu.reset();
+    cpu.run_until_fetch(&mut ram);
+    cpu.x = x;
+    cpu.y = y;
+    cpu.run_until_fetch(&mut ram);
+    cpu.pc
+}
+
+// Every documented instruction that doesn't change the flow of the program should leave PC just
+// past itself, including read-modify-write instructions with and without a page crossing
+#[test]
+fn pc_after_each_addressing_mode() {
+    let symbols = SymbolTable::new();
+    for op in opcode_table().iter().filter(|op| op.documented) {
+        let flow = ["BRK", "JMP", "JSR", "RTI", "RTS", "BCC", "BCS", "BEQ", "BMI", "BNE", "BPL",
+                    "BVC", "BVS"];
+        if flow.contains(&op.mnemonic.as_str()) {
+            continue;
+        }
+        let program = [op.code, 0x80, 0x20];
+        let len = disassemble(PROGRAM_ADDR, program, &symbols).1;
+        for &index in &[0x00, 0xff] {
+            assert_eq!(PROGRAM_ADDR + len, pc_after(&program, index, index),
+                       "{} ${:0>2X} with X = Y = ${:0>2X}", op.mnemonic, op.code, index);
+        }
+    }
+}
+
+#[test]
+fn pc_after_rmw_absolute_x() {
+    // INC $2080,X, ASL $2080,X, ROR $2080,X crossing into the next page
+    for &code in &[0xfe, 0x1e, 0x7e] {
+        assert_eq!(PROGRAM_ADDR + 3, pc_after(&[code, 0x80, 0x20], 0x90, 0));
+    }
+}
+
+#[test]
+fn pc_after_branches_and_jumps() {
+    // BNE +$10 taken, BEQ +$10 not taken, BNE -$10 taken across a page
+    assert_eq!(PROGRAM_ADDR + 0x12, pc_after(&[0xd0, 0x10], 0, 0));
+    assert_eq!(PROGRAM_ADDR + 2, pc_after(&[0xf0, 0x10], 0, 0));
+    assert_eq!(PROGRAM_ADDR + 2 - 0x10, pc_after(&[0xd0, 0xf0], 0, 0));
+    // JMP $2010 and JSR $2010
+    assert_eq!(0x2010, pc_after(&[0x4c, 0x10, 0x20], 0, 0));
+    assert_eq!(0x2010, pc_after(&[0x20, 0x10, 0x20], 0, 0));
+}
+
+#[test]
+fn pc_stays_on_kil() {
+    assert_eq!(PROGRAM_ADDR, pc_after(&[0x02], 0, 0));
+}
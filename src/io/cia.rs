@@ -3,10 +3,23 @@
 //
 // Data structures and functions related to CIA #1
 
-use super::{write_high_byte, write_low_byte};
+use super::{write_high_byte, write_low_byte, Region};
+
+use std::cell::Cell;
 
 const CONTROL_REG_COUNT: usize = 0x10;
 
+// Control register bits
+const CTL_FORCE_LOAD: u8 = 0x10;
+const CTL_ALARM: u8 = 0x80;    // Timer B control: time of day writes set the alarm
+
+// Interrupt sources in the interrupt control register
+const INT_TIMER_A: u8 = 0x01;
+const INT_ALARM: u8 = 0x04;
+const INT_SERIAL: u8 = 0x08;
+const INT_FLAG: u8 = 0x10;
+
+#[derive(Clone)]
 pub struct Cia {
     port_a: u8,         // Port A (keybord col and joystick 2)
     port_b: u8,         // Port B (keybord row and joystick 1)
@@ -14,15 +27,30 @@ pub struct Cia {
     port_b_dir: u8,     // Port B data direction
     timer_a: u16,       // Timer A
     timer_b: u16,       // Timer B
+    timer_a_latch: u16, // Timer A reload value
     tod_ds: u8,         // Time of day in hundreds of ms (BCD)
     tod_s: u8,          // Time of day in seconds (BCD)
     tod_m: u8,          // Time of day in minutes (BCD)
     tod_h: u8,          // Time of day in hours (BCD)
-    serial_shift: u8,   // Serial shift register
+    tod_alarm: [u8; 4], // Alarm time as tenths, seconds, minutes, hours (BCD)
+    tod_tick_cycles: u32,   // Clock cycles per mains tick
+    tod_cycles: u32,    // Clock cycles since the last mains tick
+    tod_ticks: u8,      // Mains ticks since the time of day last changed
+    serial_shift: u8,   // Serial data register
+    shift_reg: u8,      // Internal shift register behind the serial data register
+    shift_count: u8,    // Number of bits shifted through the shift register
+    shifting: bool,     // Whether the shift register is sending a byte
+    sdr_loaded: bool,   // Whether a byte is waiting in the data register to be sent
+    cnt: bool,          // CNT pin
+    sp: bool,           // SP pin
     int_enable: u8,     // Interrupt enable status
-    int_status: u8,     // Interrupt status
+    int_status: Cell<u8>,   // Interrupt status. Reading it clears it.
     timer_a_ctl: u8,    // Timer A control register
     timer_b_ctl: u8,    // Timer B control register
+    key_matrix: [u8; 8],// Pressed keys connecting each port A line to port B
+    port_a_in: u8,      // Lines pulled low on port A by external devices
+    port_b_in: u8,      // Lines pulled low on port B by external devices
+    pc_low: Cell<bool>, // PC pin goes low for a cycle after port B is read or written
 
     base_addr: usize,   // Base memory address for this CIA
 }
@@ -36,15 +64,30 @@ impl Cia {
             port_b_dir: 0,
             timer_a: 0,
             timer_b: 0,
+            timer_a_latch: 0xffff,
+            tod_alarm: [0; 4],
             tod_ds: 0,
             tod_s: 0,
             tod_m: 0,
             tod_h: 0,
+            tod_tick_cycles: Region::Pal.clock_hz() / Region::Pal.mains_hz(),
+            tod_cycles: 0,
+            tod_ticks: 0,
             serial_shift: 0,
+            shift_reg: 0,
+            shift_count: 0,
+            shifting: false,
+            sdr_loaded: false,
+            cnt: true,
+            sp: true,
             int_enable: 0,
-            int_status: 0,
+            int_status: Cell::new(0),
             timer_a_ctl: 0,
             timer_b_ctl: 0,
+            key_matrix: [0; 8],
+            port_a_in: 0xff,
+            port_b_in: 0xff,
+            pc_low: Cell::new(false),
 
             base_addr: base_addr,
         }
@@ -52,10 +95,8 @@ impl Cia {
 
     // Translate a memory address to a register index
     fn translate_addr(&self, addr: usize) -> u8 {
-        if addr < self.base_addr {
-            panic!("Invalid address for CIA control register: ${:0>4X}", addr);
-        }
-        ((addr - self.base_addr) % CONTROL_REG_COUNT) as u8
+        // Only the low address lines are decoded, so anything else just hits a mirror
+        (addr.wrapping_sub(self.base_addr) % CONTROL_REG_COUNT) as u8
     }
 
 
@@ -63,13 +104,17 @@ impl Cia {
         let reg = self.translate_addr(addr);
 
         match reg {
-            0 => self.port_a,
-            1 => self.port_b,
+            // Output lines read back the latch and input lines read the pins
+            0 => (self.port_a & self.port_a_dir) | (self.port_a_pins() & !self.port_a_dir),
+            1 => {
+                self.pc_low.set(true);
+                (self.port_b & self.port_b_dir) | (self.port_b_pins() & !self.port_b_dir)
+            },
             2 => self.port_a_dir,
             3 => self.port_b_dir,
             4 => {
                 // Low byte
-                (self.timer_a & 0x0f) as u8
+                (self.timer_a & 0xff) as u8
             },
             5 => {
                 // High byte
@@ -77,7 +122,7 @@ impl Cia {
             },
             6 => {
                 // Low byte
-                (self.timer_b & 0x0f) as u8
+                (self.timer_b & 0xff) as u8
             },
             7 => {
                 // High byte
@@ -88,7 +133,17 @@ impl Cia {
             10 => self.tod_m,
             11 => self.tod_h,
             12 => self.serial_shift,
-            13 => self.int_status,
+            13 => {
+                // The high bit is set if any enabled interrupt has occurred. Reading the
+                // register acknowledges the interrupts.
+                let status = if self.irq_requested() {
+                    self.int_status.get() | 0x80
+                } else {
+                    self.int_status.get()
+                };
+                self.int_status.set(0);
+                status
+            },
             14 => self.timer_a_ctl,
             15 => self.timer_b_ctl,
             _ => 0
@@ -100,22 +155,275 @@ impl Cia {
         // TODO: This is completely wrong and bad
         match reg {
             0 => { self.port_a = value; },
-            1 => { self.port_b = value; },
+            1 => {
+                self.port_b = value;
+                self.pc_low.set(true);
+            },
             2 => { self.port_a_dir = value; },
             3 => { self.port_b_dir = value; },
-            4 => { self.timer_a = write_low_byte(self.timer_a, value); },
-            5 => { self.timer_a = write_high_byte(self.timer_a, value); },
+            4 => { self.timer_a_latch = write_low_byte(self.timer_a_latch, value); },
+            5 => {
+                self.timer_a_latch = write_high_byte(self.timer_a_latch, value);
+                // The counter is loaded from the latch if the timer is stopped
+                if self.timer_a_ctl & 0x01 == 0 {
+                    self.timer_a = self.timer_a_latch;
+                }
+            },
             6 => { self.timer_b = write_low_byte(self.timer_b, value); },
             7 => { self.timer_b = write_high_byte(self.timer_b, value); },
+            8..=11 if self.timer_b_ctl & CTL_ALARM == CTL_ALARM => {
+                self.tod_alarm[reg as usize - 8] = value;
+            },
             8 => { self.tod_ds = value; },
             9 => { self.tod_s = value; },
             10 => { self.tod_m = value; },
             11 => { self.tod_h = value; },
-            12 => { self.serial_shift = value; },
-            13 => { self.int_enable = value; },
-            14 => { self.timer_a_ctl = value; },
-            15 => { self.timer_b_ctl = value; },
+            12 => {
+                self.serial_shift = value;
+                self.sdr_loaded = true;
+            },
+            13 => {
+                // Bit 7 determines whether the other bits are set or cleared
+                if value & 0x80 == 0x80 {
+                    self.int_enable |= value & 0x1f;
+                } else {
+                    self.int_enable &= !(value & 0x1f);
+                }
+            },
+            14 => {
+                // Switching the serial port direction resets the shift register
+                if (value ^ self.timer_a_ctl) & 0x40 == 0x40 {
+                    self.shift_count = 0;
+                    self.shifting = false;
+                    self.sdr_loaded = false;
+                }
+                // Force load the timer from the latch. It's a strobe, so it always reads as 0.
+                if value & CTL_FORCE_LOAD == CTL_FORCE_LOAD {
+                    self.timer_a = self.timer_a_latch;
+                }
+                self.timer_a_ctl = value & !CTL_FORCE_LOAD;
+            },
+            15 => { self.timer_b_ctl = value & !CTL_FORCE_LOAD; },
             _ => { },
         }
     }
+
+    // Set the clock speed and mains frequency that drive the timers
+    pub fn set_region(&mut self, region: Region) {
+        self.tod_tick_cycles = region.clock_hz() / region.mains_hz();
+    }
+
+    // Advance the timers by one clock cycle
+    pub fn cycle(&mut self) {
+        self.pc_low.set(false);
+
+        self.tod_cycles += 1;
+        if self.tod_cycles >= self.tod_tick_cycles {
+            self.tod_cycles = 0;
+            self.mains_tick();
+        }
+
+        if self.timer_a_ctl & 0x01 == 0x01 {
+            if self.timer_a == 0 {
+                self.timer_a_underflow();
+            } else {
+                self.timer_a -= 1;
+            }
+        }
+    }
+
+    // Count a tick of the mains frequency. Bit 7 of control register A says whether the time of
+    // day changes every 5 (50 Hz) or 6 (60 Hz) ticks.
+    fn mains_tick(&mut self) {
+        self.tod_ticks += 1;
+        let ticks_per_tenth = if self.timer_a_ctl & 0x80 == 0x80 { 5 } else { 6 };
+        if self.tod_ticks >= ticks_per_tenth {
+            self.tod_ticks = 0;
+            self.advance_tod();
+        }
+    }
+
+    // Advance the time of day by a tenth of a second
+    fn advance_tod(&mut self) {
+        self.advance_tod_digits();
+        if [self.tod_ds, self.tod_s, self.tod_m, self.tod_h] == self.tod_alarm {
+            self.raise_interrupt(INT_ALARM);
+        }
+    }
+
+    fn advance_tod_digits(&mut self) {
+        let (ds, carry) = bcd_increment(self.tod_ds, 0x10);
+        self.tod_ds = ds;
+        if !carry {
+            return;
+        }
+        let (s, carry) = bcd_increment(self.tod_s, 0x60);
+        self.tod_s = s;
+        if !carry {
+            return;
+        }
+        let (m, carry) = bcd_increment(self.tod_m, 0x60);
+        self.tod_m = m;
+        if !carry {
+            return;
+        }
+
+        // Hours count from 1 to 12 and bit 7 is the PM flag
+        let pm = self.tod_h & 0x80;
+        self.tod_h = match self.tod_h & 0x1f {
+            0x11 => 0x12 | (pm ^ 0x80),
+            0x12 => 0x01 | pm,
+            h => bcd_increment(h, 0x13).0 | pm,
+        };
+    }
+
+    fn timer_a_underflow(&mut self) {
+        self.timer_a = self.timer_a_latch;
+        self.raise_interrupt(INT_TIMER_A);
+
+        // Stop the timer in one-shot mode
+        if self.timer_a_ctl & 0x08 == 0x08 {
+            self.timer_a_ctl &= !0x01;
+        }
+
+        if self.serial_output() {
+            self.shift_out();
+        }
+    }
+
+    // Determine whether the serial port is set to output
+    fn serial_output(&self) -> bool {
+        self.timer_a_ctl & 0x40 == 0x40
+    }
+
+    // Shift the serial port on a timer A underflow. CNT toggles on every underflow and a bit is
+    // sent on each rising edge, so a byte takes 16 underflows.
+    fn shift_out(&mut self) {
+        if !self.shifting {
+            if !self.sdr_loaded {
+                return;
+            }
+            self.shift_reg = self.serial_shift;
+            self.sdr_loaded = false;
+            self.shifting = true;
+            self.shift_count = 0;
+        }
+
+        self.cnt = !self.cnt;
+        if self.cnt {
+            // Bits are sent MSB first
+            self.sp = self.shift_reg & 0x80 == 0x80;
+            self.shift_reg <<= 1;
+            self.shift_count += 1;
+
+            if self.shift_count == 8 {
+                self.shifting = false;
+                self.raise_interrupt(INT_SERIAL);
+            }
+        }
+    }
+
+    // Lines that are inputs float high
+    fn port_a_out(&self) -> u8 {
+        self.port_a | !self.port_a_dir
+    }
+
+    fn port_b_out(&self) -> u8 {
+        self.port_b | !self.port_b_dir
+    }
+
+    // State of the port A pins. A pressed key pulls its port A line low when its port B line is
+    // low.
+    fn port_a_pins(&self) -> u8 {
+        let rows = self.port_b_out() & self.port_b_in;
+        let mut cols = self.port_a_out() & self.port_a_in;
+        for col in 0..8 {
+            if self.key_matrix[col] & !rows != 0 {
+                cols &= !(1 << col);
+            }
+        }
+        cols
+    }
+
+    // State of the port B pins. A pressed key pulls its port B line low when its port A line is
+    // low.
+    fn port_b_pins(&self) -> u8 {
+        let cols = self.port_a_out() & self.port_a_in;
+        let mut rows = self.port_b_out() & self.port_b_in;
+        for col in 0..8 {
+            if cols & (1 << col) == 0 {
+                rows &= !self.key_matrix[col];
+            }
+        }
+        rows
+    }
+
+    // Set the lines an external device such as a joystick is pulling low. Bits are 0 for lines
+    // that are pulled low.
+    pub fn set_port_a_input(&mut self, value: u8) {
+        self.port_a_in = value;
+    }
+
+    pub fn set_port_b_input(&mut self, value: u8) {
+        self.port_b_in = value;
+    }
+
+    // Press or release the key between a port A line and a port B line
+    pub fn set_key(&mut self, col: u8, row: u8, pressed: bool) {
+        if pressed {
+            self.key_matrix[col as usize] |= 1 << row;
+        } else {
+            self.key_matrix[col as usize] &= !(1 << row);
+        }
+    }
+
+    // A falling edge on the FLAG pin sets the FLAG interrupt
+    pub fn pulse_flag(&mut self) {
+        self.raise_interrupt(INT_FLAG);
+    }
+
+    // Level of the PC handshake output
+    pub fn pc(&self) -> bool {
+        !self.pc_low.get()
+    }
+
+    // Whether an enabled interrupt has occurred
+    pub fn irq_requested(&self) -> bool {
+        self.int_status.get() & self.int_enable != 0
+    }
+
+    fn raise_interrupt(&mut self, source: u8) {
+        self.int_status.set(self.int_status.get() | source);
+    }
+
+    pub fn timer_a(&self) -> u16 {
+        self.timer_a
+    }
+
+    pub fn timer_b(&self) -> u16 {
+        self.timer_b
+    }
+
+    // Levels of the serial port's CNT and SP pins
+    pub fn cnt(&self) -> bool {
+        self.cnt
+    }
+
+    pub fn sp(&self) -> bool {
+        self.sp
+    }
+}
+
+// Increment a BCD number, wrapping to zero at the limit. Returns the new value and whether it
+// wrapped.
+fn bcd_increment(value: u8, limit: u8) -> (u8, bool) {
+    let mut value = value.wrapping_add(1);
+    if value & 0x0f > 9 {
+        value = (value & 0xf0) + 0x10;
+    }
+    if value >= limit {
+        (0, true)
+    } else {
+        (value, false)
+    }
 }
@@ -0,0 +1,66 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+//
+// Data structures and functions related to the GEORAM memory expansion
+
+// The selected page shows up in the first expansion I/O area
+pub const WINDOW_START: usize = 0xde00;
+pub const WINDOW_END: usize = 0xdeff;
+
+// Write-only registers that select the page the window shows
+const BLOCK_REG: usize = 0xdffe;
+const PAGE_REG: usize = 0xdfff;
+
+const PAGE_SIZE: usize = 0x100;
+const PAGES_PER_BLOCK: u8 = 64;
+const BLOCK_SIZE: usize = PAGE_SIZE * PAGES_PER_BLOCK as usize;
+
+// Cartridges came in sizes from 64K to 4M
+pub const MIN_SIZE_KB: usize = 64;
+pub const MAX_SIZE_KB: usize = 4096;
+
+pub struct GeoRam {
+    ram: Vec<u8>,
+    block: u8,  // Selected 16K block
+    page: u8,   // Selected 256 byte page within the block
+}
+
+impl GeoRam {
+    // Create a GEORAM with a size in K, which should be a power of two
+    pub fn new(size_kb: usize) -> GeoRam {
+        GeoRam {
+            ram: vec![0u8; size_kb * 1024],
+            block: 0,
+            page: 0,
+        }
+    }
+
+    // Get the offset into the expansion RAM of a window address. Block numbers past the end of
+    // the RAM wrap around since the unused address lines aren't connected.
+    fn offset(&self, addr: usize) -> usize {
+        let offset = self.block as usize * BLOCK_SIZE + self.page as usize * PAGE_SIZE +
+            (addr - WINDOW_START);
+        offset & (self.ram.len() - 1)
+    }
+
+    // Read the window. The registers can't be read back.
+    pub fn read_register(&self, addr: usize) -> Option<u8> {
+        if (WINDOW_START..=WINDOW_END).contains(&addr) {
+            Some(self.ram[self.offset(addr)])
+        } else {
+            None
+        }
+    }
+
+    pub fn write_register(&mut self, addr: usize, value: u8) {
+        match addr {
+            WINDOW_START..=WINDOW_END => {
+                let offset = self.offset(addr);
+                self.ram[offset] = value;
+            },
+            BLOCK_REG => { self.block = value; },
+            PAGE_REG => { self.page = value % PAGES_PER_BLOCK; },
+            _ => { },
+        }
+    }
+}
@@ -6,11 +6,50 @@
 pub mod vic;
 pub mod sid;
 pub mod cia;
+pub mod georam;
+pub mod mouse;
+pub mod parallel;
+
+// Video standard of the machine, which determines its clock speed and mains frequency
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Region {
+    Pal,
+    Ntsc,
+}
+
+impl Region {
+    // System clock frequency in Hz
+    pub fn clock_hz(&self) -> u32 {
+        match *self {
+            Region::Pal => 985248,
+            Region::Ntsc => 1022727,
+        }
+    }
+
+    // Mains frequency in Hz. This drives the CIA time of day clocks.
+    pub fn mains_hz(&self) -> u32 {
+        match *self {
+            Region::Pal => 50,
+            Region::Ntsc => 60,
+        }
+    }
+
+    // Number of raster lines in a frame
+    pub fn raster_lines(&self) -> u16 {
+        match *self {
+            Region::Pal => 312,
+            Region::Ntsc => 263,
+        }
+    }
+}
 
 fn write_low_byte(word: u16, byte: u8) -> u16 {
-    (word & 0xf0) + byte as u16
+    (word & 0xff00) + byte as u16
 }
 
 fn write_high_byte(word: u16, byte: u8) -> u16 {
-    ((byte as u16) << 8) + (word >> 8)
+    ((byte as u16) << 8) + (word & 0x00ff)
 }
+
+#[cfg(test)]
+mod test_mod;
@@ -0,0 +1,64 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+//
+// Data structures and functions related to the 1351 proportional mouse
+
+// Joystick lines the buttons pull low. The left button is fire and the right button is up.
+const LEFT_BUTTON_LINE: u8 = 0x10;
+const RIGHT_BUTTON_LINE: u8 = 0x01;
+
+// The 1351 puts the position mod 64 in bits 1-6 of the POT registers. Bit 0 is noise, which is
+// always 0 here, and bit 7 is unused.
+const POSITION_MASK: u16 = 0x3f;
+
+pub struct Mouse1351 {
+    x: u16,     // Position counters. Only the low 6 bits reach the C64.
+    y: u16,
+    left: bool,
+    right: bool,
+}
+
+impl Mouse1351 {
+    pub fn new() -> Mouse1351 {
+        Mouse1351 {
+            x: 0,
+            y: 0,
+            left: false,
+            right: false,
+        }
+    }
+
+    // Move the mouse. Positive Y deltas are down like on the host, but the 1351 counts up as it
+    // moves away from the user, so they're subtracted.
+    pub fn move_by(&mut self, dx: i32, dy: i32) {
+        self.x = self.x.wrapping_add(dx as u16);
+        self.y = self.y.wrapping_sub(dy as u16);
+    }
+
+    pub fn set_buttons(&mut self, left: bool, right: bool) {
+        self.left = left;
+        self.right = right;
+    }
+
+    // Value of the POTX register
+    pub fn pot_x(&self) -> u8 {
+        ((self.x & POSITION_MASK) << 1) as u8
+    }
+
+    // Value of the POTY register
+    pub fn pot_y(&self) -> u8 {
+        ((self.y & POSITION_MASK) << 1) as u8
+    }
+
+    // Joystick lines on the control port. Bits are 0 for lines that are pulled low.
+    pub fn port_lines(&self) -> u8 {
+        let mut lines = 0xff;
+        if self.left {
+            lines &= !LEFT_BUTTON_LINE;
+        }
+        if self.right {
+            lines &= !RIGHT_BUTTON_LINE;
+        }
+        lines
+    }
+}
@@ -0,0 +1,45 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+//
+// Data structures and functions related to a parallel cable on the user port
+
+// Sends bytes from the host to the user port. Each byte goes on PB0-7 of CIA #2 and the cable
+// pulses FLAG2 to say it's there. The C64 touching port B pulses PC2, which tells the cable to put
+// the next byte out.
+pub struct ParallelCable {
+    data: Vec<u8>,
+    pos: usize,     // Byte on the data lines
+    strobe: bool,   // FLAG needs a pulse for the byte on the data lines
+}
+
+impl ParallelCable {
+    pub fn new(data: Vec<u8>) -> ParallelCable {
+        let strobe = !data.is_empty();
+        ParallelCable {
+            data: data,
+            pos: 0,
+            strobe: strobe,
+        }
+    }
+
+    // Run the cable for a cycle with the PC line at the given level. Returns whether to pulse FLAG.
+    pub fn cycle(&mut self, pc: bool) -> bool {
+        if !pc && self.pos < self.data.len() {
+            self.pos += 1;
+            self.strobe = self.pos < self.data.len();
+        }
+
+        let strobe = self.strobe;
+        self.strobe = false;
+        strobe
+    }
+
+    // Data lines on the user port. Bits are 0 for lines that are pulled low. Once everything has
+    // been sent the lines float high.
+    pub fn port_lines(&self) -> u8 {
+        match self.data.get(self.pos) {
+            Some(&b) => b,
+            None => 0xff,
+        }
+    }
+}
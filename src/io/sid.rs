@@ -3,12 +3,41 @@
 //
 // Data structures and functions related to the SID sound chip
 
-use super::{write_high_byte, write_low_byte};
+use super::{write_high_byte, write_low_byte, Region};
 
 pub const MIN_CONTROL_ADDR: usize = 0xd400;
 pub const MAX_CONTROL_ADDR: usize = 0xd7ff;
-const CONTROL_REG_COUNT: usize = 0x20;
+pub const CONTROL_REG_COUNT: usize = 0x20;
 
+// Waveform select bits in the voice control registers
+const CTL_GATE: u8 = 0x01;
+const CTL_TRIANGLE: u8 = 0x10;
+const CTL_SAWTOOTH: u8 = 0x20;
+const CTL_PULSE: u8 = 0x40;
+const CTL_NOISE: u8 = 0x80;
+
+const ACCUMULATOR_MASK: u32 = 0xffffff;
+const NOISE_MASK: u32 = 0x7fffff;
+const NOISE_SEED: u32 = 0x7ffff8;
+const NOISE_CLOCK_BIT: u32 = 0x080000;
+
+// Reading a write-only register gets whatever was last written to the chip. The value leaks away
+// after about this many cycles.
+const BUS_VALUE_TTL: u32 = 0x1d00;
+
+// Waveform outputs are 12 bits. Silence is in the middle.
+const WAVE_MAX: u32 = 0xfff;
+const WAVE_ZERO: i32 = 0x800;
+const WAVE_BITS: usize = 12;
+
+// Selecting more than one waveform shorts their outputs together, and each output bit gets pulled
+// towards its neighbours. This is approximated by averaging the selected waveforms' bits, then
+// keeping the bits whose neighbourhood is mostly on. Pulse acts like an extra bit above the top.
+const COMBINED_PULL: f32 = 0.6;     // Weight of a bit on the next one over
+const COMBINED_BIAS: f32 = 0.85;    // Level a bit has to reach to stay on
+const COMBINED_PULSE: f32 = 2.0;    // Strength of the pulse waveform
+
+#[derive(Clone)]
 pub struct Sid {
     v1_f: u16,       // Voice 1 frequency
     v1_pw: u16,      // Voice 1 pulse width
@@ -32,11 +61,22 @@ pub struct Sid {
     filter_ctl: u8, // Filter control
     vol_mode: u8,   // Volume/filter mode
 
-    paddle_x: u8,   // X value of paddle at $DD00
-    paddle_y: u8,   // Y value of paddle at $DD00
+    paddle_x: u8,   // POTX, the X paddle position
+    paddle_y: u8,   // POTY, the Y paddle position
 
     v3_wave: u8,    // Voice 3 waveform
     v3_adsr: u8,    // Voice 3 envelope
+
+    bus_value: u8,      // Last value written to any register
+    bus_value_ttl: u32, // Cycles until the bus value decays to 0
+
+    clock_hz: u32,  // Clock frequency driving the oscillators
+
+    accumulators: [u32; 3], // 24-bit oscillator phase for each voice
+    noise: [u32; 3],        // 23-bit noise shift register for each voice
+    muted: [bool; 3],       // Voices left out of the mix for debugging
+
+    combined_waves: Vec<u16>,   // Combined waveform tables, built when first needed
 }
 
 impl Sid {
@@ -69,15 +109,157 @@ impl Sid {
 
             v3_wave: 0,
             v3_adsr: 0,
+
+            bus_value: 0,
+            bus_value_ttl: 0,
+
+            clock_hz: Region::Pal.clock_hz(),
+
+            accumulators: [0; 3],
+            noise: [NOISE_SEED; 3],
+            muted: [false; 3],
+
+            combined_waves: Vec::new(),
         }
     }
 
+    // Set the starting state of the noise generators. This is normally the same every time the
+    // chip is powered on.
+    pub fn seed_noise(&mut self, seed: u32) {
+        let seed = seed & NOISE_MASK;
+        self.noise = [if seed == 0 { NOISE_SEED } else { seed }; 3];
+    }
+
+    // Set the clock speed that drives the oscillators
+    pub fn set_region(&mut self, region: Region) {
+        self.clock_hz = region.clock_hz();
+    }
+
+    // Get the output frequency of a voice (1-3) in Hz
+    #[cfg(test)]
+    pub fn voice_frequency(&self, voice: u8) -> f32 {
+        let f = match voice {
+            1 => self.v1_f,
+            2 => self.v2_f,
+            3 => self.v3_f,
+            _ => panic!("Invalid SID voice: {}", voice),
+        };
+        // The oscillators are 24-bit accumulators that add the frequency register every cycle
+        (f as f32) * (self.clock_hz as f32) / 16777216f32
+    }
+
+    // Get the frequency, pulse width, and control register of a voice (1-3)
+    fn voice_regs(&self, voice: u8) -> (u16, u16, u8) {
+        match voice {
+            1 => (self.v1_f, self.v1_pw, self.v1_ctl),
+            2 => (self.v2_f, self.v2_pw, self.v2_ctl),
+            3 => (self.v3_f, self.v3_pw, self.v3_ctl),
+            _ => panic!("Invalid SID voice: {}", voice),
+        }
+    }
+
+    // Set the paddle positions the POTX and POTY registers read back
+    pub fn set_paddles(&mut self, x: u8, y: u8) {
+        self.paddle_x = x;
+        self.paddle_y = y;
+    }
+
+    // Leave a voice (1-3) out of the mix
+    pub fn set_voice_muted(&mut self, voice: u8, muted: bool) {
+        if !(1..=3).contains(&voice) {
+            panic!("Invalid SID voice: {}", voice);
+        }
+        self.muted[(voice - 1) as usize] = muted;
+    }
+
+    pub fn voice_muted(&self, voice: u8) -> bool {
+        self.muted[(voice - 1) as usize]
+    }
+
+    // Advance the oscillators by one clock cycle
+    pub fn cycle(&mut self) {
+        for v in 0..3 {
+            let (f, _, _) = self.voice_regs(v as u8 + 1);
+            let old = self.accumulators[v];
+            let new = (old + f as u32) & ACCUMULATOR_MASK;
+            self.accumulators[v] = new;
+
+            // The noise generator is clocked by bit 19 of the accumulator
+            if old & NOISE_CLOCK_BIT == 0 && new & NOISE_CLOCK_BIT != 0 {
+                let lfsr = self.noise[v];
+                let bit = ((lfsr >> 22) ^ (lfsr >> 17)) & 1;
+                self.noise[v] = ((lfsr << 1) | bit) & NOISE_MASK;
+            }
+        }
+        // The upper 8 bits of voice 3's waveform can be read back
+        self.v3_wave = (self.waveform(3) >> 4) as u8;
+
+        if self.bus_value_ttl > 0 {
+            self.bus_value_ttl -= 1;
+            if self.bus_value_ttl == 0 {
+                self.bus_value = 0;
+            }
+        }
+    }
+
+    // Get the 12-bit output of a voice's (1-3) oscillator
+    fn waveform(&self, voice: u8) -> u32 {
+        let (_, pw, ctl) = self.voice_regs(voice);
+        let acc = self.accumulators[(voice - 1) as usize];
+
+        let mut wave = WAVE_MAX;
+        if ctl & (CTL_TRIANGLE | CTL_SAWTOOTH | CTL_PULSE | CTL_NOISE) == 0 {
+            return 0;
+        }
+        let shape = ctl & (CTL_TRIANGLE | CTL_SAWTOOTH | CTL_PULSE);
+        if shape & CTL_PULSE != 0 && (acc >> 12) < (pw as u32 & WAVE_MAX) {
+            wave = 0;
+        } else if shape == CTL_TRIANGLE {
+            let folded = if acc & 0x800000 != 0 { !acc } else { acc };
+            wave = (folded >> 11) & WAVE_MAX;
+        } else if shape == CTL_SAWTOOTH {
+            wave = acc >> 12;
+        } else if shape != CTL_PULSE && shape != 0 {
+            // Selecting more than one waveform combines them
+            let index = ((shape >> 4) as usize) << WAVE_BITS | (acc >> 12) as usize;
+            wave = self.combined_waves[index] as u32;
+        }
+        if ctl & CTL_NOISE != 0 {
+            let n = self.noise[(voice - 1) as usize];
+            let bits = ((n >> 20) & 1) << 7 | ((n >> 18) & 1) << 6 | ((n >> 14) & 1) << 5 |
+                ((n >> 11) & 1) << 4 | ((n >> 9) & 1) << 3 | ((n >> 5) & 1) << 2 |
+                ((n >> 2) & 1) << 1 | (n & 1);
+            wave &= bits << 4;
+        }
+        wave
+    }
+
+    // Get the signed output of a voice (1-3) before mixing
+    // TODO: Add the ADSR envelope. For now voices are at full volume while the gate is on.
+    pub fn voice_output(&self, voice: u8) -> i32 {
+        let (_, _, ctl) = self.voice_regs(voice);
+        if ctl & CTL_GATE == 0 || ctl & (CTL_TRIANGLE | CTL_SAWTOOTH | CTL_PULSE | CTL_NOISE) == 0 {
+            0
+        } else {
+            self.waveform(voice) as i32 - WAVE_ZERO
+        }
+    }
+
+    // Mix the voices that aren't muted and apply the master volume
+    pub fn output(&self) -> i32 {
+        let mut mix = 0;
+        for voice in 1..4 {
+            if !self.voice_muted(voice) {
+                mix += self.voice_output(voice);
+            }
+        }
+        mix * (self.vol_mode & 0x0f) as i32 / 15
+    }
+
     // Translate a memory address to a register index
     fn translate_addr(&self, addr: usize) -> u8 {
-        if addr > MAX_CONTROL_ADDR || addr < MIN_CONTROL_ADDR {
-            panic!("Invalid address for SID control register: ${:0>4X}", addr);
-        }
-        ((addr - MIN_CONTROL_ADDR) % CONTROL_REG_COUNT) as u8
+        // Only the low address lines are decoded, so anything else just hits a mirror
+        (addr.wrapping_sub(MIN_CONTROL_ADDR) % CONTROL_REG_COUNT) as u8
     }
 
 
@@ -90,12 +272,14 @@ impl Sid {
             0x1a => self.paddle_y,
             0x1b => self.v3_wave,
             0x1c => self.v3_adsr,
-            _ => 0
+            _ => self.bus_value,
         }
     }
 
     pub fn write_register(&mut self, addr: usize, value: u8) {
         let reg = self.translate_addr(addr);
+        self.bus_value = value;
+        self.bus_value_ttl = BUS_VALUE_TTL;
 
         match reg {
             0 => {
@@ -159,5 +343,71 @@ impl Sid {
             24 => { self.vol_mode = value; },
             _ => { /* Remaining registers are non-existent or read-only */ },
         };
+
+        // The combined waveform tables are slow to build so wait until something uses them
+        let shape = value & (CTL_TRIANGLE | CTL_SAWTOOTH | CTL_PULSE);
+        if matches!(reg, 4 | 11 | 18) && shape.count_ones() > 1 && self.combined_waves.is_empty() {
+            self.combined_waves = combined_waveforms();
+        }
+    }
+}
+
+// Build the combined waveform tables. Each table covers one combination of the triangle (bit 0),
+// sawtooth (bit 1), and pulse (bit 2) waveforms and is indexed by the top 12 bits of the
+// accumulator. Pulse is assumed to be high since its output is zero otherwise.
+fn combined_waveforms() -> Vec<u16> {
+    // Weight of a bit on another one by the distance between them
+    let mut weights = [1f32; WAVE_BITS + 1];
+    for i in 1..weights.len() {
+        weights[i] = weights[i - 1] * COMBINED_PULL;
+    }
+
+    let mut tables = vec![0u16; 8 << WAVE_BITS];
+    for shape in 3..8usize {
+        if shape == 4 {
+            continue;
+        }
+        for acc in 0..(1 << WAVE_BITS) {
+            tables[shape << WAVE_BITS | acc] = combined_waveform(shape, acc as u32, &weights);
+        }
+    }
+    tables
+}
+
+fn combined_waveform(shape: usize, acc: u32, weights: &[f32]) -> u16 {
+    let folded = if acc & 0x800 != 0 { acc ^ WAVE_MAX } else { acc };
+    let triangle = (folded << 1) & WAVE_MAX;
+
+    // Average the bits of the selected waveforms
+    let mut bits = [0f32; WAVE_BITS];
+    let count = (shape & 0x03).count_ones() as f32;
+    for (i, bit) in bits.iter_mut().enumerate() {
+        if shape & 0x01 != 0 {
+            *bit += ((triangle >> i) & 1) as f32;
+        }
+        if shape & 0x02 != 0 {
+            *bit += ((acc >> i) & 1) as f32;
+        }
+        *bit /= count;
+    }
+
+    let mut wave = 0u16;
+    for i in 0..WAVE_BITS {
+        let mut level = 0f32;
+        let mut total = 0f32;
+        for (j, bit) in bits.iter().enumerate() {
+            let weight = weights[i.abs_diff(j)];
+            level += weight * bit;
+            total += weight;
+        }
+        if shape & 0x04 != 0 {
+            let weight = weights[WAVE_BITS - i];
+            level += weight * COMBINED_PULSE;
+            total += weight;
+        }
+        if level / total > COMBINED_BIAS {
+            wave |= 1 << i;
+        }
     }
+    wave
 }
@@ -0,0 +1,817 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+use super::super::Screen;
+use super::vic::Vic;
+use super::cia::Cia;
+use super::sid::Sid;
+use super::Region;
+use super::mouse::Mouse1351;
+use std::cell::RefCell;
+
+// Test the VIC-II display window
+#[test]
+fn vic_csel_narrows_display_window() {
+    let mut vic = Vic::new(320, 240);
+
+    vic.write_register(0xd016, 0xc8);
+    let (left_40, right_40, _, _) = vic.display_window();
+    vic.write_register(0xd016, 0xc0);
+    let (left_38, right_38, _, _) = vic.display_window();
+
+    assert_eq!(320, right_40 - left_40);
+    assert_eq!(304, right_38 - left_38);
+    assert_eq!(7, left_38 - left_40);
+}
+
+#[test]
+fn vic_rsel_narrows_display_window() {
+    let mut vic = Vic::new(320, 240);
+
+    vic.write_register(0xd011, 0x1b);
+    let (_, _, top_25, bottom_25) = vic.display_window();
+    vic.write_register(0xd011, 0x13);
+    let (_, _, top_24, bottom_24) = vic.display_window();
+
+    assert_eq!(200, bottom_25 - top_25);
+    assert_eq!(192, bottom_24 - top_24);
+    assert_eq!(4, top_24 - top_25);
+}
+
+// A screen smaller than the VIC's visible area only gets the part that fits
+#[test]
+fn vic_clips_to_screen() {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(64, 48);
+    vic.write_register(0xd020, 0x02);
+
+    for _ in 0..63 * 312 * 2 {
+        vic.rising_edge(&mut screen, false);
+    }
+    assert_eq!(0x0068372b, screen.pixel_at(0, 0));
+    assert_eq!(0x0068372b, screen.pixel_at(63, 19));
+}
+
+// Color RAM is read by position in the video matrix, wherever the matrix is in memory
+#[test]
+fn vic_fetch_line_reads_color_by_matrix_position() {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+    vic.write_register(0xd011, 0x1b);
+    // Screen at $0C00
+    vic.write_register(0xd018, 0x38);
+
+    let matrix_reads = RefCell::new(Vec::new());
+    let color_reads = RefCell::new(Vec::new());
+    for _ in 0..(312 * 63) {
+        vic.rising_edge(&mut screen, false);
+        if vic.line_fetch_pending() {
+            vic.fetch_line(
+                |addr| {
+                    if (0x0c00..0x1000).contains(&addr) {
+                        matrix_reads.borrow_mut().push(addr);
+                    }
+                    0
+                },
+                |pos| {
+                    color_reads.borrow_mut().push(pos);
+                    0
+                });
+        }
+        vic.falling_edge(&mut screen, false);
+    }
+
+    assert_eq!((0x0c00..0x0fe8).collect::<Vec<u16>>(), matrix_reads.into_inner());
+    assert_eq!((0..1000).collect::<Vec<u16>>(), color_reads.into_inner());
+}
+
+// Test VIC-II bus arbitration on bad lines
+#[test]
+fn vic_bad_line_stalls_cpu() {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    // Run until the VIC pulls BA low on the first bad line
+    let mut cycles = 0;
+    while vic.rdy() {
+        vic.rising_edge(&mut screen, false);
+        cycles += 1;
+        assert!(cycles < 63 * 0x40, "No bad line found");
+    }
+
+    let mut ba_cycles = 1;
+    let mut aec_cycles = 0;
+    for _ in 0..62 {
+        vic.rising_edge(&mut screen, false);
+        if !vic.rdy() {
+            ba_cycles += 1;
+        }
+        if !vic.aec() {
+            aec_cycles += 1;
+        }
+    }
+
+    assert_eq!(43, ba_cycles);
+    assert_eq!(40, aec_cycles);
+}
+
+// Run the VIC until it has just started a raster line
+fn run_to_line(vic: &mut Vic, screen: &mut Screen, line: u16) {
+    while vic.current_raster() != line {
+        vic.rising_edge(screen, false);
+    }
+}
+
+// Run the rest of a raster line and count the cycles BA is low
+fn ba_cycles_left(vic: &mut Vic, screen: &mut Screen, cycles: u8) -> u32 {
+    let mut ba_cycles = 0;
+    for _ in 0..cycles {
+        vic.rising_edge(screen, false);
+        if !vic.rdy() {
+            ba_cycles += 1;
+        }
+    }
+    ba_cycles
+}
+
+// Moving YSCROLL away from the line before BA goes low cancels the bad line, like FLD does
+#[test]
+fn vic_cr1_write_cancels_bad_line() {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    // YSCROLL 3 makes $33 the first bad line
+    run_to_line(&mut vic, &mut screen, 0x33);
+    assert!(vic.line_fetch_pending());
+
+    vic.write_register(0xd011, 0x1c);
+    assert!(!vic.line_fetch_pending());
+    assert_eq!(0, ba_cycles_left(&mut vic, &mut screen, 62));
+}
+
+// Moving YSCROLL onto the line partway through makes it a bad line, like VSP does
+#[test]
+fn vic_cr1_write_starts_bad_line() {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    // $30 isn't a bad line with YSCROLL 3, so the display is still idle
+    run_to_line(&mut vic, &mut screen, 0x30);
+    assert!(!vic.line_fetch_pending());
+
+    ba_cycles_left(&mut vic, &mut screen, 20);
+    vic.write_register(0xd011, 0x18);
+    assert!(vic.line_fetch_pending());
+    assert_eq!(34, ba_cycles_left(&mut vic, &mut screen, 42));
+}
+
+#[test]
+fn vic_no_bad_lines_when_blanked() {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    // Clear DEN
+    vic.write_register(0xd011, 0x0b);
+    for _ in 0..(63 * 0x80) {
+        vic.rising_edge(&mut screen, false);
+        assert!(vic.rdy() && vic.aec());
+    }
+}
+
+// Test the CIA serial shift register
+#[test]
+fn cia_sdr_output_completes() {
+    let mut cia = Cia::new(0xdc00);
+
+    // Timer A underflows every 3 cycles
+    cia.write_register(0xdc04, 0x02);
+    cia.write_register(0xdc05, 0x00);
+    cia.write_register(0xdc0e, 0x41);
+    cia.write_register(0xdc0c, 0xa5);
+
+    let mut bits = 0u8;
+    let mut last_cnt = cia.cnt();
+    for _ in 0..(8 * 2 * 3) {
+        cia.cycle();
+        if cia.cnt() && !last_cnt {
+            bits = (bits << 1) | (cia.sp() as u8);
+        }
+        last_cnt = cia.cnt();
+    }
+
+    assert_eq!(0xa5, bits);
+    assert_eq!(0x08, cia.read_register(0xdc0d) & 0x08);
+}
+
+#[test]
+fn cia_sdr_output_waits_for_timer() {
+    let mut cia = Cia::new(0xdc00);
+
+    cia.write_register(0xdc04, 0x02);
+    cia.write_register(0xdc05, 0x00);
+    cia.write_register(0xdc0e, 0x41);
+    cia.write_register(0xdc0c, 0xa5);
+
+    for _ in 0..(8 * 2 * 3 - 1) {
+        cia.cycle();
+    }
+
+    assert_eq!(0x00, cia.read_register(0xdc0d) & 0x08);
+}
+
+// Test the CIA time of day clock
+// Count the cycles until the time of day changes
+fn tod_tick_cycles(cia: &mut Cia) -> u32 {
+    let start = cia.read_register(0xdc08);
+    let mut cycles = 0;
+    while cia.read_register(0xdc08) == start {
+        cia.cycle();
+        cycles += 1;
+        assert!(cycles < 200000, "Time of day never changed");
+    }
+    cycles
+}
+
+#[test]
+fn cia_tod_pal_interval() {
+    let mut cia = Cia::new(0xdc00);
+    cia.set_region(Region::Pal);
+    // 50 Hz input
+    cia.write_register(0xdc0e, 0x80);
+
+    assert_eq!(Region::Pal.clock_hz() / 50 * 5, tod_tick_cycles(&mut cia));
+}
+
+#[test]
+fn cia_tod_ntsc_interval() {
+    let mut cia = Cia::new(0xdc00);
+    cia.set_region(Region::Ntsc);
+    // 60 Hz input
+    cia.write_register(0xdc0e, 0x00);
+
+    assert_eq!(Region::Ntsc.clock_hz() / 60 * 6, tod_tick_cycles(&mut cia));
+}
+
+#[test]
+fn cia_tod_rolls_over_hours() {
+    let mut cia = Cia::new(0xdc00);
+    cia.write_register(0xdc0e, 0x80);
+    cia.write_register(0xdc0b, 0x11);
+    cia.write_register(0xdc0a, 0x59);
+    cia.write_register(0xdc09, 0x59);
+    cia.write_register(0xdc08, 0x09);
+
+    tod_tick_cycles(&mut cia);
+
+    assert_eq!(0x92, cia.read_register(0xdc0b));
+    assert_eq!(0x00, cia.read_register(0xdc0a));
+    assert_eq!(0x00, cia.read_register(0xdc09));
+    assert_eq!(0x00, cia.read_register(0xdc08));
+}
+
+// The force load strobe reads as 0 and a one-shot timer clears its start bit when it's done
+#[test]
+fn cia_control_registers_read_back() {
+    let mut cia = Cia::new(0xdc00);
+
+    cia.write_register(0xdc04, 0x02);
+    cia.write_register(0xdc05, 0x00);
+    cia.write_register(0xdc0e, 0x19);
+    assert_eq!(0x09, cia.read_register(0xdc0e));
+    cia.write_register(0xdc0f, 0x98);
+    assert_eq!(0x88, cia.read_register(0xdc0f));
+
+    for _ in 0..4 {
+        cia.cycle();
+    }
+    assert_eq!(0x08, cia.read_register(0xdc0e));
+}
+
+// With bit 7 of control register B set, time of day writes go to the alarm
+#[test]
+fn cia_tod_alarm_select() {
+    let mut cia = Cia::new(0xdc00);
+    cia.write_register(0xdc0e, 0x80);
+    cia.write_register(0xdc0b, 0x01);
+    cia.write_register(0xdc0a, 0x00);
+    cia.write_register(0xdc09, 0x00);
+    cia.write_register(0xdc08, 0x00);
+
+    cia.write_register(0xdc0f, 0x80);
+    cia.write_register(0xdc0b, 0x01);
+    cia.write_register(0xdc0a, 0x00);
+    cia.write_register(0xdc09, 0x00);
+    cia.write_register(0xdc08, 0x01);
+    assert_eq!(0x00, cia.read_register(0xdc08));
+
+    tod_tick_cycles(&mut cia);
+    assert_eq!(0x04, cia.read_register(0xdc0d) & 0x04);
+}
+
+// Test SID oscillator timing
+#[test]
+fn sid_voice_frequency_follows_region() {
+    let mut sid = Sid::new();
+    // Concert A on a PAL machine
+    sid.write_register(0xd400, 0x45);
+    sid.write_register(0xd401, 0x1d);
+
+    sid.set_region(Region::Pal);
+    let pal = sid.voice_frequency(1);
+    sid.set_region(Region::Ntsc);
+    let ntsc = sid.voice_frequency(1);
+
+    assert!((pal - 440.0).abs() < 0.5);
+    assert!(ntsc > pal);
+}
+
+// Test VIC-II raster interrupts
+fn run_to_vic_irq(vic: &mut Vic, screen: &mut Screen) {
+    let mut cycles = 0;
+    while vic.irq() {
+        vic.rising_edge(screen, false);
+        cycles += 1;
+        assert!(cycles < 63 * 0x10, "No raster interrupt");
+    }
+}
+
+#[test]
+fn vic_raster_irq_acknowledge() {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    vic.write_register(0xd012, 0x05);
+    vic.write_register(0xd01a, 0x01);
+    run_to_vic_irq(&mut vic, &mut screen);
+    assert_eq!(0x81, vic.read_register(0xd019) & 0x81);
+
+    // Acknowledging a different source leaves the IRQ asserted
+    vic.write_register(0xd019, 0x02);
+    assert!(!vic.irq());
+
+    vic.write_register(0xd019, 0x01);
+    assert!(vic.irq());
+    assert_eq!(0x00, vic.read_register(0xd019) & 0x81);
+}
+
+#[test]
+fn vic_disabled_raster_irq_is_latched() {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    vic.write_register(0xd012, 0x02);
+    for _ in 0..63 * 3 {
+        vic.rising_edge(&mut screen, false);
+    }
+    assert!(vic.irq());
+    assert_eq!(0x01, vic.read_register(0xd019) & 0x81);
+
+    // Enabling the source raises IRQ for the pending interrupt
+    vic.write_register(0xd01a, 0x01);
+    assert!(!vic.irq());
+}
+
+// Test sprite DMA cycle stealing
+fn sprite_dma_cycles(enable: u8, y: u8) -> (u32, u32) {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    vic.write_register(0xd015, enable);
+    for sprite in 0..8 {
+        vic.write_register(0xd001 + 2 * sprite, y);
+    }
+
+    // Run to the start of the sprites' first line
+    while vic.read_register(0xd012) != y {
+        vic.rising_edge(&mut screen, false);
+    }
+
+    let mut ba_cycles = 0;
+    let mut aec_cycles = 0;
+    for _ in 0..63 {
+        if !vic.rdy() {
+            ba_cycles += 1;
+        }
+        if !vic.aec() {
+            aec_cycles += 1;
+        }
+        vic.rising_edge(&mut screen, false);
+    }
+    (ba_cycles, aec_cycles)
+}
+
+#[test]
+fn vic_sprite_dma_steals_cycles() {
+    // Sprites 3-5 are fetched at the start of the line. BA also goes low for the last two cycles
+    // ahead of sprite 3's fetch for the next line.
+    let (ba_cycles, aec_cycles) = sprite_dma_cycles(0x38, 0x10);
+    assert_eq!(9, ba_cycles);
+    assert_eq!(6, aec_cycles);
+
+    // Sprites 0-2 are fetched at the end of the line before. Sprite 2's fetch for this line
+    // finishes in its first cycle.
+    let (_, aec_cycles) = sprite_dma_cycles(0x07, 0x10);
+    assert_eq!(6, aec_cycles);
+}
+
+#[test]
+fn vic_no_sprite_dma_when_disabled() {
+    let (ba_cycles, aec_cycles) = sprite_dma_cycles(0x00, 0x10);
+    assert_eq!(0, ba_cycles);
+    assert_eq!(0, aec_cycles);
+}
+
+// Test SID voice muting
+#[test]
+fn sid_muted_voice_left_out_of_mix() {
+    let mut sid = Sid::new();
+
+    // Sawtooth, triangle, and pulse at different frequencies with the gate on
+    sid.write_register(0xd400, 0x00);
+    sid.write_register(0xd401, 0x10);
+    sid.write_register(0xd404, 0x21);
+    sid.write_register(0xd407, 0x00);
+    sid.write_register(0xd408, 0x25);
+    sid.write_register(0xd40b, 0x11);
+    sid.write_register(0xd40e, 0x00);
+    sid.write_register(0xd40f, 0x07);
+    sid.write_register(0xd410, 0x00);
+    sid.write_register(0xd411, 0x08);
+    sid.write_register(0xd412, 0x41);
+    sid.write_register(0xd418, 0x0f);
+
+    for _ in 0..1234 {
+        sid.cycle();
+    }
+    let v1 = sid.voice_output(1);
+    let v2 = sid.voice_output(2);
+    let v3 = sid.voice_output(3);
+    assert!(v2 != 0);
+    assert_eq!(v1 + v2 + v3, sid.output());
+
+    sid.set_voice_muted(2, true);
+    assert_eq!(v1 + v3, sid.output());
+
+    sid.set_voice_muted(2, false);
+    assert_eq!(v1 + v2 + v3, sid.output());
+}
+
+#[test]
+fn sid_sawtooth_follows_accumulator() {
+    let mut sid = Sid::new();
+
+    sid.write_register(0xd40e, 0x00);
+    sid.write_register(0xd40f, 0x10);
+    sid.write_register(0xd412, 0x20);
+
+    // $1000 per cycle takes 16 cycles to move the top 8 bits by one
+    for _ in 0..32 {
+        sid.cycle();
+    }
+    assert_eq!(0x02, sid.read_register(0xd41b));
+}
+
+// Test the 9-bit raster counter
+#[test]
+fn vic_raster_irq_above_line_255() {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    // Interrupt on line $105
+    vic.write_register(0xd011, 0x9b);
+    vic.write_register(0xd012, 0x05);
+    vic.write_register(0xd01a, 0x01);
+
+    let mut cycles = 0;
+    while vic.irq() {
+        vic.rising_edge(&mut screen, false);
+        cycles += 1;
+        assert!(cycles < 63 * 312, "No raster interrupt");
+    }
+
+    assert_eq!(0x105, vic.current_raster());
+    assert_eq!(0x05, vic.read_register(0xd012));
+    assert_eq!(0x80, vic.read_register(0xd011) & 0x80);
+}
+
+// Bit 7 of $D011 reads the live raster rather than what was written, and the unused bits of
+// $D016 read as 1
+#[test]
+fn vic_control_registers_read_live_values() {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    // Setting the raster compare high bit doesn't show up on line 0
+    vic.write_register(0xd011, 0x9b);
+    assert_eq!(0x1b, vic.read_register(0xd011));
+    vic.write_register(0xd016, 0x08);
+    assert_eq!(0xc8, vic.read_register(0xd016));
+
+    // Clearing it doesn't hide the raster high bit past line 255
+    vic.write_register(0xd011, 0x1b);
+    while vic.current_raster() < 0x101 {
+        vic.rising_edge(&mut screen, false);
+    }
+    assert_eq!(0x9b, vic.read_register(0xd011));
+    vic.write_register(0xd011, 0x13);
+    assert_eq!(0x93, vic.read_register(0xd011));
+}
+
+#[test]
+fn vic_raster_wraps_after_last_line() {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    let mut max_raster = 0;
+    for _ in 0..63 * 312 {
+        vic.rising_edge(&mut screen, false);
+        if vic.current_raster() > max_raster {
+            max_raster = vic.current_raster();
+        }
+    }
+
+    assert_eq!(311, max_raster);
+    assert_eq!(0, vic.current_raster());
+    assert_eq!(0x00, vic.read_register(0xd011) & 0x80);
+}
+
+fn raster_lines_per_frame(region: Region) -> u32 {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+    vic.set_region(region);
+
+    let mut lines = 0;
+    loop {
+        vic.rising_edge(&mut screen, false);
+        if vic.frame_ready() {
+            return lines + 1;
+        }
+        if vic.current_raster() as u32 != lines {
+            lines += 1;
+        }
+        assert!(lines < 512, "Frame never finished");
+    }
+}
+
+#[test]
+fn vic_raster_lines_per_frame() {
+    assert_eq!(312, raster_lines_per_frame(Region::Pal));
+    assert_eq!(263, raster_lines_per_frame(Region::Ntsc));
+}
+
+// Test reading the CIA ports through the data direction registers
+#[test]
+fn cia_port_reads_latch_for_outputs() {
+    let mut cia = Cia::new(0xdc00);
+
+    // Low nybble output, high nybble input
+    cia.write_register(0xdc02, 0x0f);
+    cia.write_register(0xdc00, 0x05);
+    assert_eq!(0xf5, cia.read_register(0xdc00));
+
+    // External devices only affect the inputs
+    cia.set_port_a_input(0xee);
+    assert_eq!(0xe5, cia.read_register(0xdc00));
+
+    // All inputs
+    cia.write_register(0xdc02, 0x00);
+    assert_eq!(0xee, cia.read_register(0xdc00));
+
+    cia.set_port_b_input(0xfe);
+    assert_eq!(0xfe, cia.read_register(0xdc01));
+}
+
+#[test]
+fn cia_reverse_keyboard_scan() {
+    let mut cia = Cia::new(0xdc00);
+
+    // Drive port B low and read the pressed key's column on port A
+    cia.write_register(0xdc02, 0x00);
+    cia.write_register(0xdc03, 0xff);
+    cia.write_register(0xdc01, 0x00);
+    cia.set_key(7, 7, true);
+    assert_eq!(0x7f, cia.read_register(0xdc00));
+
+    // Rows that aren't driven low don't see the key
+    cia.write_register(0xdc01, 0x80);
+    assert_eq!(0xff, cia.read_register(0xdc00));
+}
+
+#[test]
+fn vic_set_raster_then_step() {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    vic.set_raster(0x100);
+    assert_eq!(0x100, vic.current_raster());
+    for _ in 0..63 {
+        vic.rising_edge(&mut screen, false);
+    }
+    assert_eq!(0x101, vic.current_raster());
+
+    // The last line wraps to the first
+    vic.set_raster(311);
+    for _ in 0..63 {
+        vic.rising_edge(&mut screen, false);
+    }
+    assert_eq!(0, vic.current_raster());
+}
+
+// Test the CIA handshake lines
+#[test]
+fn cia_flag_requests_irq_when_enabled() {
+    let mut cia = Cia::new(0xdd00);
+
+    cia.pulse_flag();
+    assert!(!cia.irq_requested());
+
+    cia.write_register(0xdd0d, 0x90);
+    assert!(cia.irq_requested());
+    assert_eq!(0x90, cia.read_register(0xdd0d));
+
+    // Reading the interrupt status acknowledges it
+    assert!(!cia.irq_requested());
+    assert_eq!(0x00, cia.read_register(0xdd0d));
+}
+
+#[test]
+fn cia_pc_pulses_on_port_b_access() {
+    let mut cia = Cia::new(0xdd00);
+
+    assert!(cia.pc());
+    cia.read_register(0xdd01);
+    assert!(!cia.pc());
+    cia.cycle();
+    assert!(cia.pc());
+
+    cia.write_register(0xdd01, 0x55);
+    assert!(!cia.pc());
+    cia.cycle();
+    assert!(cia.pc());
+
+    // Port A doesn't affect PC
+    cia.read_register(0xdd00);
+    assert!(cia.pc());
+}
+
+// Test VIC register mirroring
+#[test]
+fn vic_registers_mirror_every_64_bytes() {
+    let mut vic = Vic::new(320, 240);
+
+    vic.write_register(0xd020, 0x05);
+    assert_eq!(0xf5, vic.read_register(0xd060));
+    assert_eq!(0xf5, vic.read_register(0xd3e0));
+
+    // Writes to a mirror hit the same register
+    vic.write_register(0xd3e0, 0x0e);
+    assert_eq!(0xfe, vic.read_register(0xd020));
+
+    // Only the unconnected registers read as $FF
+    for base in (0xd000..0xd400).step_by(0x40) {
+        assert_eq!(0xfe, vic.read_register(base + 0x20));
+        for reg in 0x2f..0x40 {
+            vic.write_register(base + reg, 0x00);
+            assert_eq!(0xff, vic.read_register(base + reg));
+        }
+    }
+}
+
+// Test the VIC-II's idle fetches
+#[test]
+fn vic_idle_fetch_address() {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    // Nothing is displayed on the first lines of the frame
+    vic.rising_edge(&mut screen, false);
+    assert_eq!(0x3fff, vic.read_addr_bus());
+
+    vic.write_register(0xd011, 0x5b);
+    vic.rising_edge(&mut screen, false);
+    assert_eq!(0x39ff, vic.read_addr_bus());
+}
+
+// Get one period of a voice 1 waveform
+fn sid_waveform_samples(ctl: u8) -> Vec<i32> {
+    let mut sid = Sid::new();
+    sid.write_register(0xd400, 0x00);
+    sid.write_register(0xd401, 0x10);
+    sid.write_register(0xd404, ctl);
+    (0..0x1000).map(|_| {
+        sid.cycle();
+        sid.voice_output(1)
+    }).collect()
+}
+
+#[test]
+fn sid_combined_waveform_differs() {
+    let triangle = sid_waveform_samples(0x11);
+    let sawtooth = sid_waveform_samples(0x21);
+    let combined = sid_waveform_samples(0x31);
+
+    assert!(combined != triangle);
+    assert!(combined != sawtooth);
+    // Triangle and sawtooth mostly cancel out
+    let silent = combined.iter().filter(|&&s| s == -0x800).count();
+    assert!(silent > combined.len() / 2);
+    assert!(combined.iter().any(|&s| s > -0x800));
+}
+
+#[test]
+fn sid_write_only_register_reads_bus_value() {
+    let mut sid = Sid::new();
+    assert_eq!(0x00, sid.read_register(0xd401));
+
+    sid.write_register(0xd400, 0x5a);
+    assert_eq!(0x5a, sid.read_register(0xd401));
+    assert_eq!(0x5a, sid.read_register(0xd41d));
+    // Readable registers aren't affected
+    assert_eq!(0x00, sid.read_register(0xd419));
+
+    // The value decays after a while
+    for _ in 0..0x1cff {
+        sid.cycle();
+    }
+    assert_eq!(0x5a, sid.read_register(0xd401));
+    sid.cycle();
+    assert_eq!(0x00, sid.read_register(0xd401));
+}
+
+#[test]
+fn vic_test_pattern_follows_registers() {
+    let mut vic = Vic::new(320, 240);
+    let mut screen = Screen::new(320, 240);
+    vic.set_test_pattern(true);
+    // Red border, bars starting from green
+    vic.write_register(0xd020, 0x02);
+    vic.write_register(0xd021, 0x05);
+
+    for _ in 0..(63 * 312) {
+        vic.rising_edge(&mut screen, false);
+        assert!(vic.rdy() && vic.aec());
+    }
+
+    assert_eq!(0x0068372b, screen.pixel_at(0, 0));
+    assert_eq!(0x0068372b, screen.pixel_at(319, 239));
+    // The display window starts 20 lines down
+    assert_eq!(0x00588d43, screen.pixel_at(0, 20));
+    assert_eq!(0x00352879, screen.pixel_at(20, 20));
+    assert_eq!(0x0070a4b2, screen.pixel_at(319 - 20, 219));
+}
+
+// Addresses outside a chip's range should hit a mirror rather than panic
+#[test]
+fn io_addresses_outside_range_mirror() {
+    let mut vic = Vic::new(320, 240);
+    let mut sid = Sid::new();
+    let mut cia = Cia::new(0xdc00);
+
+    vic.write_register(0xd3e0, 0x05);
+    assert_eq!(0xf5, vic.read_register(0xd020));
+    for &addr in &[0x0000, 0xcfff, 0xd400, 0xffff] {
+        vic.read_register(addr);
+        vic.write_register(addr, 0xff);
+    }
+
+    for &addr in &[0x0000, 0xd3ff, 0xd800, 0xffff] {
+        sid.read_register(addr);
+        sid.write_register(addr, 0xff);
+    }
+
+    cia.write_register(0xdcf2, 0x3f);
+    assert_eq!(0x3f, cia.read_register(0xdc02));
+    for &addr in &[0x0000, 0xdbff, 0xdd00, 0xffff] {
+        cia.read_register(addr);
+        cia.write_register(addr, 0xff);
+    }
+}
+
+// Test the 1351 mouse's POT encoding
+#[test]
+fn mouse_pots_hold_position_mod_64() {
+    let mut mouse = Mouse1351::new();
+    assert_eq!((0x00, 0x00), (mouse.pot_x(), mouse.pot_y()));
+
+    // Moving up counts up on the Y axis
+    mouse.move_by(10, -5);
+    assert_eq!((20, 10), (mouse.pot_x(), mouse.pot_y()));
+
+    // Only bits 1-6 change, so the counters wrap every 64 counts
+    mouse.move_by(60, 10);
+    assert_eq!((12, 0x76), (mouse.pot_x(), mouse.pot_y()));
+    mouse.move_by(-70, 0);
+    assert_eq!((0, 0x76), (mouse.pot_x(), mouse.pot_y()));
+}
+
+#[test]
+fn mouse_buttons_pull_joystick_lines() {
+    let mut mouse = Mouse1351::new();
+    assert_eq!(0xff, mouse.port_lines());
+
+    mouse.set_buttons(true, false);
+    assert_eq!(0xef, mouse.port_lines());
+    mouse.set_buttons(false, true);
+    assert_eq!(0xfe, mouse.port_lines());
+    mouse.set_buttons(true, true);
+    assert_eq!(0xee, mouse.port_lines());
+}
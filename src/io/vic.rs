@@ -6,33 +6,77 @@
 use std::fmt;
 
 use super::super::Screen;
+use super::Region;
 
 pub const MIN_CONTROL_ADDR: usize = 0xd000;
 pub const MAX_CONTROL_ADDR: usize = 0xd3ff;
 const CONTROL_REG_COUNT: usize = 0x40;
 
-// TODO: Add code for NTSC
+// TODO: NTSC lines are 65 cycles long
 const HORZ_CYCLE_COUNT: u8 = 63;    // Number of cycles per line
 
-// Mapping from color nybble to gamma-corrected color
+// Bad lines can only occur in this range of raster lines
+const FIRST_DMA_LINE: u16 = 0x30;
+const LAST_DMA_LINE: u16 = 0xf7;
+
+// Cycles of a bad line where the VIC pulls BA low and where it takes the bus to fetch the video
+// matrix. BA goes low three cycles early so the CPU can finish any writes.
+const BAD_LINE_BA_START: u8 = 12;
+const BAD_LINE_FETCH_START: u8 = 15;
+const BAD_LINE_FETCH_END: u8 = 54;
+
+// Sprite n fetches its pointer and data starting 2n - 5 cycles from the start of the line it's
+// displayed on, so sprites 0-2 are fetched at the end of the previous line. The VIC takes the
+// bus for two cycles per sprite and pulls BA low three cycles before that.
+const SPRITE_DMA_FIRST_OFFSET: i16 = -5;
+const SPRITE_DMA_BA_LEAD: i16 = 3;
+const SPRITE_DMA_CYCLES: i16 = 2;
+const SPRITE_HEIGHT: u16 = 21;
+
+// Edges of the display window in VIC coordinates. The right and bottom edges are exclusive.
+const WINDOW_LEFT_40_COLS: u16 = 0x18;
+const WINDOW_RIGHT_40_COLS: u16 = 0x158;
+const WINDOW_LEFT_38_COLS: u16 = 0x1f;
+const WINDOW_RIGHT_38_COLS: u16 = 0x14f;
+const WINDOW_TOP_25_ROWS: u16 = 0x33;
+const WINDOW_BOTTOM_25_ROWS: u16 = 0xfb;
+const WINDOW_TOP_24_ROWS: u16 = 0x37;
+const WINDOW_BOTTOM_24_ROWS: u16 = 0xf7;
+
+// Number of character cells on each row of the video matrix
+const MATRIX_COLUMNS: usize = 40;
+
+// VIC coordinates of the top left corner of the screen
+const FIRST_VISIBLE_X: u16 = 0x18;
+const FIRST_VISIBLE_LINE: u16 = 0x1f;
+
+// Mode bits in CR1
+const CR1_ECM: u8 = 0x40;
+const CR1_BMM: u8 = 0x20;
+
+// Interrupt sources in the interrupt register
+const INT_RASTER: u8 = 0x01;
+const INT_SOURCES: u8 = 0x0f;
+
+// Mapping from color nybble to gamma-corrected color, packed as 0x00RRGGBB
 // Values from Philip "Pepto" Timmermann's research here: http://www.pepto.de/projects/colorvic/
-const COLOR: [(u8, u8, u8); 16] = [
-    (0x00, 0x00, 0x00), // Black        0
-    (0xff, 0xff, 0xff), // White        1
-    (0x68, 0x37, 0x2b), // Red          2
-    (0x70, 0xa4, 0xb2), // Cyan         3
-    (0x6f, 0x3d, 0x86), // Purple       4
-    (0x58, 0x8d, 0x43), // Green        5
-    (0x35, 0x28, 0x79), // Blue         6
-    (0xb8, 0xc7, 0x6f), // Yellow       7
-    (0x6f, 0x4f, 0x25), // Orange       8
-    (0x43, 0x39, 0x00), // Brown        9
-    (0x9a, 0x67, 0x59), // Light red    a
-    (0x44, 0x44, 0x44), // Dark grey    b
-    (0x6c, 0x6c, 0x6c), // Grey         c
-    (0x9a, 0xd2, 0x84), // Light green  d
-    (0x6c, 0x5e, 0xb5), // Light blue   e
-    (0x95, 0x95, 0x95), // Light grey   f
+const COLOR: [u32; 16] = [
+    0x00000000, // Black        0
+    0x00ffffff, // White        1
+    0x0068372b, // Red          2
+    0x0070a4b2, // Cyan         3
+    0x006f3d86, // Purple       4
+    0x00588d43, // Green        5
+    0x00352879, // Blue         6
+    0x00b8c76f, // Yellow       7
+    0x006f4f25, // Orange       8
+    0x00433900, // Brown        9
+    0x009a6759, // Light red    a
+    0x00444444, // Dark grey    b
+    0x006c6c6c, // Grey         c
+    0x009ad284, // Light green  d
+    0x006c5eb5, // Light blue   e
+    0x00959595, // Light grey   f
 ];
 
 #[derive(Eq, PartialEq, Debug, Copy, Clone)]
@@ -41,6 +85,7 @@ enum VicState {
     MatrixRead,
 }
 
+#[derive(Clone)]
 pub struct Vic {
     // Output pins (active low)
     irq: bool,  // IRQ pin triggers interrupts in the CPU
@@ -66,7 +111,8 @@ pub struct Vic {
     sy7: u8,        // Sprite 7 y coord
     msbx: u8,       // MSBs of X coordinates
     cr1: u8,        // Control register 1
-    raster: u8,     // Raster counter
+    raster: u16,    // Raster counter (9 bits)
+    line_count: u16,// Number of raster lines per frame
     lpx: u8,        // Light pen x
     lpy: u8,        // Light pen y
     s_enable: u8,   // Sprite enabled
@@ -101,13 +147,31 @@ pub struct Vic {
     data_bus: u16,   // Data bus -- lower nybble of upper byte is for color ram
     matrix_pos: u16,// Current position in the video matrix
 
+    display: bool,  // Whether the VIC is in the display state
+    vc_base: u16,   // Video matrix offset of the current character row
+    rc: u8,         // Line within the current character row
+    fetch_matrix: bool,     // Whether the video matrix needs to be read on this line
+    row_started: bool,      // Whether the current line is a bad line that started a character row
+    line_rc: u8,            // Row counter before any bad line on the current line reset it
+    line_display: bool,     // Display state before any bad line on the current line set it
+    fetch_pending: bool,    // Whether the data for this line still needs to be read
+    video_line: [u8; MATRIX_COLUMNS],   // Screen codes read on the last bad line
+    color_line: [u8; MATRIX_COLUMNS],   // Color nybbles read on the last bad line
+    glyph_line: [u8; MATRIX_COLUMNS],   // Character data for the current line
+
     xpos: u8,       // X-position on the current raster line
     cycles: u64,    // Number of cycles since startup
-    raster_int: u8, // Value of raster to interrupt on
+    raster_int: u16,// Value of raster to interrupt on (9 bits)
+
+    width: u32,     // Size of the visible area drawn to the screen
+    height: u32,
+    test_pattern: bool, // Draw color bars instead of the video matrix
 }
 
 impl Vic {
-    pub fn new() -> Vic {
+    // Create a VIC that draws a visible area of the given size, starting at the top left of
+    // the border
+    pub fn new(width: u32, height: u32) -> Vic {
         Vic {
             irq: true,
             rdy: true,
@@ -132,6 +196,7 @@ impl Vic {
             msbx: 0,
             cr1: 0x1b,
             raster: 0,
+            line_count: Region::Pal.raster_lines(),
             lpx: 0,
             lpy: 0,
             s_enable: 0,
@@ -166,18 +231,32 @@ impl Vic {
             data_bus: 0u16,
             matrix_pos: 0u16,
 
+            display: false,
+            vc_base: 0,
+            rc: 0,
+            fetch_matrix: false,
+            row_started: false,
+            line_rc: 0,
+            line_display: false,
+            fetch_pending: false,
+            video_line: [0u8; MATRIX_COLUMNS],
+            color_line: [0u8; MATRIX_COLUMNS],
+            glyph_line: [0u8; MATRIX_COLUMNS],
+
             xpos: 0u8,
-            raster_int: 0xff,
+            raster_int: 0,
             cycles: 0u64,
+
+            width: width,
+            height: height,
+            test_pattern: false,
         }
     }
 
     // Translate a memory address to a register index
     fn translate_addr(&self, addr: usize) -> u8 {
-        if addr > MAX_CONTROL_ADDR || addr < MIN_CONTROL_ADDR {
-            panic!("Invalid address for VIC-II control register: ${:0>4X}", addr);
-        }
-        ((addr - MIN_CONTROL_ADDR) % CONTROL_REG_COUNT) as u8
+        // Only the low address lines are decoded, so anything else just hits a mirror
+        (addr.wrapping_sub(MIN_CONTROL_ADDR) % CONTROL_REG_COUNT) as u8
     }
 
     pub fn read_register(&self, addr: usize) -> u8 {
@@ -201,15 +280,20 @@ impl Vic {
             14 => self.sx7,
             15 => self.sy7,
             16 => self.msbx,
-            17 => self.cr1,
-            18 => self.raster,
+            // Bit 7 of CR1 is bit 8 of the raster counter
+            17 => self.cr1 | ((self.raster >> 1) & 0x80) as u8,
+            18 => (self.raster & 0xff) as u8,
             19 => self.lpx,
             20 => self.lpy,
             21 => self.s_enable,
             22 => self.cr2,
             23 => self.sye,
             24 => self.mem,
-            25 => self.int,
+            25 => {
+                // Bit 7 is set if any enabled interrupt is pending. Unused bits read as 1.
+                let pending = if self.irq { 0 } else { 0x80 };
+                self.int | pending | 0x70
+            },
             26 => self.int_enable,
             27 => self.s_priority,
             28 => self.s_multi,
@@ -256,16 +340,29 @@ impl Vic {
             14 => { self.sx7 = value; },
             15 => { self.sy7 = value; },
             16 => { self.msbx = value; },
-            17 => { self.cr1 = value | 0xc0; },
-            18 => { self.raster_int = value; },
+            17 => {
+                // Bit 7 is bit 8 of the raster compare value
+                self.cr1 = value & 0x7f;
+                self.raster_int = (self.raster_int & 0xff) | (((value & 0x80) as u16) << 1);
+                self.recheck_bad_line();
+            },
+            18 => { self.raster_int = (self.raster_int & 0x100) | value as u16; },
             19 => { self.lpx = value; },
             20 => { self.lpy = value; },
             21 => { self.s_enable = value; },
-            22 => { self.cr2 = value; },
+            // The top two bits aren't connected and read as 1
+            22 => { self.cr2 = value | 0xc0; },
             23 => { self.sye = value; },
             24 => { self.mem = value | 1; },
-            25 => { self.int = value | 0x70; },
-            26 => { self.int_enable = value | 0x70; },
+            25 => {
+                // Writing a 1 acknowledges an interrupt source
+                self.int &= !(value & INT_SOURCES);
+                self.update_irq();
+            },
+            26 => {
+                self.int_enable = value | 0x70;
+                self.update_irq();
+            },
             27 => { self.s_priority = value; },
             28 => { self.s_multi = value; },
             29 => { self.sxe = value; },
@@ -292,30 +389,144 @@ impl Vic {
 
     pub fn read_addr_bus(&self) -> u16 {
         // Only use the lower 14 bits of the address
-        self.addr_bus & 0x40
+        self.addr_bus & 0x3fff
     }
 
     // Calculate the current 14-bit video matrix address
     fn matrix_addr(&self) -> u16 {
-        let addr = ((self.mem & 0xf0) as u16) << 6;
-        addr + (self.matrix_pos & 0x3ff)
+        self.video_matrix_base() + (self.matrix_pos & 0x3ff)
+    }
+
+    // Get the 14-bit address of the start of the video matrix
+    pub fn video_matrix_base(&self) -> u16 {
+        ((self.mem & 0xf0) as u16) << 6
+    }
+
+    // Determine whether the character pointer selects the lower case half of a character set
+    pub fn lowercase_chars(&self) -> bool {
+        self.mem & 0x02 == 0x02
     }
 
     // Calculate a 14-bit character pointer address
     fn char_addr(&self, pointer: u8) -> u16 {
         let addr = ((self.mem & 0x0e) as u16) << 10;
         let addr = addr + ((pointer as u16) << 3);
-        addr + (self.raster % 8) as u16
+        addr + self.rc as u16
+    }
+
+    // Get the character a screen code points to. In ECM the top two bits select the background
+    // color instead, so only the first 64 characters can be used.
+    fn char_pointer(&self, code: u8) -> u8 {
+        if self.cr1 & CR1_ECM == CR1_ECM {
+            code & 0x3f
+        } else {
+            code
+        }
+    }
+
+    // Get the background color of a character cell
+    pub fn cell_background(&self, code: u8) -> u8 {
+        if self.cr1 & CR1_ECM != CR1_ECM {
+            return self.bg0;
+        }
+        match code >> 6 {
+            0 => self.bg0,
+            1 => self.bg1,
+            2 => self.bg2,
+            _ => self.bg3,
+        }
+    }
+
+    // Get the color of a pixel in the display window
+    fn window_color(&self, x: u16) -> u8 {
+        if self.test_pattern {
+            return self.test_pattern_color(x);
+        }
+        // ECM and BMM together are an invalid mode that only displays black
+        if self.cr1 & (CR1_ECM | CR1_BMM) == CR1_ECM | CR1_BMM {
+            return 0;
+        }
+        if !self.display {
+            // Whatever the idle fetch read is drawn in black over background 0
+            let bit = 0x80 >> ((x - WINDOW_LEFT_40_COLS) % 8);
+            return if self.read_data_bus() & bit != 0 { 0 } else { self.bg0 };
+        }
+
+        let col = ((x - WINDOW_LEFT_40_COLS) / 8) as usize;
+        let bit = 0x80 >> ((x - WINDOW_LEFT_40_COLS) % 8);
+        if self.glyph_line[col] & bit != 0 {
+            self.color_line[col]
+        } else {
+            self.cell_background(self.video_line[col])
+        }
+    }
+
+    // The test pattern is 16 vertical bars across the display window, one for each color starting
+    // with background color 0
+    fn test_pattern_color(&self, x: u16) -> u8 {
+        let (left, right, _, _) = self.display_window();
+        let bar = (x - left) * 16 / (right - left);
+        self.bg0.wrapping_add(bar as u8) & 0x0f
+    }
+
+    // Get the edges of the display window as (left, right, top, bottom)
+    pub fn display_window(&self) -> (u16, u16, u16, u16) {
+        // CSEL selects 38 or 40 columns
+        let (left, right) = if self.cr2 & 0x08 == 0x08 {
+            (WINDOW_LEFT_40_COLS, WINDOW_RIGHT_40_COLS)
+        } else {
+            (WINDOW_LEFT_38_COLS, WINDOW_RIGHT_38_COLS)
+        };
+        // RSEL selects 24 or 25 rows
+        let (top, bottom) = if self.cr1 & 0x08 == 0x08 {
+            (WINDOW_TOP_25_ROWS, WINDOW_BOTTOM_25_ROWS)
+        } else {
+            (WINDOW_TOP_24_ROWS, WINDOW_BOTTOM_24_ROWS)
+        };
+        (left, right, top, bottom)
+    }
+
+    // Draw the eight pixels output during the current cycle
+    fn draw_pixels(&self, screen: &mut Screen) {
+        let line = self.raster;
+        if line < FIRST_VISIBLE_LINE || (line - FIRST_VISIBLE_LINE) as u32 >= screen.height() {
+            return;
+        }
+        let y = (line - FIRST_VISIBLE_LINE) as usize;
+
+        let (left, right, top, bottom) = self.display_window();
+        for i in 0..8 {
+            let x = (self.xpos as u16) * 8 + i;
+            if x < FIRST_VISIBLE_X {
+                continue;
+            } else if (x - FIRST_VISIBLE_X) as u32 >= screen.width() {
+                break;
+            }
+
+            // Everything outside the display window is border
+            let color = if x < left || x >= right || line < top || line >= bottom {
+                self.border
+            } else {
+                self.window_color(x)
+            };
+            screen.set_pixel_at((x - FIRST_VISIBLE_X) as usize, y, COLOR[(color & 0x0f) as usize]);
+        }
     }
 
     pub fn rising_edge(&mut self, screen: &mut Screen, debug: bool) {
         use self::VicState::*;
 
-        self.aec = false;
+        // The test pattern doesn't need anything from memory
+        if self.test_pattern {
+            self.advance_beam();
+            self.draw_pixels(screen);
+            self.cycles = self.cycles.wrapping_add(1);
+            return;
+        }
 
         match self.state {
             Idle => {
-                self.state = MatrixRead;
+                self.addr_bus = self.idle_addr();
             },
             MatrixRead => {
                 self.addr_bus = self.matrix_addr();
@@ -323,21 +534,187 @@ impl Vic {
             },
         }
 
-        if self.raster == self.raster_int {
-            // Do interrupt
+        if self.advance_beam() {
+            self.start_line();
         }
+        self.draw_pixels(screen);
+
+        self.update_bus_signals();
+        self.cycles = self.cycles.wrapping_add(1);
+    }
+
+    // Move on to the next cycle and check for a raster interrupt. Returns true at the start of a
+    // new line.
+    fn advance_beam(&mut self) -> bool {
         self.xpos = self.xpos.wrapping_add(1);
-        if self.xpos == HORZ_CYCLE_COUNT {
+        let new_line = self.xpos == HORZ_CYCLE_COUNT;
+        if new_line {
             self.xpos = 0;
-            self.raster = self.raster.wrapping_add(1);
+            self.raster += 1;
+            if self.raster >= self.line_count {
+                self.raster = 0;
+            }
         }
-        if self.raster > 100 {
-            self.raster = 0;
+        if self.xpos == 0 && self.raster == self.raster_int {
+            self.int |= INT_RASTER;
+            self.update_irq();
         }
-        screen.set_pixel_at(self.xpos as usize, self.raster as usize, COLOR[6]);
+        new_line
+    }
 
-        self.aec = true;
-        self.cycles = self.cycles.wrapping_add(1);
+    // Advance the row counter at the start of a line and start a new character row on bad lines
+    fn start_line(&mut self) {
+        if self.raster == 0 {
+            self.vc_base = 0;
+            self.display = false;
+        } else if self.display {
+            if self.rc == 7 {
+                self.vc_base += MATRIX_COLUMNS as u16;
+                self.display = false;
+            } else {
+                self.rc += 1;
+            }
+        }
+        self.line_rc = self.rc;
+        self.line_display = self.display;
+        self.row_started = self.bad_line();
+        self.enter_row_state();
+    }
+
+    // Start a new character row if this is a bad line and get ready to read the line's data
+    fn enter_row_state(&mut self) {
+        self.fetch_matrix = self.row_started;
+        if self.row_started {
+            self.display = true;
+            self.rc = 0;
+        } else {
+            self.display = self.line_display;
+            self.rc = self.line_rc;
+        }
+        self.fetch_pending = self.display;
+
+        if self.display {
+            self.state = VicState::MatrixRead;
+            self.matrix_pos = self.vc_base;
+        } else {
+            self.state = VicState::Idle;
+        }
+    }
+
+    // YSCROLL and DEN take effect straight away, so writing CR1 partway through a line can make
+    // it a bad line or stop it being one. This is what FLD and VSP rely on. A row can start any
+    // time before the matrix fetch would end, but can only be cancelled before BA goes low.
+    fn recheck_bad_line(&mut self) {
+        let bad_line = self.bad_line();
+        let start = bad_line && !self.row_started && self.xpos <= BAD_LINE_FETCH_END;
+        let cancel = !bad_line && self.row_started && self.xpos < BAD_LINE_BA_START;
+        if start || cancel {
+            self.row_started = start;
+            self.enter_row_state();
+        }
+    }
+
+    // Get the address the VIC reads from when it has nothing to fetch. ECM holds address lines 9
+    // and 10 low.
+    fn idle_addr(&self) -> u16 {
+        if self.cr1 & CR1_ECM == CR1_ECM {
+            0x39ff
+        } else {
+            0x3fff
+        }
+    }
+
+    // Determine whether the VIC needs memory to be read for the current line
+    pub fn line_fetch_pending(&self) -> bool {
+        self.fetch_pending
+    }
+
+    // Read the screen codes and colors for the character row on a bad line and the character data
+    // for every displayed line. The read function takes a 14-bit address and returns the byte on
+    // the data bus. The color function takes a position in the video matrix and returns the color
+    // RAM nybble for that cell.
+    pub fn fetch_line<F, G>(&mut self, read: F, color: G)
+        where F: Fn(u16) -> u8, G: Fn(u16) -> u8 {
+        if self.fetch_matrix {
+            let base = self.video_matrix_base() + self.vc_base;
+            for i in 0..MATRIX_COLUMNS {
+                let pos = self.vc_base + i as u16;
+                self.video_line[i] = read(base + i as u16);
+                self.color_line[i] = color(pos & 0x3ff) & 0x0f;
+            }
+            self.fetch_matrix = false;
+        }
+        for i in 0..MATRIX_COLUMNS {
+            let pointer = self.char_pointer(self.video_line[i]);
+            self.glyph_line[i] = read(self.char_addr(pointer));
+        }
+        self.fetch_pending = false;
+    }
+
+    // Pull IRQ low while any enabled interrupt source is pending
+    fn update_irq(&mut self) {
+        self.irq = self.int & self.int_enable & INT_SOURCES == 0;
+    }
+
+    // Determine whether the VIC needs to fetch the video matrix on the current line
+    fn bad_line(&self) -> bool {
+        self.raster >= FIRST_DMA_LINE && self.raster <= LAST_DMA_LINE &&
+            (self.raster & 0x07) as u8 == (self.cr1 & 0x07) &&
+            self.cr1 & 0x10 == 0x10
+    }
+
+    // Get the y coordinate of a sprite
+    fn sprite_y(&self, sprite: u8) -> u8 {
+        match sprite {
+            0 => self.sy0,
+            1 => self.sy1,
+            2 => self.sy2,
+            3 => self.sy3,
+            4 => self.sy4,
+            5 => self.sy5,
+            6 => self.sy6,
+            _ => self.sy7,
+        }
+    }
+
+    // Determine whether a sprite needs DMA to be displayed on a raster line
+    fn sprite_dma_line(&self, sprite: u8, line: u16) -> bool {
+        let top = self.sprite_y(sprite) as u16;
+        let height = if self.sye & (1 << sprite) != 0 { SPRITE_HEIGHT * 2 } else { SPRITE_HEIGHT };
+        self.s_enable & (1 << sprite) != 0 && line >= top && line < top + height
+    }
+
+    // Determine whether sprite DMA is pulling BA low and whether it has the bus this cycle
+    fn sprite_dma(&self) -> (bool, bool) {
+        let mut ba = false;
+        let mut fetch = false;
+        for sprite in 0..8u8 {
+            let start = SPRITE_DMA_FIRST_OFFSET + 2 * (sprite as i16);
+            // The current cycle is either near the start of this line's fetches or the end of
+            // the previous line's fetches for the next line
+            let candidates = [
+                (self.raster, self.xpos as i16),
+                (self.raster + 1, self.xpos as i16 - HORZ_CYCLE_COUNT as i16),
+            ];
+            for &(line, offset) in candidates.iter() {
+                if offset < start - SPRITE_DMA_BA_LEAD || offset >= start + SPRITE_DMA_CYCLES {
+                    continue;
+                }
+                if self.sprite_dma_line(sprite, line) {
+                    ba = true;
+                    fetch = fetch || offset >= start;
+                }
+            }
+        }
+        (ba, fetch)
+    }
+
+    // Set BA (which drives the CPU's RDY pin) and AEC for the current cycle
+    fn update_bus_signals(&mut self) {
+        let bad_line = self.bad_line();
+        let (sprite_ba, sprite_fetch) = self.sprite_dma();
+        self.rdy = !(sprite_ba || (bad_line && self.xpos >= BAD_LINE_BA_START && self.xpos <= BAD_LINE_FETCH_END));
+        self.aec = !(sprite_fetch || (bad_line && self.xpos >= BAD_LINE_FETCH_START && self.xpos <= BAD_LINE_FETCH_END));
     }
 
     pub fn falling_edge(&mut self, screen: &mut Screen, debug: bool) {
@@ -347,22 +724,23 @@ impl Vic {
     // Write a color nybble to the data bus
     pub fn color_in(&mut self, byte: u8) {
         self.data_bus &= 0x00ff;
-        self.data_bus &= ((byte as u16) & 0x0f) << 8;
+        self.data_bus |= ((byte as u16) & 0x0f) << 8;
     }
 
     // Write a byte to the data bus
     pub fn data_in(&mut self, byte: u8) {
         self.data_bus &= 0x0f00;
-        self.data_bus &= byte as u16;
+        self.data_bus |= byte as u16;
     }
 
     // Read the color nybble of the data bus
-    fn read_color_nybble(&self) -> u8 {
+    #[cfg(test)]
+    pub fn read_color_nybble(&self) -> u8 {
         ((self.data_bus & 0x0f00) >> 8) as u8
     }
 
     // Read the low byte of the data bus
-    fn read_data_bus(&self) -> u8 {
+    pub fn read_data_bus(&self) -> u8 {
         (self.data_bus & 0xff) as u8
     }
 
@@ -370,6 +748,42 @@ impl Vic {
         self.xpos == 0 && self.raster == 0
     }
 
+    // Number of cycles since startup
+    #[cfg(test)]
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Draw a test pattern that only depends on the registers instead of real video. The VIC stops
+    // fetching from memory and never takes the bus from the CPU.
+    pub fn set_test_pattern(&mut self, test_pattern: bool) {
+        self.test_pattern = test_pattern;
+        self.rdy = true;
+        self.aec = true;
+    }
+
+    // Size of the screen the VIC draws to
+    pub fn screen_size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    // The full 9-bit raster line
+    pub fn current_raster(&self) -> u16 {
+        self.raster
+    }
+
+    // Set the number of raster lines per frame
+    pub fn set_region(&mut self, region: Region) {
+        self.line_count = region.raster_lines();
+        self.raster %= self.line_count;
+    }
+
+    // Jump to a raster line without drawing the lines in between
+    #[cfg(test)]
+    pub fn set_raster(&mut self, line: u16) {
+        self.raster = line % self.line_count;
+    }
+
     pub fn irq(&self) -> bool {
         self.irq
     }
@@ -383,10 +797,25 @@ impl Vic {
     }
 }
 
+// The 16 colors the VIC-II can draw, packed as 0x00RRGGBB
+pub fn palette() -> [u32; 16] {
+    COLOR
+}
+
+// The 40 column, 25 row display window in screen coordinates as x, y, width, height
+pub fn display_area() -> (u32, u32, u32, u32) {
+    (
+        (WINDOW_LEFT_40_COLS - FIRST_VISIBLE_X) as u32,
+        (WINDOW_TOP_25_ROWS - FIRST_VISIBLE_LINE) as u32,
+        (WINDOW_RIGHT_40_COLS - WINDOW_LEFT_40_COLS) as u32,
+        (WINDOW_BOTTOM_25_ROWS - WINDOW_TOP_25_ROWS) as u32,
+    )
+}
+
 impl fmt::Debug for Vic {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f,
-               "  Cycle {:0>5} :: AB: ${:0>4X} // DB: ${:0>3X} // X: ${:0>2X} // Raster: ${:0>2X} // S: {:?}",
+               "  Cycle {:0>5} :: AB: ${:0>4X} // DB: ${:0>3X} // X: ${:0>2X} // Raster: ${:0>3X} // S: {:?}",
                self.cycles, self.addr_bus, self.data_bus, self.xpos, self.raster, self.state
                )
     }
@@ -4,30 +4,65 @@
 mod cpu;
 mod bus;
 mod io;
+mod symbols;
+mod programs;
+mod overlay;
+mod rng;
+mod pacer;
+mod png;
 
-use bus::Bus;
+#[cfg(test)]
+mod test_mod;
+
+use bus::{Bus, IoDevice, io_device_named};
+use io::Region;
 
 extern crate sdl2;
 use sdl2::video::WindowBuilder;
 use sdl2::surface::Surface;
 use sdl2::pixels::PixelFormatEnum;
-use sdl2::event::Event;
+use sdl2::rect::Rect;
+use sdl2::event::{Event, WindowEventId};
 use sdl2::keyboard::{Keycode, Mod};
+use sdl2::mouse::Mouse as MouseButton;
 
 extern crate getopts;
 use getopts::Options;
 use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::panic;
 
 use std::thread;
 use std::sync::mpsc;
-use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::{Sender, Receiver, TryRecvError};
+use std::time::Duration;
 
 const SCREEN_X:u32 = 320;
 const SCREEN_Y:u32 = 240;
 
-const RAM_IMAGE_FILE: &'static str = "src/ram-default-image.bin";
+// Where --io-trace writes by default
+const IO_TRACE_FILE: &'static str = "io-trace.log";
+
+// Where the machine state goes if the emulator crashes
+const CRASH_REPORT_FILE: &'static str = "crash-report.txt";
+
+// Host key for the freeze button
+const FREEZE_KEY: Keycode = Keycode::F12;
+
+// Host key that shows or hides the frame rate
+const FPS_OVERLAY_KEY: Keycode = Keycode::F11;
+
+// How often the window checks for events while the emulator is paused
+const PAUSE_POLL_MS: u64 = 10;
 
-const ROM_DIR: &'static str = ".vice/c64";
+// How far a joystick axis has to move from the center to count as pushed in that direction
+const JOYSTICK_DEAD_ZONE: i16 = 0x4000;
+
+// ROMs are looked for in --firmware-dir, then $C64_ROM_DIR, then these directories in $HOME
+const ROM_DIR_VAR: &'static str = "C64_ROM_DIR";
+const LOCAL_ROM_DIR: &'static str = ".local/share/rust-c64";
+const VICE_ROM_DIR: &'static str = ".vice/c64";
 const KERNAL_ROM_FILE: &'static str = "kernal";
 const BASIC_ROM_FILE: &'static str = "basic";
 const CHAR_ROM_FILE: &'static str = "chargen";
@@ -40,35 +75,64 @@ const PAL_CLK: u32 = 985248444;
 pub struct Screen {
     width: u32,
     height: u32,
-    pixels: Vec<(u8, u8, u8)>,
+    pixels: Vec<u32>,   // Packed 0x00RRGGBB
 }
 
 impl Screen {
     pub fn new(w: u32, h: u32) -> Screen {
-        let mut p: Vec<(u8, u8, u8)> = Vec::with_capacity((w*h) as usize);
-        for _ in 0..w*h {
-            p.push((0, 0, 0));
-        }
-
         Screen {
             width: w,
             height: h,
-            pixels: p,
+            pixels: vec![0u32; (w*h) as usize],
         }
     }
 
-    pub fn set_pixel_at(&mut self, x: usize, y: usize, pixel: (u8, u8, u8)) {
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn set_pixel_at(&mut self, x: usize, y: usize, pixel: u32) {
         let index = y*(self.width as usize) + x;
         self.pixels[index] = pixel;
     }
 
-    pub fn pixel_data(&self) -> Vec<u8> {
-        // Convert pixel data to surface data
+    pub fn pixel_at(&self, x: usize, y: usize) -> u32 {
+        self.pixels[y*(self.width as usize) + x]
+    }
+
+    // Convert pixel data to 3 bytes per pixel in R, G, B order
+    pub fn rgb24_data(&self) -> Vec<u8> {
         let mut data: Vec<u8> = Vec::with_capacity(self.pixels.len() * 3);
-        for i in 0..self.pixels.len() {
-            data.push(self.pixels[i].0);
-            data.push(self.pixels[i].1);
-            data.push(self.pixels[i].2);
+        for p in &self.pixels {
+            data.push((p >> 16) as u8);
+            data.push((p >> 8) as u8);
+            data.push(*p as u8);
+        }
+        data
+    }
+
+    // Get the index of each pixel's color in a palette. Fails if a pixel isn't in the palette.
+    pub fn palette_indices(&self, palette: &[u32]) -> Result<Vec<u8>, String> {
+        self.pixels.iter().map(|p| {
+            match palette.iter().position(|c| c == p) {
+                Some(i) => Ok(i as u8),
+                None => Err(format!("Color {:0>6X} isn't in the palette", p)),
+            }
+        }).collect()
+    }
+
+    // Convert pixel data to 4 bytes per pixel in R, G, B, A order
+    pub fn rgba8888_data(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::with_capacity(self.pixels.len() * 4);
+        for p in &self.pixels {
+            data.push((p >> 16) as u8);
+            data.push((p >> 8) as u8);
+            data.push(*p as u8);
+            data.push(0xff);
         }
         data
     }
@@ -76,7 +140,90 @@ impl Screen {
 
 pub enum EmulatorEvent {
     Quit,
-    Key(Keycode, Mod),
+    Freeze,
+    ToggleFpsOverlay,
+    Pause,
+    Resume,
+    Key(Keycode, Mod, bool),    // True if the key was pressed, false if released
+    Paddles(u8, u8),            // New X and Y paddle positions
+    Mouse(i32, i32, bool, bool),    // Mouse movement and whether the left and right buttons are down
+    Joystick(u8),               // Lines joystick 2 pulls low. Bits are 0 for lines that are pulled low.
+}
+
+impl EmulatorEvent {
+    // Format an event as a line in an event log
+    pub fn to_log_line(&self, cycle: u64) -> String {
+        match *self {
+            EmulatorEvent::Quit => format!("{} quit", cycle),
+            EmulatorEvent::Freeze => format!("{} freeze", cycle),
+            EmulatorEvent::ToggleFpsOverlay => format!("{} fps", cycle),
+            EmulatorEvent::Pause => format!("{} pause", cycle),
+            EmulatorEvent::Resume => format!("{} resume", cycle),
+            EmulatorEvent::Paddles(x, y) => format!("{} paddles {} {}", cycle, x, y),
+            EmulatorEvent::Mouse(dx, dy, left, right) => {
+                format!("{} mouse {} {} {} {}", cycle, dx, dy, left as u8, right as u8)
+            },
+            EmulatorEvent::Joystick(lines) => format!("{} joystick {}", cycle, lines),
+            EmulatorEvent::Key(keycode, m, pressed) => {
+                let action = if pressed { "keydown" } else { "keyup" };
+                format!("{} {} {} {}", cycle, action, m.bits(), keycode.name())
+            },
+        }
+    }
+
+    // Parse a line from an event log into the cycle it happened on and the event
+    pub fn from_log_line(line: &str) -> Option<(u64, EmulatorEvent)> {
+        let mut fields = line.trim().split(' ');
+        let cycle = fields.next().and_then(|c| c.parse::<u64>().ok())?;
+
+        let pressed = match fields.next() {
+            Some("quit") => return Some((cycle, EmulatorEvent::Quit)),
+            Some("freeze") => return Some((cycle, EmulatorEvent::Freeze)),
+            Some("fps") => return Some((cycle, EmulatorEvent::ToggleFpsOverlay)),
+            Some("pause") => return Some((cycle, EmulatorEvent::Pause)),
+            Some("resume") => return Some((cycle, EmulatorEvent::Resume)),
+            Some("mouse") => {
+                let button = |b: &str| match b {
+                    "0" => Some(false),
+                    "1" => Some(true),
+                    _ => None,
+                };
+                let dx = fields.next().and_then(|d| d.parse::<i32>().ok());
+                let dy = fields.next().and_then(|d| d.parse::<i32>().ok());
+                let left = fields.next().and_then(button);
+                let right = fields.next().and_then(button);
+                return match (dx, dy, left, right) {
+                    (Some(dx), Some(dy), Some(l), Some(r)) => {
+                        Some((cycle, EmulatorEvent::Mouse(dx, dy, l, r)))
+                    },
+                    _ => None,
+                };
+            },
+            Some("paddles") => {
+                let x = fields.next().and_then(|x| x.parse::<u8>().ok());
+                let y = fields.next().and_then(|y| y.parse::<u8>().ok());
+                return match (x, y) {
+                    (Some(x), Some(y)) => Some((cycle, EmulatorEvent::Paddles(x, y))),
+                    _ => None,
+                };
+            },
+            Some("joystick") => {
+                let lines = fields.next().and_then(|l| l.parse::<u8>().ok())?;
+                return Some((cycle, EmulatorEvent::Joystick(lines)));
+            },
+            Some("keydown") => true,
+            Some("keyup") => false,
+            _ => return None,
+        };
+
+        // Key names can contain spaces so the name is the rest of the line
+        let m = fields.next().and_then(|m| m.parse::<u32>().ok()).and_then(Mod::from_bits);
+        let keycode = Keycode::from_name(&fields.collect::<Vec<&str>>().join(" "));
+        match (keycode, m) {
+            (Some(k), Some(m)) => Some((cycle, EmulatorEvent::Key(k, m, pressed))),
+            _ => None,
+        }
+    }
 }
 
 struct C64 {
@@ -84,6 +231,8 @@ struct C64 {
     kernal_rom_file: String,
     basic_rom_file: String,
     char_rom_file: String,
+    symbol_file: String,
+    crash_report_file: String,
 
     clock: u32,
     bus: Bus,
@@ -96,21 +245,25 @@ impl C64 {
             kernal_rom_file: String::new(),
             basic_rom_file: String::new(),
             char_rom_file: String::new(),
+            symbol_file: String::new(),
+            crash_report_file: CRASH_REPORT_FILE.to_string(),
 
             clock: 0,
-            bus: Bus::new(debug),
+            bus: Bus::new(debug, SCREEN_X, SCREEN_Y),
         }
     }
 
     pub fn new_ntsc(debug: bool) -> C64 {
         let mut c = C64::new(debug);
         c.clock = NTSC_CLK;
+        c.bus.set_region(Region::Ntsc);
         c
     }
 
     pub fn new_pal(debug: bool) -> C64 {
         let mut c = C64::new(debug);
         c.clock = PAL_CLK;
+        c.bus.set_region(Region::Pal);
         c
     }
 
@@ -130,10 +283,143 @@ impl C64 {
         self.char_rom_file = fname.to_string();
     }
 
-    pub fn run(&mut self, screen_tx: Sender<Screen>, event_rx: Receiver<EmulatorEvent>) {
-        self.bus.initialize(&self.ram_image_file);
+    pub fn set_cycle_exact(&mut self, cycle_exact: bool) {
+        self.bus.set_cycle_exact(cycle_exact);
+    }
+
+    pub fn set_fast_boot(&mut self, fast_boot: bool) {
+        self.bus.set_fast_boot(fast_boot);
+    }
+
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.bus.set_quiet(quiet);
+    }
+
+    pub fn set_idle_sleep(&mut self, idle_sleep: bool) {
+        self.bus.set_idle_sleep(idle_sleep);
+    }
+
+    pub fn set_jiffy_irq(&mut self, jiffy_irq: bool) {
+        self.bus.set_jiffy_irq(jiffy_irq);
+    }
+
+    pub fn set_host_dir(&mut self, dir: &str) {
+        self.bus.set_host_dir(dir);
+    }
+
+    pub fn set_lax_magic(&mut self, magic: u8) {
+        self.bus.set_lax_magic(magic);
+    }
+
+    pub fn set_strict_opcodes(&mut self, strict: bool) {
+        self.bus.set_strict_opcodes(strict);
+    }
+
+    pub fn set_mouse(&mut self, mouse: bool) {
+        self.bus.set_mouse(mouse);
+    }
+
+    pub fn set_parallel_input(&mut self, fname: &str) -> Result<(), String> {
+        self.bus.set_parallel_input(fname)
+    }
+
+    pub fn set_vic_test_pattern(&mut self, test_pattern: bool) {
+        self.bus.set_vic_test_pattern(test_pattern);
+    }
+
+    pub fn mute_voice(&mut self, voice: u8) {
+        self.bus.set_voice_muted(voice, true);
+    }
+
+    pub fn type_text(&mut self, text: &str) {
+        self.bus.inject_text(text);
+    }
+
+    pub fn set_type_interval(&mut self, cycles: u32) {
+        self.bus.set_inject_interval(cycles);
+    }
+
+    pub fn set_record_file(&mut self, fname: &str) -> std::io::Result<()> {
+        self.bus.record_events(fname)
+    }
+
+    pub fn set_playback_file(&mut self, fname: &str) -> Result<(), String> {
+        self.bus.load_playback(fname)
+    }
+
+    pub fn set_symbol_file(&mut self, fname: &str) {
+        self.symbol_file = fname.to_string();
+    }
+
+    pub fn add_exit_dump(&mut self, addr: u16, len: usize, fname: &str) {
+        self.bus.add_exit_dump(addr, len, fname);
+    }
+
+    pub fn set_video_out(&mut self, fname: &str) -> std::io::Result<()> {
+        self.bus.set_video_out(fname)
+    }
+
+    pub fn set_audio_out(&mut self, fname: &str) -> std::io::Result<()> {
+        self.bus.set_audio_out(fname)
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.bus.set_seed(seed);
+    }
+
+    pub fn set_stereo_sid(&mut self, addr: usize) -> Result<(), String> {
+        self.bus.set_stereo_sid(addr)
+    }
+
+    pub fn set_georam(&mut self, size_kb: usize) -> Result<(), String> {
+        self.bus.set_georam(size_kb)
+    }
+
+    pub fn set_max_instructions(&mut self, max: u64) {
+        self.bus.set_max_instructions(max);
+    }
+
+    pub fn set_map_file(&mut self, fname: &str) {
+        self.bus.set_exec_map_file(fname);
+    }
+
+    pub fn set_io_trace(&mut self, devices: &[IoDevice], fname: &str) -> std::io::Result<()> {
+        self.bus.set_io_trace(devices, fname)
+    }
+
+    pub fn set_kernal_log(&mut self, fname: &str) -> std::io::Result<()> {
+        self.bus.set_kernal_log(fname)
+    }
+
+    pub fn set_crash_report_file(&mut self, fname: &str) {
+        self.crash_report_file = fname.to_string();
+    }
+
+    pub fn set_break_at(&mut self, addr: u16) {
+        self.bus.add_breakpoint(addr, true);
+    }
+
+    pub fn set_monitor_script(&mut self, fname: &str) {
+        self.bus.set_monitor_script(fname);
+    }
+
+    // Set up RAM, the ROMs, and the symbol file the way the machine starts
+    fn power_on(&mut self) {
+        if self.ram_image_file.is_empty() {
+            self.bus.initialize(None);
+        } else {
+            self.bus.initialize(Some(&self.ram_image_file));
+        }
         self.bus.load_roms(&self.kernal_rom_file, &self.basic_rom_file, &self.char_rom_file);
-        self.bus.run(self.clock, screen_tx, event_rx);
+        if !self.symbol_file.is_empty() {
+            self.bus.load_symbols(&self.symbol_file);
+        }
+    }
+
+    pub fn run(&mut self, screen_tx: Sender<Screen>, event_rx: Receiver<EmulatorEvent>) {
+        self.power_on();
+        let clock = self.clock;
+        self.bus.catch_crash(&self.crash_report_file, |bus| bus.run(clock, screen_tx, event_rx));
     }
 }
 
@@ -142,6 +428,167 @@ fn print_usage(pname: &str, opts: Options) {
     print!("{}", opts.usage(&brief));
 }
 
+// Print the CPU's opcode table and how much of it works
+fn list_opcodes() {
+    // Unimplemented opcodes panic, which is expected here
+    panic::set_hook(Box::new(|_| { }));
+    let table = cpu::opcode_table();
+    let _ = panic::take_hook();
+
+    // Cycle counts come from running each opcode, so they show the emulator's timing, which
+    // doesn't match the documented timing for every instruction yet
+    println!("Code  Instr  Mode                      Measured cycles");
+    for op in &table {
+        let cycles = op.cycles.map(|c| c.to_string()).unwrap_or_else(|| "-".to_string());
+        let mark = if op.documented { "" } else { "  (undocumented)" };
+        println!("${:0>2X}   {}    {:<24}  {:>15}{}", op.code, op.mnemonic,
+                 op.addr_mode.as_deref().unwrap_or("?"), cycles, mark);
+    }
+
+    let documented = table.iter().filter(|op| op.documented).count();
+    let count = |documented: bool| {
+        table.iter().filter(|op| op.documented == documented && op.cycles.is_some()).count()
+    };
+    println!("{} of {} documented opcodes implemented", count(true), documented);
+    println!("{} of {} undocumented opcodes implemented", count(false), table.len() - documented);
+}
+
+// Parse a memory dump of the form ADDR:LEN:FILE. The address is in hex and the length is decimal
+// unless it starts with '$'.
+fn parse_dump_spec(spec: &str) -> Option<(u16, usize, String)> {
+    let mut fields = spec.splitn(3, ':');
+    let addr = fields.next().and_then(|a| {
+        u16::from_str_radix(a.trim_start_matches('$'), 16).ok()
+    });
+    let len = fields.next().and_then(|l| {
+        match l.strip_prefix('$') {
+            Some(hex) => usize::from_str_radix(hex, 16).ok(),
+            None => l.parse::<usize>().ok(),
+        }
+    });
+    match (addr, len, fields.next()) {
+        (Some(a), Some(l), Some(f)) if !f.is_empty() => Some((a, l, f.to_string())),
+        _ => None,
+    }
+}
+
+// Parse an address or byte from the command line the same way the monitor does
+fn parse_number(s: &str) -> Result<u16, String> {
+    symbols::parse_addr(s, &symbols::SymbolTable::new())
+}
+
+// Parse a comma-separated list of I/O device names. "all" is every device.
+fn parse_io_devices(list: &str) -> Option<Vec<IoDevice>> {
+    if list == "all" {
+        return Some(vec![IoDevice::Vic, IoDevice::Sid, IoDevice::StereoSid, IoDevice::ColorRam,
+                         IoDevice::Cia1, IoDevice::Cia2, IoDevice::Expansion]);
+    }
+    list.split(',').map(|name| io_device_named(name.trim())).collect()
+}
+
+// Host input that drives the paddles
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum PaddleInput {
+    Mouse,      // Pointer position across the window
+    Joystick,   // First two axes of the first joystick or gamepad
+}
+
+fn parse_paddle_input(name: &str) -> Option<PaddleInput> {
+    match name {
+        "mouse" => Some(PaddleInput::Mouse),
+        "joystick" => Some(PaddleInput::Joystick),
+        _ => None,
+    }
+}
+
+// Map a mouse position in a window to paddle positions. The left and top edges are 0.
+fn mouse_paddles(x: i32, y: i32, width: u32, height: u32) -> (u8, u8) {
+    let scale = |pos: i32, size: u32| {
+        let size = size.max(2) as i32;
+        (pos.max(0).min(size - 1) * 255 / (size - 1)) as u8
+    };
+    (scale(x, width), scale(y, height))
+}
+
+// Map a joystick axis to a paddle position. All the way left or up is 0.
+fn axis_paddle(value: i16) -> u8 {
+    ((value as i32 + 0x8000) >> 8) as u8
+}
+
+// Map the first two axes and the fire button of a host joystick to the lines a C64 joystick pulls
+// low: up, down, left, right, and fire from bit 0 up.
+fn joystick_lines(x: i16, y: i16, fire: bool) -> u8 {
+    let mut lines = 0xff;
+    if y < -JOYSTICK_DEAD_ZONE {
+        lines &= !0x01;
+    }
+    if y > JOYSTICK_DEAD_ZONE {
+        lines &= !0x02;
+    }
+    if x < -JOYSTICK_DEAD_ZONE {
+        lines &= !0x04;
+    }
+    if x > JOYSTICK_DEAD_ZONE {
+        lines &= !0x08;
+    }
+    if fire {
+        lines &= !0x10;
+    }
+    lines
+}
+
+// Get the event that pauses or resumes the emulator when the window loses or gains focus
+fn focus_event(event: &Event) -> Option<EmulatorEvent> {
+    match *event {
+        Event::Window {win_event_id: WindowEventId::FocusLost, ..} => Some(EmulatorEvent::Pause),
+        Event::Window {win_event_id: WindowEventId::FocusGained, ..} => Some(EmulatorEvent::Resume),
+        _ => None,
+    }
+}
+
+// Get the part of the screen to show when the border is cropped off as x, y, width, height. This
+// is the display window clipped to the screen.
+fn crop_area(width: u32, height: u32) -> (u32, u32, u32, u32) {
+    let (x, y, w, h) = io::vic::display_area();
+    let x = x.min(width);
+    let y = y.min(height);
+    (x, y, w.min(width - x), h.min(height - y))
+}
+
+// Get the directories to look for ROM files in, in order
+fn rom_dirs(firmware_dir: Option<String>) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(d) = firmware_dir {
+        dirs.push(PathBuf::from(d));
+    }
+    if let Ok(d) = env::var(ROM_DIR_VAR) {
+        dirs.push(PathBuf::from(d));
+    }
+    if let Ok(home) = env::var("HOME") {
+        let home = PathBuf::from(home);
+        dirs.push(home.join(LOCAL_ROM_DIR));
+        dirs.push(home.join(VICE_ROM_DIR));
+    }
+    dirs
+}
+
+// Find a ROM file in the first directory that has it. If none do, use the last directory so the
+// error when it fails to load points somewhere sensible.
+fn find_rom(name: &str, dirs: &[PathBuf]) -> String {
+    let path = match dirs.iter().map(|d| d.join(name)).find(|p| p.is_file()) {
+        Some(p) => p,
+        None => dirs.last().map(|d| d.join(name)).unwrap_or_else(|| PathBuf::from(name)),
+    };
+    path.to_string_lossy().into_owned()
+}
+
+// Report a problem with the command line arguments and exit
+fn arg_error(pname: &str, msg: &str) -> ! {
+    eprintln!("{}: {}", pname, msg);
+    eprintln!("See --help for options");
+    process::exit(1);
+}
+
 fn main() {
     // Read and parse command line arguments
     let args: Vec<String> = env::args().collect();
@@ -152,21 +599,66 @@ fn main() {
     opts.optopt("k", "kernal", "Location of the KERNAL ROM file.", "FILE");
     opts.optopt("b", "basic", "Location of the BASIC ROM file.", "FILE");
     opts.optopt("r", "char", "Location of the charater ROM file.", "FILE");
+    opts.optopt("", "firmware-dir", "Directory to look for the kernal, basic, and chargen ROM files in.", "DIR");
+    opts.optopt("", "record", "Record input events to a file for later playback.", "FILE");
+    opts.optopt("", "playback", "Replay input events from a file made with --record.", "FILE");
+
+    opts.optopt("", "symbols", "Load labels for the debugger from a VICE label file.", "FILE");
+    opts.optopt("", "break-at", "Run until the CPU reaches an address, then start the debugger. Addresses are decimal or hex with a '$' prefix.", "ADDR");
+    opts.optopt("", "monitor-script", "Start the debugger and run the monitor commands in a file. End it with g to keep running.", "FILE");
 
+    opts.optmulti("", "dump-on-exit", "Write memory to a file on exit. Can be given more than once.", "ADDR:LEN:FILE");
+    opts.optflag("", "crop", "Only show the 320x200 display window, not the border.");
+    opts.optflag("", "test-pattern", "Have the VIC-II draw color bars from the border and background colors instead of video.");
+    opts.optopt("", "video-out", "Write each frame to a file or pipe as raw 320x240 RGB24 video.", "FILE");
+    opts.optopt("", "audio-out", "Write the SID output to a file or pipe as raw signed 16-bit stereo samples at 44.1 kHz. A second SID is on the right channel.", "FILE");
+    opts.optopt("", "io-trace", "Log writes to the registers of these I/O devices: vic, sid, sid2, color, cia1, cia2, expansion, or all.", "DEVICES");
+    opts.optopt("", "io-trace-file", &format!("File for --io-trace to write to. Default is {}.", IO_TRACE_FILE), "FILE");
+    opts.optopt("", "kernal-log", "Log calls to the KERNAL jump table with the A, X, and Y registers to a file.", "FILE");
+    opts.optopt("", "host-dir", "Directory for programs saved to device 8.", "DIR");
+    opts.optopt("", "crash-report", &format!("Where to write the machine state if the emulator crashes. Default is {}.", CRASH_REPORT_FILE), "FILE");
+    opts.optopt("", "map-file", "Count how often each instruction runs and write the counts to a file on exit.", "FILE");
+    opts.optopt("", "max-instructions", "Exit with status 2 after running this many instructions.", "N");
+    opts.optopt("", "stereo-sid", "Map a second SID at an address such as $D420, $DE00, or $DF00.", "ADDR");
+    opts.optopt("", "georam", "Plug in a GEORAM expansion of this many K (64 to 4096).", "SIZE");
+    opts.optflag("", "mouse", "Plug a 1351 mouse driven by the host mouse into control port 1.");
+    opts.optopt("", "paddles", "Drive the paddles with the mouse or the first joystick's analog axes.", "mouse|joystick");
+    opts.optflag("", "joystick", "Plug the first host joystick or gamepad into control port 2.");
+    opts.optopt("", "parallel-in", "Plug a parallel cable into the user port that sends a file a byte at a time with the FLAG2/PC2 handshake.", "FILE");
+    opts.optopt("", "mute", "Comma-separated list of SID voices (1-3) to mute.", "VOICES");
+
+    opts.optflag("", "strict-opcodes", "Exit with status 3 instead of running an undocumented opcode.");
+    opts.optopt("", "lax-magic", &format!("Magic constant for the unstable LAX #imm opcode. Default is ${:0>2X}.", cpu::DEFAULT_LAX_MAGIC), "BYTE");
+    opts.optopt("", "ram-image", "Load RAM from a 64K image at power on instead of filling it with the usual pattern.", "FILE");
+    opts.optopt("", "seed", "Seed for power-on RAM contents and other random state.", "N");
+    opts.optopt("", "type", "Text to type after starting. Use \\n for RETURN.", "TEXT");
+    opts.optopt("", "type-interval", &format!("Minimum cycles between typed keys. Default is {}.", bus::DEFAULT_INJECT_INTERVAL), "CYCLES");
+    opts.optflag("", "cycle-exact", "Let the VIC-II stall the CPU for cycle-exact timing");
+    opts.optflag("", "no-boot-delay", "Run at full speed until the KERNAL has booted");
+    opts.optflag("", "pause-on-blur", "Pause the emulator while its window doesn't have focus");
+    opts.optflag("", "idle-sleep", "Sleep while the emulated CPU is stuck in a wait loop");
+    opts.optflag("", "jiffy-irq", "Interrupt the CPU every frame so the KERNAL's jiffy clock runs");
+    opts.optflag("", "list-opcodes", "Print every opcode with how the CPU decodes it and how many cycles the emulator takes to run it, then exit");
+    opts.optflag("q", "quiet", "Don't print clock speed and CPU state while running");
     opts.optflag("d", "debug", "Show debugging information");
     opts.optflag("h", "help", "Display this information");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
-        Err(e) => panic!(e.to_string()),
+        Err(e) => arg_error(&pname, &e.to_string()),
     };
 
     if matches.opt_present("h") {
         print_usage(&pname, opts);
         return;
     }
+    if matches.opt_present("list-opcodes") {
+        list_opcodes();
+        return;
+    }
 
-    let debug = matches.opt_present("d");
+    // A monitor script starts in the debugger so it can set things up before anything runs
+    let debug = matches.opt_present("d") || matches.opt_present("monitor-script");
     let clocktype = match matches.opt_str("c") {
         Some(s) => s,
         None => "PAL".to_string(),
@@ -175,51 +667,207 @@ fn main() {
     let mut commodore = match clocktype.as_ref() {
         "PAL" | "pal" => C64::new_pal(debug),
         "NTSC" | "ntsc" => C64::new_ntsc(debug),
-        _ => panic!("Invalid clock type. See --help for options"),
+        _ => arg_error(&pname, &format!("Invalid clock type: {}", clocktype)),
     };
 
+    if let Some(f) = matches.opt_str("ram-image") {
+        commodore.set_ram_image_file(&f);
+    }
+
     // Set the locations of the ROM files
-    commodore.set_ram_image_file(RAM_IMAGE_FILE);
-
-    let mut home = env::home_dir().unwrap();
-    home.push(ROM_DIR);
-
-    match matches.opt_str("k") {
-        Some(f) => {
-            commodore.set_kernal_rom(&f);
-        },
-        None => {
-            home.push(KERNAL_ROM_FILE);
-            commodore.set_kernal_rom(home.to_str().unwrap());
-            home.pop();
-        },
-    }
-
-    match matches.opt_str("b") {
-        Some(f) => {
-            commodore.set_basic_rom(&f);
-        },
-        None => {
-            home.push(BASIC_ROM_FILE);
-            commodore.set_basic_rom(home.to_str().unwrap());
-            home.pop();
-        },
-    }
-
-    match matches.opt_str("r") {
-        Some(f) => {
-            commodore.set_char_rom(&f);
-        },
-        None => {
-            home.push(CHAR_ROM_FILE);
-            commodore.set_char_rom(home.to_str().unwrap());
-        },
+
+    // Files given on their own win over the search path
+    let dirs = rom_dirs(matches.opt_str("firmware-dir"));
+    let kernal = matches.opt_str("k").unwrap_or_else(|| find_rom(KERNAL_ROM_FILE, &dirs));
+    let basic = matches.opt_str("b").unwrap_or_else(|| find_rom(BASIC_ROM_FILE, &dirs));
+    let chars = matches.opt_str("r").unwrap_or_else(|| find_rom(CHAR_ROM_FILE, &dirs));
+    if !matches.opt_present("q") {
+        println!("KERNAL ROM: {}", kernal);
+        println!("BASIC ROM: {}", basic);
+        println!("Character ROM: {}", chars);
+    }
+    commodore.set_kernal_rom(&kernal);
+    commodore.set_basic_rom(&basic);
+    commodore.set_char_rom(&chars);
+
+    commodore.set_cycle_exact(matches.opt_present("cycle-exact"));
+    commodore.set_fast_boot(matches.opt_present("no-boot-delay"));
+    commodore.set_idle_sleep(matches.opt_present("idle-sleep"));
+    commodore.set_jiffy_irq(matches.opt_present("jiffy-irq"));
+    commodore.set_strict_opcodes(matches.opt_present("strict-opcodes"));
+    commodore.set_quiet(matches.opt_present("q"));
+
+    if let Some(s) = matches.opt_str("seed") {
+        match s.parse::<u64>() {
+            Ok(seed) => commodore.set_seed(seed),
+            Err(_) => arg_error(&pname, &format!("Invalid seed: {}", s)),
+        }
+    }
+    if let Some(m) = matches.opt_str("lax-magic") {
+        match parse_number(&m) {
+            Ok(magic) if magic <= 0xff => commodore.set_lax_magic(magic as u8),
+            _ => arg_error(&pname, &format!("Invalid LAX magic constant: {}", m)),
+        }
+    }
+    if matches.opt_present("test-pattern") {
+        commodore.set_vic_test_pattern(true);
+    }
+    if let Some(t) = matches.opt_str("type-interval") {
+        match t.parse::<u32>() {
+            Ok(cycles) => commodore.set_type_interval(cycles),
+            Err(_) => arg_error(&pname, &format!("Invalid type interval: {}", t)),
+        }
+    }
+    if let Some(t) = matches.opt_str("type") {
+        commodore.type_text(&t.replace("\\n", "\n"));
+    }
+
+    if let Some(f) = matches.opt_str("record") {
+        if let Err(e) = commodore.set_record_file(&f) {
+            arg_error(&pname, &format!("Failed to create event log file {}: {}", f, e));
+        }
+    }
+    if let Some(f) = matches.opt_str("playback") {
+        if let Err(e) = commodore.set_playback_file(&f) {
+            arg_error(&pname, &e);
+        }
+    }
+    if let Some(a) = matches.opt_str("stereo-sid") {
+        let result = parse_number(&a).and_then(|addr| commodore.set_stereo_sid(addr as usize));
+        if let Err(e) = result {
+            arg_error(&pname, &e);
+        }
+    }
+    if let Some(s) = matches.opt_str("georam") {
+        let result = match s.trim_end_matches(&['k', 'K'][..]).parse::<usize>() {
+            Ok(size) => commodore.set_georam(size),
+            Err(_) => Err(format!("Invalid GEORAM size: {}", s)),
+        };
+        if let Err(e) = result {
+            arg_error(&pname, &e);
+        }
+    }
+    if let Some(f) = matches.opt_str("parallel-in") {
+        if let Err(e) = commodore.set_parallel_input(&f) {
+            arg_error(&pname, &e);
+        }
+    }
+    if let Some(voices) = matches.opt_str("mute") {
+        for v in voices.split(',') {
+            match v.trim().parse::<u8>() {
+                Ok(voice) if (1..=3).contains(&voice) => commodore.mute_voice(voice),
+                _ => arg_error(&pname, &format!("Invalid SID voice: {}", v)),
+            }
+        }
+    }
+    if let Some(f) = matches.opt_str("symbols") {
+        commodore.set_symbol_file(&f);
+    }
+    if let Some(f) = matches.opt_str("monitor-script") {
+        commodore.set_monitor_script(&f);
+    }
+    if let Some(a) = matches.opt_str("break-at") {
+        match parse_number(&a) {
+            Ok(addr) => commodore.set_break_at(addr),
+            Err(e) => arg_error(&pname, &e),
+        }
+    }
+    if let Some(f) = matches.opt_str("video-out") {
+        if let Err(e) = commodore.set_video_out(&f) {
+            arg_error(&pname, &format!("Failed to create video output file {}: {}", f, e));
+        }
+    }
+    if let Some(f) = matches.opt_str("audio-out") {
+        if let Err(e) = commodore.set_audio_out(&f) {
+            arg_error(&pname, &format!("Failed to create audio output file {}: {}", f, e));
+        }
+    }
+    if let Some(list) = matches.opt_str("io-trace") {
+        let fname = matches.opt_str("io-trace-file").unwrap_or_else(|| IO_TRACE_FILE.to_string());
+        let devices = match parse_io_devices(&list) {
+            Some(d) => d,
+            None => arg_error(&pname, &format!("Invalid I/O device list: {}", list)),
+        };
+        if let Err(e) = commodore.set_io_trace(&devices, &fname) {
+            arg_error(&pname, &format!("Failed to create I/O trace file {}: {}", fname, e));
+        }
+    }
+    if let Some(f) = matches.opt_str("kernal-log") {
+        if let Err(e) = commodore.set_kernal_log(&f) {
+            arg_error(&pname, &format!("Failed to create KERNAL log file {}: {}", f, e));
+        }
+    }
+    if let Some(d) = matches.opt_str("host-dir") {
+        commodore.set_host_dir(&d);
+    }
+    if let Some(f) = matches.opt_str("crash-report") {
+        commodore.set_crash_report_file(&f);
+    }
+    if let Some(n) = matches.opt_str("max-instructions") {
+        match n.parse::<u64>() {
+            Ok(max) => commodore.set_max_instructions(max),
+            Err(_) => arg_error(&pname, &format!("Invalid instruction count: {}", n)),
+        }
+    }
+    if let Some(f) = matches.opt_str("map-file") {
+        commodore.set_map_file(&f);
+    }
+    for spec in matches.opt_strs("dump-on-exit") {
+        match parse_dump_spec(&spec) {
+            Some((addr, len, f)) => commodore.add_exit_dump(addr, len, &f),
+            None => arg_error(&pname, &format!("Invalid memory dump: {}", spec)),
+        }
+    }
+
+    let paddles = matches.opt_str("paddles").map(|p| {
+        match parse_paddle_input(&p) {
+            Some(input) => input,
+            None => arg_error(&pname, &format!("Invalid paddle input: {}", p)),
+        }
+    });
+
+    let mouse = matches.opt_present("mouse");
+    if mouse && paddles.is_some() {
+        arg_error(&pname, "--mouse and --paddles can't be used together");
+    }
+    commodore.set_mouse(mouse);
+
+    let joystick = matches.opt_present("joystick");
+    if joystick && paddles == Some(PaddleInput::Joystick) {
+        arg_error(&pname, "--joystick and --paddles joystick can't be used together");
     }
 
     // Set up the screen
     let sdl2_context = sdl2::init().unwrap();
+    // The pointer is captured so the mouse can keep moving past the edge of the window
+    if mouse {
+        sdl2_context.mouse().set_relative_mouse_mode(true);
+    }
+    // The joystick has to stay open for its events to arrive
+    let _joystick = if joystick || paddles == Some(PaddleInput::Joystick) {
+        match sdl2_context.joystick().and_then(|j| j.open(0).map_err(|e| format!("{:?}", e))) {
+            Ok(j) => Some(j),
+            Err(e) => {
+                println!("Failed to open joystick: {}", e);
+                None
+            },
+        }
+    } else {
+        None
+    };
+    // The emulator always draws the border, so cropping just shows part of each frame
+    let crop = if matches.opt_present("crop") {
+        Some(crop_area(SCREEN_X, SCREEN_Y))
+    } else {
+        None
+    };
+    let (window_x, window_y) = match crop {
+        Some((_, _, w, h)) => (w, h),
+        None => (SCREEN_X, SCREEN_Y),
+    };
+    let crop = crop.map(|(x, y, w, h)| Rect::new(x as i32, y as i32, w, h));
     let window = WindowBuilder::new(
-        &(sdl2_context.video().unwrap()), "rust-c64", SCREEN_X, SCREEN_Y
+        &(sdl2_context.video().unwrap()), "rust-c64", window_x, window_y
     ).build().unwrap();
     let mut renderer = window.renderer().build().unwrap();
 
@@ -232,34 +880,136 @@ fn main() {
     });
     
     // Loop until quit event
+    let pause_on_blur = matches.opt_present("pause-on-blur");
+    let mut paused = false;
+    let mut paddle_pos = (0u8, 0u8);
+    let mut mouse_buttons = (false, false);
+    let mut joystick_state = (0i16, 0i16, false);   // X and Y axes and the fire button
     let mut events = sdl2_context.event_pump().unwrap();
     loop {
         for event in events.poll_iter() {
+            if pause_on_blur {
+                if let Some(e) = focus_event(&event) {
+                    paused = matches!(e, EmulatorEvent::Pause);
+                    match event_tx.send(e) {
+                        Ok(_) => continue,
+                        Err(e) => panic!("Error sending event to emulator: {}", e),
+                    }
+                }
+            }
             match event {
                 Event::Quit{..} => {
                     event_tx.send(EmulatorEvent::Quit).unwrap();
                     break;
                 },
-                Event::KeyDown {keycode: Some(keycode), keymod: m, ..} |
+                Event::KeyDown {keycode: Some(FREEZE_KEY), ..} => {
+                    match event_tx.send(EmulatorEvent::Freeze) {
+                        Ok(_) => continue,
+                        Err(e) => panic!("Error sending event to emulator: {}", e),
+                    }
+                },
+                Event::KeyUp {keycode: Some(FREEZE_KEY), ..} => {
+                    continue;
+                },
+                Event::KeyDown {keycode: Some(FPS_OVERLAY_KEY), ..} => {
+                    match event_tx.send(EmulatorEvent::ToggleFpsOverlay) {
+                        Ok(_) => continue,
+                        Err(e) => panic!("Error sending event to emulator: {}", e),
+                    }
+                },
+                Event::KeyUp {keycode: Some(FPS_OVERLAY_KEY), ..} => {
+                    continue;
+                },
+                Event::KeyDown {keycode: Some(keycode), keymod: m, ..} => {
+                    match event_tx.send(EmulatorEvent::Key(keycode, m, true)) {
+                        Ok(_) => continue,
+                        Err(e) => panic!("Error sending event to emulator: {}", e),
+                    }
+                },
                 Event::KeyUp {keycode: Some(keycode), keymod: m, ..} => {
-                    match event_tx.send(EmulatorEvent::Key(keycode, m)) {
+                    match event_tx.send(EmulatorEvent::Key(keycode, m, false)) {
                         Ok(_) => continue,
                         Err(e) => panic!("Error sending event to emulator: {}", e),
                     }
-                }
+                },
+                Event::MouseMotion {xrel, yrel, ..} if mouse => {
+                    match event_tx.send(EmulatorEvent::Mouse(xrel, yrel, mouse_buttons.0, mouse_buttons.1)) {
+                        Ok(_) => continue,
+                        Err(e) => panic!("Error sending event to emulator: {}", e),
+                    }
+                },
+                Event::MouseButtonDown {mouse_btn, ..} | Event::MouseButtonUp {mouse_btn, ..} if mouse => {
+                    let pressed = matches!(event, Event::MouseButtonDown {..});
+                    match mouse_btn {
+                        MouseButton::Left => mouse_buttons.0 = pressed,
+                        MouseButton::Right => mouse_buttons.1 = pressed,
+                        _ => continue,
+                    }
+                    match event_tx.send(EmulatorEvent::Mouse(0, 0, mouse_buttons.0, mouse_buttons.1)) {
+                        Ok(_) => continue,
+                        Err(e) => panic!("Error sending event to emulator: {}", e),
+                    }
+                },
+                Event::MouseMotion {x, y, ..} if paddles == Some(PaddleInput::Mouse) => {
+                    paddle_pos = mouse_paddles(x, y, window_x, window_y);
+                    match event_tx.send(EmulatorEvent::Paddles(paddle_pos.0, paddle_pos.1)) {
+                        Ok(_) => continue,
+                        Err(e) => panic!("Error sending event to emulator: {}", e),
+                    }
+                },
+                Event::JoyAxisMotion {axis_idx, value, ..} if paddles == Some(PaddleInput::Joystick) && axis_idx < 2 => {
+                    if axis_idx == 0 {
+                        paddle_pos.0 = axis_paddle(value);
+                    } else {
+                        paddle_pos.1 = axis_paddle(value);
+                    }
+                    match event_tx.send(EmulatorEvent::Paddles(paddle_pos.0, paddle_pos.1)) {
+                        Ok(_) => continue,
+                        Err(e) => panic!("Error sending event to emulator: {}", e),
+                    }
+                },
+                Event::JoyAxisMotion {axis_idx, value, ..} if joystick && axis_idx < 2 => {
+                    if axis_idx == 0 {
+                        joystick_state.0 = value;
+                    } else {
+                        joystick_state.1 = value;
+                    }
+                    let (x, y, fire) = joystick_state;
+                    match event_tx.send(EmulatorEvent::Joystick(joystick_lines(x, y, fire))) {
+                        Ok(_) => continue,
+                        Err(e) => panic!("Error sending event to emulator: {}", e),
+                    }
+                },
+                Event::JoyButtonDown {button_idx: 0, ..} | Event::JoyButtonUp {button_idx: 0, ..} if joystick => {
+                    joystick_state.2 = matches!(event, Event::JoyButtonDown {..});
+                    let (x, y, fire) = joystick_state;
+                    match event_tx.send(EmulatorEvent::Joystick(joystick_lines(x, y, fire))) {
+                        Ok(_) => continue,
+                        Err(e) => panic!("Error sending event to emulator: {}", e),
+                    }
+                },
                 _ => {
                     continue;
                 },
             }
         }
 
+        // No frames come while the emulator is paused, so just wait for more events
+        if paused {
+            if let Err(TryRecvError::Disconnected) = screen_rx.try_recv() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(PAUSE_POLL_MS));
+            continue;
+        }
+
         // This will block until it gets a frame from the emulator. Is that what it should do?
         let scr = match screen_rx.recv() {
             Ok(s) => s,
             Err(_) => break,
         };
         
-        let mut data = scr.pixel_data();
+        let mut data = scr.rgb24_data();
         let surf = Surface::from_data(
             &mut data[..],
             scr.width,
@@ -270,7 +1020,7 @@ fn main() {
         let tex = renderer.create_texture_from_surface(&surf).unwrap();
 
         renderer.clear();
-        renderer.copy(&tex, None, None);
+        renderer.copy(&tex, crop, None);
         renderer.present();
     }
 }
@@ -0,0 +1,119 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+//
+// Functions and datatypes for drawing status information over the emulator's display
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use super::Screen;
+
+// Number of frames the frame rate is averaged over
+const FPS_WINDOW: usize = 50;
+
+// Glyphs are 3x5 pixels, one row per byte with the leftmost pixel in bit 2
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+const TEXT_COLOR: u32 = 0x00ffffff;
+const BACKGROUND_COLOR: u32 = 0x00000000;
+
+// Keeps track of when recent frames were shown to work out the frame rate and clock speed
+pub struct FpsCounter {
+    frames: VecDeque<(Duration, u64)>,  // Time each frame was shown and the cycle count then
+}
+
+impl FpsCounter {
+    pub fn new() -> FpsCounter {
+        FpsCounter {
+            frames: VecDeque::with_capacity(FPS_WINDOW),
+        }
+    }
+
+    // Record a frame shown at a time since startup after the given number of cycles
+    pub fn add_frame(&mut self, time: Duration, cycles: u64) {
+        if self.frames.len() == FPS_WINDOW {
+            self.frames.pop_front();
+        }
+        self.frames.push_back((time, cycles));
+    }
+
+    // Seconds between the oldest and newest frames
+    fn window_secs(&self) -> Option<f32> {
+        match (self.frames.front(), self.frames.back()) {
+            (Some(&(first, _)), Some(&(last, _))) if last > first => {
+                let d = last - first;
+                Some(d.as_secs() as f32 + d.subsec_nanos() as f32 / 1_000_000_000f32)
+            },
+            _ => None,
+        }
+    }
+
+    // Frames per second
+    pub fn fps(&self) -> f32 {
+        match self.window_secs() {
+            Some(secs) => (self.frames.len() - 1) as f32 / secs,
+            None => 0f32,
+        }
+    }
+
+    // Emulated clock speed in kHz
+    pub fn khz(&self) -> f32 {
+        match (self.window_secs(), self.frames.front(), self.frames.back()) {
+            (Some(secs), Some(&(_, first)), Some(&(_, last))) => {
+                (last - first) as f32 / secs / 1000f32
+            },
+            _ => 0f32,
+        }
+    }
+}
+
+// Draw the frame rate and clock speed in the top left corner of the screen
+pub fn draw_fps(screen: &mut Screen, counter: &FpsCounter) {
+    let text = format!("{:.1} FPS {:.0} KHZ", counter.fps(), counter.khz());
+    draw_text(screen, 1, 1, &text);
+}
+
+// Draw a line of text on a solid background. Characters without a glyph are left blank.
+pub fn draw_text(screen: &mut Screen, x: usize, y: usize, text: &str) {
+    let width = screen.width as usize;
+    let height = screen.height as usize;
+    let mut left = x;
+    for c in text.chars() {
+        let glyph = glyph(c);
+        // One pixel of background around each character
+        for row in 0..GLYPH_HEIGHT + 2 {
+            for col in 0..GLYPH_WIDTH + 1 {
+                let (px, py) = (left + col, y + row);
+                if px >= width || py >= height {
+                    continue;
+                }
+                let lit = (1..=GLYPH_HEIGHT).contains(&row) && col < GLYPH_WIDTH &&
+                    glyph[row - 1] & (0x04 >> col) != 0;
+                screen.set_pixel_at(px, py, if lit { TEXT_COLOR } else { BACKGROUND_COLOR });
+            }
+        }
+        left += GLYPH_WIDTH + 1;
+    }
+}
+
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        '0' => [0x7, 0x5, 0x5, 0x5, 0x7],
+        '1' => [0x2, 0x6, 0x2, 0x2, 0x7],
+        '2' => [0x7, 0x1, 0x7, 0x4, 0x7],
+        '3' => [0x7, 0x1, 0x3, 0x1, 0x7],
+        '4' => [0x5, 0x5, 0x7, 0x1, 0x1],
+        '5' => [0x7, 0x4, 0x7, 0x1, 0x7],
+        '6' => [0x7, 0x4, 0x7, 0x5, 0x7],
+        '7' => [0x7, 0x1, 0x1, 0x2, 0x2],
+        '8' => [0x7, 0x5, 0x7, 0x5, 0x7],
+        '9' => [0x7, 0x5, 0x7, 0x1, 0x7],
+        '.' => [0x0, 0x0, 0x0, 0x0, 0x2],
+        'F' => [0x7, 0x4, 0x6, 0x4, 0x4],
+        'H' => [0x5, 0x5, 0x7, 0x5, 0x5],
+        'K' => [0x5, 0x5, 0x6, 0x5, 0x5],
+        'P' => [0x7, 0x5, 0x7, 0x4, 0x4],
+        'S' => [0x7, 0x4, 0x7, 0x1, 0x7],
+        'Z' => [0x7, 0x1, 0x2, 0x4, 0x7],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+//
+// Keeps emulated time in step with the wall clock
+use std::time::{Instant, Duration};
+
+// How often the speed is measured
+pub const SAMPLE_CYCLES: u64 = 10000;
+
+// How much the sleep after each cycle changes every time the speed is measured
+const SLEEP_STEP_NS: u32 = 100;
+
+// Works out how long to sleep after each cycle so the emulator runs at the real clock speed.
+// The sleep grows while the emulator is ahead and shrinks while it's behind.
+pub struct Pacer {
+    target_khz: f32,
+    start: Instant,
+    speed_khz: f32,     // Mean speed since the start as of the last sample
+    sleep: Duration,    // Time to sleep after each cycle
+    last_sample: u64,   // Cycle count at the last sample
+}
+
+impl Pacer {
+    // Pace to a clock speed given in mHz
+    pub fn new(clock_speed_mhz: u32) -> Pacer {
+        Pacer {
+            target_khz: (clock_speed_mhz as f32) / 1_000_000f32,
+            start: Instant::now(),
+            speed_khz: 0f32,
+            sleep: Duration::new(0, 0),
+            last_sample: 0,
+        }
+    }
+
+    // Get how long to sleep after a cycle, measuring the speed against the wall clock every
+    // SAMPLE_CYCLES cycles
+    pub fn should_sleep(&mut self, cycles: u64) -> Option<Duration> {
+        if cycles % SAMPLE_CYCLES == 0 && cycles != self.last_sample {
+            let elapsed = self.start.elapsed();
+            self.sample(cycles, elapsed);
+        }
+        self.sleep_time()
+    }
+
+    // Adjust the sleep time given how many cycles have run in how long since the start
+    pub fn sample(&mut self, cycles: u64, elapsed: Duration) {
+        let elapsed_ms = elapsed.as_secs() as f32 * 1000f32 +
+            elapsed.subsec_nanos() as f32 / 1_000_000f32;
+        self.speed_khz = (cycles as f32) / elapsed_ms;
+        self.last_sample = cycles;
+
+        let step = Duration::new(0, SLEEP_STEP_NS);
+        if self.speed_khz > self.target_khz {
+            self.sleep += step;
+        } else if self.sleep > Duration::new(0, 0) {
+            self.sleep -= step;
+        }
+    }
+
+    pub fn sleep_time(&self) -> Option<Duration> {
+        if self.sleep > Duration::new(0, 0) {
+            Some(self.sleep)
+        } else {
+            None
+        }
+    }
+
+    pub fn speed_khz(&self) -> f32 {
+        self.speed_khz
+    }
+}
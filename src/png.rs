@@ -0,0 +1,95 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+//
+// Functions for writing palette-indexed PNG images. The image data is stored without
+// compression, which is simple and still half the size of RGB since each pixel is 4 bits.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+// Indexed color with 4 bits per pixel, which is enough for 16 colors
+const BIT_DEPTH: u8 = 4;
+const COLOR_TYPE_INDEXED: u8 = 3;
+
+// Deflate's stored blocks hold at most this many bytes
+const MAX_STORED_BLOCK: usize = 0xffff;
+
+// Encode an image as a PNG. Each pixel is an index into a palette of up to 16 colors packed as
+// 0x00RRGGBB.
+pub fn encode_indexed(width: u32, height: u32, palette: &[u32], pixels: &[u8]) -> Vec<u8> {
+    let mut png = SIGNATURE.to_vec();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&be32(width));
+    header.extend_from_slice(&be32(height));
+    header.extend_from_slice(&[BIT_DEPTH, COLOR_TYPE_INDEXED, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    let colors: Vec<u8> = palette.iter()
+        .flat_map(|&c| vec![(c >> 16) as u8, (c >> 8) as u8, c as u8])
+        .collect();
+    write_chunk(&mut png, b"PLTE", &colors);
+
+    // Every row starts with a filter type, which is always none here
+    let mut raw = Vec::new();
+    for row in pixels.chunks(width as usize) {
+        raw.push(0);
+        for pair in row.chunks(2) {
+            let lo = if pair.len() > 1 { pair[1] & 0x0f } else { 0 };
+            raw.push((pair[0] << 4) | lo);
+        }
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&be32(data.len() as u32));
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&be32(crc));
+}
+
+// Wrap data in a zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = if blocks.peek().is_none() { 0x01 } else { 0x00 };
+        let len = block.len() as u16;
+        out.push(last);
+        out.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&be32(adler32(data)));
+    out
+}
+
+fn be32(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
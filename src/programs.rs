@@ -0,0 +1,114 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+//
+// Functions and datatypes for reading programs out of the file formats they're distributed in
+
+// P00 files start with a signature, the original file name, and a record size
+const P00_SIGNATURE: &[u8] = b"C64File\0";
+const P00_NAME_START: usize = 8;
+const P00_NAME_LEN: usize = 16;
+const P00_HEADER_SIZE: usize = 26;
+
+// T64 tape images have a 64 byte header followed by a directory of 32 byte entries. The header
+// starts with one of two signatures depending on the program that made it.
+const T64_SIGNATURES: [&[u8]; 2] = [b"C64 tape image", b"C64S tape"];
+const T64_HEADER_SIZE: usize = 64;
+const T64_ENTRY_SIZE: usize = 32;
+const T64_NAME_START: usize = 16;
+const T64_NAME_LEN: usize = 16;
+
+// A program along with the address it loads at
+pub struct Program {
+    pub name: String,
+    pub load_addr: u16,
+    pub data: Vec<u8>,
+}
+
+// Read the programs in a file. PRG and P00 files hold one program while T64 images can have
+// several.
+pub fn parse_programs(data: &[u8]) -> Result<Vec<Program>, String> {
+    if data.starts_with(P00_SIGNATURE) {
+        parse_p00(data).map(|p| vec![p])
+    } else if T64_SIGNATURES.iter().any(|s| data.starts_with(s)) {
+        parse_t64(data)
+    } else {
+        parse_prg(String::new(), data).map(|p| vec![p])
+    }
+}
+
+// A PRG file is just the load address followed by the program
+pub fn parse_prg(name: String, data: &[u8]) -> Result<Program, String> {
+    if data.len() < 2 {
+        return Err("PRG file is too short".to_string());
+    }
+    Ok(Program {
+        name: name,
+        load_addr: word(data, 0),
+        data: data[2..].to_vec(),
+    })
+}
+
+// A P00 file is a PRG file with a header that keeps the name it had on the C64
+pub fn parse_p00(data: &[u8]) -> Result<Program, String> {
+    if data.len() < P00_HEADER_SIZE {
+        return Err("P00 file is too short".to_string());
+    }
+    let name = petscii_name(&data[P00_NAME_START..P00_NAME_START + P00_NAME_LEN]);
+    parse_prg(name, &data[P00_HEADER_SIZE..])
+}
+
+// Get every used entry in a T64 tape image
+pub fn parse_t64(data: &[u8]) -> Result<Vec<Program>, String> {
+    if data.len() < T64_HEADER_SIZE {
+        return Err("T64 file is too short".to_string());
+    }
+
+    let max_entries = word(data, 0x22) as usize;
+    let mut programs = Vec::new();
+    for i in 0..max_entries {
+        let start = T64_HEADER_SIZE + i * T64_ENTRY_SIZE;
+        let entry = match data.get(start..start + T64_ENTRY_SIZE) {
+            Some(e) => e,
+            None => return Err(format!("T64 directory entry {} is past the end of the file", i)),
+        };
+        // Type 0 is a free entry
+        if entry[0] == 0 {
+            continue;
+        }
+
+        let load_addr = word(entry, 2);
+        let end_addr = word(entry, 4);
+        let offset = (entry[8] as usize) | (entry[9] as usize) << 8 |
+            (entry[10] as usize) << 16 | (entry[11] as usize) << 24;
+        if offset > data.len() {
+            return Err(format!("T64 entry {} starts past the end of the file", i));
+        }
+
+        // Lots of T64 files have a bad end address, so don't read past the end of the file
+        let len = (end_addr.wrapping_sub(load_addr) as usize).min(data.len() - offset);
+        programs.push(Program {
+            name: petscii_name(&entry[T64_NAME_START..T64_NAME_START + T64_NAME_LEN]),
+            load_addr: load_addr,
+            data: data[offset..offset + len].to_vec(),
+        });
+    }
+
+    if programs.is_empty() {
+        Err("T64 file has no programs".to_string())
+    } else {
+        Ok(programs)
+    }
+}
+
+fn word(data: &[u8], i: usize) -> u16 {
+    (data[i] as u16) | ((data[i + 1] as u16) << 8)
+}
+
+// File names are padded with spaces, shifted spaces, or zeros
+fn petscii_name(bytes: &[u8]) -> String {
+    let name: String = bytes.iter()
+        .take_while(|&&b| b != 0 && b != 0xa0)
+        .map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { '?' })
+        .collect();
+    name.trim_end().to_string()
+}
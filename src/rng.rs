@@ -0,0 +1,32 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+//
+// Seedable pseudorandom number generator so that emulation is reproducible
+
+// Used instead of a zero seed, which would get the generator stuck at zero
+const ZERO_SEED_REPLACEMENT: u64 = 0x9e3779b97f4a7c15;
+
+// xorshift64* generator
+#[derive(Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng {
+            state: if seed == 0 { ZERO_SEED_REPLACEMENT } else { seed },
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545f4914f6cdd1d) >> 32) as u32
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u32() >> 24) as u8
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+//
+// Functions and datatypes for mapping between addresses and labels
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+
+pub struct SymbolTable {
+    by_name: HashMap<String, u16>,
+    by_addr: HashMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            by_name: HashMap::new(),
+            by_addr: HashMap::new(),
+        }
+    }
+
+    // Load symbols from a label file
+    pub fn load(&mut self, fname: &str) {
+        let mut file = match File::open(fname) {
+            Ok(f) => f,
+            Err(e) => panic!("Failed to open symbol file: {}", e)
+        };
+        let mut text = String::new();
+        match file.read_to_string(&mut text) {
+            Ok(_) => { },
+            Err(e) => {
+                panic!("Error reading symbol file: {}", e);
+            },
+        }
+        if let Err(line) = self.parse(&text) {
+            panic!("Invalid line in symbol file: {}", line);
+        }
+    }
+
+    // Add symbols from lines of the form "NAME = $ADDR" or VICE's "al C:ADDR .NAME". Returns the
+    // first line that couldn't be parsed if there is one.
+    pub fn parse(&mut self, text: &str) -> Result<(), String> {
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let symbol = if let Some(vice) = line.strip_prefix("al ") {
+                let mut fields = vice.split_whitespace();
+                let addr = fields.next().map(|a| a.trim_start_matches("C:"));
+                match (addr, fields.next()) {
+                    (Some(a), Some(name)) => u16::from_str_radix(a, 16).ok().map(|a| (name, a)),
+                    _ => None,
+                }
+            } else {
+                let mut fields = line.splitn(2, '=');
+                match (fields.next(), fields.next()) {
+                    (Some(name), Some(a)) => parse_addr(a.trim(), self).ok().map(|a| (name.trim(), a)),
+                    _ => None,
+                }
+            };
+
+            match symbol {
+                Some((name, addr)) if !name.is_empty() => self.insert(name, addr),
+                _ => return Err(line.to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    // Add a symbol. Names are stored without VICE's leading '.'
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        let name = name.trim_start_matches('.').to_string();
+        self.by_addr.insert(addr, name.clone());
+        self.by_name.insert(name, addr);
+    }
+
+    pub fn addr_of(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name.trim_start_matches('.')).cloned()
+    }
+
+    pub fn name_of(&self, addr: u16) -> Option<&str> {
+        self.by_addr.get(&addr).map(|n| n.as_str())
+    }
+
+    // Format an address as a label if there is one, otherwise as hex
+    pub fn format_addr(&self, addr: u16) -> String {
+        match self.name_of(addr) {
+            Some(name) => format!(".{}", name),
+            None => format!("${:0>4X}", addr),
+        }
+    }
+}
+
+// Parse a monitor argument as an address. Numbers are hex with a '$' prefix, binary with a '%'
+// prefix, and decimal otherwise. Anything else is looked up as a symbol.
+pub fn parse_addr(s: &str, symbols: &SymbolTable) -> Result<u16, String> {
+    let value = match s.chars().next() {
+        Some('$') => u32::from_str_radix(&s[1..], 16),
+        Some('%') => u32::from_str_radix(&s[1..], 2),
+        Some(c) if c.is_ascii_digit() => s.parse::<u32>(),
+        Some(_) => return symbols.addr_of(s).ok_or_else(|| format!("Unknown symbol: {}", s)),
+        None => return Err("Missing address".to_string()),
+    };
+    match value {
+        Ok(v) if v <= 0xffff => Ok(v as u16),
+        _ => Err(format!("Invalid address: {}", s)),
+    }
+}
@@ -0,0 +1,2490 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+use bus::{Bus, MachineState, MemRegion, IoDevice, BankConfig, compute_banking, save_indexed_png};
+use symbols::{SymbolTable, parse_addr};
+use programs::parse_programs;
+use overlay::{FpsCounter, draw_text};
+use pacer::Pacer;
+use super::{C64, Screen, EmulatorEvent, parse_dump_spec, parse_io_devices, rom_dirs, find_rom, crop_area, focus_event};
+use super::{PaddleInput, parse_paddle_input, mouse_paddles, axis_paddle, joystick_lines, parse_number};
+
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::mpsc;
+use std::time::Duration;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::Mutex;
+
+extern crate sdl2;
+use sdl2::keyboard::{Keycode, NOMOD};
+use sdl2::event::{Event, WindowEventId};
+
+// Create a bus with I/O mapped in and VIC bank 0 selected
+fn new_bus() -> Bus {
+    let mut bus = Bus::new(false, 320, 240);
+    bus.write_byte(0x0000, 0x2f);
+    bus.write_byte(0x0001, 0x37);
+    bus.write_byte(0xdd00, 0x03);
+    bus
+}
+
+// Reset into a program in the RAM underneath the KERNAL at $FCE2
+fn reset_to_ram(bus: &mut Bus) {
+    bus.reset();
+    bus.write_byte(0x0001, 0x35);
+    bus.write_byte(0xfffc, 0xe2);
+    bus.write_byte(0xfffd, 0xfc);
+}
+
+// Test the text screen dump
+#[test]
+fn dump_screen_text_layout() {
+    let bus = new_bus();
+
+    let text = bus.dump_screen_text();
+    let lines: Vec<&str> = text.lines().collect();
+
+    assert_eq!(25, lines.len());
+    assert!(lines.iter().all(|l| l.len() == 40));
+}
+
+#[test]
+fn dump_screen_text_ready() {
+    let mut bus = new_bus();
+
+    // Screen at $0400 with the upper case character set
+    bus.write_byte(0xd018, 0x14);
+    let ready = [0x12, 0x05, 0x01, 0x04, 0x19, 0x2e];
+    for (i, code) in ready.iter().enumerate() {
+        bus.write_byte(0x0400 + 40 + i, *code);
+    }
+
+    let text = bus.dump_screen_text();
+
+    assert_eq!(Some("READY."), text.lines().nth(1).map(|l| &l[..6]));
+}
+
+#[test]
+fn dump_screen_text_lowercase() {
+    let mut bus = new_bus();
+
+    // Screen at $0400 with the lower case character set
+    bus.write_byte(0xd018, 0x16);
+    let ready = [0x52, 0x05, 0x01, 0x04, 0x19, 0xae];
+    for (i, code) in ready.iter().enumerate() {
+        bus.write_byte(0x0400 + i, *code);
+    }
+
+    let text = bus.dump_screen_text();
+
+    assert!(text.starts_with("Ready."));
+}
+
+#[test]
+fn dump_screen_text_follows_charset() {
+    let mut bus = new_bus();
+
+    // "Hello World" in screen codes
+    let hello = [0x48, 0x05, 0x0c, 0x0c, 0x0f, 0x20, 0x57, 0x0f, 0x12, 0x0c, 0x04];
+    for (i, code) in hello.iter().enumerate() {
+        bus.write_byte(0x0400 + i, *code);
+    }
+
+    bus.write_byte(0xd018, 0x17);
+    assert!(bus.dump_screen_text().starts_with("Hello World"));
+    bus.write_byte(0xd018, 0x15);
+    assert!(bus.dump_screen_text().starts_with(".ELLO .ORLD"));
+}
+
+// Draw screen code 1 with the given $D018 and return the color of one of its pixels. The upper
+// case half of the character ROM is blank and the lower case half is solid.
+fn charset_pixel(d018: u8) -> u32 {
+    let mut bus = new_bus();
+    let mut screen = Screen::new(320, 240);
+    let mut char_rom = [0u8; 4096];
+    for b in char_rom[0x800..].iter_mut() {
+        *b = 0xff;
+    }
+    bus.load_roms_from_bytes(&[0; 8192], &[0; 8192], &char_rom).unwrap();
+    reset_to_ram(&mut bus);
+    bus.write_byte(0xfce2, 0x4c);
+    bus.write_byte(0xfce3, 0xe2);
+    bus.write_byte(0xfce4, 0xfc);
+
+    bus.write_byte(0xd018, d018);
+    bus.write_byte(0xd021, 0x00);
+    bus.write_byte(0x0400, 0x01);
+    bus.write_byte(0xd800, 0x01);
+    for _ in 0..(312 * 63 * 2) {
+        bus.cycle(&mut screen);
+    }
+    screen.pixel_at(4, 22)
+}
+
+#[test]
+fn renderer_follows_charset() {
+    assert_eq!(0x00000000, charset_pixel(0x14));
+    assert_eq!(0x00ffffff, charset_pixel(0x16));
+}
+
+// Each character cell should be drawn in its own color RAM entry wherever the screen is
+#[test]
+fn color_ram_follows_matrix_cell() {
+    let mut bus = new_bus();
+    let mut screen = Screen::new(320, 240);
+    reset_to_ram(&mut bus);
+    bus.write_byte(0xfce2, 0x4c);
+    bus.write_byte(0xfce3, 0xe2);
+    bus.write_byte(0xfce4, 0xfc);
+
+    // Screen at $0C00 and a solid character 0 at $2000
+    bus.write_byte(0xd018, 0x38);
+    bus.write_byte(0xd021, 0x00);
+    for addr in 0x2000..0x2008 {
+        bus.write_byte(addr, 0xff);
+    }
+    let colors = [0x02, 0x05, 0x06, 0x07];
+    for (i, &c) in colors.iter().enumerate() {
+        bus.write_byte(0x0c00 + i, 0x00);
+        bus.write_byte(0xd800 + i, c);
+        // Same colors in reverse on the second row
+        bus.write_byte(0x0c28 + i, 0x00);
+        bus.write_byte(0xd828 + i, colors[3 - i]);
+    }
+    for _ in 0..(312 * 63 * 2) {
+        bus.cycle(&mut screen);
+    }
+
+    let expected = [0x0068372b, 0x00588d43, 0x00352879, 0x00b8c76f];
+    for (i, &color) in expected.iter().enumerate() {
+        assert_eq!(color, screen.pixel_at(i * 8 + 4, 22));
+        assert_eq!(expected[3 - i], screen.pixel_at(i * 8 + 4, 30));
+    }
+}
+
+// Test that guest code can't crash the bus
+#[test]
+fn bus_read_every_address() {
+    let bus = new_bus();
+
+    for addr in 0x0000..0x10000 {
+        bus.read_byte(addr);
+    }
+}
+
+#[test]
+fn bus_write_every_address() {
+    let mut bus = new_bus();
+
+    for addr in 0x0000..0x10000 {
+        bus.write_byte(addr, 0xff);
+    }
+}
+
+#[test]
+fn bus_unmapped_io_reads_open_bus() {
+    let bus = new_bus();
+
+    assert_eq!(0xff, bus.read_byte(0xde00));
+    assert_eq!(0xff, bus.read_byte(0xdfff));
+}
+
+#[test]
+fn bus_addresses_wrap() {
+    let mut bus = new_bus();
+
+    bus.write_byte(0x10002, 0x42);
+
+    assert_eq!(0x42, bus.read_byte(0x0002));
+    assert_eq!(0x42, bus.read_byte(0x10002));
+}
+
+// Test memory watchpoints
+#[test]
+fn watchpoint_write_hit() {
+    let mut bus = new_bus();
+
+    bus.debug_command("watch $d020 w");
+
+    assert!(bus.watchpoint_hit(0xd020, true));
+    assert!(!bus.watchpoint_hit(0xd020, false));
+    assert!(!bus.watchpoint_hit(0xd021, true));
+}
+
+#[test]
+fn watchpoint_defaults_to_read_write() {
+    let mut bus = new_bus();
+
+    bus.debug_command("watch $d020");
+
+    assert!(bus.watchpoint_hit(0xd020, true));
+    assert!(bus.watchpoint_hit(0xd020, false));
+}
+
+// Test the monitor's go command
+#[test]
+fn go_stops_at_address() {
+    let mut bus = Bus::new(true, 320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    // Run a string of NOPs from RAM underneath the KERNAL
+    reset_to_ram(&mut bus);
+    for addr in 0xfce2..0xfcf0 {
+        bus.write_byte(addr, 0xea);
+    }
+
+    bus.debug_command("g $fce6");
+    assert!(!bus.stopped());
+    for _ in 0..100 {
+        bus.cycle(&mut screen);
+        if bus.stopped() {
+            break;
+        }
+    }
+
+    assert!(bus.stopped());
+    assert_eq!(0xfce6, bus.pc());
+}
+
+#[test]
+fn monitor_script_sets_breakpoint_and_register() {
+    let mut bus = Bus::new(true, 320, 240);
+    let mut screen = Screen::new(320, 240);
+    let fname = temp_file("rust-c64-test-monitor-script.txt");
+    File::create(&fname).unwrap()
+        .write_all(b"; Store $42 and stop\nbreak $fce6\n\nset a $42\ng\n").unwrap();
+
+    // NOP; STA $0400; JMP $FCE2
+    reset_to_ram(&mut bus);
+    let program = [0xea, 0x8d, 0x00, 0x04, 0x4c, 0xe2, 0xfc];
+    for (i, b) in program.iter().enumerate() {
+        bus.write_byte(0xfce2 + i, *b);
+    }
+    while !bus.cpu().at_instruction_start() {
+        bus.cycle(&mut screen);
+    }
+
+    bus.run_monitor_script(&fname).unwrap();
+    assert!(!bus.stopped());
+    for _ in 0..100 {
+        bus.cycle(&mut screen);
+        if bus.stopped() {
+            break;
+        }
+    }
+
+    assert!(bus.stopped());
+    assert_eq!(0xfce6, bus.pc());
+    assert_eq!(0x42, bus.read_byte(0x0400));
+    assert!(bus.run_monitor_script("/nonexistent/rust-c64-script").is_err());
+}
+
+// Run a monitor script from power on and count the cycles before the debugger wants a command
+fn cycles_before_prompt(script: &[u8], name: &str) -> (Option<u32>, Bus) {
+    let mut bus = Bus::new(true, 320, 240);
+    let mut screen = Screen::new(320, 240);
+    let fname = temp_file(name);
+    File::create(&fname).unwrap().write_all(script).unwrap();
+    bus.set_monitor_script(&fname);
+
+    // JMP $FCE2; JMP $FCE5
+    reset_to_ram(&mut bus);
+    for (i, b) in [0x4c, 0xe2, 0xfc, 0x4c, 0xe5, 0xfc].iter().enumerate() {
+        bus.write_byte(0xfce2 + i, *b);
+    }
+    for cycles in 0..100 {
+        if bus.awaiting_command() {
+            return (Some(cycles), bus);
+        }
+        bus.step_system(&mut screen);
+    }
+    (None, bus)
+}
+
+#[test]
+fn monitor_script_runs_before_first_prompt() {
+    // The reset sequence runs without prompting and the script runs as soon as it's done
+    let (cycles, bus) = cycles_before_prompt(b"set pc $fce5\n", "rust-c64-test-script-step.txt");
+    assert!(cycles.unwrap() > 0);
+    // The cycle after the script fetched the opcode at the new PC
+    assert_eq!(0xfce6, bus.pc());
+
+    // Ending with g keeps running
+    let (cycles, bus) = cycles_before_prompt(b"set pc $fce5\ng\n", "rust-c64-test-script-go.txt");
+    assert_eq!(None, cycles);
+    assert!(!bus.stopped());
+}
+
+#[test]
+fn go_breakpoint_is_one_shot() {
+    let mut bus = Bus::new(true, 320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    // Loop forever with JMP $FCE2
+    reset_to_ram(&mut bus);
+    bus.write_byte(0xfce2, 0x4c);
+    bus.write_byte(0xfce3, 0xe2);
+    bus.write_byte(0xfce4, 0xfc);
+
+    bus.debug_command("g $fce2");
+    for _ in 0..10 {
+        bus.cycle(&mut screen);
+        if bus.stopped() {
+            break;
+        }
+    }
+    assert!(bus.stopped());
+
+    bus.debug_command("g");
+    for _ in 0..100 {
+        bus.cycle(&mut screen);
+    }
+    assert!(!bus.stopped());
+}
+
+// Test starting in Run mode with a breakpoint like --break-at does
+#[test]
+fn break_at_starts_debugger() {
+    let mut bus = Bus::new(false, 320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    reset_to_ram(&mut bus);
+    for addr in 0xfce2..0xfcf0 {
+        bus.write_byte(addr, 0xea);
+    }
+    bus.add_breakpoint(0xfce8, true);
+
+    for _ in 0..100 {
+        bus.cycle(&mut screen);
+        if bus.stopped() {
+            break;
+        }
+    }
+
+    assert!(bus.stopped());
+    assert_eq!(0xfce8, bus.pc());
+}
+
+// Test breaking when the VIC-II reaches a raster line
+#[test]
+fn raster_break_stops_on_line() {
+    let mut bus = Bus::new(false, 320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    reset_to_ram(&mut bus);
+    bus.write_byte(0xfce2, 0x4c);
+    bus.write_byte(0xfce3, 0xe2);
+    bus.write_byte(0xfce4, 0xfc);
+    bus.debug_command("rbreak $64");
+
+    for _ in 0..(63 * 312) {
+        bus.cycle(&mut screen);
+        if bus.stopped() {
+            break;
+        }
+    }
+
+    assert!(bus.stopped());
+    assert_eq!(100, bus.raster_line());
+}
+
+// Test that the VIC's data bus only holds what the VIC fetched itself
+#[test]
+fn vic_ignores_cpu_reads() {
+    let mut bus = new_bus();
+    let mut screen = Screen::new(320, 240);
+    bus.load_roms_from_bytes(&[0; 8192], &[0; 8192], &[0x55; 4096]).unwrap();
+
+    // Everything the VIC can see in bank 0 is $55 with color 5
+    reset_to_ram(&mut bus);
+    for addr in 0x0002..0x4000 {
+        bus.write_byte(addr, 0x55);
+    }
+    for addr in 0xd800..0xdc00 {
+        bus.write_byte(addr, 0x05);
+    }
+    // Keep the video matrix clear of the CPU port at $0000
+    bus.write_byte(0xd018, 0x14);
+
+    // LDA $C000; STA $C001; JMP $FCE2
+    bus.write_byte(0xc000, 0xaa);
+    for (i, &b) in [0xad, 0x00, 0xc0, 0x8d, 0x01, 0xc0, 0x4c, 0xe2, 0xfc].iter().enumerate() {
+        bus.write_byte(0xfce2 + i, b);
+    }
+
+    for _ in 0..1000 {
+        bus.cycle(&mut screen);
+        assert_eq!(0x55, bus.vic().read_data_bus());
+        assert_eq!(0x05, bus.vic().read_color_nybble());
+    }
+    assert_eq!(0xaa, bus.read_byte(0xc001));
+}
+
+// Test VIC bank selection through CIA 2 port A
+#[test]
+fn vic_bank_1_fetches_from_4000() {
+    let mut bus = new_bus();
+    let mut screen = Screen::new(320, 240);
+
+    // JMP $FCE2 with bank 1 selected
+    reset_to_ram(&mut bus);
+    bus.write_byte(0xfce2, 0x4c);
+    bus.write_byte(0xfce3, 0xe2);
+    bus.write_byte(0xfce4, 0xfc);
+    bus.write_byte(0xdd02, 0x03);
+    bus.write_byte(0xdd00, 0x02);
+    // The VIC starts out idle, reading from the top of its bank
+    bus.write_byte(0x3fff, 0x11);
+    bus.write_byte(0x7fff, 0x77);
+
+    // Bank out I/O so the CPU can't see the CIA any more
+    bus.write_byte(0x0001, 0x34);
+    bus.cycle(&mut screen);
+    for _ in 0..100 {
+        bus.cycle(&mut screen);
+        assert_eq!(0x77, bus.vic().read_data_bus());
+    }
+}
+
+// Run a program that counts interrupts in the jiffy clock for a few frames and return the clock
+fn jiffy_clock(jiffy_irq: bool) -> u32 {
+    let mut bus = new_bus();
+    let mut screen = Screen::new(320, 240);
+    bus.set_jiffy_irq(jiffy_irq);
+
+    // CLI; JMP $FCE3 with an IRQ handler at $FD00 that does INC $A2; BNE +2; INC $A1; RTI
+    reset_to_ram(&mut bus);
+    let program = [0x58, 0x4c, 0xe3, 0xfc];
+    let handler = [0xe6, 0xa2, 0xd0, 0x02, 0xe6, 0xa1, 0x40];
+    for (i, &b) in program.iter().enumerate() {
+        bus.write_byte(0xfce2 + i, b);
+    }
+    for (i, &b) in handler.iter().enumerate() {
+        bus.write_byte(0xfd00 + i, b);
+    }
+    bus.write_byte(0xfffe, 0x00);
+    bus.write_byte(0xffff, 0xfd);
+
+    for _ in 0..(312 * 63 * 5) {
+        bus.cycle(&mut screen);
+    }
+    ((bus.read_byte(0xa1) as u32) << 8) + bus.read_byte(0xa2) as u32
+}
+
+// Test the stand-in jiffy interrupt
+#[test]
+fn jiffy_irq_once_per_frame() {
+    let jiffies = jiffy_clock(true);
+    assert!((4..=5).contains(&jiffies), "{} jiffies", jiffies);
+}
+
+// A tick while interrupts are disabled should wait for CLI instead of getting lost
+#[test]
+fn jiffy_irq_waits_for_cli() {
+    let mut bus = new_bus();
+    let mut screen = Screen::new(320, 240);
+    bus.set_jiffy_irq(true);
+
+    // SEI; delay for more than a frame; CLI; JMP $FCEE
+    reset_to_ram(&mut bus);
+    let program = [
+        0x78, 0xa0, 0x20, 0xa2, 0x00,   // SEI; LDY #$20; LDX #$00
+        0xca, 0xd0, 0xfd,               // DEX; BNE $FCE7
+        0x88, 0xd0, 0xf8,               // DEY; BNE $FCE5
+        0x58, 0x4c, 0xee, 0xfc,         // CLI; JMP $FCEE
+    ];
+    let handler = [0xe6, 0xa2, 0x40];   // INC $A2; RTI
+    for (i, &b) in program.iter().enumerate() {
+        bus.write_byte(0xfce2 + i, b);
+    }
+    for (i, &b) in handler.iter().enumerate() {
+        bus.write_byte(0xfd00 + i, b);
+    }
+    bus.write_byte(0xfffe, 0x00);
+    bus.write_byte(0xffff, 0xfd);
+
+    let mut cycles = 0;
+    while bus.pc() != 0xfcee {
+        bus.cycle(&mut screen);
+        cycles += 1;
+    }
+    assert!(cycles > 312 * 63, "Delay only took {} cycles", cycles);
+    assert_eq!(0, bus.read_byte(0xa2));
+
+    for _ in 0..100 {
+        bus.cycle(&mut screen);
+    }
+    assert_eq!(1, bus.read_byte(0xa2));
+}
+
+#[test]
+fn no_jiffy_irq_by_default() {
+    assert_eq!(0, jiffy_clock(false));
+}
+
+// Run a program that starts a CIA's timer A with its interrupt enabled for 20 underflows and
+// count the interrupts. The handler acknowledges them by reading the interrupt status.
+fn cia_timer_interrupts(cia_base: usize, vector: usize) -> u8 {
+    let mut bus = new_bus();
+    let mut screen = Screen::new(320, 240);
+
+    // Timer A reloads from $00FF, so it underflows every 256 cycles
+    reset_to_ram(&mut bus);
+    let (lo, hi) = ((cia_base & 0xff) as u8, (cia_base >> 8) as u8);
+    let program = [
+        0xa9, 0xff, 0x8d, lo + 0x04, hi,    // LDA #$FF; STA TALO
+        0xa9, 0x00, 0x8d, lo + 0x05, hi,    // LDA #$00; STA TAHI
+        0xa9, 0x81, 0x8d, lo + 0x0d, hi,    // LDA #$81; STA ICR
+        0xa9, 0x11, 0x8d, lo + 0x0e, hi,    // LDA #$11; STA CRA
+        0x58, 0x4c, 0xf7, 0xfc,             // CLI; JMP $FCF7
+    ];
+    let handler = [0xad, lo + 0x0d, hi, 0xe6, 0xa2, 0x40];  // LDA ICR; INC $A2; RTI
+    for (i, &b) in program.iter().enumerate() {
+        bus.write_byte(0xfce2 + i, b);
+    }
+    for (i, &b) in handler.iter().enumerate() {
+        bus.write_byte(0xfd00 + i, b);
+    }
+    bus.write_byte(vector, 0x00);
+    bus.write_byte(vector + 1, 0xfd);
+
+    for _ in 0..(256 * 20) {
+        bus.cycle(&mut screen);
+    }
+    bus.read_byte(0xa2)
+}
+
+// Test the CIA interrupt lines
+#[test]
+fn cia_1_interrupts_on_irq() {
+    let count = cia_timer_interrupts(0xdc00, 0xfffe);
+    assert!((18..=20).contains(&count), "{} interrupts", count);
+}
+
+#[test]
+fn cia_2_interrupts_on_nmi() {
+    let count = cia_timer_interrupts(0xdd00, 0xfffa);
+    assert!((18..=20).contains(&count), "{} interrupts", count);
+}
+
+// Test the parallel cable on the user port
+#[test]
+fn parallel_cable_handshakes_bytes() {
+    let mut bus = new_bus();
+    let mut screen = Screen::new(320, 240);
+    let fname = temp_file("rust-c64-test-parallel.bin");
+    File::create(&fname).unwrap().write_all(b"C64").unwrap();
+    bus.set_parallel_input(&fname).unwrap();
+
+    // Wait for FLAG, then read the byte from port B, which has the cable send the next one
+    reset_to_ram(&mut bus);
+    let program = [
+        0xa2, 0x00, 0x8e, 0x03, 0xdd,   // LDX #$00; STX $DD03
+        0xad, 0x0d, 0xdd,               // LDA $DD0D
+        0x29, 0x10, 0xf0, 0xf9,         // AND #$10; BEQ $FCE7
+        0xad, 0x01, 0xdd,               // LDA $DD01
+        0x9d, 0x00, 0xc0,               // STA $C000,X
+        0xe8, 0x4c, 0xe7, 0xfc,         // INX; JMP $FCE7
+    ];
+    for (i, &b) in program.iter().enumerate() {
+        bus.write_byte(0xfce2 + i, b);
+    }
+    bus.write_byte(0xc003, 0x00);
+
+    for _ in 0..1000 {
+        bus.cycle(&mut screen);
+    }
+    let received: Vec<u8> = (0xc000..0xc004).map(|addr| bus.read_byte(addr)).collect();
+    assert_eq!(b"C64\0".to_vec(), received);
+}
+
+// Test logging I/O register writes
+#[test]
+fn io_trace_logs_selected_devices() {
+    let mut bus = new_bus();
+    let fname = temp_file("rust-c64-test-io-trace.log");
+    bus.set_io_trace(&parse_io_devices("vic,sid").unwrap(), &fname).unwrap();
+
+    bus.write_byte(0xd020, 0x0e);
+    bus.write_byte(0xdc03, 0xff);
+    bus.write_byte(0xd418, 0x0f);
+
+    let mut text = String::new();
+    File::open(&fname).unwrap().read_to_string(&mut text).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(2, lines.len());
+    assert!(lines[0].ends_with("Vic $D020 = $0E"));
+    assert!(lines[1].ends_with("Sid $D418 = $0F"));
+}
+
+#[test]
+fn io_trace_device_lists() {
+    assert_eq!(Some(vec![IoDevice::Cia1, IoDevice::Cia2]), parse_io_devices("cia1, cia2"));
+    assert_eq!(7, parse_io_devices("all").unwrap().len());
+    assert_eq!(None, parse_io_devices("vic,tape"));
+}
+
+// Test the monitor's find command
+#[test]
+fn find_text_as_petscii_and_screen_codes() {
+    let mut bus = Bus::new(false, 320, 240);
+
+    // "HI 64" as PETSCII and as screen codes
+    for (i, &b) in [0x48, 0x49, 0x20, 0x36, 0x34].iter().enumerate() {
+        bus.write_byte(0xc000 + i, b);
+    }
+    for (i, &b) in [0x08, 0x09, 0x20, 0x36, 0x34].iter().enumerate() {
+        bus.write_byte(0x0400 + i, b);
+    }
+
+    assert_eq!(vec![0x0400, 0xc000], bus.find_text("hi 64"));
+    assert!(bus.find_text("hi 65").is_empty());
+}
+
+#[test]
+fn find_bytes_wraps_around() {
+    let mut bus = Bus::new(false, 320, 240);
+    bus.write_byte(0xfffe, 0xde);
+    bus.write_byte(0xffff, 0xad);
+    bus.write_byte(0x0002, 0xde);
+    bus.write_byte(0x0003, 0xad);
+
+    // The RAM underneath the CPU port at $0000 and $0001 is never written, so it stays clear
+    assert_eq!(vec![0x0002, 0xfffe], bus.find_bytes(&[0xde, 0xad]));
+    assert_eq!(vec![0xfffe], bus.find_bytes(&[0xde, 0xad, 0x00, 0x00, 0xde]));
+}
+
+// Test keyboard buffer injection
+#[test]
+fn inject_text_waits_for_buffer() {
+    let mut bus = Bus::new(false, 320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    // Keep the CPU busy in RAM with JMP $FCE2
+    reset_to_ram(&mut bus);
+    bus.write_byte(0xfce2, 0x4c);
+    bus.write_byte(0xfce3, 0xe2);
+    bus.write_byte(0xfce4, 0xfc);
+
+    let line = "10 PRINT \"HELLO WORLD\":GOTO 10 REM TEST\n";
+    bus.set_inject_interval(10);
+    bus.inject_text(line);
+
+    // Read the buffer like the KERNAL, a few characters at a time
+    let mut typed = Vec::new();
+    for i in 0..10000 {
+        bus.cycle(&mut screen);
+        let count = bus.read_byte(0x00c6) as usize;
+        assert!(count <= 10);
+        if i % 100 == 0 {
+            for j in 0..count {
+                typed.push(bus.read_byte(0x0277 + j));
+            }
+            bus.write_byte(0x00c6, 0);
+        }
+    }
+
+    let expected: Vec<u8> = line.bytes().map(|b| if b == b'\n' { 0x0d } else { b }).collect();
+    assert_eq!(expected, typed);
+}
+
+// Test the memory map
+#[test]
+fn region_at_default_banks() {
+    let bus = new_bus();
+
+    assert_eq!(MemRegion::Kernal, bus.region_at(0xe000));
+    assert_eq!(MemRegion::Basic, bus.region_at(0xa000));
+    assert_eq!(MemRegion::Io(IoDevice::Vic), bus.region_at(0xd000));
+    assert_eq!(MemRegion::Io(IoDevice::ColorRam), bus.region_at(0xd800));
+    assert_eq!(MemRegion::Io(IoDevice::Cia2), bus.region_at(0xdd00));
+    assert_eq!(MemRegion::Io(IoDevice::Expansion), bus.region_at(0xde00));
+    assert_eq!(MemRegion::Ram, bus.region_at(0x0800));
+    assert_eq!(MemRegion::CpuPort, bus.region_at(0x0001));
+}
+
+// A second SID takes over its block of the I/O area and keeps its own registers
+#[test]
+fn stereo_sid_mapped_in_expansion_io() {
+    let mut bus = new_bus();
+    bus.set_stereo_sid(0xde00).unwrap();
+
+    assert_eq!(MemRegion::Io(IoDevice::StereoSid), bus.region_at(0xde00));
+    assert_eq!(MemRegion::Io(IoDevice::StereoSid), bus.region_at(0xde1f));
+    assert_eq!(MemRegion::Io(IoDevice::Expansion), bus.region_at(0xde20));
+    assert_eq!(MemRegion::Io(IoDevice::Sid), bus.region_at(0xd400));
+
+    bus.write_byte(0xde18, 0x5a);
+    assert_eq!(0x5a, bus.read_byte(0xde18));
+    assert_eq!(0x00, bus.read_byte(0xd418));
+}
+
+#[test]
+fn stereo_sid_address_checked() {
+    let mut bus = new_bus();
+
+    assert!(bus.set_stereo_sid(0xd420).is_ok());
+    assert!(bus.set_stereo_sid(0xdf00).is_ok());
+    assert!(bus.set_stereo_sid(0xd400).is_err());
+    assert!(bus.set_stereo_sid(0xde10).is_err());
+    assert!(bus.set_stereo_sid(0xdc00).is_err());
+}
+
+#[test]
+fn georam_banks_switch() {
+    let mut bus = new_bus();
+    bus.set_georam(512).unwrap();
+
+    // Block 0, page 0
+    bus.write_byte(0xde00, 0x11);
+    bus.write_byte(0xdeff, 0x22);
+
+    // Block 3, page 5
+    bus.write_byte(0xdffe, 3);
+    bus.write_byte(0xdfff, 5);
+    assert_eq!(0x00, bus.read_byte(0xde00));
+    bus.write_byte(0xde00, 0x33);
+
+    bus.write_byte(0xdffe, 0);
+    bus.write_byte(0xdfff, 0);
+    assert_eq!(0x11, bus.read_byte(0xde00));
+    assert_eq!(0x22, bus.read_byte(0xdeff));
+
+    bus.write_byte(0xdffe, 3);
+    bus.write_byte(0xdfff, 5);
+    assert_eq!(0x33, bus.read_byte(0xde00));
+
+    // Blocks past the end of the RAM wrap around
+    bus.write_byte(0xdffe, 3 + 32);
+    assert_eq!(0x33, bus.read_byte(0xde00));
+
+    // The registers can't be read back
+    assert_eq!(0xff, bus.read_byte(0xdfff));
+}
+
+#[test]
+fn georam_size_checked() {
+    let mut bus = new_bus();
+
+    assert!(bus.set_georam(64).is_ok());
+    assert!(bus.set_georam(4096).is_ok());
+    assert!(bus.set_georam(32).is_err());
+    assert!(bus.set_georam(100).is_err());
+    assert!(bus.set_georam(8192).is_err());
+}
+
+#[test]
+fn region_at_char_rom() {
+    let mut bus = new_bus();
+
+    bus.write_byte(0x0001, 0x33);
+
+    assert_eq!(MemRegion::Kernal, bus.region_at(0xe000));
+    assert_eq!(MemRegion::Basic, bus.region_at(0xa000));
+    assert_eq!(MemRegion::Char, bus.region_at(0xd000));
+}
+
+#[test]
+fn region_at_io_only() {
+    let mut bus = new_bus();
+
+    bus.write_byte(0x0001, 0x35);
+
+    assert_eq!(MemRegion::Ram, bus.region_at(0xe000));
+    assert_eq!(MemRegion::Ram, bus.region_at(0xa000));
+    assert_eq!(MemRegion::Io(IoDevice::Vic), bus.region_at(0xd000));
+}
+
+#[test]
+fn region_at_all_ram() {
+    let mut bus = new_bus();
+
+    bus.write_byte(0x0001, 0x30);
+
+    assert_eq!(MemRegion::Ram, bus.region_at(0xe000));
+    assert_eq!(MemRegion::Ram, bus.region_at(0xa000));
+    assert_eq!(MemRegion::Ram, bus.region_at(0xd000));
+}
+
+// Test the banking against the memory configuration table for each kind of cartridge
+#[test]
+fn compute_banking_truth_table() {
+    let config = |kernal, basic, char_rom, io, roml, romh| {
+        BankConfig {
+            kernal: kernal,
+            basic: basic,
+            char_rom: char_rom,
+            io: io,
+            roml: roml,
+            romh: romh,
+        }
+    };
+    let table = [
+        // No cartridge
+        (0x2f, 0x37, true, true, config(true, true, false, true, false, None)),
+        (0x2f, 0x36, true, true, config(true, false, false, true, false, None)),
+        (0x2f, 0x35, true, true, config(false, false, false, true, false, None)),
+        (0x2f, 0x34, true, true, config(false, false, false, false, false, None)),
+        (0x2f, 0x33, true, true, config(true, true, true, false, false, None)),
+        (0x2f, 0x32, true, true, config(true, false, true, false, false, None)),
+        (0x2f, 0x31, true, true, config(false, false, true, false, false, None)),
+        (0x2f, 0x30, true, true, config(false, false, false, false, false, None)),
+        // Lines set as inputs are pulled up
+        (0x00, 0x00, true, true, config(true, true, false, true, false, None)),
+        (0x2d, 0x35, true, true, config(true, true, false, true, false, None)),
+        (0x2e, 0x36, true, true, config(true, true, false, true, false, None)),
+        (0x2b, 0x33, true, true, config(true, true, false, true, false, None)),
+        // 8K cartridge
+        (0x2f, 0x37, true, false, config(true, true, false, true, true, None)),
+        (0x2f, 0x36, true, false, config(true, false, false, true, false, None)),
+        (0x2f, 0x35, true, false, config(false, false, false, true, false, None)),
+        (0x2f, 0x34, true, false, config(false, false, false, false, false, None)),
+        // 16K cartridge
+        (0x2f, 0x37, false, false, config(true, false, false, true, true, Some(0xa000))),
+        (0x2f, 0x36, false, false, config(true, false, false, true, false, Some(0xa000))),
+        (0x2f, 0x35, false, false, config(false, false, false, true, false, None)),
+        (0x2f, 0x33, false, false, config(true, false, true, false, true, Some(0xa000))),
+        (0x2f, 0x30, false, false, config(false, false, false, false, false, None)),
+        // Ultimax doesn't care about the port
+        (0x2f, 0x37, false, true, config(false, false, false, true, true, Some(0xe000))),
+        (0x2f, 0x30, false, true, config(false, false, false, true, true, Some(0xe000))),
+    ];
+
+    for &(ddr, port, game, exrom, expected) in table.iter() {
+        assert_eq!(expected, compute_banking(ddr, port, game, exrom),
+                   "DDR ${:02X} port ${:02X} GAME {} EXROM {}", ddr, port, game, exrom);
+    }
+}
+
+#[test]
+fn region_at_follows_ddr() {
+    let mut bus = new_bus();
+
+    // Turning HIRAM into an input lets it be pulled up without touching the port
+    bus.write_byte(0x0001, 0x35);
+    assert_eq!(MemRegion::Ram, bus.region_at(0xe000));
+    bus.write_byte(0x0000, 0x2d);
+    assert_eq!(MemRegion::Basic, bus.region_at(0xa000));
+    assert_eq!(MemRegion::Kernal, bus.region_at(0xe000));
+}
+
+// Test stepping backwards in the debugger
+fn step_instruction(bus: &mut Bus, screen: &mut Screen) {
+    bus.cycle(screen);
+    while !bus.cpu().at_instruction_start() {
+        bus.cycle(screen);
+    }
+}
+
+#[test]
+fn step_back_restores_state() {
+    let mut bus = Bus::new(true, 320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    // LDA #$01; STA $0400; INX
+    reset_to_ram(&mut bus);
+    let program = [0xa9, 0x01, 0x8d, 0x00, 0x04, 0xe8];
+    for (i, b) in program.iter().enumerate() {
+        bus.write_byte(0xfce2 + i, *b);
+    }
+    bus.write_byte(0x0400, 0x20);
+
+    step_instruction(&mut bus, &mut screen);
+    let after_lda = bus.cpu().clone();
+    step_instruction(&mut bus, &mut screen);
+    let after_sta = bus.cpu().clone();
+    step_instruction(&mut bus, &mut screen);
+    assert!(bus.cpu() != &after_sta);
+    assert_eq!(0x01, bus.read_byte(0x0400));
+
+    assert!(bus.step_back());
+    assert!(bus.cpu() == &after_sta);
+    assert!(bus.step_back());
+    assert!(bus.cpu() == &after_lda);
+    assert_eq!(0x20, bus.read_byte(0x0400));
+}
+
+#[test]
+fn step_back_without_history() {
+    let mut bus = new_bus();
+
+    assert!(!bus.step_back());
+}
+
+// Test the screen buffer
+#[test]
+fn screen_pixel_round_trip() {
+    let mut screen = Screen::new(4, 2);
+
+    screen.set_pixel_at(3, 1, 0x0068372b);
+
+    assert_eq!(0x0068372b, screen.pixel_at(3, 1));
+    assert_eq!(0, screen.pixel_at(2, 1));
+}
+
+#[test]
+fn screen_output_formats() {
+    let mut screen = Screen::new(2, 1);
+
+    screen.set_pixel_at(1, 0, 0x00123456);
+
+    assert_eq!(vec![0, 0, 0, 0x12, 0x34, 0x56], screen.rgb24_data());
+    assert_eq!(vec![0, 0, 0, 0xff, 0x12, 0x34, 0x56, 0xff], screen.rgba8888_data());
+}
+
+// Test boot detection with a stand-in KERNAL that prints READY. and goes to the wait-for-key loop
+#[test]
+fn is_booted_at_wait_for_key_loop() {
+    let mut bus = new_bus();
+    bus.load_roms_from_bytes(&slow_boot_kernal(), &[0; 8192], &[0; 4096]).unwrap();
+    bus.reset();
+    assert!(!bus.is_booted());
+    assert!(!bus.dump_screen_text().contains("READY."));
+
+    boot(&mut bus);
+    assert!(bus.dump_screen_text().starts_with("READY."));
+}
+
+// A stand-in KERNAL that takes about as long as the real one to boot. It spends ~330,000 cycles
+// in a delay loop, prints READY., and goes to the wait-for-key loop.
+fn slow_boot_kernal() -> Vec<u8> {
+    let mut kernal = vec![0xeau8; 8192];
+    let reset = [
+        0xa9, 0x14, 0x8d, 0x18, 0xd0,   // $FCE2: LDA #$14; STA $D018
+        0xa0, 0x00, 0xa2, 0x00,         // $FCE7: LDY #$00; LDX #$00
+        0xca, 0xd0, 0xfd,               // $FCEB: DEX; BNE $FCEB
+        0x88, 0xd0, 0xf8,               // $FCEE: DEY; BNE $FCE9
+        0xa2, 0x05,                     // $FCF1: LDX #$05
+        0xbd, 0xff, 0xfc,               // $FCF3: LDA $FCFF,X
+        0x9d, 0x00, 0x04,               // $FCF6: STA $0400,X
+        0xca, 0x10, 0xf7,               // $FCF9: DEX; BPL $FCF3
+        0x4c, 0xcd, 0xe5,               // $FCFC: JMP $E5CD
+        0x12, 0x05, 0x01, 0x04, 0x19, 0x2e, // $FCFF: READY.
+    ];
+    kernal[0x1ce2..0x1ce2 + reset.len()].copy_from_slice(&reset);
+    // $E5CD: LDA $C6; BEQ $E5CD
+    kernal[0x05cd..0x05d1].copy_from_slice(&[0xa5, 0xc6, 0xf0, 0xfc]);
+    kernal[0x1ffc] = 0xe2;
+    kernal[0x1ffd] = 0xfc;
+    kernal
+}
+
+// Reset a bus and run it until the KERNAL has booted. Returns the number of cycles it took.
+fn boot(bus: &mut Bus) -> u32 {
+    let mut screen = Screen::new(320, 240);
+    bus.reset();
+
+    let mut cycles = 0;
+    while !bus.is_booted() {
+        assert!(cycles < 10_000_000, "Still booting after {} cycles", cycles);
+        bus.cycle(&mut screen);
+        cycles += 1;
+    }
+    cycles
+}
+
+// Get a bus that has booted with the given ROMs. The first call for a set of ROMs does a cold
+// boot and saves the machine state, and every call after that restores it.
+fn booted_bus(kernal: &[u8], basic: &[u8], chars: &[u8]) -> Bus {
+    static BOOTED: Mutex<Vec<(Vec<u8>, MachineState)>> = Mutex::new(Vec::new());
+    let mut bus = Bus::new(false, 320, 240);
+    bus.load_roms_from_bytes(kernal, basic, chars).unwrap();
+    let roms = [kernal, basic, chars].concat();
+
+    // Hold the lock while booting so two tests don't both do the cold boot
+    let mut booted = BOOTED.lock().unwrap_or_else(|e| e.into_inner());
+    match booted.iter().find(|(r, _)| *r == roms) {
+        Some((_, state)) => bus.restore_state(state),
+        None => {
+            boot(&mut bus);
+            booted.push((roms, bus.save_state()));
+        },
+    }
+    bus
+}
+
+#[test]
+fn booted_bus_restores_state() {
+    let kernal = slow_boot_kernal();
+    let mut cold = new_bus();
+    cold.load_roms_from_bytes(&kernal, &[0; 8192], &[0; 4096]).unwrap();
+    let cold_cycles = boot(&mut cold);
+    assert!(cold_cycles > 300_000, "Cold boot took {} cycles", cold_cycles);
+
+    // Every call gets a machine that's already booted and carries on from there
+    let mut screen = Screen::new(320, 240);
+    for _ in 0..2 {
+        let mut warm = booted_bus(&kernal, &[0; 8192], &[0; 4096]);
+        assert!(warm.is_booted());
+        assert_eq!(cold.instruction_count(), warm.instruction_count());
+        assert_eq!(cold.dump_screen_text(), warm.dump_screen_text());
+        assert!(warm.dump_screen_text().starts_with("READY."));
+
+        for _ in 0..1000 {
+            warm.cycle(&mut screen);
+        }
+        assert!(warm.is_booted());
+    }
+}
+
+// Test parsing monitor addresses
+#[test]
+fn parse_addr_formats() {
+    let mut symbols = SymbolTable::new();
+    symbols.insert("main", 0x0810);
+
+    assert_eq!(Ok(0xc000), parse_addr("$C000", &symbols));
+    assert_eq!(Ok(0xc000), parse_addr("49152", &symbols));
+    assert_eq!(Ok(0x000a), parse_addr("%1010", &symbols));
+    assert_eq!(Ok(0x0810), parse_addr("main", &symbols));
+    assert_eq!(Ok(0x0810), parse_addr(".main", &symbols));
+}
+
+#[test]
+fn parse_addr_errors() {
+    let symbols = SymbolTable::new();
+
+    assert!(parse_addr("$10000", &symbols).is_err());
+    assert!(parse_addr("65536", &symbols).is_err());
+    assert!(parse_addr("%102", &symbols).is_err());
+    assert!(parse_addr("$", &symbols).is_err());
+    assert!(parse_addr("nowhere", &symbols).is_err());
+    assert!(parse_addr("", &symbols).is_err());
+}
+
+// Test symbols in the disassembler and monitor
+fn symbol_bus() -> Bus {
+    let mut bus = Bus::new(true, 320, 240);
+    reset_to_ram(&mut bus);
+    bus.symbols_mut().parse("main = $C000\nal C:0810 .print\n").unwrap();
+    bus
+}
+
+#[test]
+fn disassemble_jsr_to_symbol() {
+    let mut bus = symbol_bus();
+
+    // JSR $0810; LDA #$01; STA $D020
+    let program = [0x20, 0x10, 0x08, 0xa9, 0x01, 0x8d, 0x20, 0xd0];
+    for (i, b) in program.iter().enumerate() {
+        bus.write_byte(0xc000 + i, *b);
+    }
+
+    let (text, len) = bus.disassemble(0xc000);
+    assert_eq!(3, len);
+    assert!(text.contains(".main:"));
+    assert!(text.ends_with("JSR .print"));
+
+    let (text, len) = bus.disassemble(0xc003);
+    assert_eq!(2, len);
+    assert!(text.ends_with("LDA #$01"));
+
+    let (text, _) = bus.disassemble(0xc005);
+    assert!(text.ends_with("STA $D020"));
+}
+
+#[test]
+fn assemble_command_writes_contiguous_instructions() {
+    let mut bus = Bus::new(true, 320, 240);
+
+    bus.debug_command("a $c000 lda #$01");
+    bus.debug_command("a sta $0400");
+
+    assert_eq!(0xa9, bus.read_byte(0xc000));
+    assert_eq!(0x01, bus.read_byte(0xc001));
+    assert_eq!(0x8d, bus.read_byte(0xc002));
+    assert_eq!(0x00, bus.read_byte(0xc003));
+    assert_eq!(0x04, bus.read_byte(0xc004));
+
+    let (text, len) = bus.disassemble(0xc000);
+    assert_eq!(2, len);
+    assert!(text.ends_with("LDA #$01"));
+    let (text, _) = bus.disassemble(0xc002);
+    assert!(text.ends_with("STA $0400"));
+}
+
+#[test]
+fn disassemble_branch_target() {
+    let mut bus = symbol_bus();
+
+    // $0810: BNE $0810
+    bus.write_byte(0x0810, 0xd0);
+    bus.write_byte(0x0811, 0xfe);
+
+    let (text, _) = bus.disassemble(0x0810);
+    assert!(text.ends_with("BNE .print"));
+}
+
+#[test]
+fn break_at_symbol() {
+    let mut bus = symbol_bus();
+    let mut screen = Screen::new(320, 240);
+
+    for addr in 0xfce2..0xfcf0 {
+        bus.write_byte(addr, 0xea);
+    }
+    bus.symbols_mut().insert("nops", 0xfce8);
+
+    bus.debug_command("break .nops");
+    bus.debug_command("run");
+    for _ in 0..100 {
+        bus.cycle(&mut screen);
+        if bus.stopped() {
+            break;
+        }
+    }
+
+    assert!(bus.stopped());
+    assert_eq!(0xfce8, bus.pc());
+}
+
+// Test memory snapshots and PRG loading
+fn temp_file(name: &str) -> String {
+    let mut path = env::temp_dir();
+    path.push(name);
+    path.to_str().unwrap().to_string()
+}
+
+// Split a PNG into its chunks
+fn png_chunks(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut chunks = Vec::new();
+    let mut i = 8;
+    while i < data.len() {
+        let len = ((data[i] as usize) << 24) | ((data[i + 1] as usize) << 16) |
+            ((data[i + 2] as usize) << 8) | data[i + 3] as usize;
+        let kind = String::from_utf8_lossy(&data[i + 4..i + 8]).into_owned();
+        chunks.push((kind, data[i + 8..i + 8 + len].to_vec()));
+        i += len + 12;
+    }
+    chunks
+}
+
+// Get the image data back out of a zlib stream of stored blocks
+fn unstore(zlib: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut i = 2;
+    loop {
+        let last = zlib[i] & 0x01 == 0x01;
+        let len = zlib[i + 1] as usize | (zlib[i + 2] as usize) << 8;
+        data.extend_from_slice(&zlib[i + 5..i + 5 + len]);
+        i += 5 + len;
+        if last {
+            return data;
+        }
+    }
+}
+
+#[test]
+fn indexed_screenshot_uses_vic_palette() {
+    let mut bus = new_bus();
+    let mut screen = Screen::new(320, 240);
+    reset_to_ram(&mut bus);
+    bus.write_byte(0xfce2, 0x4c);
+    bus.write_byte(0xfce3, 0xe2);
+    bus.write_byte(0xfce4, 0xfc);
+    bus.write_byte(0xd020, 0x02);
+    bus.write_byte(0xd021, 0x06);
+    for _ in 0..(312 * 63 * 2) {
+        bus.cycle(&mut screen);
+    }
+
+    let fname = temp_file("c64-indexed-screenshot.png");
+    save_indexed_png(&screen, &fname).unwrap();
+    let mut data = Vec::new();
+    File::open(&fname).unwrap().read_to_end(&mut data).unwrap();
+    fs::remove_file(&fname).unwrap();
+
+    assert_eq!(b"\x89PNG\r\n\x1a\n", &data[..8]);
+    let chunks = png_chunks(&data);
+    let kinds: Vec<&str> = chunks.iter().map(|c| c.0.as_str()).collect();
+    assert_eq!(vec!["IHDR", "PLTE", "IDAT", "IEND"], kinds);
+    assert_eq!(vec![0, 0, 1, 64, 0, 0, 0, 240, 4, 3, 0, 0, 0], chunks[0].1);
+    assert_eq!(48, chunks[1].1.len());
+    assert_eq!(&[0x68, 0x37, 0x2b], &chunks[1].1[6..9]);
+
+    // Each row is a filter byte and two pixels per byte
+    let pixels = unstore(&chunks[2].1);
+    assert_eq!(240 * 161, pixels.len());
+    let index = |x: usize, y: usize| (pixels[y * 161 + 1 + x / 2] >> (4 - 4 * (x % 2))) & 0x0f;
+    assert_eq!(2, index(0, 0));
+    assert_eq!(2, index(319, 239));
+    assert_eq!(6, index(161, 120));
+}
+
+#[test]
+fn snapshot_round_trip_includes_color_ram() {
+    let mut bus = new_bus();
+    let fname = temp_file("rust-c64-test-snapshot.bin");
+
+    bus.write_byte(0xd800, 0xf5);
+    bus.write_byte(0x0400, 0x01);
+    bus.save_snapshot(&fname).unwrap();
+
+    bus.write_byte(0xd800, 0x00);
+    bus.write_byte(0x0400, 0x00);
+    bus.load_snapshot(&fname).unwrap();
+
+    assert_eq!(0x05, bus.read_byte(0xd800));
+    assert_eq!(0x01, bus.read_byte(0x0400));
+}
+
+#[test]
+fn load_prg_writes_color_ram() {
+    let mut bus = new_bus();
+    let fname = temp_file("rust-c64-test-colors.prg");
+    File::create(&fname).unwrap().write_all(&[0xfe, 0xd7, 0x11, 0x22, 0xf3, 0x04]).unwrap();
+
+    // Load with I/O banked out. Colors still end up in color RAM.
+    bus.write_byte(0x0001, 0x30);
+    assert_eq!((0xd7fe, 0xd802), bus.load_prg(&fname).unwrap());
+    assert_eq!(0x11, bus.read_byte(0xd7fe));
+    assert_eq!(0x22, bus.read_byte(0xd7ff));
+
+    bus.write_byte(0x0001, 0x37);
+    assert_eq!(0x03, bus.read_byte(0xd800));
+    assert_eq!(0x04, bus.read_byte(0xd801));
+}
+
+#[test]
+fn parse_p00_strips_header() {
+    let mut data = b"C64File\0HELLO".to_vec();
+    data.resize(26, 0);
+    data.extend_from_slice(&[0x01, 0x08, 0x0b, 0x08]);
+
+    let programs = parse_programs(&data).unwrap();
+    assert_eq!(1, programs.len());
+    assert_eq!("HELLO", programs[0].name);
+    assert_eq!(0x0801, programs[0].load_addr);
+    assert_eq!(vec![0x0b, 0x08], programs[0].data);
+}
+
+fn t64_image(end_addr: u16) -> Vec<u8> {
+    let mut data = b"C64 tape image file".to_vec();
+    data.resize(64, 0);
+    // Room for two entries, one used
+    data[0x22] = 2;
+    data[0x24] = 1;
+
+    let mut entry = vec![0u8; 32];
+    entry[0] = 1;
+    entry[1] = 0x82;
+    entry[2] = 0x00;
+    entry[3] = 0xc0;
+    entry[4] = end_addr as u8;
+    entry[5] = (end_addr >> 8) as u8;
+    entry[8] = 128;
+    entry[16..32].copy_from_slice(b"GAME            ");
+    data.extend_from_slice(&entry);
+    data.extend_from_slice(&[0u8; 32]);
+
+    data.extend_from_slice(&[0xa9, 0x01, 0x60]);
+    data
+}
+
+#[test]
+fn parse_t64_entries() {
+    let programs = parse_programs(&t64_image(0xc003)).unwrap();
+    assert_eq!(1, programs.len());
+    assert_eq!("GAME", programs[0].name);
+    assert_eq!(0xc000, programs[0].load_addr);
+    assert_eq!(vec![0xa9, 0x01, 0x60], programs[0].data);
+
+    // A bad end address can't make the program run past the end of the file
+    let programs = parse_programs(&t64_image(0xc3c6)).unwrap();
+    assert_eq!(3, programs[0].data.len());
+}
+
+#[test]
+fn parse_t64_signatures() {
+    let mut data = t64_image(0xc003);
+    data[..19].copy_from_slice(b"C64S tape file\0\0\0\0\0");
+    assert_eq!("GAME", parse_programs(&data).unwrap()[0].name);
+
+    // A PRG that loads at $3643 starts with "C64" but isn't a tape image
+    let programs = parse_programs(&[0x43, 0x36, 0x34, 0x00]).unwrap();
+    assert_eq!(1, programs.len());
+    assert_eq!(0x3643, programs[0].load_addr);
+    assert_eq!(vec![0x34, 0x00], programs[0].data);
+}
+
+// Test logging KERNAL calls
+#[test]
+fn kernal_log_records_chrout() {
+    let mut kernal = vec![0u8; 8192];
+    // LDA #$41; JSR $FFD2; JMP $FCE7
+    kernal[0x1ce2..0x1cea].copy_from_slice(&[0xa9, 0x41, 0x20, 0xd2, 0xff, 0x4c, 0xe7, 0xfc]);
+    kernal[0x1fd2] = 0x60;
+    kernal[0x1ffc] = 0xe2;
+    kernal[0x1ffd] = 0xfc;
+
+    let fname = temp_file("rust-c64-test-kernal.log");
+    let mut bus = Bus::new(false, 320, 240);
+    let mut screen = Screen::new(320, 240);
+    bus.load_roms_from_bytes(&kernal, &[0u8; 8192], &[0u8; 4096]).unwrap();
+    bus.set_kernal_log(&fname).unwrap();
+    bus.reset();
+    for _ in 0..100 {
+        bus.cycle(&mut screen);
+    }
+
+    let mut text = String::new();
+    File::open(&fname).unwrap().read_to_string(&mut text).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(vec!["$FCE4  CHROUT A=$41 X=$00 Y=$00 'A'"], lines);
+}
+
+// Boot a KERNAL that calls SAVE as soon as it starts, with the zero page set up to save
+// $C000-$C002 to a file
+fn save_bus(dir: &str, name: &str) -> Bus {
+    let mut kernal = vec![0u8; 8192];
+    // JSR $F5ED; JMP $FCE5
+    kernal[0x1ce2..0x1ce8].copy_from_slice(&[0x20, 0xed, 0xf5, 0x4c, 0xe5, 0xfc]);
+    // The real SAVE is just RTS
+    kernal[0x15ed] = 0x60;
+    kernal[0x1ffc] = 0xe2;
+    kernal[0x1ffd] = 0xfc;
+
+    let mut bus = Bus::new(false, 320, 240);
+    bus.load_roms_from_bytes(&kernal, &[0u8; 8192], &[0u8; 4096]).unwrap();
+    bus.set_host_dir(dir);
+    bus.reset();
+
+    let zp = [(0xc1, 0x00), (0xc2, 0xc0), (0xae, 0x03), (0xaf, 0xc0), (0xba, 0x08),
+              (0xb7, name.len() as u8), (0xbb, 0x00), (0xbc, 0xc1)];
+    for &(addr, value) in zp.iter() {
+        bus.write_byte(addr, value);
+    }
+    for (i, c) in name.bytes().enumerate() {
+        bus.write_byte(0xc100 + i, c);
+    }
+    bus.write_byte(0xc000, 0xa9);
+    bus.write_byte(0xc001, 0x01);
+    bus.write_byte(0xc002, 0x60);
+    bus
+}
+
+fn run_save(bus: &mut Bus) {
+    let mut screen = Screen::new(320, 240);
+    for _ in 0..100 {
+        bus.cycle(&mut screen);
+    }
+}
+
+#[test]
+fn kernal_save_writes_prg() {
+    let dir = temp_file("rust-c64-test-save");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut bus = save_bus(&dir, "0:GAME");
+    run_save(&mut bus);
+
+    let mut data = Vec::new();
+    File::open(format!("{}/game.prg", dir)).unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(vec![0x00, 0xc0, 0xa9, 0x01, 0x60], data);
+
+    // SAVE returned to the loop after the JSR
+    let pc = bus.pc();
+    assert!((0xfce5..0xfce8).contains(&pc), "PC is ${:0>4X}", pc);
+}
+
+#[test]
+fn kernal_save_only_replaces_with_at_sign() {
+    let dir = temp_file("rust-c64-test-save-replace");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let fname = format!("{}/game.prg", dir);
+    File::create(&fname).unwrap().write_all(&[0x01, 0x08]).unwrap();
+
+    let mut bus = save_bus(&dir, "GAME");
+    run_save(&mut bus);
+    assert_eq!(2, fs::metadata(&fname).unwrap().len());
+
+    let mut bus = save_bus(&dir, "@0:GAME");
+    run_save(&mut bus);
+    assert_eq!(5, fs::metadata(&fname).unwrap().len());
+}
+
+#[test]
+fn kernal_save_ignores_other_devices() {
+    let dir = temp_file("rust-c64-test-save-device");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut bus = save_bus(&dir, "GAME");
+    bus.write_byte(0xba, 0x01);
+    run_save(&mut bus);
+
+    assert!(fs::read_dir(&dir).unwrap().next().is_none());
+}
+
+#[test]
+fn load_t64_program() {
+    let mut bus = new_bus();
+    let fname = temp_file("rust-c64-test-game.t64");
+    File::create(&fname).unwrap().write_all(&t64_image(0xc003)).unwrap();
+
+    assert_eq!(("GAME".to_string(), 0xc000, 0xc003), bus.load_program(&fname, 0).unwrap());
+    assert_eq!(0xa9, bus.read_byte(0xc000));
+    assert!(bus.load_program(&fname, 1).is_err());
+}
+
+#[test]
+fn crash_writes_report_and_snapshot() {
+    let fname = temp_file("rust-c64-test-crash.txt");
+    let snapshot = temp_file("rust-c64-test-crash.bin");
+    let _ = fs::remove_file(&fname);
+    let _ = fs::remove_file(&snapshot);
+
+    // LDA #$01, then $0F which isn't implemented
+    let mut bus = Bus::new(false, 320, 240);
+    let mut screen = Screen::new(320, 240);
+    reset_to_ram(&mut bus);
+    for (i, b) in [0xa9, 0x01, 0x0f].iter().enumerate() {
+        bus.write_byte(0xfce2 + i, *b);
+    }
+    bus.write_byte(0x0400, 0x42);
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        bus.catch_crash(&fname, |b| {
+            for _ in 0..100 {
+                b.cycle(&mut screen);
+            }
+        })
+    }));
+    assert!(result.is_err());
+
+    let mut report = String::new();
+    File::open(&fname).unwrap().read_to_string(&mut report).unwrap();
+    assert!(report.contains("Emulator crashed: Unknown addressing mode"));
+    assert!(report.contains("LDA #$01"));
+
+    let mut data = Vec::new();
+    File::open(&snapshot).unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(65536 + 1024, data.len());
+    assert_eq!(0x42, data[0x0400]);
+}
+
+#[test]
+fn small_screen_renders_frame() {
+    let mut bus = Bus::new(false, 64, 64);
+    let mut screen = Screen::new(64, 64);
+    reset_to_ram(&mut bus);
+    // JMP $FCE2
+    for (i, b) in [0x4c, 0xe2, 0xfc].iter().enumerate() {
+        bus.write_byte(0xfce2 + i, *b);
+    }
+    // Red border, green background, blank screen memory
+    bus.write_byte(0xd020, 0x02);
+    bus.write_byte(0xd021, 0x05);
+    for addr in 0x0400..0x0800 {
+        bus.write_byte(addr, 0x20);
+    }
+
+    for _ in 0..63 * 312 * 2 {
+        bus.cycle(&mut screen);
+    }
+    assert_eq!(0x0068372b, screen.pixel_at(0, 0));
+    assert_eq!(0x0068372b, screen.pixel_at(63, 19));
+    assert_eq!(0x00588d43, screen.pixel_at(63, 63));
+}
+
+// Boot the real ROMs, type in a BASIC program, and run it. Run it with --ignored and C64_ROM_DIR
+// set to a directory with the kernal, basic, and chargen files.
+#[test]
+#[ignore = "needs the real ROMs, which can't be distributed with the emulator"]
+fn basic_program_runs() {
+    let dir = env::var("C64_ROM_DIR").expect("C64_ROM_DIR has to be set to run this test");
+    let rom = |name: &str| fs::read(Path::new(&dir).join(name)).unwrap();
+
+    let mut bus = booted_bus(&rom("kernal"), &rom("basic"), &rom("chargen"));
+    let mut screen = Screen::new(320, 240);
+    let mut cycles = 0;
+
+    // Wait for READY. after RUN, checking the screen once a frame
+    bus.inject_text("10 POKE 1024,1\nRUN\n");
+    let mut text = bus.dump_screen_text();
+    while !text.lines().skip_while(|l| !l.starts_with("RUN")).any(|l| l.starts_with("READY.")) {
+        for _ in 0..63 * 312 {
+            bus.cycle(&mut screen);
+        }
+        cycles += 63 * 312;
+        assert!(cycles < 20_000_000, "Program didn't finish:\n{}", text);
+        text = bus.dump_screen_text();
+    }
+
+    assert_eq!(1, bus.read_byte(0x0400));
+    assert!(text.starts_with('A'));
+}
+
+// Test idle loop detection
+fn run_loop(program: &[u8], cycles: u32) -> Bus {
+    let mut bus = Bus::new(false, 320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    reset_to_ram(&mut bus);
+    for (i, b) in program.iter().enumerate() {
+        bus.write_byte(0xfce2 + i, *b);
+    }
+    for _ in 0..cycles {
+        bus.cycle(&mut screen);
+    }
+    bus
+}
+
+// A JMP * loop should stop at the instruction limit instead of running forever
+#[test]
+fn max_instructions_stops_loop() {
+    let mut bus = Bus::new(false, 320, 240);
+    let mut screen = Screen::new(320, 240);
+    reset_to_ram(&mut bus);
+    bus.write_byte(0xfce2, 0x4c);
+    bus.write_byte(0xfce3, 0xe2);
+    bus.write_byte(0xfce4, 0xfc);
+    bus.set_max_instructions(100);
+
+    let mut cycles = 0;
+    while !bus.instruction_limit_reached() {
+        bus.cycle(&mut screen);
+        cycles += 1;
+        assert!(cycles < 10_000, "Instruction limit never reached");
+    }
+
+    assert_eq!(100, bus.instruction_count());
+    assert_eq!(0xfce2, bus.cpu().pc());
+}
+
+#[test]
+fn cpu_idle_in_jmp_loop() {
+    // JMP $FCE2
+    let bus = run_loop(&[0x4c, 0xe2, 0xfc], 63 * 312 + 100);
+    assert!(bus.cpu_idle());
+
+    let bus = run_loop(&[0x4c, 0xe2, 0xfc], 1000);
+    assert!(!bus.cpu_idle());
+}
+
+#[test]
+fn cpu_not_idle_when_writing() {
+    // INC $0400; JMP $FCE2
+    let bus = run_loop(&[0xee, 0x00, 0x04, 0x4c, 0xe2, 0xfc], 63 * 312 * 2);
+    assert!(!bus.cpu_idle());
+}
+
+#[test]
+fn cpu_not_idle_in_long_loop() {
+    // 16 NOPs then JMP $FCE2
+    let mut program = vec![0xea; 16];
+    program.extend_from_slice(&[0x4c, 0xe2, 0xfc]);
+    let bus = run_loop(&program, 63 * 312 * 2);
+    assert!(!bus.cpu_idle());
+}
+
+// Test the JSON state dump
+#[test]
+fn bus_state_json_includes_chips() {
+    let mut bus = new_bus();
+    let mut screen = Screen::new(320, 240);
+
+    reset_to_ram(&mut bus);
+    for addr in 0xfce2..0xfd00 {
+        bus.write_byte(addr, 0xea);
+    }
+    while !bus.cpu().at_instruction_start() {
+        bus.cycle(&mut screen);
+    }
+    // Start CIA 1 timer A at $0010
+    bus.write_byte(0xdc04, 0x10);
+    bus.write_byte(0xdc05, 0x00);
+    bus.write_byte(0xdc0e, 0x01);
+    for _ in 0..4 {
+        bus.cycle(&mut screen);
+    }
+
+    let json = bus.state_json();
+    assert!(json.starts_with("{\"cpu\":{\"pc\":64740,"));
+    assert!(json.ends_with(",\"vic\":{\"raster\":0},\"cia1\":{\"timer_a\":12,\"timer_b\":0,\"cnt\":true,\"sp\":true},\"cia2\":{\"timer_a\":0,\"timer_b\":0,\"cnt\":true,\"sp\":true}}"));
+}
+
+// Test the RESTORE key
+#[test]
+fn restore_key_triggers_nmi() {
+    let mut bus = Bus::new(false, 320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    // Run NOPs from RAM with the NMI vector pointing to $C000
+    reset_to_ram(&mut bus);
+    for addr in 0xfce2..0xfd00 {
+        bus.write_byte(addr, 0xea);
+    }
+    bus.write_byte(0xfffa, 0x00);
+    bus.write_byte(0xfffb, 0xc0);
+    bus.write_byte(0xfffe, 0x00);
+    bus.write_byte(0xffff, 0xd0);
+
+    for _ in 0..4 {
+        bus.cycle(&mut screen);
+    }
+    bus.key_event(Keycode::PageUp, NOMOD, true);
+    bus.key_event(Keycode::PageUp, NOMOD, false);
+    for _ in 0..20 {
+        bus.cycle(&mut screen);
+        if bus.pc() == 0xc000 {
+            break;
+        }
+    }
+
+    assert_eq!(0xc000, bus.pc());
+}
+
+#[test]
+fn run_stop_key_in_matrix() {
+    let mut bus = new_bus();
+
+    bus.write_byte(0xdc02, 0xff);
+    bus.write_byte(0xdc03, 0x00);
+    bus.write_byte(0xdc00, 0x7f);
+
+    assert_eq!(0xff, bus.read_byte(0xdc01));
+    bus.key_event(Keycode::Escape, NOMOD, true);
+    assert_eq!(0x7f, bus.read_byte(0xdc01));
+
+    // Only column 7 sees the key
+    bus.write_byte(0xdc00, 0xfe);
+    assert_eq!(0xff, bus.read_byte(0xdc01));
+
+    bus.write_byte(0xdc00, 0x7f);
+    bus.key_event(Keycode::Escape, NOMOD, false);
+    assert_eq!(0xff, bus.read_byte(0xdc01));
+}
+
+#[test]
+fn mouse_updates_pots_each_frame() {
+    let mut bus = run_loop(&[0x4c, 0xe2, 0xfc], 0);
+    let mut screen = Screen::new(320, 240);
+    bus.set_mouse(true);
+    bus.write_byte(0xdc02, 0x00);
+    bus.write_byte(0xdc03, 0x00);
+
+    bus.mouse_event(3, -2, true, false);
+    // The fire button shows up straight away but the position waits for a frame
+    assert_eq!(0xef, bus.read_byte(0xdc01));
+    assert_eq!((0x00, 0x00), (bus.read_byte(0xd419), bus.read_byte(0xd41a)));
+
+    for _ in 0..(312 * 63 * 2) {
+        bus.cycle(&mut screen);
+    }
+    assert_eq!((0x06, 0x04), (bus.read_byte(0xd419), bus.read_byte(0xd41a)));
+
+    bus.mouse_event(0, 0, false, false);
+    assert_eq!(0xff, bus.read_byte(0xdc01));
+}
+
+#[test]
+fn mouse_event_log_round_trip() {
+    let line = EmulatorEvent::Mouse(-3, 12, true, false).to_log_line(7);
+    assert_eq!("7 mouse -3 12 1 0", line);
+
+    match EmulatorEvent::from_log_line(&line) {
+        Some((7, EmulatorEvent::Mouse(-3, 12, true, false))) => { },
+        _ => panic!("Mouse event not parsed"),
+    }
+    assert!(EmulatorEvent::from_log_line("7 mouse -3 12 1").is_none());
+    assert!(EmulatorEvent::from_log_line("7 mouse -3 12 1 2").is_none());
+}
+
+// Test joystick 2
+#[test]
+fn joystick_reads_on_port_a() {
+    let mut bus = new_bus();
+    bus.write_byte(0xdc02, 0x00);
+
+    // Up and left with fire held
+    bus.joystick_event(joystick_lines(-0x7fff, -0x7fff, true));
+    assert_eq!(0xea, bus.read_byte(0xdc00));
+
+    bus.joystick_event(joystick_lines(0, 0, false));
+    assert_eq!(0xff, bus.read_byte(0xdc00));
+}
+
+#[test]
+fn joystick_lines_ignore_dead_zone() {
+    assert_eq!(0xff, joystick_lines(0x1000, -0x1000, false));
+    assert_eq!(0xfd, joystick_lines(0, 0x7fff, false));
+    assert_eq!(0xf7, joystick_lines(0x7fff, 0, false));
+    assert_eq!(0xef, joystick_lines(0, 0, true));
+}
+
+#[test]
+fn joystick_event_log_round_trip() {
+    let line = EmulatorEvent::Joystick(0xea).to_log_line(42);
+    assert_eq!("42 joystick 234", line);
+
+    match EmulatorEvent::from_log_line(&line) {
+        Some((42, EmulatorEvent::Joystick(0xea))) => { },
+        _ => panic!("Joystick event not parsed"),
+    }
+    assert!(EmulatorEvent::from_log_line("42 joystick").is_none());
+}
+
+// Test status output
+#[test]
+fn status_only_shown_when_debugging() {
+    let bus = Bus::new(false, 320, 240);
+    assert!(!bus.show_status());
+
+    let mut bus = Bus::new(true, 320, 240);
+    bus.debug_command("run");
+    assert!(bus.show_status());
+
+    bus.set_quiet(true);
+    assert!(!bus.show_status());
+}
+
+// Test loading ROMs from memory
+#[test]
+fn load_roms_from_bytes_banked() {
+    let mut bus = new_bus();
+
+    bus.write_byte(0x0001, 0x30);
+    bus.write_byte(0xa000, 0x11);
+    bus.write_byte(0xd000, 0x22);
+    bus.write_byte(0xe000, 0x33);
+    bus.write_byte(0x0001, 0x37);
+    bus.load_roms_from_bytes(&[0xcc; 8192], &[0xbb; 8192], &[0xdd; 4096]).unwrap();
+
+    assert_eq!(0xbb, bus.read_byte(0xa000));
+    assert_eq!(0xcc, bus.read_byte(0xffff));
+
+    // Character ROM replaces I/O
+    bus.write_byte(0x0001, 0x33);
+    assert_eq!(0xdd, bus.read_byte(0xd000));
+
+    // RAM underneath is still there with the ROMs banked out
+    bus.write_byte(0x0001, 0x30);
+    assert_eq!(0x11, bus.read_byte(0xa000));
+    assert_eq!(0x22, bus.read_byte(0xd000));
+    assert_eq!(0x33, bus.read_byte(0xe000));
+}
+
+#[test]
+fn charen_selects_d000_contents() {
+    let mut bus = new_bus();
+    bus.load_roms_from_bytes(&[0xcc; 8192], &[0xbb; 8192], &[0xdd; 4096]).unwrap();
+    // Sprite 0's X coordinate
+    bus.write_byte(0x0001, 0x37);
+    bus.write_byte(0xd000, 0x11);
+    bus.write_byte(0x0001, 0x30);
+    bus.write_byte(0xd000, 0x22);
+
+    // RAM, character ROM three times, RAM, then I/O three times
+    let expected = [0x22, 0xdd, 0xdd, 0xdd, 0x22, 0x11, 0x11, 0x11];
+    for (port, &value) in expected.iter().enumerate() {
+        bus.write_byte(0x0001, 0x30 | port as u8);
+        assert_eq!(value, bus.read_byte(0xd000), "Wrong value at $D000 with port = ${:0>2X}", port);
+    }
+}
+
+#[test]
+fn load_roms_from_bytes_checks_size() {
+    let mut bus = new_bus();
+
+    assert!(bus.load_roms_from_bytes(&[0; 8192], &[0; 8192], &[0; 2048]).is_err());
+    assert!(bus.load_roms_from_bytes(&[0xcc; 100], &[0xbb; 8192], &[0; 4096]).is_err());
+
+    // Nothing is loaded if any image is the wrong size
+    assert_eq!(0x00, bus.read_byte(0xa000));
+}
+
+// Test swapping ROMs while running
+#[test]
+fn reload_roms_from_bytes_replaces_rom() {
+    let mut bus = new_bus();
+    let mut kernal = vec![0xcc; 8192];
+    bus.load_roms_from_bytes(&kernal, &[0xbb; 8192], &[0xdd; 4096]).unwrap();
+    assert_eq!(0xcc, bus.read_byte(0xe000));
+
+    kernal[0] = 0x42;
+    bus.load_roms_from_bytes(&kernal, &[0xbb; 8192], &[0xdd; 4096]).unwrap();
+    assert_eq!(0x42, bus.read_byte(0xe000));
+    assert_eq!(0xcc, bus.read_byte(0xe001));
+
+    // A bad image leaves the old one in place
+    assert!(bus.load_roms_from_bytes(&[0x11; 100], &[0xbb; 8192], &[0xdd; 4096]).is_err());
+    assert_eq!(0x42, bus.read_byte(0xe000));
+}
+
+#[test]
+fn reload_roms_keeps_missing_rom() {
+    let kernal_file = temp_file("rust-c64-test-reload-kernal.bin");
+    let basic_file = temp_file("rust-c64-test-reload-basic.bin");
+    let char_file = temp_file("rust-c64-test-reload-char.bin");
+    File::create(&kernal_file).unwrap().write_all(&[0xcc; 8192]).unwrap();
+    File::create(&basic_file).unwrap().write_all(&[0xbb; 8192]).unwrap();
+    File::create(&char_file).unwrap().write_all(&[0xdd; 4096]).unwrap();
+
+    let mut bus = new_bus();
+    assert!(bus.reload_roms().is_err());
+    bus.load_roms(&kernal_file, &basic_file, &char_file);
+
+    let mut kernal = vec![0xcc; 8192];
+    kernal[0x1fff] = 0x42;
+    File::create(&kernal_file).unwrap().write_all(&kernal).unwrap();
+    fs::remove_file(&basic_file).unwrap();
+    File::create(&char_file).unwrap().write_all(&[0xee; 2048]).unwrap();
+
+    let err = bus.reload_roms().unwrap_err();
+    assert!(err.contains("BASIC ROM"));
+    assert!(err.contains("character ROM"));
+    assert_eq!(0x42, bus.read_byte(0xffff));
+    assert_eq!(0xbb, bus.read_byte(0xa000));
+    bus.write_byte(0x0001, 0x33);
+    assert_eq!(0xdd, bus.read_byte(0xd000));
+}
+
+// Test finding ROM files
+#[test]
+fn firmware_dir_searched_first() {
+    let dir = env::temp_dir().join("rust-c64-test-firmware");
+    fs::create_dir_all(&dir).unwrap();
+    File::create(dir.join("kernal")).unwrap().write_all(&[0; 8192]).unwrap();
+
+    let dirs = rom_dirs(Some(dir.to_string_lossy().into_owned()));
+    assert_eq!(dir, dirs[0]);
+    assert_eq!(dir.join("kernal").to_string_lossy(), find_rom("kernal", &dirs));
+}
+
+#[test]
+fn missing_rom_falls_back_to_last_dir() {
+    let dirs = rom_dirs(Some("/nonexistent/rust-c64".to_string()));
+    let last = dirs.last().unwrap().join("no-such-rom");
+    assert_eq!(last.to_string_lossy(), find_rom("no-such-rom", &dirs));
+}
+
+#[test]
+fn parse_dump_spec_fields() {
+    assert_eq!(Some((0xc000, 256, "out.bin".to_string())), parse_dump_spec("c000:256:out.bin"));
+    assert_eq!(Some((0x0400, 0x3e8, "a:b".to_string())), parse_dump_spec("$0400:$3e8:a:b"));
+    assert_eq!(None, parse_dump_spec("c000:256"));
+    assert_eq!(None, parse_dump_spec("xyz:256:out.bin"));
+}
+
+// Addresses on the command line read the same as in the monitor
+#[test]
+fn parse_number_matches_monitor() {
+    assert_eq!(Ok(0xd420), parse_number("$d420"));
+    assert_eq!(Ok(0xd420), parse_number("54304"));
+    assert_eq!(Ok(0xee), parse_number("%11101110"));
+    assert!(parse_number("$fcxx").is_err());
+    assert!(parse_number("65536").is_err());
+}
+
+#[test]
+fn exit_dump_written_on_quit() {
+    let mut bus = Bus::new(false, 320, 240);
+    let fname = temp_file("rust-c64-test-exit-dump.bin");
+    let log = temp_file("rust-c64-test-exit-dump.log");
+
+    // LDA #$42; STA $C001; JMP $FCE7 at the reset address
+    let mut kernal = [0u8; 8192];
+    kernal[0x1ce2..0x1cea].copy_from_slice(&[0xa9, 0x42, 0x8d, 0x01, 0xc0, 0x4c, 0xe7, 0xfc]);
+    kernal[0x1ffc..0x1ffe].copy_from_slice(&[0xe2, 0xfc]);
+    bus.load_roms_from_bytes(&kernal, &[0; 8192], &[0; 4096]).unwrap();
+    bus.add_exit_dump(0xc000, 3, &fname);
+
+    File::create(&log).unwrap().write_all(b"1000 quit\n").unwrap();
+    bus.load_playback(&log).unwrap();
+
+    let (screen_tx, _screen_rx) = mpsc::channel();
+    let (_event_tx, event_rx) = mpsc::channel();
+    bus.run(985248444, screen_tx, event_rx);
+
+    let mut data = Vec::new();
+    File::open(&fname).unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(vec![0x00, 0x42, 0x00], data);
+}
+
+// Run a KERNAL that counts loops while RUN/STOP is held, with events from a playback file and
+// maybe recording them. Returns the bus after it quits.
+fn run_stop_counter(playback: &str, record: Option<&str>) -> Bus {
+    let mut kernal = [0u8; 8192];
+    let program = [
+        0xa9, 0xff, 0x8d, 0x02, 0xdc,   // LDA #$FF; STA $DC02
+        0xa9, 0x00, 0x8d, 0x03, 0xdc,   // LDA #$00; STA $DC03
+        0xa9, 0x7f, 0x8d, 0x00, 0xdc,   // LDA #$7F; STA $DC00
+        0xad, 0x01, 0xdc, 0x30, 0xfb,   // $FCF1: LDA $DC01; BMI $FCF1
+        0xee, 0x00, 0xc0, 0xd0, 0xf6,   // INC $C000; BNE $FCF1
+        0xee, 0x01, 0xc0, 0x4c, 0xf1, 0xfc, // INC $C001; JMP $FCF1
+    ];
+    kernal[0x1ce2..0x1ce2 + program.len()].copy_from_slice(&program);
+    kernal[0x1ffc..0x1ffe].copy_from_slice(&[0xe2, 0xfc]);
+
+    let mut bus = Bus::new(false, 320, 240);
+    bus.load_roms_from_bytes(&kernal, &[0; 8192], &[0; 4096]).unwrap();
+    if let Some(f) = record {
+        bus.record_events(f).unwrap();
+    }
+    bus.load_playback(playback).unwrap();
+    let (screen_tx, _screen_rx) = mpsc::channel();
+    let (_event_tx, event_rx) = mpsc::channel();
+    bus.run(985248444, screen_tx, event_rx);
+    bus
+}
+
+// Record a session while playing back a log, then play the recording back to the same state
+#[test]
+fn record_and_playback_round_trip() {
+    let log = temp_file("rust-c64-test-round-trip.log");
+    let recording = temp_file("rust-c64-test-round-trip-recording.log");
+    let first_snapshot = temp_file("rust-c64-test-round-trip-first.bin");
+    let replay_snapshot = temp_file("rust-c64-test-round-trip-replay.bin");
+
+    let events = "20000 keydown 0 Escape\n50000 keyup 0 Escape\n70000 keydown 0 Escape\n100000 quit\n";
+    File::create(&log).unwrap().write_all(events.as_bytes()).unwrap();
+
+    let first = run_stop_counter(&log, Some(&recording));
+    first.save_snapshot(&first_snapshot).unwrap();
+    let mut text = String::new();
+    File::open(&recording).unwrap().read_to_string(&mut text).unwrap();
+    assert_eq!(events, text);
+
+    let replay = run_stop_counter(&recording, None);
+    replay.save_snapshot(&replay_snapshot).unwrap();
+
+    assert!(first.read_byte(0xc000) != 0 || first.read_byte(0xc001) != 0);
+    assert_eq!(first.pc(), replay.pc());
+    assert!(fs::read(&first_snapshot).unwrap() == fs::read(&replay_snapshot).unwrap());
+}
+
+#[test]
+fn freeze_breaks_at_nmi_handler() {
+    let mut bus = Bus::new(false, 320, 240);
+    let mut screen = Screen::new(320, 240);
+
+    // JMP $FCE2 at the reset address and the NMI handler at $FD00
+    let mut kernal = [0u8; 8192];
+    kernal[0x1ce2..0x1ce5].copy_from_slice(&[0x4c, 0xe2, 0xfc]);
+    kernal[0x1ffa..0x1ffe].copy_from_slice(&[0x00, 0xfd, 0xe2, 0xfc]);
+    bus.load_roms_from_bytes(&kernal, &[0; 8192], &[0; 4096]).unwrap();
+    bus.reset();
+
+    for _ in 0..100 {
+        bus.cycle(&mut screen);
+    }
+    assert!(!bus.stopped());
+
+    bus.freeze();
+    for _ in 0..100 {
+        bus.cycle(&mut screen);
+        if bus.stopped() {
+            break;
+        }
+    }
+
+    assert!(bus.stopped());
+    assert_eq!(0xfd00, bus.pc());
+}
+
+#[test]
+fn freeze_event_log_round_trip() {
+    let line = EmulatorEvent::Freeze.to_log_line(1234);
+    assert_eq!("1234 freeze", line);
+
+    match EmulatorEvent::from_log_line(&line) {
+        Some((1234, EmulatorEvent::Freeze)) => { },
+        _ => panic!("Freeze event not parsed"),
+    }
+}
+
+#[test]
+fn paddles_read_through_sid() {
+    let mut bus = new_bus();
+    bus.set_paddles(0x40, 0xc8);
+
+    assert_eq!(0x40, bus.read_byte(0xd419));
+    assert_eq!(0xc8, bus.read_byte(0xd41a));
+}
+
+#[test]
+fn paddle_input_mapping() {
+    assert_eq!(Some(PaddleInput::Mouse), parse_paddle_input("mouse"));
+    assert_eq!(Some(PaddleInput::Joystick), parse_paddle_input("joystick"));
+    assert_eq!(None, parse_paddle_input("trackball"));
+
+    assert_eq!((0, 255), mouse_paddles(0, 239, 320, 240));
+    assert_eq!((127, 0), mouse_paddles(160, -5, 320, 240));
+    assert_eq!((255, 255), mouse_paddles(400, 300, 320, 240));
+
+    assert_eq!(0, axis_paddle(-32768));
+    assert_eq!(128, axis_paddle(0));
+    assert_eq!(255, axis_paddle(32767));
+}
+
+#[test]
+fn paddles_event_log_round_trip() {
+    let line = EmulatorEvent::Paddles(12, 250).to_log_line(99);
+    assert_eq!("99 paddles 12 250", line);
+
+    match EmulatorEvent::from_log_line(&line) {
+        Some((99, EmulatorEvent::Paddles(12, 250))) => { },
+        _ => panic!("Paddles event not parsed"),
+    }
+    assert!(EmulatorEvent::from_log_line("99 paddles 12").is_none());
+}
+
+fn window_event(id: WindowEventId) -> Event {
+    Event::Window { timestamp: 0, window_id: 1, win_event_id: id, data1: 0, data2: 0 }
+}
+
+#[test]
+fn focus_changes_pause_emulator() {
+    match focus_event(&window_event(WindowEventId::FocusLost)) {
+        Some(EmulatorEvent::Pause) => { },
+        _ => panic!("Losing focus didn't pause"),
+    }
+    match focus_event(&window_event(WindowEventId::FocusGained)) {
+        Some(EmulatorEvent::Resume) => { },
+        _ => panic!("Gaining focus didn't resume"),
+    }
+    assert!(focus_event(&window_event(WindowEventId::Moved)).is_none());
+    assert!(focus_event(&Event::Quit { timestamp: 0 }).is_none());
+}
+
+// Test the frame rate overlay
+#[test]
+fn fps_counter_from_frame_times() {
+    let mut counter = FpsCounter::new();
+    assert_eq!(0.0, counter.fps());
+
+    // 50 frames a second with a PAL frame's worth of cycles each
+    for i in 0..11u32 {
+        counter.add_frame(Duration::from_millis(20 * i as u64), 19656 * i as u64);
+    }
+    assert!((counter.fps() - 50.0).abs() < 0.01);
+    assert!((counter.khz() - 982.8).abs() < 0.1);
+}
+
+#[test]
+fn fps_counter_uses_recent_frames() {
+    let mut counter = FpsCounter::new();
+
+    // Slow frames at first, then 60 per second
+    for i in 0..10u64 {
+        counter.add_frame(Duration::from_millis(100 * i), 0);
+    }
+    let start = Duration::from_millis(1000);
+    for i in 0..100u32 {
+        counter.add_frame(start + Duration::from_millis(50) * i / 3, 0);
+    }
+    assert!((counter.fps() - 60.0).abs() < 0.01);
+}
+
+#[test]
+fn fps_overlay_draws_in_corner() {
+    let mut screen = Screen::new(320, 240);
+    for y in 0..240 {
+        for x in 0..320 {
+            screen.set_pixel_at(x, y, 0x123456);
+        }
+    }
+    draw_text(&mut screen, 1, 1, "1");
+
+    // The middle column of a 1 is lit and it has a black background
+    assert_eq!(0xffffff, screen.pixel_at(2, 2));
+    assert_eq!(0x000000, screen.pixel_at(1, 2));
+    assert_eq!(0x123456, screen.pixel_at(0, 0));
+    assert_eq!(0x123456, screen.pixel_at(20, 20));
+}
+
+// Test the execution heatmap
+#[test]
+fn exec_map_counts_loop() {
+    let mut bus = Bus::new(false, 320, 240);
+    let mut screen = Screen::new(320, 240);
+    let fname = temp_file("rust-c64-test-exec-map.txt");
+
+    // LDX #$05; DEX; BNE $FCE4; JMP $FCE7
+    reset_to_ram(&mut bus);
+    let program = [0xa2, 0x05, 0xca, 0xd0, 0xfd, 0x4c, 0xe7, 0xfc];
+    for (i, b) in program.iter().enumerate() {
+        bus.write_byte(0xfce2 + i, *b);
+    }
+    bus.set_exec_map_file(&fname);
+    for _ in 0..100 {
+        bus.cycle(&mut screen);
+    }
+
+    assert_eq!(1, bus.exec_count(0xfce2));
+    assert_eq!(5, bus.exec_count(0xfce4));
+    assert_eq!(5, bus.exec_count(0xfce5));
+    assert_eq!(0, bus.exec_count(0xfce3));
+
+    // The JMP loop is the hottest
+    bus.write_exit_files();
+    let mut text = String::new();
+    File::open(&fname).unwrap().read_to_string(&mut text).unwrap();
+    assert_eq!(4, text.lines().count());
+    assert!(text.lines().next().unwrap().contains("JMP"));
+    assert!(text.contains("         5  $FCE4  CA"));
+}
+
+// Test reproducibility with a seed
+fn seeded_snapshot(seed: u64, name: &str) -> Vec<u8> {
+    let mut bus = Bus::new(false, 320, 240);
+    let mut screen = Screen::new(320, 240);
+    let fname = temp_file(name);
+
+    bus.set_seed(seed);
+    bus.fill_ram_pattern();
+    reset_to_ram(&mut bus);
+
+    // Copy 256 samples of voice 3's noise output to $C000
+    let program = [
+        0xa9, 0xff, 0x8d, 0x0f, 0xd4,   // LDA #$FF; STA $D40F
+        0xa9, 0x80, 0x8d, 0x12, 0xd4,   // LDA #$80; STA $D412
+        0xad, 0x1b, 0xd4,               // LDA $D41B
+        0x9d, 0x00, 0xc0,               // STA $C000,X
+        0xe8, 0xd0, 0xf7,               // INX; BNE $FCEC
+        0x4c, 0xf5, 0xfc,               // JMP $FCF5
+    ];
+    for (i, b) in program.iter().enumerate() {
+        bus.write_byte(0xfce2 + i, *b);
+    }
+    for _ in 0..5000 {
+        bus.cycle(&mut screen);
+    }
+
+    bus.save_snapshot(&fname).unwrap();
+    let mut data = Vec::new();
+    File::open(&fname).unwrap().read_to_end(&mut data).unwrap();
+    data
+}
+
+#[test]
+fn same_seed_is_deterministic() {
+    let first = seeded_snapshot(1234, "rust-c64-test-seed-a.bin");
+    let second = seeded_snapshot(1234, "rust-c64-test-seed-b.bin");
+    assert!(first == second);
+
+    let other = seeded_snapshot(4321, "rust-c64-test-seed-c.bin");
+    assert!(first != other);
+    assert!(first[0xc000..0xc100] != other[0xc000..0xc100]);
+}
+
+// Power on the whole machine with a seed and get the RAM below BASIC
+fn power_on_ram(seed: u64) -> Vec<u8> {
+    let kernal = temp_file("rust-c64-test-power-on-kernal.bin");
+    let basic = temp_file("rust-c64-test-power-on-basic.bin");
+    let chars = temp_file("rust-c64-test-power-on-chargen.bin");
+    File::create(&kernal).unwrap().write_all(&[0; 8192]).unwrap();
+    File::create(&basic).unwrap().write_all(&[0; 8192]).unwrap();
+    File::create(&chars).unwrap().write_all(&[0; 4096]).unwrap();
+
+    let mut c64 = C64::new_pal(false);
+    c64.set_seed(seed);
+    c64.set_kernal_rom(&kernal);
+    c64.set_basic_rom(&basic);
+    c64.set_char_rom(&chars);
+    c64.power_on();
+    (0x0800..0xa000).map(|addr| c64.bus.read_byte(addr)).collect()
+}
+
+#[test]
+fn seed_reaches_ram_at_power_on() {
+    let first = power_on_ram(1234);
+    assert!(first == power_on_ram(1234));
+    assert!(first != power_on_ram(4321));
+
+    // Most of RAM still has the stripes
+    assert_eq!(0x00, first[0]);
+    assert_eq!(0xff, first[0x40]);
+}
+
+// Test RAM initialization
+#[test]
+fn fill_ram_pattern_stripes() {
+    let mut bus = Bus::new(false, 320, 240);
+    bus.write_byte(0x0000, 0x2f);
+    bus.write_byte(0x0001, 0x30);
+    bus.fill_ram_pattern();
+
+    // Blocks of 64 bytes alternate between $00 and $FF with a few random bits
+    let mut matches = 0;
+    for addr in 0x0002..0x10000 {
+        let expected = if (addr / 64) % 2 == 0 { 0x00 } else { 0xff };
+        if bus.read_byte(addr) == expected {
+            matches += 1;
+        }
+    }
+    assert!(matches > 0xfe00);
+
+    assert_eq!(0x00, bus.read_byte(0x0800));
+    assert_eq!(0xff, bus.read_byte(0x0840));
+    assert_eq!(0x00, bus.read_byte(0xc000));
+    assert_eq!(0xff, bus.read_byte(0xc07f));
+
+    bus.clear_ram();
+    assert_eq!(0x00, bus.read_byte(0x0840));
+}
+
+#[test]
+fn initialize_fills_pattern_without_image() {
+    let mut bus = Bus::new(false, 320, 240);
+    bus.initialize(None);
+
+    assert_eq!(0x00, bus.read_byte(0x0800));
+    assert_eq!(0xff, bus.read_byte(0x0840));
+    assert_eq!(0x00, bus.read_byte(0x4000));
+    assert_eq!(0xff, bus.read_byte(0x4040));
+
+    // An image replaces the pattern
+    let fname = temp_file("rust-c64-test-ram-image.bin");
+    File::create(&fname).unwrap().write_all(&[0xaa; 65536]).unwrap();
+    bus.initialize(Some(&fname));
+    assert_eq!(0xaa, bus.read_byte(0x0800));
+    assert_eq!(0xaa, bus.read_byte(0x0840));
+}
+
+#[test]
+fn reset_clear_command() {
+    let mut bus = new_bus();
+
+    bus.write_byte(0x0800, 0x42);
+    bus.debug_command("reset");
+    assert_eq!(0x42, bus.read_byte(0x0800));
+
+    bus.debug_command("reset clear");
+    assert_eq!(0x00, bus.read_byte(0x0800));
+    assert!(!bus.cpu().at_instruction_start());
+}
+
+// Test raw video output
+#[test]
+fn video_out_writes_whole_frames() {
+    let mut bus = Bus::new(false, 320, 240);
+    let fname = temp_file("rust-c64-test-video.rgb");
+    let log = temp_file("rust-c64-test-video.log");
+
+    // JMP $FCE2 at the reset address
+    let mut kernal = [0u8; 8192];
+    kernal[0x1ce2..0x1ce5].copy_from_slice(&[0x4c, 0xe2, 0xfc]);
+    kernal[0x1ffc..0x1ffe].copy_from_slice(&[0xe2, 0xfc]);
+    bus.load_roms_from_bytes(&kernal, &[0; 8192], &[0; 4096]).unwrap();
+
+    bus.set_video_out(&fname).unwrap();
+    File::create(&log).unwrap().write_all(b"40000 quit\n").unwrap();
+    bus.load_playback(&log).unwrap();
+
+    let (screen_tx, screen_rx) = mpsc::channel();
+    let (_event_tx, event_rx) = mpsc::channel();
+    bus.run(985248444, screen_tx, event_rx);
+
+    let frames = screen_rx.try_iter().count();
+    let mut data = Vec::new();
+    File::open(&fname).unwrap().read_to_end(&mut data).unwrap();
+    assert!(frames >= 2);
+    assert_eq!(320 * 240 * 3 * frames, data.len());
+}
+
+// Run the SIDs for a frame with audio output on and get the samples as left and right pairs
+fn audio_frame(bus: &mut Bus) -> Vec<(i16, i16)> {
+    let mut screen = Screen::new(320, 240);
+    let fname = temp_file("rust-c64-test-audio.raw");
+    bus.set_audio_out(&fname).unwrap();
+    while !bus.step_system(&mut screen) { }
+
+    let mut data = Vec::new();
+    File::open(&fname).unwrap().read_to_end(&mut data).unwrap();
+    let sample = |i: usize| ((data[i + 1] as u16) << 8 | data[i] as u16) as i16;
+    (0..data.len()).step_by(4).map(|i| (sample(i), sample(i + 2))).collect()
+}
+
+#[test]
+fn audio_out_leaves_out_muted_voices() {
+    let mut bus = run_loop(&[0x4c, 0xe2, 0xfc], 0);
+
+    // Pulse waves with zero width are always high
+    bus.write_byte(0xd404, 0x41);
+    bus.write_byte(0xd412, 0x41);
+    bus.write_byte(0xd418, 0x0f);
+    bus.set_voice_muted(1, true);
+
+    let samples = audio_frame(&mut bus);
+    assert!(samples.len() > 800, "{} samples", samples.len());
+    assert!(samples.iter().all(|&s| s == (0x7ff * 5, 0x7ff * 5)));
+}
+
+#[test]
+fn audio_out_puts_second_sid_on_right() {
+    let mut bus = run_loop(&[0x4c, 0xe2, 0xfc], 0);
+    bus.set_stereo_sid(0xde00).unwrap();
+
+    // Voice 1 of the first SID plays and the second SID is silent
+    bus.write_byte(0xd404, 0x41);
+    bus.write_byte(0xd418, 0x0f);
+    bus.write_byte(0xde18, 0x0f);
+
+    let samples = audio_frame(&mut bus);
+    assert!(samples.len() > 800, "{} samples", samples.len());
+    assert!(samples.iter().all(|&s| s == (0x7ff * 5, 0)));
+}
+
+// Run the VIC with ECM on and the given CR1 value, then read the background color of each of the
+// first four cells on the top character row
+fn ecm_cell_colors(cr1: u8) -> Vec<u32> {
+    let mut bus = new_bus();
+    let mut screen = Screen::new(320, 240);
+    reset_to_ram(&mut bus);
+    bus.write_byte(0xfce2, 0x4c);
+    bus.write_byte(0xfce3, 0xe2);
+    bus.write_byte(0xfce4, 0xfc);
+
+    // Screen at $0400 and characters at $3000, which are all blank
+    bus.write_byte(0xd018, 0x1c);
+    for (i, &code) in [0x01u8, 0x41, 0x81, 0xc1].iter().enumerate() {
+        bus.write_byte(0x0400 + i, code);
+    }
+    for (i, color) in (0x22..0x26).enumerate() {
+        bus.write_byte(0xd021 + i, color);
+    }
+    bus.write_byte(0xd011, cr1);
+
+    for _ in 0..(312 * 63 * 2) {
+        bus.cycle(&mut screen);
+    }
+    // The top of the display window is 20 lines down the screen
+    (0..4).map(|i| screen.pixel_at(i * 8 + 4, 22)).collect()
+}
+
+// Test extended background color mode
+#[test]
+fn ecm_selects_background_per_cell() {
+    // Red, cyan, purple, green
+    assert_eq!(vec![0x0068372b, 0x0070a4b2, 0x006f3d86, 0x00588d43], ecm_cell_colors(0x5b));
+}
+
+#[test]
+fn ecm_off_uses_background_0() {
+    assert_eq!(vec![0x0068372b; 4], ecm_cell_colors(0x1b));
+}
+
+#[test]
+fn ecm_with_bitmap_mode_is_black() {
+    assert_eq!(vec![0; 4], ecm_cell_colors(0x7b));
+}
+
+#[test]
+fn vstep_only_advances_vic() {
+    let mut bus = run_loop(&[0x4c, 0xe2, 0xfc], 20);
+    let mut screen = Screen::new(320, 240);
+
+    let vic_cycles = bus.vic().cycles();
+    let cpu_cycles = bus.cpu().cycles();
+    bus.debug_command("vstep");
+    bus.cycle(&mut screen);
+
+    assert_eq!(vic_cycles + 1, bus.vic().cycles());
+    assert_eq!(cpu_cycles, bus.cpu().cycles());
+
+    // Back to normal on the next cycle
+    bus.cycle(&mut screen);
+    assert_eq!(cpu_cycles + 1, bus.cpu().cycles());
+}
+
+#[test]
+fn step_system_advances_vic_and_cpu() {
+    let mut bus = run_loop(&[0x4c, 0xe2, 0xfc], 20);
+    let mut screen = Screen::new(320, 240);
+
+    let vic_cycles = bus.vic().cycles();
+    let cpu_cycles = bus.cpu().cycles();
+    bus.step_system(&mut screen);
+
+    assert_eq!(vic_cycles + 1, bus.vic().cycles());
+    assert_eq!(cpu_cycles + 1, bus.cpu().cycles());
+
+    // A cycle step replaces a VIC-only step that was asked for
+    bus.debug_command("vstep");
+    bus.debug_command("cycle");
+    bus.step_system(&mut screen);
+    assert_eq!(vic_cycles + 2, bus.vic().cycles());
+    assert_eq!(cpu_cycles + 2, bus.cpu().cycles());
+}
+
+// Test the border crop
+// Run a JMP * loop for raster line $33, which is a bad line with YSCROLL 3, and count the cycles
+// the CPU got
+fn cpu_cycles_on_bad_line(cycle_exact: bool) -> u64 {
+    let mut bus = Bus::new(false, 320, 240);
+    let mut screen = Screen::new(320, 240);
+    bus.set_cycle_exact(cycle_exact);
+    reset_to_ram(&mut bus);
+    for (i, b) in [0x4c, 0xe2, 0xfc].iter().enumerate() {
+        bus.write_byte(0xfce2 + i, *b);
+    }
+    bus.write_byte(0xd011, 0x1b);
+
+    while bus.read_byte(0xd012) != 0x33 {
+        bus.cycle(&mut screen);
+    }
+    let start = bus.cpu().cycles();
+    for _ in 0..63 {
+        bus.cycle(&mut screen);
+    }
+    bus.cpu().cycles() - start
+}
+
+// The VIC only steals cycles from the CPU on a bad line in cycle-exact mode. It takes 40 for the
+// fetches, and a CPU that only reads also loses the 3 cycles BA is low before them.
+#[test]
+fn cycle_exact_stalls_cpu_on_bad_line() {
+    assert_eq!(63, cpu_cycles_on_bad_line(false));
+    assert_eq!(20, cpu_cycles_on_bad_line(true));
+}
+
+#[test]
+fn crop_area_is_display_window() {
+    assert_eq!((0, 20, 320, 200), crop_area(320, 240));
+}
+
+#[test]
+fn crop_area_stays_on_screen() {
+    assert_eq!((0, 20, 320, 100), crop_area(320, 120));
+    assert_eq!((0, 10, 160, 0), crop_area(160, 10));
+}
+
+// Test the pacer's sleep decisions against a made up clock
+#[test]
+fn pacer_sleeps_while_ahead() {
+    // 1 MHz is 1000 cycles per ms
+    let mut pacer = Pacer::new(1_000_000_000);
+    assert_eq!(None, pacer.sleep_time());
+
+    // Twice as fast as it should be
+    pacer.sample(10000, Duration::from_millis(5));
+    assert_eq!(Some(Duration::new(0, 100)), pacer.sleep_time());
+    pacer.sample(20000, Duration::from_millis(10));
+    assert_eq!(Some(Duration::new(0, 200)), pacer.sleep_time());
+    assert_eq!(2000f32, pacer.speed_khz());
+
+    // Slower than it should be
+    pacer.sample(30000, Duration::from_millis(40));
+    assert_eq!(Some(Duration::new(0, 100)), pacer.sleep_time());
+    pacer.sample(40000, Duration::from_millis(80));
+    pacer.sample(50000, Duration::from_millis(100));
+    assert_eq!(None, pacer.sleep_time());
+}
+
+#[test]
+fn pacer_only_samples_on_interval() {
+    let mut pacer = Pacer::new(1_000_000_000);
+
+    // Nothing has been measured yet, so there's nothing to slow down
+    for cycles in 0..9999 {
+        assert_eq!(None, pacer.should_sleep(cycles));
+    }
+    assert_eq!(0f32, pacer.speed_khz());
+}
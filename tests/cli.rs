@@ -0,0 +1,111 @@
+// Copyright 2016 Peter Beard
+// Distributed under the GNU GPL v3. For full terms, see the LICENSE file.
+use std::process::Command;
+
+// Run the emulator binary with the given arguments
+fn run_c64(args: &[&str]) -> (Option<i32>, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_c64"))
+        .args(args)
+        .output()
+        .expect("Failed to run c64");
+    (output.status.code(), String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+#[test]
+fn bad_clock_type_exits_cleanly() {
+    let (code, stderr) = run_c64(&["--clock", "SECAM"]);
+
+    assert_eq!(Some(1), code);
+    assert!(stderr.contains("Invalid clock type: SECAM"));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn unknown_option_exits_cleanly() {
+    let (code, stderr) = run_c64(&["--not-an-option"]);
+
+    assert_eq!(Some(1), code);
+    assert!(stderr.contains("not-an-option"));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn bad_break_address_exits_cleanly() {
+    let (code, stderr) = run_c64(&["--break-at", "$fcxx"]);
+
+    assert_eq!(Some(1), code);
+    assert!(stderr.contains("Invalid address: $fcxx"));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn bad_instruction_count_exits_cleanly() {
+    let (code, stderr) = run_c64(&["--max-instructions", "lots"]);
+
+    assert_eq!(Some(1), code);
+    assert!(stderr.contains("Invalid instruction count: lots"));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn bad_georam_size_exits_cleanly() {
+    let (code, stderr) = run_c64(&["--georam", "100"]);
+
+    assert_eq!(Some(1), code);
+    assert!(stderr.contains("Invalid GEORAM size: 100K"));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn bad_paddle_input_exits_cleanly() {
+    let (code, stderr) = run_c64(&["--paddles", "trackball"]);
+
+    assert_eq!(Some(1), code);
+    assert!(stderr.contains("Invalid paddle input: trackball"));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn bad_kernal_log_exits_cleanly() {
+    let (code, stderr) = run_c64(&["--kernal-log", "/nonexistent/rust-c64/kernal.log"]);
+
+    assert_eq!(Some(1), code);
+    assert!(stderr.contains("Failed to create KERNAL log file /nonexistent/rust-c64/kernal.log"));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn bad_video_out_exits_cleanly() {
+    let (code, stderr) = run_c64(&["--video-out", "/nonexistent/rust-c64/video.raw"]);
+
+    assert_eq!(Some(1), code);
+    assert!(stderr.contains("Failed to create video output file /nonexistent/rust-c64/video.raw"));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn bad_io_trace_file_exits_cleanly() {
+    let (code, stderr) = run_c64(&["--io-trace", "vic", "--io-trace-file", "/nonexistent/rust-c64/io.log"]);
+
+    assert_eq!(Some(1), code);
+    assert!(stderr.contains("Failed to create I/O trace file /nonexistent/rust-c64/io.log"));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn bad_record_file_exits_cleanly() {
+    let (code, stderr) = run_c64(&["--record", "/nonexistent/rust-c64/events.log"]);
+
+    assert_eq!(Some(1), code);
+    assert!(stderr.contains("Failed to create event log file /nonexistent/rust-c64/events.log"));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn bad_playback_file_exits_cleanly() {
+    let (code, stderr) = run_c64(&["--playback", "/nonexistent/rust-c64/events.log"]);
+
+    assert_eq!(Some(1), code);
+    assert!(stderr.contains("Failed to open playback file"));
+    assert!(!stderr.contains("panicked"));
+}